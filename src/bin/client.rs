@@ -1,568 +1,169 @@
-use std::{
-    env,
-    net::{ToSocketAddrs, UdpSocket},
-    sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
-    },
-    thread,
-    time::{Duration, Instant},
-};
+use std::{env, sync::Arc, time::Instant};
 
 use od_nat_piercer::{
-    client::{
-        handlers::*,
-        networking::*,
-        structures::{NatKind, PeerInfo, PunchState, PunchSync, RelayState, RelaySync},
-    },
-    proto::{
-        control_text::{
-            MSG_CONNECT, MSG_CONTROL, MSG_MODE, MSG_RELAY, MSG_SERVER_RELAY, NAT_TYPE_CONE,
-            NAT_TYPE_PUBLIC, NAT_TYPE_SYMMETRIC, NAT_TYPE_UNKNOWN,
-        },
-        packet::Kind,
-    },
+    client::{ClientEvent, Session, SessionConfig, nat::SymmetricPunch},
+    log::{self, LogFilter},
+    proto::token,
 };
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+fn parse_arguments(args: Vec<String>) -> (SessionConfig, LogFilter) {
+    // flags may appear anywhere after the positional arguments
+    let mut symmetric_punch = SymmetricPunch::Off;
+    let mut server_key = None;
+    let mut plain = false;
+    let mut log_filter = env::var("ODNP_LOG_LEVEL").unwrap_or_default();
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server-key" => {
+                let hex = args.next().unwrap_or_default();
+                server_key = Some(token::from_hex(&hex).expect("Invalid server key, expected hex"));
+            }
+            "--plain" => plain = true,
+            "--log-level" => log_filter = args.next().unwrap_or_default(),
+            _ => positional.push(arg),
+        }
+    }
+    let args: Vec<String> = positional
+        .into_iter()
+        .filter(|a| match a.as_str() {
+            "--predict" => {
+                symmetric_punch = SymmetricPunch::Predict;
+                false
+            }
+            "--birthday" => {
+                symmetric_punch = SymmetricPunch::Birthday;
+                false
+            }
+            _ => true,
+        })
+        .collect();
 
-const SETUP_POLL_SLEEP_MS: u64 = 20;
-const MAIN_POLL_SLEEP_MS: u64 = 50;
-const SETUP_DEADLINE_MS: u64 = 1500;
-
-fn parse_arguments(args: Vec<String>) -> (String, String, String, String, u16) {
-    if args.len() < 6 {
-        eprintln!("Usage: client <signaling_ip> <server_id> <channel> <user> <local_port>");
+    if args.len() < 6 || server_key.is_some() == plain {
+        eprintln!(
+            "Usage: client <signaling_ip> <server_id> <channel> <user> <local_port> [token_hex] (--server-key <hex>|--plain) [--predict|--birthday] [--log-level <level>[,<module>=<level>...]]"
+        );
         std::process::exit(1);
     }
 
-    let signaling_ip = args[1].clone();
-    let server_id = args[2].clone();
-    let channel = args[3].clone();
-    let user = args[4].clone();
-    let local_port: u16 = args[5].parse().expect("Invalid port number");
-
-    (signaling_ip, server_id, channel, user, local_port)
-}
-
-fn setup_socket(local_port: u16) -> UdpSocket {
-    //Socket UDP local
-    let socket = UdpSocket::bind(("0.0.0.0", local_port)).expect("Failed to bind socket");
-    socket
-        .set_nonblocking(true)
-        .expect("Failed to set non-blocking");
-
-    socket
-}
-
-fn send_connect_message(
-    socket: &UdpSocket,
-    signaling_addr: &str,
-    server_id: &str,
-    channel: &str,
-    user: &str,
-    my_nat: NatKind,
-) {
-    let nat_type = match my_nat {
-        NatKind::Symmetric => NAT_TYPE_SYMMETRIC,
-        NatKind::Cone => NAT_TYPE_CONE,
-        NatKind::Public => NAT_TYPE_PUBLIC,
-        NatKind::Unknown => NAT_TYPE_UNKNOWN,
+    let token = match args.get(6) {
+        Some(hex) => token::from_hex(hex).expect("Invalid token, expected hex"),
+        None => Vec::new(),
     };
 
-    let connect_msg = format!("{MSG_CONNECT} {server_id} {channel} {user} {nat_type}");
-    socket
-        .send_to(connect_msg.as_bytes(), &signaling_addr)
-        .expect("Failed to send CONNECT");
-    println!("Sent {MSG_CONNECT} to signaling server");
-}
-
-fn update_relay_is_active(
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-    relay_sync: &RelaySync,
-) {
-    let active = *is_relay.lock().unwrap() || channel_has_server_relays.load(Ordering::Acquire);
-    let (lock, cvar) = &**relay_sync;
-    let mut st = lock.lock().unwrap();
-    if st.is_active != active {
-        st.is_active = active;
-        cvar.notify_all(); //starts/stops relay loop instantly
-    }
+    let log_filter = log_filter.parse().unwrap_or_else(|e| {
+        eprintln!("Invalid log level: {e}");
+        std::process::exit(1);
+    });
+    let config = SessionConfig {
+        signaling_ip: args[1].clone(),
+        server_id: args[2].clone(),
+        channel: args[3].clone(),
+        user: args[4].clone(),
+        local_port: args[5].parse().expect("Invalid port number"),
+        token,
+        symmetric_punch,
+        server_key,
+    };
+    (config, log_filter)
 }
 
-fn process_server_response(
-    response: &str,
-    user: &str,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-    send_via_server: &Arc<AtomicBool>,
-    punch_sync: &PunchSync,
-) {
-    if !response
-        .lines()
-        .any(|l| l.trim_start().starts_with(&format!("{MSG_MODE} ")))
-    {
-        return;
-    }
-    println!("Server response:\n{}", response);
-    for line in response.lines() {
-        let line = line.trim();
-
-        if line == format!("{MSG_MODE} {MSG_RELAY}") {
-            if send_via_server.load(Ordering::Acquire) {
-                send_via_server.store(false, Ordering::Release);
-                println!("I am relay now - stop sending via server.");
-            }
-
-            //resume punching if it was paused
-            let (lock, cvar) = &**punch_sync;
-            let mut st = lock.lock().unwrap();
-            if st.paused {
-                st.paused = false;
-                cvar.notify_all();
-            }
+fn print_event(event: ClientEvent) {
+    match event {
+        ClientEvent::PeerAdded {
+            peer_id,
+            username,
+            addr,
+        } => println!("* {username} #{peer_id} joined ({addr})"),
+        ClientEvent::PeerConnected {
+            peer_id,
+            username,
+            via,
+            ..
+        } => println!("* {username} #{peer_id} connected ({via:?})"),
+        ClientEvent::PeerLeft { peer_id, username } => println!("* {username} #{peer_id} left"),
+        ClientEvent::BecameRelay => println!("* you are the channel relay"),
+        ClientEvent::RelayLost => println!("* you are no longer the channel relay"),
+        ClientEvent::ServerRelayEnabled { peer_id, username } => {
+            println!("* server relays traffic for {username} #{peer_id}")
         }
-
-        if line.starts_with(&format!("{MSG_MODE} {MSG_SERVER_RELAY} ")) {
-            // MODE SERVER_RELAY <username>
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 3 {
-                let who = parts[2];
-
-                if who == user {
-                    //i am symmetric (or lone) => send via server
-                    if !send_via_server.load(Ordering::Acquire) {
-                        send_via_server.store(true, Ordering::Release);
-                        println!("I will send via server from now on.");
-                    }
-
-                    // stop the thread of punching
-                    let (lock, cvar) = &**punch_sync;
-                    let mut st = lock.lock().unwrap();
-                    if !st.paused {
-                        st.paused = true;
-                        cvar.notify_all();
-                    }
-                } else if *is_relay.lock().unwrap() {
-                    // i am the relay -> note that channel has server-relayed peers
-                    if !channel_has_server_relays.load(Ordering::Acquire) {
-                        channel_has_server_relays.store(true, Ordering::Release);
-                        println!("Relay will mirror traffic to server.");
-                    }
-                }
-            }
+        ClientEvent::PeerSecured { peer_id, username } => {
+            println!("* {username} #{peer_id} secured (DTLS)")
         }
+        ClientEvent::WelcomeReceived {
+            channel_id,
+            peer_id,
+        } => println!("* joined channel {channel_id} as peer {peer_id}"),
     }
 }
 
-fn handle_recv_result(
-    result: std::io::Result<(usize, std::net::SocketAddr)>,
-    buf: &mut [u8],
-    socket: &UdpSocket,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    user: &str,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-    send_via_server: &Arc<AtomicBool>,
-    server_socketaddr: &std::net::SocketAddr,
-    signaling_addr: &str,
-    saw_mode: &mut bool,
-    punch_sync: &PunchSync,
-    relay_sync: &RelaySync,
-    channel_id: &Arc<AtomicU64>,
-    my_peer_id: &Arc<AtomicU32>,
-) -> bool {
-    match result {
-        Ok((len, src)) => {
-            if let Some((hdr, payload)) = od_nat_piercer::proto::packet::decode(&buf[..len]) {
-                match hdr.kind {
-                    Kind::Control => {
-                        println!(
-                            "(setup) Got {MSG_CONTROL} {} bytes from {src}",
-                            payload.len()
-                        );
-
-                        //temporarly: if payload is text, process as before
-                        if let Ok(s) = std::str::from_utf8(payload) {
-                            println!("(setup) {MSG_CONTROL} payload: {}", s.trim());
-                            try_handle_welcome(s, &channel_id, &my_peer_id);
-                            if &src == server_socketaddr {
-                                if s.lines()
-                                    .any(|l| l.trim_start().starts_with(&format!("{MSG_MODE} ")))
-                                {
-                                    *saw_mode = true;
-                                }
-
-                                // 1) Normal processing: MODE / DATA / USER_LEFT, etc
-                                process_incoming_message(
-                                    socket,
-                                    s,
-                                    src,
-                                    peers,
-                                    &user,
-                                    is_relay,
-                                    channel_has_server_relays,
-                                    signaling_addr,
-                                );
-
-                                // 2) Local mode + send_via_server / punching behaviors
-                                process_server_response(
-                                    s,
-                                    user,
-                                    is_relay,
-                                    channel_has_server_relays,
-                                    send_via_server,
-                                    punch_sync,
-                                );
-
-                                update_relay_is_active(
-                                    is_relay,
-                                    channel_has_server_relays,
-                                    relay_sync,
-                                );
-                            } else {
-                                // peer traffic (arrived during setup)
-                                handle_peer_message(peers, src);
-
-                                process_incoming_message(
-                                    socket,
-                                    s,
-                                    src,
-                                    peers,
-                                    &user,
-                                    is_relay,
-                                    channel_has_server_relays,
-                                    signaling_addr,
-                                );
-                            }
-                        }
-                    }
-                    Kind::Dtls => {
-                        println!("(setup) Got DTLS {} bytes from {}", payload.len(), src);
-                    }
-                    Kind::Srtp => {
-                        println!("(setup) Got SRTP {} bytes from {}", payload.len(), src);
-                    }
-                }
-                return true;
-            }
-            let resp = String::from_utf8_lossy(&buf[..len]).to_string();
-            if &src == server_socketaddr {
-                if resp
-                    .lines()
-                    .any(|l| l.trim_start().starts_with(&format!("{MSG_MODE} ")))
-                {
-                    *saw_mode = true;
-                }
-
-                // 1) Normal processing: MODE / DATA / USER_LEFT, etc
-                process_incoming_message(
-                    socket,
-                    &resp,
-                    src,
-                    peers,
-                    &user,
-                    is_relay,
-                    channel_has_server_relays,
-                    signaling_addr,
-                );
-
-                // 2) Local mode + send_via_server / punching behaviors
-                process_server_response(
-                    &resp,
-                    user,
-                    is_relay,
-                    channel_has_server_relays,
-                    send_via_server,
-                    punch_sync,
-                );
-
-                update_relay_is_active(is_relay, channel_has_server_relays, relay_sync);
-            } else {
-                // peer traffic (arrived during setup)
-                handle_peer_message(peers, src);
-
-                process_incoming_message(
-                    socket,
-                    &resp,
-                    src,
-                    peers,
-                    &user,
-                    is_relay,
-                    channel_has_server_relays,
-                    signaling_addr,
-                );
-            }
-            true
+const MEDIA_STREAM: u32 = 1;
+const MEDIA_CLOCK_HZ: u128 = 8000;
+
+// Reads lines from stdin and sends each one to the channel,
+// `/media <text>` goes as an SRTP frame instead
+async fn user_input_loop(session: Arc<Session>) {
+    let started = Instant::now();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let msg = line.trim();
+        if msg.is_empty() {
+            continue;
         }
-        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-            thread::sleep(Duration::from_millis(SETUP_POLL_SLEEP_MS));
-            true
+        if let Some(frame) = msg.strip_prefix("/media ") {
+            let timestamp = (started.elapsed().as_millis() * MEDIA_CLOCK_HZ / 1000) as u32;
+            if let Err(e) = session
+                .send_media(MEDIA_STREAM, timestamp, frame.as_bytes())
+                .await
+            {
+                eprintln!("Failed to send media: {}", e);
+            }
+            continue;
         }
-        Err(e) => {
-            eprintln!("recv error during setup: {}", e);
-            false
+        if let Err(e) = session.send(msg.as_bytes()).await {
+            eprintln!("Failed to send message: {}", e);
         }
     }
 }
 
-fn server_responses_during_setup(
-    socket: &UdpSocket,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    user: &str,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-    send_via_server: &Arc<AtomicBool>,
-    server_socketaddr: &std::net::SocketAddr,
-    signaling_addr: &str,
-    punch_sync: &PunchSync,
-    relay_sync: &RelaySync,
-    channel_id: &Arc<AtomicU64>,
-    my_peer_id: &Arc<AtomicU32>,
-) {
-    let mut buf = [0u8; 2048];
-    let setup_deadline = Instant::now() + Duration::from_millis(SETUP_DEADLINE_MS);
-    let mut saw_mode = false;
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let (config, log_filter) = parse_arguments(env::args().collect());
+    log::set_filter(log_filter);
 
-    while Instant::now() < setup_deadline && !saw_mode {
-        let res = socket.recv_from(&mut buf);
-        if !handle_recv_result(
-            res,
-            &mut buf,
-            socket,
-            peers,
-            user,
-            is_relay,
-            channel_has_server_relays,
-            send_via_server,
-            server_socketaddr,
-            signaling_addr,
-            &mut saw_mode,
-            punch_sync,
-            relay_sync,
-            &channel_id,
-            &my_peer_id,
-        ) {
-            break;
-        }
-    }
-}
+    let session = Arc::new(Session::connect(config).await?);
+    let mut events = session.subscribe();
 
-fn main_loop(
-    socket: &UdpSocket,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    user: String,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-    signaling_addr: &str,
-    server_socketaddr: &std::net::SocketAddr,
-    punch_sync: &PunchSync,
-    relay_sync: &RelaySync,
-    send_via_server: &Arc<AtomicBool>,
-    channel_id: &Arc<AtomicU64>,
-    my_peer_id: &Arc<AtomicU32>,
-) -> std::io::Result<()> {
-    let mut buf = [0u8; 2048];
+    tokio::spawn(user_input_loop(Arc::clone(&session)));
 
     println!("Starting main message loop...");
     loop {
-        match socket.recv_from(&mut buf) {
-            Ok((len, src)) => {
-                if let Some((hdr, payload)) = od_nat_piercer::proto::packet::decode(&buf[..len]) {
-                    match hdr.kind {
-                        Kind::Control => {
-                            println!("Got {MSG_CONTROL} {} bytes from {src}", payload.len());
-                            if let Ok(s) = std::str::from_utf8(payload) {
-                                println!("{MSG_CONTROL} payload: {}", s.trim());
-                                try_handle_welcome(s, &channel_id, &my_peer_id);
-
-                                process_incoming_message(
-                                    socket,
-                                    s,
-                                    src,
-                                    peers,
-                                    &user,
-                                    is_relay,
-                                    channel_has_server_relays,
-                                    signaling_addr,
-                                );
-                            }
-                        }
-                        Kind::Dtls => println!("Got DTLS {} bytes from {}", payload.len(), src),
-                        Kind::Srtp => println!("Got SRTP {} bytes from {}", payload.len(), src),
-                    }
-                    continue;
+        tokio::select! {
+            data = session.recv() => match data {
+                Some((peer_id, sender, payload)) => {
+                    println!("[{} #{}]: {}", sender, peer_id, String::from_utf8_lossy(&payload));
                 }
-
-                let message = String::from_utf8_lossy(&buf[..len]).to_string();
-
-                if &src == server_socketaddr {
-                    // 1) Process MODE / DATA / USER_LEFT etc.
-                    process_incoming_message(
-                        socket,
-                        &message,
-                        src,
-                        peers,
-                        &user,
-                        is_relay,
-                        channel_has_server_relays,
-                        signaling_addr,
-                    );
-
-                    // 2) Update send_via_server + punching according to MODE lines
-                    process_server_response(
-                        &message,
-                        &user,
-                        is_relay,
-                        channel_has_server_relays,
-                        send_via_server,
-                        punch_sync,
-                    );
-                } else {
-                    // Peer traffic
-                    handle_peer_message(&peers, src);
-
-                    process_incoming_message(
-                        socket,
-                        &message,
-                        src,
-                        peers,
-                        &user,
-                        is_relay,
-                        channel_has_server_relays,
-                        signaling_addr,
-                    );
+                None => return Ok(()),
+            },
+            frame = session.recv_media() => if let Some(frame) = frame {
+                println!(
+                    "[media #{} stream {} @{}]: {}",
+                    frame.peer_id,
+                    frame.stream_id,
+                    frame.timestamp,
+                    String::from_utf8_lossy(&frame.payload)
+                );
+            },
+            event = events.recv() => {
+                if let Ok(event) = event {
+                    print_event(event);
                 }
-
-                update_relay_is_active(is_relay, channel_has_server_relays, relay_sync);
-            }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                thread::sleep(Duration::from_millis(MAIN_POLL_SLEEP_MS));
-            }
-            Err(e) => {
-                eprintln!("Error receiving: {}", e);
-                return Err(e);
             }
+            _ = tokio::signal::ctrl_c() => break,
         }
     }
-}
-
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let (signaling_ip, server_id, channel, user, local_port) = parse_arguments(args);
-
-    let socket = setup_socket(local_port);
-
-    let my_peer_id = Arc::new(AtomicU32::new(0));
-    let channel_id = Arc::new(AtomicU64::new(0));
-
-    // NAT detection before CONNECT
-    let my_nat = detect_nat_kind(&socket, &signaling_ip);
-    println!("My NAT kind: {:?}", my_nat);
-
-    //Address for the signalization server (UDP on port 2131)
-    let signaling_addr = format!("{}:2131", signaling_ip);
-    let server_socketaddr: std::net::SocketAddr = signaling_addr
-        .to_socket_addrs()
-        .expect("resolve signaling server")
-        .next()
-        .expect("no addr for signaling server");
-
-    send_connect_message(
-        &socket,
-        &signaling_addr,
-        &server_id,
-        &channel,
-        &user,
-        my_nat,
-    );
-
-    let peers: Vec<PeerInfo> = Vec::new();
-    let peers = Arc::new(Mutex::new(peers));
-
-    let is_relay = Arc::new(Mutex::new(false));
-    let relay_started = Arc::new(Mutex::new(false));
-
-    let send_via_server = Arc::new(AtomicBool::new(false)); //this client must send via server
-    let channel_has_server_relays = Arc::new(AtomicBool::new(false)); //as relay, i should also mirror to serveri
-
-    let punch_sync: PunchSync = Arc::new((
-        Mutex::new(PunchState { paused: false }),
-        std::sync::Condvar::new(),
-    ));
-
-    let relay_sync: RelaySync = Arc::new((
-        Mutex::new(RelayState { is_active: false }),
-        std::sync::Condvar::new(),
-    ));
-
-    start_heartbeat(
-        socket.try_clone()?,
-        server_id.to_string(),
-        channel.to_string(),
-        user.to_string(),
-        signaling_addr.clone(),
-    );
-
-    server_responses_during_setup(
-        &socket,
-        &peers,
-        &user,
-        &is_relay,
-        &channel_has_server_relays,
-        &send_via_server,
-        &server_socketaddr,
-        &signaling_addr,
-        &punch_sync,
-        &relay_sync,
-        &channel_id,
-        &my_peer_id,
-    );
-
-    // start punching ONLY if NAT is not symmetric
-    if my_nat != NatKind::Symmetric {
-        start_hole_punching(
-            socket.try_clone()?,
-            Arc::clone(&peers),
-            Arc::clone(&punch_sync),
-        );
-    } else {
-        println!("Symmetric NAT detected - skipping hole punching, relying on server relay.");
-    }
-
-    //Relay keepalive thread starter
-    start_relay_keepalive(
-        socket.try_clone()?,
-        Arc::clone(&peers),
-        Arc::clone(&relay_started),
-        server_id.to_string(),
-        channel.to_string(),
-        signaling_addr.clone(),
-        Arc::clone(&relay_sync),
-    );
-
-    //Thread for sending messages
-    start_user_input(
-        socket.try_clone()?,
-        Arc::clone(&peers),
-        user.to_string(),
-        Arc::clone(&send_via_server),
-        signaling_addr.clone(),
-        Arc::clone(&is_relay),
-        Arc::clone(&channel_has_server_relays),
-    );
 
-    main_loop(
-        &socket,
-        &peers,
-        user,
-        &is_relay,
-        &channel_has_server_relays,
-        &signaling_addr,
-        &server_socketaddr,
-        &punch_sync,
-        &relay_sync,
-        &send_via_server,
-        &channel_id,
-        &my_peer_id,
-    )
+    session.close().await
 }
@@ -0,0 +1,37 @@
+use std::env;
+
+use od_nat_piercer::proto::token;
+
+// Mints a CONNECT token for one user, signed with the server's ODNP_JOIN_SECRET;
+// with --turn it prints the TURN username and password for that token instead
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 5 {
+        eprintln!("Usage: join_token <server_id> <channel> <user> <ttl_secs> [--turn]");
+        std::process::exit(1);
+    }
+
+    let secret = env::var("ODNP_JOIN_SECRET").unwrap_or_else(|_| {
+        eprintln!("ODNP_JOIN_SECRET is not set");
+        std::process::exit(1);
+    });
+    let ttl: u64 = args[4].parse().expect("Invalid ttl");
+
+    let t = token::sign(
+        secret.as_bytes(),
+        &args[1],
+        &args[2],
+        &args[3],
+        token::now_secs() + ttl,
+    );
+    if args.get(5).is_some_and(|a| a == "--turn") {
+        let Some((username, password)) = token::turn_credentials(&args[1], &args[2], &args[3], &t)
+        else {
+            eprintln!("server_id and channel must not contain ':' for TURN");
+            std::process::exit(1);
+        };
+        println!("{username}\n{password}");
+        return;
+    }
+    println!("{}", token::to_hex(&t));
+}
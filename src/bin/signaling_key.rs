@@ -0,0 +1,10 @@
+use od_nat_piercer::proto::{noise::StaticKeypair, token};
+
+// Generates the signaling server's Noise key: the private half goes to the
+// server's ODNP_SIGNALING_KEY, the public half to clients (--server-key)
+fn main() {
+    let key = StaticKeypair::generate().expect("Failed to generate X25519 key");
+    let private = key.private_bytes().expect("Failed to export private key");
+    println!("ODNP_SIGNALING_KEY={}", token::to_hex(&private));
+    println!("public key: {}", token::to_hex(key.public()));
+}
@@ -1,66 +1,312 @@
-use od_nat_piercer::signaling::{
-    handlers::handle_message, heartbeat::start_heartbeat, structures::ServerMap,
+use od_nat_piercer::{
+    log, net,
+    proto::{
+        control::{self, ControlMessage},
+        noise::StaticKeypair,
+        stun::{self, Class, Message},
+        token,
+    },
+    signaling::{
+        admin, cluster,
+        cluster::Cluster,
+        config::{ConfigError, NetworkConfig, ServerConfig, USAGE},
+        handlers::{handle_message, spawn_packet},
+        heartbeat::start_heartbeat,
+        metrics,
+        probe::{ProbeSlot, ProbeSockets},
+        secure::Inbound,
+        snapshot::{self, start_snapshots},
+        state::ServerState,
+        turn::TurnServer,
+    },
+};
+use std::{
+    env,
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
 };
-use std::sync::Arc;
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+
+async fn bind_pair(ip: IpAddr, network: &NetworkConfig) -> std::io::Result<[Arc<UdpSocket>; 2]> {
+    // `::` serves IPv4 as well, and falls back to IPv4 only on hosts without IPv6
+    let (main, probe) = if ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        (
+            net::bind_any(network.port).await?,
+            net::bind_any(network.probe_port()).await?,
+        )
+    } else {
+        (
+            net::bind(ip, network.port).await?,
+            net::bind(ip, network.probe_port()).await?,
+        )
+    };
+    Ok([Arc::new(main), Arc::new(probe)])
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let socket_main = UdpSocket::bind("0.0.0.0:2131").await?;
-    let socket_probe = UdpSocket::bind("0.0.0.0:2132").await?;
+    let config = match ServerConfig::load(env::args().skip(1), |name| env::var(name).ok()) {
+        Ok(config) => config,
+        Err(ConfigError::Help) => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    log::set_filter(config.log.filter());
+    log::set_format(config.log.format);
+    let network = &config.network;
+
+    // an alternate address lets clients classify address-dependent NAT behaviour;
+    // both addresses are then bound explicitly instead of ::
+    let probes = match network.alt_ip {
+        Some(alt) => {
+            log!(Info, "NAT probes answered from {} and {alt}", network.bind);
+            ProbeSockets::with_alternate(
+                bind_pair(network.bind, network).await?,
+                bind_pair(alt, network).await?,
+                alt,
+            )
+        }
+        None => ProbeSockets::new(bind_pair(network.bind, network).await?),
+    };
+
+    log!(
+        Info,
+        "Signaling server listening on port {} (probes on {})",
+        network.port,
+        network.probe_port()
+    );
+
+    // with a secret set, CONNECT must carry a token minted by the join_token tool
+    let security = &config.security;
+    let state = match &security.join_secret {
+        Some(secret) => {
+            log!(Info, "Join tokens required");
+            ServerState::with_join_secret(secret.clone().into_bytes())
+        }
+        None => ServerState::new(),
+    };
+
+    // clients pin the public half; a generated key changes with every restart
+    let key = match &security.signaling_key {
+        Some(hex) => token::from_hex(hex)
+            .and_then(|bytes| StaticKeypair::from_private(&bytes).ok())
+            .ok_or("signaling_key is not a hex X25519 private key")?,
+        None => {
+            log!(
+                Warn,
+                "No signaling key configured, using a one-off key (see signaling_key)"
+            );
+            StaticKeypair::generate()?
+        }
+    };
+    log!(Info, "Signaling key: {}", token::to_hex(key.public()));
+    if security.plain_signaling {
+        log!(
+            Info,
+            "Plain signaling allowed for clients without a pinned key"
+        );
+    }
+    let mut state = state
+        .with_secure_signaling(key, security.plain_signaling)
+        .with_limits(config.limits);
+
+    // other instances own some of the channels and forward packets for our users
+    let cluster = &config.cluster;
+    if let (Some(listen), Some(node), Some(secret)) =
+        (cluster.listen, cluster.node(), &cluster.secret)
+    {
+        let socket = net::bind(listen.ip(), listen.port()).await?;
+        log!(
+            Info,
+            "Cluster node {node}, {} other instances",
+            cluster.nodes.iter().filter(|n| **n != node).count()
+        );
+        if security.signaling_key.is_none() {
+            log!(
+                Warn,
+                "Cluster without a signaling_key: secured clients cannot move between instances"
+            );
+        }
+        let link = Cluster::new(
+            Arc::new(socket),
+            node,
+            cluster.nodes.clone(),
+            secret.clone().into_bytes(),
+        );
+        state = state.with_cluster(link);
+    }
+    let state = Arc::new(state);
+
+    // channels of the previous run, kept for clients to RESUME
+    if let Some(path) = &config.snapshot.path {
+        if let Some(snap) = snapshot::load(path)
+            .await
+            .map_err(|e| format!("cannot read snapshot {}: {e}", path.display()))?
+        {
+            let count = snap.restore(&state);
+            log!(Info, "Restored {count} channels from {}", path.display());
+        }
+        if security.signaling_key.is_none() {
+            log!(
+                Warn,
+                "Snapshots without a signaling_key: secured clients cannot resume after a restart"
+            );
+        }
+        start_snapshots(
+            Arc::clone(&state),
+            path.clone(),
+            config.snapshot.interval_secs,
+        );
 
-    println!("Signaling server listening on 0.0.0.0:2131");
+        // a last snapshot on Ctrl-C, so nothing since the previous one is lost
+        let (state, path) = (Arc::clone(&state), path.clone());
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                if let Err(e) = snapshot::save(&state, &path).await {
+                    log!(Error, "Failed to write snapshot {}: {}", path.display(), e);
+                }
+                std::process::exit(0);
+            }
+        });
+    }
 
-    let socket_main = Arc::new(socket_main);
-    let socket_probe = Arc::new(socket_probe);
+    // TURN for third-party WebRTC stacks, with passwords derived from join tokens
+    let mut tasks = Vec::new();
+    if let (Some(port), Some(secret)) = (config.turn.port, &security.join_secret) {
+        let socket = if network.bind.is_unspecified() {
+            net::bind_any(port).await?
+        } else {
+            net::bind(network.bind, port).await?
+        };
+        let relay_ip = config.turn.relay_ip.unwrap_or(network.bind);
+        log!(Info, "TURN relay on port {port}, relaying from {relay_ip}");
+        let turn = TurnServer::new(
+            Arc::new(socket),
+            relay_ip,
+            secret.clone().into_bytes(),
+            config.turn.clone(),
+        );
+        tasks.push(tokio::spawn(turn.serve()));
+    }
 
-    let state = Arc::new(Mutex::new(ServerMap::new()));
+    let probes = Arc::new(probes);
+    let main_socket = probes.socket(ProbeSlot::MAIN).unwrap();
+    start_heartbeat(Arc::clone(main_socket), Arc::clone(&state), config.timeouts);
+    if state.cluster().is_some() {
+        tasks.push(tokio::spawn(cluster::serve(
+            Arc::clone(main_socket),
+            Arc::clone(&state),
+        )));
+    }
 
-    start_heartbeat(Arc::clone(&socket_main), Arc::clone(&state));
+    if let (Some(listen), Some(token)) = (config.admin.listen, &config.admin.token) {
+        let (addr, server) = admin::bind(
+            listen,
+            token.clone(),
+            Arc::clone(main_socket),
+            Arc::clone(&state),
+        )?;
+        log!(Info, "Admin API on http://{addr}");
+        tasks.push(tokio::spawn(server));
+    }
+    if let Some(listen) = config.metrics.listen {
+        let (addr, server) = metrics::bind(listen, Arc::clone(&state))?;
+        log!(Info, "Metrics on http://{addr}/metrics");
+        tasks.push(tokio::spawn(server));
+    }
 
-    run_server(socket_main, socket_probe, state).await;
+    for slot in ProbeSlot::ALL {
+        if let Some(socket) = probes.socket(slot) {
+            let socket = Arc::clone(socket);
+            tasks.push(tokio::spawn(serve(
+                slot,
+                socket,
+                Arc::clone(&probes),
+                Arc::clone(&state),
+            )));
+        }
+    }
+    for task in tasks {
+        task.await?;
+    }
     Ok(())
 }
 
-async fn run_server(
-    socket_main: Arc<UdpSocket>,
-    socket_probe: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
+async fn serve(
+    slot: ProbeSlot,
+    socket: Arc<UdpSocket>,
+    probes: Arc<ProbeSockets>,
+    state: Arc<ServerState>,
 ) {
-    let mut buf_main = [0u8; 2048];
-    let mut buf_probe = [0u8; 2048];
+    let mut buf = [0u8; 2048];
 
     loop {
-        tokio::select! {
-            res = socket_main.recv_from(&mut buf_main) => {
-                if let Ok((len,src)) = res{
-                    if let Some((hdr, payload)) = od_nat_piercer::proto::packet::decode(&buf_main[..len]){
-                        if hdr.kind == od_nat_piercer::proto::packet::Kind::Control{
-                            if let Ok(s) = std::str::from_utf8(payload){
-                                handle_message(s.to_string(), src, Arc::clone(&socket_main), Arc::clone(&state)).await;
-                            }
-                        }
-                        continue;
-                    }
-                    let msg = String::from_utf8_lossy(&buf_main[..len]).to_string();
-                    handle_message(msg, src, Arc::clone(&socket_main), Arc::clone(&state)).await;
+        let Ok((len, src)) = net::recv_from(&socket, &mut buf).await else {
+            continue;
+        };
+        // standard STUN clients use the probe ports for reflexive discovery
+        if stun::is_stun(&buf[..len]) {
+            if let Some(req) = Message::decode(&buf[..len])
+                && req.method == stun::BINDING
+                && req.class == Class::Request
+            {
+                probes.answer_binding(slot, &req, src).await;
+            }
+            continue;
+        }
+        let opened = match state.inbound(&buf[..len], src) {
+            Inbound::Packet(pkt) => pkt,
+            Inbound::Reply(reply) => {
+                let _ = net::send_to(&socket, &reply, src).await;
+                continue;
+            }
+            // only NAT probes may come without a session
+            Inbound::OneShot { packet, responder } => {
+                if let Some((_, ControlMessage::NatProbe { seq, flags })) =
+                    control::decode_packet(&packet)
+                {
+                    probes.answer(slot, seq, flags, src, Some(responder)).await;
                 }
+                continue;
             }
+            Inbound::Drop(reason) => {
+                log!(Debug, addr = src; "Dropping packet: {}", reason);
+                continue;
+            }
+        };
+        let pkt = &opened[..];
+
+        // probes may ask to be answered from another socket
+        if let Some((_, ControlMessage::NatProbe { seq, flags })) = control::decode_packet(pkt) {
+            probes.answer(slot, seq, flags, src, None).await;
+            continue;
+        }
 
-            res = socket_probe.recv_from(&mut buf_probe) => {
-                if let Ok((len,src)) = res{
-                    if let Some((hdr, payload)) = od_nat_piercer::proto::packet::decode(&buf_main[..len]){
-                        if hdr.kind == od_nat_piercer::proto::packet::Kind::Control{
-                            if let Ok(s) = std::str::from_utf8(payload){
-                                handle_message(s.to_string(), src, Arc::clone(&socket_probe), Arc::clone(&state)).await;
-                            }
-                        }
-                        continue;
-                    }
-
-                    let msg = String::from_utf8_lossy(&buf_probe[..len]).to_string();
-                    handle_message(msg, src, Arc::clone(&socket_probe), Arc::clone(&state)).await;
+        // handlers run on their own tasks, so channels are served in parallel
+        if slot == ProbeSlot::MAIN {
+            spawn_packet(
+                opened.into_owned(),
+                src,
+                Arc::clone(&socket),
+                Arc::clone(&state),
+            );
+        } else if !slot.alternate {
+            // media is only relayed through the main port
+            match control::decode_packet(pkt) {
+                Some((hdr, msg)) => {
+                    let (socket, state) = (Arc::clone(&socket), Arc::clone(&state));
+                    tokio::spawn(async move {
+                        handle_message(msg, &hdr, src, socket, state).await;
+                    });
+                }
+                None => {
+                    metrics::MALFORMED_PACKETS.inc();
+                    log!(Debug, addr = src; "Unknown/Bad packet ({} bytes)", len);
                 }
             }
         }
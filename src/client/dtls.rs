@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    ssl::{ErrorCode, Ssl, SslContext, SslMethod, SslOptions, SslStream, SslVerifyMode},
+    x509::{X509, X509Name},
+};
+
+const DTLS_MTU: u32 = 1200; // leaves room for our header under common path MTUs
+const RETRANSMIT_MS: u64 = 500; // resend the last flight if the peer stays silent this long
+const HANDSHAKE_TIMEOUT_SEC: u64 = 10; // give up and let the active side start over
+const CERT_VALIDITY_DAYS: u32 = 30;
+
+const SRTP_PROFILE: &str = "SRTP_AES128_CM_SHA1_80";
+const SRTP_EXPORTER_LABEL: &str = "EXTRACTOR-dtls_srtp";
+pub const SRTP_KEY_LEN: usize = 16;
+pub const SRTP_SALT_LEN: usize = 14;
+
+// OpenSSL talks to this instead of a socket: every write is one datagram out,
+// every read hands over one datagram that arrived in a Kind::Dtls packet
+#[derive(Default)]
+struct DatagramPipe {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for DatagramPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(datagram) = self.incoming.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for DatagramPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The session's self-signed certificate. Peers do not trust it through a CA
+/// but pin its SHA-256 fingerprint, which the signaling server hands out in
+/// `MODE DIRECT` / `MODE SERVER_RELAY`.
+pub struct DtlsIdentity {
+    context: SslContext,
+    fingerprint: Vec<u8>,
+}
+
+impl DtlsIdentity {
+    pub fn generate() -> Result<Self, ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "od-nat-piercer")?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+        let serial = serial.to_asn1_integer()?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(CERT_VALIDITY_DAYS)?;
+
+        let mut cert = X509::builder()?;
+        cert.set_version(2)?;
+        cert.set_serial_number(&serial)?;
+        cert.set_subject_name(&name)?;
+        cert.set_issuer_name(&name)?;
+        cert.set_pubkey(&key)?;
+        cert.set_not_before(&not_before)?;
+        cert.set_not_after(&not_after)?;
+        cert.sign(&key, MessageDigest::sha256())?;
+        let cert = cert.build();
+
+        let mut context = SslContext::builder(SslMethod::dtls())?;
+        context.set_certificate(&cert)?;
+        context.set_private_key(&key)?;
+        context.check_private_key()?;
+        // the pipe cannot tell OpenSSL the path MTU, each Ssl sets it instead
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        context.set_tlsext_use_srtp(SRTP_PROFILE)?;
+
+        Ok(Self {
+            fingerprint: cert.digest(MessageDigest::sha256())?.to_vec(),
+            context: context.build(),
+        })
+    }
+
+    pub fn fingerprint(&self) -> &[u8] {
+        &self.fingerprint
+    }
+}
+
+/// Who sends the ClientHello, like RFC 5763 `setup:active` / `setup:passive`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DtlsRole {
+    Active,
+    Passive,
+}
+
+impl DtlsRole {
+    // the lower peer_id starts, so both sides agree without another message
+    pub fn for_pair(my_peer_id: u32, peer_id: u32) -> Self {
+        if my_peer_id < peer_id {
+            DtlsRole::Active
+        } else {
+            DtlsRole::Passive
+        }
+    }
+}
+
+/// SRTP master keys and salts exported from a finished handshake (RFC 5764).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaKeys {
+    pub local_key: Vec<u8>,
+    pub local_salt: Vec<u8>,
+    pub remote_key: Vec<u8>,
+    pub remote_salt: Vec<u8>,
+}
+
+/// One DTLS association with one peer, driven by datagrams instead of a socket.
+pub struct DtlsPeer {
+    stream: SslStream<DatagramPipe>,
+    role: DtlsRole,
+    keys: Option<MediaKeys>,
+    received: Vec<Vec<u8>>,
+    last_flight: Vec<Vec<u8>>,
+    last_sent: Instant,
+    started: Instant,
+}
+
+impl DtlsPeer {
+    /// `expected` is the fingerprint the peer announced through signaling; a
+    /// certificate with any other digest fails the handshake.
+    pub fn new(identity: &DtlsIdentity, role: DtlsRole, expected: Vec<u8>) -> io::Result<Self> {
+        let mut ssl = Ssl::new(&identity.context)?;
+        ssl.set_mtu(DTLS_MTU)?;
+        ssl.set_verify_callback(
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            move |_, ctx| {
+                // self-signed, so OpenSSL's own verdict is ignored: only the pin counts
+                if ctx.error_depth() != 0 {
+                    return true;
+                }
+                ctx.current_cert()
+                    .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+                    .is_some_and(|digest| *digest == *expected)
+            },
+        );
+        match role {
+            DtlsRole::Active => ssl.set_connect_state(),
+            DtlsRole::Passive => ssl.set_accept_state(),
+        }
+
+        let now = Instant::now();
+        Ok(Self {
+            stream: SslStream::new(ssl, DatagramPipe::default())?,
+            role,
+            keys: None,
+            received: Vec::new(),
+            last_flight: Vec::new(),
+            last_sent: now,
+            started: now,
+        })
+    }
+
+    /// Feeds one datagram from the peer (`None` starts an active handshake)
+    /// and returns the datagrams to send back.
+    pub fn handle(&mut self, datagram: Option<&[u8]>) -> io::Result<Vec<Vec<u8>>> {
+        if let Some(datagram) = datagram {
+            self.stream.get_mut().incoming.push_back(datagram.to_vec());
+        }
+
+        if self.keys.is_none() {
+            match self.stream.do_handshake() {
+                Ok(()) => self.keys = Some(self.export_keys()?),
+                Err(e) if e.code() == ErrorCode::WANT_READ => {}
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        if self.keys.is_some() {
+            // reading also lets OpenSSL answer a retransmitted final flight
+            let mut buf = [0u8; 2048];
+            while let Ok(len) = self.stream.ssl_read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                self.received.push(buf[..len].to_vec());
+            }
+        }
+
+        let out = std::mem::take(&mut self.stream.get_mut().outgoing);
+        if !out.is_empty() {
+            self.last_flight = out.clone();
+            self.last_sent = Instant::now();
+        }
+        Ok(out)
+    }
+
+    /// Encrypts one application record for the peer, once the handshake finished.
+    pub fn send(&mut self, data: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        if self.keys.is_none() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.stream.ssl_write(data).map_err(io::Error::other)?;
+        Ok(std::mem::take(&mut self.stream.get_mut().outgoing))
+    }
+
+    /// Application records received since the last call.
+    pub fn take_received(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.received)
+    }
+
+    /// The last flight again, if the handshake is stuck waiting for the peer.
+    pub fn retransmit(&mut self) -> Option<Vec<Vec<u8>>> {
+        if self.keys.is_some()
+            || self.last_flight.is_empty()
+            || self.last_sent.elapsed() < Duration::from_millis(RETRANSMIT_MS)
+        {
+            return None;
+        }
+        self.last_sent = Instant::now();
+        Some(self.last_flight.clone())
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.keys.is_none() && self.started.elapsed() > Duration::from_secs(HANDSHAKE_TIMEOUT_SEC)
+    }
+
+    pub fn keys(&self) -> Option<&MediaKeys> {
+        self.keys.as_ref()
+    }
+
+    fn export_keys(&self) -> io::Result<MediaKeys> {
+        let ssl = self.stream.ssl();
+        if ssl.selected_srtp_profile().is_none() {
+            return Err(io::Error::other("peer did not negotiate an SRTP profile"));
+        }
+
+        // client key | server key | client salt | server salt
+        let mut material = [0u8; 2 * (SRTP_KEY_LEN + SRTP_SALT_LEN)];
+        ssl.export_keying_material(&mut material, SRTP_EXPORTER_LABEL, None)?;
+        let (keys, salts) = material.split_at(2 * SRTP_KEY_LEN);
+        let (client_key, server_key) = keys.split_at(SRTP_KEY_LEN);
+        let (client_salt, server_salt) = salts.split_at(SRTP_SALT_LEN);
+
+        let (local, remote) = match self.role {
+            DtlsRole::Active => ((client_key, client_salt), (server_key, server_salt)),
+            DtlsRole::Passive => ((server_key, server_salt), (client_key, client_salt)),
+        };
+        Ok(MediaKeys {
+            local_key: local.0.to_vec(),
+            local_salt: local.1.to_vec(),
+            remote_key: remote.0.to_vec(),
+            remote_salt: remote.1.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+
+/// How traffic from a peer first reached us.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionPath {
+    Punch,
+    Direct,
+    Server,
+}
+
+/// Peer and topology changes observed by a `Session`, delivered through `Session::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientEvent {
+    PeerAdded {
+        peer_id: u32,
+        username: String,
+        addr: SocketAddr,
+    },
+    PeerConnected {
+        peer_id: u32,
+        username: String,
+        addr: SocketAddr,
+        via: ConnectionPath,
+    },
+    PeerLeft {
+        peer_id: u32,
+        username: String,
+    },
+    BecameRelay,
+    RelayLost,
+    // the signaling server now forwards traffic for `peer_id` (possibly us)
+    ServerRelayEnabled {
+        peer_id: u32,
+        username: String,
+    },
+    // DTLS with the peer finished, `Session::media_keys` has its SRTP keys
+    PeerSecured {
+        peer_id: u32,
+        username: String,
+    },
+    WelcomeReceived {
+        channel_id: u64,
+        peer_id: u32,
+    },
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+
+use crate::client::dtls::DtlsPeer;
+use crate::log;
+
+const KEY_LEN: usize = 16; // AES-128-GCM
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_RESEND_MS: u64 = 500; // resend our sender key until the peer acknowledges it
+const KEYS_PER_PEER: usize = 2; // the current key and the one before a rotation
+
+const MSG_SENDER_KEY: u8 = 1;
+const MSG_KEY_ACK: u8 = 2;
+
+/// A symmetric key one member encrypts all its DATA with. Every member has
+/// its own, handed to each peer over the pairwise DTLS association, so relays
+/// and the signaling server forward payloads they cannot read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SenderKey {
+    pub id: u32,
+    key: [u8; KEY_LEN],
+}
+
+impl SenderKey {
+    fn generate(id: u32) -> Self {
+        Self {
+            id,
+            key: rand::random(),
+        }
+    }
+}
+
+// what peers send each other as DTLS application data
+#[derive(Debug, PartialEq, Eq)]
+enum KeyMessage {
+    SenderKey(SenderKey),
+    Ack(u32),
+}
+
+impl KeyMessage {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + 4 + KEY_LEN);
+        match self {
+            KeyMessage::SenderKey(key) => {
+                out.push(MSG_SENDER_KEY);
+                out.extend_from_slice(&key.id.to_be_bytes());
+                out.extend_from_slice(&key.key);
+            }
+            KeyMessage::Ack(id) => {
+                out.push(MSG_KEY_ACK);
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+        }
+        out
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (&tag, rest) = buf.split_first()?;
+        let id = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+        match (tag, &rest[4..]) {
+            (MSG_SENDER_KEY, key) if key.len() == KEY_LEN => {
+                Some(KeyMessage::SenderKey(SenderKey {
+                    id,
+                    key: key.try_into().ok()?,
+                }))
+            }
+            (MSG_KEY_ACK, []) => Some(KeyMessage::Ack(id)),
+            _ => None,
+        }
+    }
+}
+
+/// Binds a sealed payload to the channel, the speaking peer and its name, so a
+/// relay cannot re-attribute it.
+pub fn aad(channel_id: u64, origin: u32, sender: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(12 + sender.len());
+    out.extend_from_slice(&channel_id.to_be_bytes());
+    out.extend_from_slice(&origin.to_be_bytes());
+    out.extend_from_slice(sender.as_bytes());
+    out
+}
+
+/// Our sender key, the ones peers gave us, and which peers still need ours.
+pub struct GroupKeys {
+    mine: SenderKey,
+    peers: HashMap<u32, Vec<SenderKey>>,
+    acked: HashMap<u32, u32>,       // peer_id -> id of our key it confirmed
+    sent_at: HashMap<u32, Instant>, // peer_id -> last time our key went out
+}
+
+impl Default for GroupKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GroupKeys {
+    pub fn new() -> Self {
+        Self {
+            mine: SenderKey::generate(1),
+            peers: HashMap::new(),
+            acked: HashMap::new(),
+            sent_at: HashMap::new(),
+        }
+    }
+
+    pub fn key_id(&self) -> u32 {
+        self.mine.id
+    }
+
+    /// A fresh sender key, so members that left cannot read what follows.
+    pub fn rotate(&mut self) {
+        self.mine = SenderKey::generate(self.mine.id.wrapping_add(1));
+        self.acked.clear();
+        self.sent_at.clear();
+    }
+
+    pub fn remove_peer(&mut self, peer_id: u32) {
+        self.peers.remove(&peer_id);
+        self.peer_reset(peer_id);
+    }
+
+    // a new DTLS association: the peer may be a new session without our key
+    pub fn peer_reset(&mut self, peer_id: u32) {
+        self.acked.remove(&peer_id);
+        self.sent_at.remove(&peer_id);
+    }
+
+    fn learn(&mut self, peer_id: u32, key: SenderKey) {
+        let keys = self.peers.entry(peer_id).or_default();
+        keys.retain(|k| k.id != key.id);
+        keys.push(key);
+        if keys.len() > KEYS_PER_PEER {
+            keys.remove(0);
+        }
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_128_gcm(),
+            &self.mine.key,
+            Some(&nonce),
+            aad,
+            plaintext,
+            &mut tag,
+        )
+        .expect("AES-GCM with a valid key and nonce");
+
+        // key id | nonce | ciphertext | tag
+        let mut out = Vec::with_capacity(4 + NONCE_LEN + ciphertext.len() + TAG_LEN);
+        out.extend_from_slice(&self.mine.id.to_be_bytes());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        out.extend_from_slice(&tag);
+        out
+    }
+
+    /// `None` if we hold no matching key from `origin` or the payload was tampered with.
+    pub fn open(&self, origin: u32, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < 4 + NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let id = u32::from_be_bytes(sealed[..4].try_into().ok()?);
+        let key = self.peers.get(&origin)?.iter().find(|k| k.id == id)?;
+        let (nonce, rest) = sealed[4..].split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        decrypt_aead(
+            Cipher::aes_128_gcm(),
+            &key.key,
+            Some(nonce),
+            aad,
+            ciphertext,
+            tag,
+        )
+        .ok()
+    }
+
+    /// Handles the key messages that arrived on the association with
+    /// `peer_id` and returns the datagrams to send back: acks, and our own
+    /// key if the peer does not have it yet.
+    pub fn exchange(&mut self, peer_id: u32, association: &mut DtlsPeer) -> Vec<Vec<u8>> {
+        let mut replies = Vec::new();
+        for record in association.take_received() {
+            match KeyMessage::decode(&record) {
+                Some(KeyMessage::SenderKey(key)) => {
+                    replies.push(KeyMessage::Ack(key.id));
+                    self.learn(peer_id, key);
+                }
+                Some(KeyMessage::Ack(id)) => {
+                    self.acked.insert(peer_id, id);
+                }
+                None => log!(Debug, peer_id = peer_id; "Ignoring unknown DTLS record"),
+            }
+        }
+
+        let delivered = self.acked.get(&peer_id) == Some(&self.mine.id);
+        let recently_sent = self
+            .sent_at
+            .get(&peer_id)
+            .is_some_and(|t| t.elapsed() < Duration::from_millis(KEY_RESEND_MS));
+        if association.keys().is_some() && !delivered && !recently_sent {
+            self.sent_at.insert(peer_id, Instant::now());
+            replies.push(KeyMessage::SenderKey(self.mine.clone()));
+        }
+
+        let mut out = Vec::new();
+        for reply in replies {
+            match association.send(&reply.encode()) {
+                Ok(datagrams) => out.extend(datagrams),
+                Err(e) => log!(Warn, peer_id = peer_id; "Failed to send key message: {e}"),
+            }
+        }
+        out
+    }
+}
@@ -1,273 +1,1152 @@
 use crate::{
-    client::structures::{NatKind, PeerInfo},
-    proto::control_text::{
-        MSG_DATA, MSG_DIRECT, MSG_HOLE_PUNCH, MSG_MODE, MSG_NAT_SEEN, MSG_PING, MSG_PONG,
-        MSG_RELAY, MSG_SERVER_RELAY, MSG_USER_LEFT, MSG_WELCOME,
+    client::{
+        dtls::{DtlsPeer, DtlsRole, MediaKeys},
+        events::{ClientEvent, ConnectionPath},
+        group,
+        ice::{self, Candidate, CandidateKind, Checklist},
+        networking::{send_dtls, send_packet, send_signaling, send_to_peer, socket_for},
+        structures::{MediaFrame, NatKind, PeerInfo, ResumeState, SessionShared, SessionState},
     },
-};
-use std::{
-    net::{SocketAddr, UdpSocket},
-    str::FromStr,
-    sync::{
-        Arc, Mutex,
-        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    log,
+    proto::{
+        control::{self, CHECK_TAG_LEN, ControlMessage, PortHint},
+        noise::{self, MSG_RESPONSE, MSG_TRANSPORT},
+        packet::{self, BROADCAST, Header, Kind},
+        srtp::{SrtpReceiver, SrtpSender},
     },
-    time::Instant,
 };
+use std::{borrow::Cow, collections::hash_map::Entry, net::SocketAddr, time::Instant};
 
-pub fn try_handle_welcome(s: &str, channel_id: &Arc<AtomicU64>, my_peer_id: &Arc<AtomicU32>) {
-    for line in s.lines() {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() == 3 && parts[0] == MSG_WELCOME {
-            if let (Ok(cid), Ok(pid)) = (parts[1].parse::<u64>(), parts[2].parse::<u32>()) {
-                channel_id.store(cid, Ordering::Release);
-                my_peer_id.store(pid, Ordering::Release);
-                println!("{MSG_WELCOME} received: channel_id={cid}, my_peer_id={pid}");
+// with a Noise session, the server's control packets only count when sealed;
+// media it forwards stays as the peer protected it
+fn open_from_server<'a>(shared: &SessionShared, buf: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+    let mut signaling = shared.signaling.lock().unwrap();
+    let Some(transport) = signaling.as_mut() else {
+        return Some(Cow::Borrowed(buf));
+    };
+    let (hdr, payload) = packet::decode(buf)?;
+    match hdr.kind {
+        Kind::Dtls | Kind::Srtp => Some(Cow::Borrowed(buf)),
+        Kind::Noise => match noise::unframe(payload) {
+            Some((MSG_TRANSPORT, body)) => transport.open(body).map(Cow::Owned),
+            // the answer to a handshake started to resume our session
+            Some((MSG_RESPONSE, body)) => {
+                let mut handshake = shared.handshake.lock().unwrap();
+                if let Some((_, fresh)) = handshake.as_ref().and_then(|i| i.finish(body)) {
+                    *transport = fresh;
+                    *handshake = None;
+                    shared.handshake_done.notify_one();
+                }
+                None
             }
+            _ => None,
+        },
+        Kind::Control => {
+            log!(
+                Debug,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user;
+                "Ignoring unsealed signaling from the server"
+            );
+            None
         }
     }
 }
 
-fn handle_mode_relay(is_relay: &Arc<Mutex<bool>>) {
-    let mut r = is_relay.lock().unwrap();
-    if !*r {
-        *r = true;
-        println!("You are in RELAY MODE");
+pub(crate) async fn handle_datagram(shared: &SessionShared, buf: &[u8], src: SocketAddr) {
+    let opened;
+    let buf = if src == shared.signaling_addr {
+        let Some(plain) = open_from_server(shared, buf) else {
+            return;
+        };
+        opened = plain;
+        &opened[..]
+    } else {
+        buf
+    };
+
+    let Some((hdr, payload)) = packet::decode(buf) else {
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            addr = src;
+            "Ignoring non-protocol datagram"
+        );
+        return;
+    };
+
+    match hdr.kind {
+        Kind::Control => match ControlMessage::decode(payload) {
+            Some(msg) => handle_control(shared, &hdr, &msg, src).await,
+            None => log!(
+                Debug,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                addr = src;
+                "Bad CONTROL payload"
+            ),
+        },
+        Kind::Dtls => handle_dtls(shared, &hdr, payload, src).await,
+        Kind::Srtp => handle_srtp(shared, &hdr, payload, src),
+        Kind::Noise => log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            addr = src;
+            "Ignoring Noise packet"
+        ),
     }
 }
 
-fn handle_mode_direct(parts: &[&str], peers: &Arc<Mutex<Vec<PeerInfo>>>, me: &str) {
-    let username = parts[2];
-    let addr_str = parts[3];
-    if let Ok(addr) = SocketAddr::from_str(addr_str) {
-        let mut guard = peers.lock().unwrap();
-        if username != me {
-            if !guard.iter().any(|p| p.addr == addr) {
-                guard.push(PeerInfo {
-                    addr,
-                    last_pong: Instant::now(),
-                    username: username.to_string(),
-                    connected: false,
-                    created_at: Instant::now(),
-                    use_server_relay: false,
-                    relay_requested: false,
-                    nat_kind: NatKind::Unknown,
-                });
-                println!("Added peer {} with addr {}", username, addr_str);
+pub(crate) async fn handle_control(
+    shared: &SessionShared,
+    hdr: &Header,
+    msg: &ControlMessage,
+    src: SocketAddr,
+) {
+    let from_server = src == shared.signaling_addr;
+    if from_server {
+        shared.state.lock().unwrap().server_seen = Some(Instant::now());
+        handle_server_message(shared, msg);
+    } else if !matches!(
+        msg,
+        ControlMessage::HolePunch | ControlMessage::Check { .. } | ControlMessage::CheckOk { .. }
+    ) {
+        // Peer traffic, punches and checks may come from any candidate and are
+        // accounted for by their own handlers
+        handle_peer_message(shared, src);
+    }
+
+    match msg {
+        ControlMessage::Ping => handle_ping(shared, hdr, src).await,
+        // the server's PONG only answers our HB
+        ControlMessage::Pong if !from_server => handle_pong(shared, hdr, src),
+        ControlMessage::HolePunch => handle_hole_punch(shared, hdr, src, false),
+        ControlMessage::Check { txn, tag } if !from_server => {
+            handle_check(shared, hdr, *txn, tag, src).await
+        }
+        ControlMessage::CheckOk { txn, tag } if !from_server => {
+            handle_check_ok(shared, hdr, *txn, tag, src)
+        }
+        ControlMessage::Data { .. } => handle_data_message(shared, hdr, msg, from_server).await,
+        _ => {}
+    }
+
+    update_relay_is_active(shared);
+}
+
+fn handle_server_message(shared: &SessionShared, msg: &ControlMessage) {
+    match msg {
+        ControlMessage::Welcome {
+            channel_id,
+            peer_id,
+            nonce,
+        } => {
+            {
+                let mut st = shared.state.lock().unwrap();
+                st.channel_id = *channel_id;
+                st.my_peer_id = *peer_id;
+                st.session_nonce = *nonce;
+                st.resume = ResumeState::Idle;
             }
-        } else {
-            println!("Server confirms you ({}) are relay for {}", me, addr_str);
+            log!(
+                Info,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                peer_id = *peer_id;
+                "WELCOME received: channel_id={channel_id}"
+            );
+            shared.emit(ClientEvent::WelcomeReceived {
+                channel_id: *channel_id,
+                peer_id: *peer_id,
+            });
         }
+        ControlMessage::ModeRelay => handle_mode_relay(shared),
+        ControlMessage::ModePeer => handle_mode_peer(shared),
+        ControlMessage::ModeServerRelay {
+            user,
+            peer_id,
+            fingerprint,
+        } => handle_mode_server_relay(shared, user, *peer_id, fingerprint),
+        ControlMessage::ModeDirect {
+            user,
+            addr,
+            peer_id,
+            port_hint,
+            fingerprint,
+            candidates,
+            check_key,
+        } => handle_mode_direct(
+            shared,
+            user,
+            *addr,
+            *peer_id,
+            *port_hint,
+            fingerprint,
+            candidates,
+            check_key,
+        ),
+        ControlMessage::UserLeft { peer_id, .. } => handle_user_left(shared, *peer_id),
+        ControlMessage::Rejected { reason } => handle_rejected(shared, reason),
+        ControlMessage::Ping
+        | ControlMessage::Pong
+        | ControlMessage::Data { .. }
+        | ControlMessage::NatSeen { .. } => {}
+        _ => log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "Unhandled control message: {}",
+            msg.name()
+        ),
+    }
+
+    if msg.is_mode() {
+        shared.mode_seen.notify_one();
     }
 }
 
-fn handle_user_left(parts: &[&str], peers: &Arc<Mutex<Vec<PeerInfo>>>) {
-    let username = parts[1];
-    let mut guard = peers.lock().unwrap();
-    guard.retain(|p| p.username != username.to_string());
-    println!("[CLIENT:user] {} left, removed from list", username);
+fn handle_rejected(shared: &SessionShared, reason: &str) {
+    let mut st = shared.state.lock().unwrap();
+    // a refused RESUME is answered with a fresh CONNECT, see `networking::resume`
+    if st.resume == ResumeState::Sent {
+        st.resume = ResumeState::Refused;
+        log!(
+            Info,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "RESUME refused by signaling server: {reason}"
+        );
+        return;
+    }
+    st.rejected = Some(reason.to_string());
+    drop(st);
+    log!(
+        Warn,
+        server_id = &shared.config.server_id,
+        channel = &shared.config.channel,
+        user = &shared.config.user;
+        "CONNECT rejected by signaling server: {reason}"
+    );
+    // wake connect() instead of letting it wait for a MODE that never comes
+    shared.mode_seen.notify_one();
 }
 
-fn handle_unrecognized_command(line: &str) {
-    if line != MSG_PING && line != MSG_HOLE_PUNCH {
+fn handle_mode_relay(shared: &SessionShared) {
+    let newly_relay = {
+        let mut st = shared.state.lock().unwrap();
+        if st.send_via_server {
+            st.send_via_server = false;
+            log!(
+                Info,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user;
+                "I am relay now - stop sending via server."
+            );
+        }
+        !std::mem::replace(&mut st.is_relay, true)
+    };
+
+    //resume punching if it was paused
+    shared
+        .punch_paused
+        .send_if_modified(|p| std::mem::replace(p, false));
+
+    if newly_relay {
+        log!(
+            Info,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "You are in RELAY MODE"
+        );
+        shared.emit(ClientEvent::BecameRelay);
+    }
+}
+
+// the server made another user the relay; its MODE DIRECT follows
+fn handle_mode_peer(shared: &SessionShared) {
+    let was_relay = std::mem::replace(&mut shared.state.lock().unwrap().is_relay, false);
+    if was_relay {
+        log!(
+            Info,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "No longer relay - another peer relays now."
+        );
+        shared.emit(ClientEvent::RelayLost);
+    }
+}
+
+// messages about ourselves carry the peer_id from WELCOME
+fn is_me(shared: &SessionShared, peer_id: u32) -> bool {
+    let (_, my_peer_id) = shared.ids();
+    my_peer_id != 0 && peer_id == my_peer_id
+}
+
+// the peer's own candidates plus the address the server saw, which is its
+// server reflexive candidate even if it could not gather one itself; nothing
+// to check without the peer's check key, it is reached by punching alone
+fn checklist(
+    shared: &SessionShared,
+    my_peer_id: u32,
+    peer_id: u32,
+    addr: SocketAddr,
+    candidates: &[Candidate],
+    check_key: &[u8],
+) -> Checklist {
+    if check_key.is_empty() {
+        return Checklist::default();
+    }
+    let mut remote = candidates.to_vec();
+    if !remote.iter().any(|c| c.addr == addr) {
+        remote.push(Candidate {
+            kind: CandidateKind::ServerReflexive,
+            addr,
+            priority: ice::priority(CandidateKind::ServerReflexive, u16::MAX),
+        });
+    }
+    Checklist::new(&shared.candidates, &remote, my_peer_id < peer_id)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_mode_direct(
+    shared: &SessionShared,
+    username: &str,
+    addr: SocketAddr,
+    peer_id: u32,
+    port_hint: PortHint,
+    fingerprint: &[u8],
+    candidates: &[Candidate],
+    check_key: &[u8],
+) {
+    if is_me(shared, peer_id) {
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            addr = addr;
+            "Server confirms you ({}) are relay",
+            username
+        );
         return;
     }
 
-    if line.starts_with(&format!("{MSG_NAT_SEEN} ")) {
+    {
+        let mut st = shared.state.lock().unwrap();
+        let my_peer_id = st.my_peer_id;
+        if let Some(peer) = st.peers.iter_mut().find(|p| p.peer_id == peer_id) {
+            peer.port_hint = port_hint;
+            if peer.server_addr != addr {
+                // same peer, new mapping: check it again
+                log!(
+                    Info,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer_id,
+                    addr = addr;
+                    "Peer {} moved",
+                    username
+                );
+                peer.addr = addr;
+                peer.server_addr = addr;
+                peer.connected = peer.use_server_relay;
+                peer.created_at = Instant::now();
+                peer.checks = checklist(shared, my_peer_id, peer_id, addr, candidates, check_key);
+            } else if peer.check_key != check_key {
+                // a restarted client: checks start over under its new key
+                peer.checks = checklist(shared, my_peer_id, peer_id, addr, candidates, check_key);
+            }
+            peer.check_key = check_key.to_vec();
+            update_fingerprint(shared, peer, fingerprint);
+            return;
+        }
+        let checks = checklist(shared, my_peer_id, peer_id, addr, candidates, check_key);
+        st.peers.push(PeerInfo {
+            peer_id,
+            addr,
+            server_addr: addr,
+            last_pong: Instant::now(),
+            username: username.to_string(),
+            connected: false,
+            created_at: Instant::now(),
+            use_server_relay: false,
+            relay_requested: false,
+            nat_kind: NatKind::Unknown,
+            port_hint,
+            via_socket: None,
+            fingerprint: fingerprint.to_vec(),
+            check_key: check_key.to_vec(),
+            media_keys: None,
+            checks,
+        });
+    }
+    log!(
+        Info,
+        server_id = &shared.config.server_id,
+        channel = &shared.config.channel,
+        user = &shared.config.user,
+        peer_id = peer_id,
+        addr = addr;
+        "Added peer {}",
+        username
+    );
+    shared.emit(ClientEvent::PeerAdded {
+        peer_id,
+        username: username.to_string(),
+        addr,
+    });
+}
+
+// a new certificate means a new client session: the old association is useless
+fn update_fingerprint(shared: &SessionShared, peer: &mut PeerInfo, fingerprint: &[u8]) {
+    if fingerprint.is_empty() || peer.fingerprint == fingerprint {
         return;
     }
+    peer.fingerprint = fingerprint.to_vec();
+    peer.media_keys = None;
+    shared.dtls.lock().unwrap().remove(&peer.peer_id);
+    shared.srtp.lock().unwrap().remove(&peer.peer_id);
+}
+
+fn handle_user_left(shared: &SessionShared, peer_id: u32) {
+    let removed = {
+        let mut st = shared.state.lock().unwrap();
+        st.peers
+            .iter()
+            .position(|p| p.peer_id == peer_id)
+            .map(|pos| st.peers.remove(pos))
+    };
+    shared.dtls.lock().unwrap().remove(&peer_id);
+    shared.srtp.lock().unwrap().remove(&peer_id);
+    if let Some(peer) = removed {
+        // whoever left must not read what comes next; dtls_loop hands the new key out
+        let mut group = shared.group.lock().unwrap();
+        group.remove_peer(peer_id);
+        group.rotate();
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "Rotated sender key to #{}",
+            group.key_id()
+        );
+        drop(group);
 
-    println!("Unhandled control line: {}", line);
+        log!(
+            Info,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id;
+            "{} left, removed from list",
+            peer.username
+        );
+        shared.emit(ClientEvent::PeerLeft {
+            peer_id,
+            username: peer.username,
+        });
+    }
 }
 
-pub fn handle_mode_line(
-    line: &str,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    me: &str,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
+fn handle_mode_server_relay(
+    shared: &SessionShared,
+    username: &str,
+    peer_id: u32,
+    fingerprint: &[u8],
 ) {
-    let parts: Vec<&str> = line.split_whitespace().collect();
-    if parts.is_empty() {
+    // if the server is relaying for 'me', i must send to server
+    if is_me(shared, peer_id) {
+        let (changed, was_relay) = {
+            let mut st = shared.state.lock().unwrap();
+            (
+                !std::mem::replace(&mut st.send_via_server, true),
+                std::mem::replace(&mut st.is_relay, false),
+            )
+        };
+
+        // the server took over relaying for the channel
+        if was_relay {
+            log!(
+                Info,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user;
+                "No longer relay - the server relays for us."
+            );
+            shared.emit(ClientEvent::RelayLost);
+        }
+
+        if changed {
+            //i am symmetric (or lone) => send via server
+            log!(
+                Info,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user;
+                "I will send via server from now on."
+            );
+            shared.emit(ClientEvent::ServerRelayEnabled {
+                peer_id,
+                username: username.to_string(),
+            });
+        }
+
+        // stop the punching loop
+        shared
+            .punch_paused
+            .send_if_modified(|p| !std::mem::replace(p, true));
         return;
     }
 
-    match parts[0] {
-        MSG_MODE if parts.len() >= 2 => match parts[1] {
-            MSG_RELAY => handle_mode_relay(is_relay),
-
-            MSG_SERVER_RELAY => {
-                if parts.len() >= 3 {
-                    let username = parts[2];
+    let newly_enabled = {
+        let mut st = shared.state.lock().unwrap();
 
-                    // if the server is relaying for 'me', i must send to server
-                    if username == me {
-                        return;
-                    }
-                    // if i am the RELAY, note that the channel has server-relayed peers
-                    if *is_relay.lock().unwrap() {
-                        if !channel_has_server_relays.load(Ordering::Acquire) {
-                            channel_has_server_relays.store(true, Ordering::Release);
-                            println!("Relay: channel has server-relayed peers.");
-                        }
-                    } else {
-                        println!("Server will relay for user: {}", username);
-                    }
+        // if i am the RELAY, note that the channel has server-relayed peers
+        if st.is_relay {
+            if !st.channel_has_server_relays {
+                st.channel_has_server_relays = true;
+                log!(
+                    Info,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user;
+                    "Relay will mirror traffic to server."
+                );
+            }
+        } else {
+            log!(
+                Info,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                peer_id = peer_id;
+                "Server will relay for user: {}",
+                username
+            );
+        }
 
-                    //mark that peer as server-relayed to stop punching it
-                    let mut guard = peers.lock().unwrap();
-                    if let Some(peer) = guard.iter_mut().find(|p| p.username == username) {
-                        peer.use_server_relay = true;
-                        peer.connected = true; // stop punching
-                        peer.relay_requested = true;
-                    }
-                } else {
-                    // just a lone user :(
-                    println!("Server is currently being relay for the lone user.");
-                }
+        //mark that peer as server-relayed to stop punching it
+        match st.peers.iter_mut().find(|p| p.peer_id == peer_id) {
+            Some(peer) => {
+                update_fingerprint(shared, peer, fingerprint);
+                let newly = !peer.use_server_relay;
+                peer.use_server_relay = true;
+                peer.connected = true; // stop punching
+                peer.relay_requested = true;
+                newly
             }
+            None => true,
+        }
+    };
 
-            MSG_DIRECT if parts.len() >= 4 => handle_mode_direct(&parts, peers, me),
-            _ => handle_unrecognized_command(line),
-        },
-        MSG_USER_LEFT if parts.len() >= 2 => handle_user_left(&parts, peers),
-        _ => handle_unrecognized_command(line),
+    if newly_enabled {
+        shared.emit(ClientEvent::ServerRelayEnabled {
+            peer_id,
+            username: username.to_string(),
+        });
     }
 }
 
-pub fn handle_ping(socket: &UdpSocket, src: std::net::SocketAddr) {
-    let _ = socket.send_to(MSG_PONG.as_bytes(), src);
-    println!("Received {MSG_PING} from {src}, sent {MSG_PONG}");
+fn update_relay_is_active(shared: &SessionShared) {
+    let active = {
+        let st = shared.state.lock().unwrap();
+        st.is_relay || st.channel_has_server_relays
+    };
+    //starts/stops relay loop instantly
+    shared
+        .relay_active
+        .send_if_modified(|a| std::mem::replace(a, active) != active);
 }
 
-pub fn handle_pong(peers: &Arc<Mutex<Vec<PeerInfo>>>, src: std::net::SocketAddr) {
-    let mut peers_guard = peers.lock().unwrap();
-    if let Some(peer) = peers_guard.iter_mut().find(|p| p.addr == src) {
-        peer.last_pong = Instant::now();
-        println!("Received {MSG_PONG} from {}", peer.username);
+async fn handle_ping(shared: &SessionShared, hdr: &Header, src: SocketAddr) {
+    let _ = send_to_peer(shared, &ControlMessage::Pong, hdr.src_peer_id, src).await;
+    log!(
+        Trace,
+        server_id = &shared.config.server_id,
+        channel = &shared.config.channel,
+        user = &shared.config.user,
+        peer_id = hdr.src_peer_id,
+        addr = src;
+        "Received PING, sent PONG"
+    );
+}
+
+// by the header's peer id, or by address for packets sent before WELCOME
+fn find_peer<'a>(
+    st: &'a mut SessionState,
+    hdr: &Header,
+    src: SocketAddr,
+) -> Option<&'a mut PeerInfo> {
+    if hdr.src_peer_id != 0 {
+        st.peers.iter_mut().find(|p| p.peer_id == hdr.src_peer_id)
+    } else {
+        st.peers.iter_mut().find(|p| p.addr == src)
     }
 }
 
-pub fn ensure_connected(p: &mut PeerInfo, con_type: &str) {
-    if !p.connected {
-        println!(
-            "Peer {} is now CONNECTED ({}) at {}",
-            p.username, con_type, p.addr
+fn handle_pong(shared: &SessionShared, hdr: &Header, src: SocketAddr) {
+    let mut st = shared.state.lock().unwrap();
+    if let Some(peer) = find_peer(&mut st, hdr, src) {
+        peer.last_pong = Instant::now();
+        log!(
+            Trace,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer.peer_id,
+            addr = src;
+            "Received PONG from {}",
+            peer.username
         );
     }
+}
+
+// Marks `p` as reachable, returning the event to emit if this is the first time
+pub fn ensure_connected(p: &mut PeerInfo, via: ConnectionPath) -> Option<ClientEvent> {
     p.last_pong = Instant::now();
+    if p.connected {
+        return None;
+    }
+
+    log!(
+        Info,
+        user = &p.username,
+        peer_id = p.peer_id,
+        addr = p.addr;
+        "Peer is now CONNECTED ({:?})",
+        via
+    );
     p.connected = true;
+    Some(ClientEvent::PeerConnected {
+        peer_id: p.peer_id,
+        username: p.username.clone(),
+        addr: p.addr,
+        via,
+    })
 }
 
-pub fn handle_hole_punch(peers: &Arc<Mutex<Vec<PeerInfo>>>, src: std::net::SocketAddr) {
-    println!("Received hole punch from {}", src);
-    let mut peers_guard = peers.lock().unwrap();
-    if let Some(peer) = peers_guard.iter_mut().find(|p| p.addr == src) {
-        ensure_connected(peer, "punch");
-    } else {
-        println!("Hole punch received from unknown peer: {}", src);
+// `authenticated` punches come from a check MACed under our check key; a
+// plain HOLE_PUNCH carries nothing an off-path sender could not forge, so it
+// only counts from the address we already have for the peer
+fn handle_hole_punch(shared: &SessionShared, hdr: &Header, src: SocketAddr, authenticated: bool) {
+    log!(
+        Trace,
+        server_id = &shared.config.server_id,
+        channel = &shared.config.channel,
+        user = &shared.config.user,
+        addr = src;
+        "Received hole punch"
+    );
+    let event = {
+        let mut st = shared.state.lock().unwrap();
+        match find_peer(&mut st, hdr, src) {
+            Some(peer) if peer.addr != src => {
+                // a symmetric peer reaches us from a new mapping, not the one the server saw
+                if !authenticated
+                    || hdr.src_peer_id == 0
+                    || peer.port_hint == PortHint::None
+                    || peer.connected
+                {
+                    log!(
+                        Debug,
+                        server_id = &shared.config.server_id,
+                        channel = &shared.config.channel,
+                        user = &shared.config.user,
+                        peer_id = peer.peer_id,
+                        addr = src;
+                        "Ignoring hole punch from {} off its address",
+                        peer.username
+                    );
+                    return;
+                }
+                log!(
+                    Debug,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer.peer_id,
+                    addr = src;
+                    "Peer {} punched from a new mapping",
+                    peer.username
+                );
+                peer.addr = src;
+                ensure_connected(peer, ConnectionPath::Punch)
+            }
+            Some(peer) => ensure_connected(peer, ConnectionPath::Punch),
+            None => {
+                log!(
+                    Debug,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    addr = src;
+                    "Hole punch received from unknown peer"
+                );
+                None
+            }
+        }
+    };
+    if let Some(event) = event {
+        shared.emit(event);
     }
 }
 
-fn handle_relay_message_to_peers(
-    socket: &UdpSocket,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    sender: &str,
-    message: &str,
+// a check opens our side of the pair like a punch does; only checks MACed
+// under our check key by peers we know get an answer, so nobody else can
+// plant a pair or use us to reflect traffic
+async fn handle_check(
+    shared: &SessionShared,
+    hdr: &Header,
+    txn: u64,
+    tag: &[u8; CHECK_TAG_LEN],
+    src: SocketAddr,
 ) {
-    let peers_guard = peers.lock().unwrap();
-    for peer in peers_guard.iter() {
-        if peer.username != sender {
-            if let Err(e) = socket.send_to(message.as_bytes(), peer.addr) {
-                eprintln!("Failed to send data to {}: {}", peer.addr, e);
+    let (channel_id, my_peer_id) = shared.ids();
+    let peer_id = hdr.src_peer_id;
+    if !ice::check_tag_matches(
+        &shared.check_key,
+        channel_id,
+        peer_id,
+        my_peer_id,
+        txn,
+        false,
+        tag,
+    ) {
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id,
+            addr = src;
+            "Dropping connectivity check with a bad tag"
+        );
+        return;
+    }
+    let known = {
+        let mut st = shared.state.lock().unwrap();
+        match st.peers.iter_mut().find(|p| p.peer_id == hdr.src_peer_id) {
+            Some(peer) => {
+                peer.checks.triggered(src);
+                true
             }
+            None => false,
         }
+    };
+    if !known {
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            addr = src;
+            "Connectivity check from unknown peer"
+        );
+        return;
     }
+    let reply = ControlMessage::CheckOk {
+        txn,
+        tag: ice::check_tag(
+            &shared.check_key,
+            channel_id,
+            my_peer_id,
+            peer_id,
+            txn,
+            true,
+        ),
+    };
+    if let Err(e) = send_to_peer(shared, &reply, hdr.src_peer_id, src).await {
+        log!(
+            Warn,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            addr = src;
+            "Failed to answer check: {}",
+            e
+        );
+    }
+    handle_hole_punch(shared, hdr, src, true);
 }
 
-pub fn handle_data_message(
-    socket: &UdpSocket,
-    line: &str,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-    signaling_addr: &str,
+// the pair answered: it works both ways, use it if it beats the selected one;
+// only the peer knows the key the answer is MACed with
+fn handle_check_ok(
+    shared: &SessionShared,
+    hdr: &Header,
+    txn: u64,
+    tag: &[u8; CHECK_TAG_LEN],
+    src: SocketAddr,
 ) {
-    if line.starts_with(&format!("{MSG_DATA} ")) {
-        let parts: Vec<&str> = line.splitn(3, ' ').collect();
-        if parts.len() >= 3 {
-            let sender = parts[1];
-            let text = parts[2];
-            println!("[{}]: {}", sender, text);
-
-            //NO MATTER THE SOURCE, we've observed sender activity
-            mark_peer_connected_by_name(peers, sender);
-
-            if *is_relay.lock().unwrap() {
-                // 1) transmit to peers
-                handle_relay_message_to_peers(socket, peers, sender, line);
-
-                // 2) mirror to server only if the channel has server-relayed users
-                if channel_has_server_relays.load(Ordering::Acquire) {
-                    let _ = socket.send_to(line.as_bytes(), signaling_addr);
-                }
-            } else {
-                // non-relay receiving data does not forward further
-                // if this non-relay is symmetric and has to send via server, that's handled in networking.rs
+    let event = {
+        let mut st = shared.state.lock().unwrap();
+        let (channel_id, my_peer_id) = (st.channel_id, st.my_peer_id);
+        let Some(peer) = st.peers.iter_mut().find(|p| p.peer_id == hdr.src_peer_id) else {
+            return;
+        };
+        let key = &peer.check_key;
+        if !ice::check_tag_matches(key, channel_id, peer.peer_id, my_peer_id, txn, true, tag) {
+            log!(
+                Debug,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                peer_id = peer.peer_id,
+                addr = src;
+                "Dropping CHECK_OK with a bad tag"
+            );
+            return;
+        }
+        match peer.checks.succeeded(txn, src) {
+            None => return,
+            Some(true) if peer.addr != src => {
+                log!(
+                    Info,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer.peer_id,
+                    addr = src;
+                    "Peer {} reachable here, switching from {}",
+                    peer.username,
+                    peer.addr
+                );
+                peer.addr = src;
             }
+            Some(_) => {}
         }
+        ensure_connected(peer, ConnectionPath::Punch)
+    };
+    if let Some(event) = event {
+        shared.emit(event);
     }
 }
 
-pub fn handle_peer_message(peers: &Arc<Mutex<Vec<PeerInfo>>>, src: std::net::SocketAddr) {
-    let mut guard = peers.lock().unwrap();
-    if let Some(p) = guard.iter_mut().find(|p| p.addr == src) {
-        ensure_connected(p, "direct");
+async fn handle_data_message(
+    shared: &SessionShared,
+    hdr: &Header,
+    msg: &ControlMessage,
+    from_server: bool,
+) {
+    let ControlMessage::Data { sender, payload } = msg else {
+        return;
+    };
+    // the header names the peer that spoke, even when a relay or the server forwarded it
+    let origin = hdr.src_peer_id;
+
+    // sealed with the origin's sender key; a relay forwards it even if it cannot read it
+    let aad = group::aad(shared.ids().0, origin, sender);
+    let opened = shared.group.lock().unwrap().open(origin, &aad, payload);
+    match opened {
+        Some(plaintext) => {
+            // only a payload sealed by the origin proves it is active, whatever the path
+            let via = if from_server {
+                ConnectionPath::Server
+            } else {
+                ConnectionPath::Direct
+            };
+            mark_peer_connected(shared, origin, via);
+            if shared
+                .data_tx
+                .try_send((origin, sender.clone(), plaintext))
+                .is_err()
+            {
+                log!(
+                    Warn,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = origin;
+                    "Dropping DATA from {}: receiver is full or closed",
+                    sender
+                );
+            }
+        }
+        None => log!(
+            Warn,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = origin;
+            "Cannot read DATA from {}: no sender key or tampered",
+            sender
+        ),
     }
-}
 
-// we will call this function when DATA sender... is seen (even received from the server)
-fn mark_peer_connected_by_name(peers: &Arc<Mutex<Vec<PeerInfo>>>, name: &str) {
-    let mut guard = peers.lock().unwrap();
-    if let Some(peer) = guard.iter_mut().find(|p| p.username == name) {
-        ensure_connected(peer, "via server");
+    // non-relay receiving data does not forward further
+    let (is_relay, mirror_to_server, targets) = {
+        let st = shared.state.lock().unwrap();
+        let targets: Vec<(u32, SocketAddr)> = st
+            .peers
+            .iter()
+            .filter(|p| p.peer_id != origin)
+            .map(|p| (p.peer_id, p.addr))
+            .collect();
+        (st.is_relay, st.channel_has_server_relays, targets)
+    };
+
+    if !is_relay {
+        return;
+    }
+
+    let (channel_id, _) = shared.ids();
+
+    // 1) transmit to peers
+    for (peer_id, addr) in targets {
+        let socket = socket_for(shared, peer_id);
+        if let Err(e) = send_packet(&socket, channel_id, origin, peer_id, msg, addr).await {
+            log!(
+                Warn,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                peer_id = peer_id,
+                addr = addr;
+                "Failed to send data: {}",
+                e
+            );
+        }
+    }
+
+    // 2) mirror to server only if the channel has server-relayed users
+    if mirror_to_server && let Ok(pkt) = control::encode_packet(channel_id, origin, BROADCAST, msg)
+    {
+        let _ = send_signaling(shared, pkt).await;
     }
 }
 
-pub fn process_incoming_message(
-    socket: &UdpSocket,
-    message: &str,
-    src: std::net::SocketAddr,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    user: &str,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-    signaling_addr: &str,
-) {
-    for line in message.lines() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
+// a ClientHello in epoch 0: the active side started over
+fn is_client_hello(datagram: &[u8]) -> bool {
+    datagram.len() > 13 && datagram[0] == 22 && datagram[3..5] == [0, 0] && datagram[13] == 1
+}
+
+async fn handle_dtls(shared: &SessionShared, hdr: &Header, payload: &[u8], src: SocketAddr) {
+    let (_, my_peer_id) = shared.ids();
+    let peer_id = hdr.src_peer_id;
+
+    // directly from the peer, or forwarded by the server by header peer ids
+    let expected = {
+        let st = shared.state.lock().unwrap();
+        st.peers
+            .iter()
+            .find(|p| p.peer_id == peer_id)
+            .filter(|p| src == p.addr || src == shared.signaling_addr)
+            .map(|p| p.fingerprint.clone())
+    };
+    let Some(expected) = expected.filter(|f| !f.is_empty()) else {
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id,
+            addr = src;
+            "Ignoring DTLS: unknown peer or no fingerprint"
+        );
+        return;
+    };
+    if hdr.dst_peer_id != my_peer_id {
+        return;
+    }
+
+    let result = {
+        let mut dtls = shared.dtls.lock().unwrap();
+        let passive = DtlsRole::for_pair(my_peer_id, peer_id) == DtlsRole::Passive;
+        if passive
+            && is_client_hello(payload)
+            && dtls.get(&peer_id).is_some_and(|p| p.keys().is_some())
+        {
+            dtls.remove(&peer_id);
         }
-        match line {
-            MSG_PING => handle_ping(socket, src),
-            MSG_PONG => handle_pong(peers, src),
-            MSG_HOLE_PUNCH => handle_hole_punch(peers, src),
-            _ => {
-                if line.starts_with(&format!("{MSG_DATA} ")) {
-                    handle_data_message(
-                        socket,
-                        line,
-                        peers,
-                        is_relay,
-                        channel_has_server_relays,
-                        signaling_addr,
-                    );
-                } else {
-                    //Handle control messages: MODE / USER_LEFT
-                    handle_mode_line(line, peers, user, is_relay, channel_has_server_relays);
+
+        let association = match dtls.entry(peer_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            // the active side's ClientHello opens the association on our side
+            Entry::Vacant(e) if passive => {
+                match DtlsPeer::new(&shared.identity, DtlsRole::Passive, expected) {
+                    Ok(association) => e.insert(association),
+                    Err(err) => {
+                        log!(
+                            Warn,
+                            server_id = &shared.config.server_id,
+                            channel = &shared.config.channel,
+                            user = &shared.config.user,
+                            peer_id = peer_id;
+                            "Failed to set up DTLS: {err}"
+                        );
+                        return;
+                    }
                 }
             }
+            Entry::Vacant(_) => return,
+        };
+
+        let was_secured = association.keys().is_some();
+        match association.handle(Some(payload)) {
+            Ok(mut out) => {
+                let keys = association.keys().filter(|_| !was_secured).cloned();
+                let mut group = shared.group.lock().unwrap();
+                if keys.is_some() {
+                    group.peer_reset(peer_id);
+                }
+                out.extend(group.exchange(peer_id, association));
+                Ok((out, keys))
+            }
+            Err(err) => {
+                dtls.remove(&peer_id);
+                Err(err)
+            }
         }
+    };
+
+    match result {
+        Ok((out, keys)) => {
+            send_dtls(shared, peer_id, out).await;
+            if let Some(keys) = keys {
+                peer_secured(shared, peer_id, keys);
+            }
+        }
+        Err(e) => log!(
+            Warn,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id;
+            "DTLS handshake failed: {e}"
+        ),
+    }
+}
+
+fn peer_secured(shared: &SessionShared, peer_id: u32, keys: MediaKeys) {
+    let username = {
+        let mut st = shared.state.lock().unwrap();
+        let Some(peer) = st.peers.iter_mut().find(|p| p.peer_id == peer_id) else {
+            return;
+        };
+        let contexts = (
+            SrtpSender::new(&keys.local_key, &keys.local_salt),
+            SrtpReceiver::new(&keys.remote_key, &keys.remote_salt),
+        );
+        // a new handshake means new keys, the old contexts go with them
+        shared.srtp.lock().unwrap().insert(peer_id, contexts);
+        peer.media_keys = Some(keys);
+        peer.username.clone()
+    };
+    log!(
+        Info,
+        server_id = &shared.config.server_id,
+        channel = &shared.config.channel,
+        user = &shared.config.user,
+        peer_id = peer_id;
+        "DTLS with {} established",
+        username
+    );
+    shared.emit(ClientEvent::PeerSecured { peer_id, username });
+}
+
+// media is end-to-end: only the peer that protected a frame can have sent it,
+// relays and the server just pass the ciphertext along
+fn handle_srtp(shared: &SessionShared, hdr: &Header, payload: &[u8], src: SocketAddr) {
+    let (_, my_peer_id) = shared.ids();
+    let peer_id = hdr.src_peer_id;
+    if hdr.dst_peer_id != my_peer_id {
+        return;
+    }
+
+    let result = {
+        let mut srtp = shared.srtp.lock().unwrap();
+        let Some((_, receiver)) = srtp.get_mut(&peer_id) else {
+            log!(
+                Debug,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                peer_id = peer_id,
+                addr = src;
+                "Ignoring SRTP: no keys for the peer"
+            );
+            return;
+        };
+        receiver.unprotect(payload)
+    };
+
+    match result {
+        Ok((rtp, payload)) if rtp.ssrc == hdr.stream_id => {
+            let frame = MediaFrame {
+                peer_id,
+                stream_id: rtp.ssrc,
+                timestamp: rtp.timestamp,
+                payload,
+            };
+            if shared.media_tx.try_send(frame).is_err() {
+                log!(
+                    Warn,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer_id;
+                    "Dropping media: receiver is full or closed"
+                );
+            }
+        }
+        Ok(_) => log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id,
+            addr = src;
+            "Ignoring SRTP: stream id does not match the SSRC"
+        ),
+        Err(e) => log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id,
+            addr = src;
+            "Ignoring SRTP: {e}"
+        ),
+    }
+}
+
+fn handle_peer_message(shared: &SessionShared, src: SocketAddr) {
+    let event = {
+        let mut st = shared.state.lock().unwrap();
+        st.peers
+            .iter_mut()
+            .find(|p| p.addr == src)
+            .and_then(|p| ensure_connected(p, ConnectionPath::Direct))
+    };
+    if let Some(event) = event {
+        shared.emit(event);
+    }
+}
+
+// we will call this function when DATA sender... is seen (even received from the server)
+fn mark_peer_connected(shared: &SessionShared, peer_id: u32, via: ConnectionPath) {
+    let event = {
+        let mut st = shared.state.lock().unwrap();
+        st.peers
+            .iter_mut()
+            .find(|p| p.peer_id == peer_id)
+            .and_then(|p| ensure_connected(p, via))
+    };
+    if let Some(event) = event {
+        shared.emit(event);
     }
 }
@@ -0,0 +1,373 @@
+use std::cmp::Reverse;
+use std::net::{IpAddr, SocketAddr};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+
+use crate::net;
+use crate::proto::control::{CHECK_TAG_LEN, MAX_CANDIDATES};
+pub use crate::proto::control::{Candidate, CandidateKind};
+
+const CHECK_ATTEMPTS: u32 = 6; // checks sent on one pair before it counts as failed
+const LAN_FIRST_ATTEMPTS: u32 = 3; // private checks before a peer behind our NAT is tried publicly
+const COMPONENT: u32 = 1; // control, DTLS and SRTP share one flow
+const MAX_PEER_REFLEXIVE: usize = 4; // learnt pairs per peer, the rest is ignored
+const CHECK_KEY_LEN: usize = 16;
+
+/// A fresh key for peers to MAC their checks to us with, the ICE password of
+/// this session. It only travels in CONNECT and MODE DIRECT, so through the
+/// Noise session with the server.
+pub fn check_key() -> Vec<u8> {
+    rand::random::<[u8; CHECK_KEY_LEN]>().to_vec()
+}
+
+fn check_mac(
+    key: &[u8],
+    channel_id: u64,
+    from: u32,
+    to: u32,
+    txn: u64,
+    answer: bool,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(b"odnp check");
+    mac.update(&[answer as u8]);
+    mac.update(&channel_id.to_le_bytes());
+    mac.update(&from.to_le_bytes());
+    mac.update(&to.to_le_bytes());
+    mac.update(&txn.to_le_bytes());
+    mac
+}
+
+/// The MAC on a CHECK (`answer` unset) or CHECK_OK from peer `from` to peer
+/// `to`, under the check key of the side answering the check.
+pub fn check_tag(
+    key: &[u8],
+    channel_id: u64,
+    from: u32,
+    to: u32,
+    txn: u64,
+    answer: bool,
+) -> [u8; CHECK_TAG_LEN] {
+    let digest = check_mac(key, channel_id, from, to, txn, answer).finalize();
+    let mut tag = [0u8; CHECK_TAG_LEN];
+    tag.copy_from_slice(&digest.into_bytes()[..CHECK_TAG_LEN]);
+    tag
+}
+
+/// Whether `tag` is the one `check_tag` gives; never without a key.
+pub fn check_tag_matches(
+    key: &[u8],
+    channel_id: u64,
+    from: u32,
+    to: u32,
+    txn: u64,
+    answer: bool,
+    tag: &[u8; CHECK_TAG_LEN],
+) -> bool {
+    !key.is_empty()
+        && check_mac(key, channel_id, from, to, txn, answer)
+            .verify_truncated_left(tag)
+            .is_ok()
+}
+
+fn type_preference(kind: CandidateKind) -> u32 {
+    match kind {
+        CandidateKind::Host => 126,
+        CandidateKind::PeerReflexive => 110,
+        CandidateKind::ServerReflexive => 100,
+        CandidateKind::Relay => 0,
+    }
+}
+
+/// Candidate priority (RFC 8445 section 5.1.2.1).
+pub fn priority(kind: CandidateKind, local_preference: u16) -> u32 {
+    (type_preference(kind) << 24) | ((local_preference as u32) << 8) | (256 - COMPONENT)
+}
+
+/// Pair priority (RFC 8445 section 6.1.2.3), the same on both sides.
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (controlling as u64, controlled as u64);
+    (1 << 32) * g.min(d) + 2 * g.max(d) + u64::from(g > d)
+}
+
+// link-local IPv6 needs a scope id peers cannot know, loopback only reaches us
+fn usable_host(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => !v4.is_loopback() && !v4.is_unspecified() && !v4.is_link_local(),
+        IpAddr::V6(v6) => {
+            !v6.is_loopback() && !v6.is_unspecified() && (v6.segments()[0] & 0xffc0) != 0xfe80
+        }
+    }
+}
+
+/// Host candidates on `port` for the interface addresses `reachable` accepts,
+/// a server reflexive one for `mapped` unless it is one of them, and `relay`;
+/// at most `MAX_CANDIDATES`, dropping the lowest host candidates.
+pub fn collect_candidates(
+    interface_ips: &[IpAddr],
+    port: u16,
+    mapped: Option<SocketAddr>,
+    relay: SocketAddr,
+    reachable: impl Fn(SocketAddr) -> bool,
+) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = interface_ips
+        .iter()
+        .filter(|ip| usable_host(**ip))
+        .map(|ip| SocketAddr::new(*ip, port))
+        .filter(|addr| reachable(*addr))
+        .take(MAX_CANDIDATES - 2) // room for the server reflexive and relay ones
+        .enumerate()
+        .map(|(i, addr)| Candidate {
+            kind: CandidateKind::Host,
+            addr,
+            priority: priority(CandidateKind::Host, u16::MAX.saturating_sub(i as u16)),
+        })
+        .collect();
+
+    if let Some(mapped) = mapped.filter(|m| !candidates.iter().any(|c| c.addr == *m)) {
+        candidates.push(Candidate {
+            kind: CandidateKind::ServerReflexive,
+            addr: mapped,
+            priority: priority(CandidateKind::ServerReflexive, u16::MAX),
+        });
+    }
+    candidates.push(Candidate {
+        kind: CandidateKind::Relay,
+        addr: relay,
+        priority: priority(CandidateKind::Relay, u16::MAX),
+    });
+    candidates
+}
+
+/// Everything this session can be reached at: the interfaces of `socket`,
+/// the mapping NAT detection saw and the signaling server as relay.
+pub fn gather(socket: &UdpSocket, mapped: Option<SocketAddr>, relay: SocketAddr) -> Vec<Candidate> {
+    let port = socket.local_addr().map(|a| a.port()).unwrap_or(0);
+    let ips: Vec<IpAddr> = local_ip_address::list_afinet_netifas()
+        .map(|ifs| ifs.into_iter().map(|(_, ip)| ip).collect())
+        .unwrap_or_default();
+    collect_candidates(&ips, port, mapped, relay, |addr| {
+        net::can_reach(socket, addr)
+    })
+}
+
+// our public IP, from the server reflexive candidate, against the peer's
+fn shares_public_ip(local: &[Candidate], remote: &[Candidate]) -> bool {
+    let public = |candidates: &[Candidate]| -> Vec<IpAddr> {
+        candidates
+            .iter()
+            .filter(|c| c.kind == CandidateKind::ServerReflexive)
+            .map(|c| c.addr.ip())
+            .collect()
+    };
+    let ours = public(local);
+    public(remote).iter().any(|ip| ours.contains(ip))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckState {
+    Waiting,
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct CandidatePair {
+    pub remote: Candidate,
+    pub priority: u64,
+    pub state: CheckState,
+    txn: u64, // kept across retransmits, a late CHECK_OK still counts
+    attempts: u32,
+}
+
+/// The connectivity checks towards one peer, best pair first.
+///
+/// All local candidates share the session socket, so a pair is its remote
+/// candidate and the best local host candidate of the same family. Relay
+/// candidates are not checked: the server relay is requested separately once
+/// no direct pair got through.
+///
+/// A peer behind the same public IP is tried on its host candidates alone
+/// first: its public mapping only answers if our NAT supports hairpinning.
+///
+/// Only authenticated checks reach `triggered` and `succeeded` (see
+/// `check_tag`), and at most `MAX_PEER_REFLEXIVE` peer reflexive pairs are
+/// learnt from them.
+#[derive(Clone, Debug, Default)]
+pub struct Checklist {
+    pairs: Vec<CandidatePair>,
+    selected: Option<usize>,
+    local: Vec<Candidate>,
+    controlling: bool, // the side with the lower peer id
+    same_nat: bool,    // the peer's public IP is ours
+}
+
+impl Checklist {
+    pub fn new(local: &[Candidate], remote: &[Candidate], controlling: bool) -> Self {
+        let mut list = Self {
+            pairs: Vec::new(),
+            selected: None,
+            local: local.to_vec(),
+            controlling,
+            same_nat: shares_public_ip(local, remote),
+        };
+        for candidate in remote.iter().filter(|c| c.kind != CandidateKind::Relay) {
+            if list.position(candidate.addr).is_none() {
+                let pair = list.pair(*candidate);
+                list.pairs.push(pair);
+            }
+        }
+        list.pairs.sort_by_key(|p| Reverse(p.priority));
+        list
+    }
+
+    fn pair(&self, remote: Candidate) -> CandidatePair {
+        let base = self
+            .local
+            .iter()
+            .filter(|c| c.kind == CandidateKind::Host && c.addr.is_ipv4() == remote.addr.is_ipv4())
+            .map(|c| c.priority)
+            .max()
+            .unwrap_or_else(|| priority(CandidateKind::Host, 0));
+        let priority = if self.controlling {
+            pair_priority(base, remote.priority)
+        } else {
+            pair_priority(remote.priority, base)
+        };
+        CandidatePair {
+            remote,
+            priority,
+            state: CheckState::Waiting,
+            txn: rand::random(),
+            attempts: 0,
+        }
+    }
+
+    fn position(&self, addr: SocketAddr) -> Option<usize> {
+        self.pairs.iter().position(|p| p.remote.addr == addr)
+    }
+
+    fn peer_reflexive(&self, addr: SocketAddr) -> Option<CandidatePair> {
+        let learnt = self
+            .pairs
+            .iter()
+            .filter(|p| p.remote.kind == CandidateKind::PeerReflexive)
+            .count();
+        (learnt < MAX_PEER_REFLEXIVE).then(|| {
+            self.pair(Candidate {
+                kind: CandidateKind::PeerReflexive,
+                addr,
+                priority: priority(CandidateKind::PeerReflexive, u16::MAX),
+            })
+        })
+    }
+
+    // keeps the order and `selected` pointing at the same pair
+    fn insert(&mut self, pair: CandidatePair) -> usize {
+        let addr = pair.remote.addr;
+        let selected = self.selected();
+        self.pairs.push(pair);
+        self.pairs.sort_by_key(|p| Reverse(p.priority));
+        self.selected = selected.and_then(|a| self.position(a));
+        self.position(addr).unwrap_or_default()
+    }
+
+    pub fn same_nat(&self) -> bool {
+        self.same_nat
+    }
+
+    /// Whether only the peer's private addresses are checked for now.
+    pub fn lan_pending(&self) -> bool {
+        self.same_nat
+            && self.selected.is_none()
+            && self.pairs.iter().any(|p| {
+                p.remote.kind == CandidateKind::Host
+                    && matches!(p.state, CheckState::Waiting | CheckState::InProgress)
+                    && p.attempts < LAN_FIRST_ATTEMPTS
+            })
+    }
+
+    pub fn pairs(&self) -> &[CandidatePair] {
+        &self.pairs
+    }
+
+    pub fn selected(&self) -> Option<SocketAddr> {
+        self.selected.map(|i| self.pairs[i].remote.addr)
+    }
+
+    /// Whether a pair better than the selected one may still succeed.
+    pub fn pending(&self) -> bool {
+        self.pairs[..self.selected.unwrap_or(self.pairs.len())]
+            .iter()
+            .any(|p| matches!(p.state, CheckState::Waiting | CheckState::InProgress))
+    }
+
+    /// The checks to send this round as `(remote address, txn)`, best pair
+    /// first; only pairs that would beat the selected one are still checked.
+    pub fn due(&mut self) -> Vec<(SocketAddr, u64)> {
+        let end = self.selected.unwrap_or(self.pairs.len());
+        let lan_only = self.lan_pending();
+        let mut out = Vec::new();
+        for pair in &mut self.pairs[..end] {
+            if !matches!(pair.state, CheckState::Waiting | CheckState::InProgress)
+                || (lan_only && pair.remote.kind != CandidateKind::Host)
+            {
+                continue;
+            }
+            if pair.attempts >= CHECK_ATTEMPTS {
+                pair.state = CheckState::Failed;
+                continue;
+            }
+            pair.attempts += 1;
+            pair.state = CheckState::InProgress;
+            out.push((pair.remote.addr, pair.txn));
+        }
+        out
+    }
+
+    /// The peer checked us from `from`: check that pair again (a triggered
+    /// check), learning `from` as a peer reflexive candidate if it is new.
+    pub fn triggered(&mut self, from: SocketAddr) {
+        match self.position(from) {
+            Some(i) if self.pairs[i].state == CheckState::Failed => {
+                self.pairs[i].state = CheckState::Waiting;
+                self.pairs[i].attempts = 0;
+            }
+            Some(_) => {}
+            None => {
+                if let Some(pair) = self.peer_reflexive(from) {
+                    self.insert(pair);
+                }
+            }
+        }
+    }
+
+    /// Records the CHECK_OK for `txn` that arrived from `from`: `None` if we
+    /// never sent that check (or `from` is new and no more pairs are learnt),
+    /// otherwise whether `from` is now the selected pair.
+    pub fn succeeded(&mut self, txn: u64, from: SocketAddr) -> Option<bool> {
+        let checked = self.pairs.iter().position(|p| p.txn == txn)?;
+        // the answer came back from another mapping: a peer reflexive candidate
+        let index = match self.position(from) {
+            Some(i) => i,
+            None => {
+                let mut pair = self.peer_reflexive(from)?;
+                pair.priority = self.pairs[checked].priority;
+                self.insert(pair)
+            }
+        };
+        self.pairs[index].state = CheckState::Succeeded;
+
+        if self
+            .selected
+            .is_some_and(|s| self.pairs[s].priority >= self.pairs[index].priority)
+        {
+            return Some(false);
+        }
+        self.selected = Some(index);
+        Some(true)
+    }
+}
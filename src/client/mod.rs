@@ -1,3 +1,16 @@
+pub mod dtls;
+pub mod events;
+pub mod group;
 pub mod handlers;
+pub mod ice;
+pub mod nat;
 pub mod networking;
+pub mod session;
 pub mod structures;
+
+pub use events::{ClientEvent, ConnectionPath};
+pub use session::Session;
+pub use structures::{MediaFrame, SessionConfig};
+
+#[cfg(test)]
+mod tests;
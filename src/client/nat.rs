@@ -0,0 +1,369 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+
+use crate::client::networking::{PROBE_PORT, SIGNALING_PORT};
+use crate::client::structures::NatKind;
+use crate::log;
+use crate::net;
+use crate::proto::{
+    control::{self, ControlMessage, PROBE_CHANGE_IP, PROBE_CHANGE_PORT, PortHint},
+    noise::{self, Initiator, MSG_INIT, MSG_RESPONSE},
+    packet::{self, Kind},
+    stun,
+};
+
+const PROBE_TIMEOUT_MS: u64 = 250; // per attempt, a probe is sent twice before giving up
+const DELTA_SAMPLES: usize = 5; // fresh mappings opened to measure port allocation
+const MAX_DELTA: i32 = 64; // larger steps are treated as random allocation
+const PREDICT_SPAN: i32 = 16; // predicted ports punched per round, past the server-seen one
+const BIRTHDAY_SPRAY: usize = 64; // random ports punched per round at a birthday peer
+pub const BIRTHDAY_SOCKETS: usize = 64; // extra mappings a birthday peer opens towards us
+
+/// What a client behind a symmetric NAT tries before falling back to the server relay.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymmetricPunch {
+    #[default]
+    Off,
+    /// Measure the port allocation step and have peers punch the predicted ports.
+    Predict,
+    /// Like `Predict`, falling back to many sockets and random peer punches
+    /// when allocation is not predictable.
+    Birthday,
+}
+
+/// How the NAT picks the public address for outgoing traffic (RFC 4787).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mapping {
+    EndpointIndependent,
+    AddressDependent,
+    AddressPortDependent,
+}
+
+/// Which outside endpoints may send back through a mapping (RFC 4787).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filtering {
+    EndpointIndependent,
+    AddressDependent,
+    AddressPortDependent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NatBehavior {
+    pub no_nat: bool,       // the server saw our own interface address and port
+    pub mapped: SocketAddr, // our public mapping, as the server saw it
+    pub mapping: Mapping,
+    pub filtering: Filtering,
+}
+
+impl NatBehavior {
+    pub fn kind(&self) -> NatKind {
+        if self.no_nat {
+            return NatKind::Public;
+        }
+        // a mapping that changes per destination cannot be punched with the
+        // address the server saw, whatever the filtering
+        match (self.mapping, self.filtering) {
+            (Mapping::EndpointIndependent, Filtering::EndpointIndependent) => NatKind::Cone,
+            (Mapping::EndpointIndependent, Filtering::AddressDependent) => NatKind::RestrictedCone,
+            (Mapping::EndpointIndependent, Filtering::AddressPortDependent) => {
+                NatKind::PortRestrictedCone
+            }
+            _ => NatKind::Symmetric,
+        }
+    }
+}
+
+// Observed addresses of the mapping tests, in RFC 5780 order: primary address,
+// alternate address/primary port, alternate address/alternate port. Without an
+// alternate address the second entry is the primary address' other port and
+// address-dependent mapping cannot be told apart from endpoint-independent.
+pub fn classify_mapping(
+    first: SocketAddr,
+    second: SocketAddr,
+    third: Option<SocketAddr>,
+) -> Mapping {
+    if first == second {
+        return Mapping::EndpointIndependent;
+    }
+    match third {
+        Some(third) if third == second => Mapping::AddressDependent,
+        _ => Mapping::AddressPortDependent,
+    }
+}
+
+// `change_ip` is None when the server has no alternate address to answer from
+pub fn classify_filtering(change_ip: Option<bool>, change_port: bool) -> Filtering {
+    if change_ip == Some(true) {
+        Filtering::EndpointIndependent
+    } else if change_port {
+        Filtering::AddressDependent
+    } else {
+        Filtering::AddressPortDependent
+    }
+}
+
+fn is_local(addr: SocketAddr, local_port: u16, local_ips: &[IpAddr]) -> bool {
+    addr.port() == local_port && local_ips.contains(&addr.ip())
+}
+
+struct ProbeReply {
+    mapped: SocketAddr,
+    from: SocketAddr,
+    other: Option<SocketAddr>,
+}
+
+// with a pinned server key every probe is its own Noise handshake, so an
+// answer from any of the server's ports can be checked; without one probes
+// are plain STUN Binding requests, which any RFC 5780 server answers too
+async fn probe(
+    socket: &UdpSocket,
+    server_key: Option<&[u8]>,
+    dst: SocketAddr,
+    seq: u8,
+    flags: u8,
+) -> Option<ProbeReply> {
+    match server_key {
+        Some(key) => sealed_probe(socket, key, dst, seq, flags).await,
+        None => stun_probe(socket, dst, flags).await,
+    }
+}
+
+async fn sealed_probe(
+    socket: &UdpSocket,
+    server_key: &[u8],
+    dst: SocketAddr,
+    seq: u8,
+    flags: u8,
+) -> Option<ProbeReply> {
+    let mut buf = [0u8; 256];
+    let pkt = control::encode_packet(0, 0, 0, &ControlMessage::NatProbe { seq, flags }).ok()?;
+
+    for _ in 0..2 {
+        let (initiator, msg) = Initiator::start(server_key, &pkt)?;
+        let _ = net::send_to(socket, &noise::frame(MSG_INIT, &msg), dst).await;
+        let deadline = Instant::now() + Duration::from_millis(PROBE_TIMEOUT_MS);
+
+        // late answers to earlier probes are skipped by seq
+        while let Ok(Ok((len, from))) = timeout_at(deadline, net::recv_from(socket, &mut buf)).await
+        {
+            let opened = match packet::decode(&buf[..len]) {
+                Some((hdr, payload)) if hdr.kind == Kind::Noise => match noise::unframe(payload) {
+                    Some((MSG_RESPONSE, body)) => initiator.finish(body).map(|(p, _)| p),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some((
+                _,
+                ControlMessage::NatSeen {
+                    addr,
+                    seq: s,
+                    other,
+                },
+            )) = opened.as_deref().and_then(control::decode_packet)
+                && s == seq
+            {
+                return Some(ProbeReply {
+                    mapped: addr,
+                    from,
+                    other,
+                });
+            }
+        }
+    }
+    None
+}
+
+async fn stun_probe(socket: &UdpSocket, dst: SocketAddr, flags: u8) -> Option<ProbeReply> {
+    let mut buf = [0u8; 512];
+    let request =
+        stun::binding_request(flags & PROBE_CHANGE_IP != 0, flags & PROBE_CHANGE_PORT != 0);
+    let pkt = request.encode(None);
+
+    for _ in 0..2 {
+        let _ = net::send_to(socket, &pkt, dst).await;
+        let deadline = Instant::now() + Duration::from_millis(PROBE_TIMEOUT_MS);
+
+        // late answers to earlier probes are skipped by transaction id
+        while let Ok(Ok((len, from))) = timeout_at(deadline, net::recv_from(socket, &mut buf)).await
+        {
+            if let Some(reply) = stun::Message::decode(&buf[..len])
+                && reply.txn == request.txn
+                && let Some((mapped, other)) = stun::binding_response(&reply)
+            {
+                return Some(ProbeReply {
+                    mapped,
+                    from,
+                    other,
+                });
+            }
+        }
+    }
+    None
+}
+
+async fn primary_addr(socket: &UdpSocket, signaling_ip: &str) -> Option<SocketAddr> {
+    net::resolve(socket, signaling_ip, SIGNALING_PORT).await
+}
+
+/// Runs the RFC 5780 mapping and filtering tests against the signaling server.
+pub async fn detect_nat_behavior(
+    socket: &UdpSocket,
+    signaling_ip: &str,
+    server_key: Option<&[u8]>,
+) -> Option<NatBehavior> {
+    let primary = primary_addr(socket, signaling_ip).await?;
+    let primary_alt_port = SocketAddr::new(primary.ip(), PROBE_PORT);
+
+    let first = probe(socket, server_key, primary, 1, 0).await?;
+    let other = first.other;
+
+    let local_port = socket.local_addr().ok()?.port();
+    let local_ips: Vec<IpAddr> = local_ip_address::list_afinet_netifas()
+        .map(|ifs| ifs.into_iter().map(|(_, ip)| ip).collect())
+        .unwrap_or_default();
+    let no_nat = is_local(first.mapped, local_port, &local_ips);
+
+    let mapping = match other {
+        Some(other) => {
+            let second = probe(
+                socket,
+                server_key,
+                SocketAddr::new(other.ip(), primary.port()),
+                2,
+                0,
+            )
+            .await?;
+            let third = if second.mapped == first.mapped {
+                None
+            } else {
+                Some(probe(socket, server_key, other, 3, 0).await?.mapped)
+            };
+            classify_mapping(first.mapped, second.mapped, third)
+        }
+        None => {
+            let second = probe(socket, server_key, primary_alt_port, 2, 0).await?;
+            classify_mapping(first.mapped, second.mapped, None)
+        }
+    };
+
+    // filtering: only count answers that really came from somewhere else
+    let change_ip = match other {
+        Some(_) => Some(
+            probe(
+                socket,
+                server_key,
+                primary,
+                4,
+                PROBE_CHANGE_IP | PROBE_CHANGE_PORT,
+            )
+            .await
+            .is_some_and(|r| r.from.ip() != primary.ip()),
+        ),
+        None => None,
+    };
+    let change_port = probe(socket, server_key, primary, 5, PROBE_CHANGE_PORT)
+        .await
+        .is_some_and(|r| r.from.port() != primary.port());
+
+    Some(NatBehavior {
+        no_nat,
+        mapped: first.mapped,
+        mapping,
+        filtering: classify_filtering(change_ip, change_port),
+    })
+}
+
+/// The NAT kind and our public mapping, which becomes the server reflexive candidate.
+pub async fn detect_nat_kind(
+    socket: &UdpSocket,
+    signaling_ip: &str,
+    server_key: Option<&[u8]>,
+) -> (NatKind, Option<SocketAddr>) {
+    match detect_nat_behavior(socket, signaling_ip, server_key).await {
+        Some(behavior) => {
+            log!(Debug, "NAT detection: {:?}", behavior);
+            (behavior.kind(), Some(behavior.mapped))
+        }
+        None => {
+            log!(
+                Warn,
+                "NAT detection: insufficient responses, treating as Unknown"
+            );
+            (NatKind::Unknown, None)
+        }
+    }
+}
+
+// The step between consecutive mapped ports, if the NAT allocates sequentially
+pub fn consistent_delta(ports: &[u16]) -> Option<i32> {
+    let deltas: Vec<i32> = ports
+        .windows(2)
+        .map(|w| w[1] as i32 - w[0] as i32)
+        .collect();
+    let first = *deltas.first()?;
+    (first != 0 && first.abs() <= MAX_DELTA && deltas.iter().all(|d| *d == first)).then_some(first)
+}
+
+// each fresh socket gets a new mapping, which shows how the NAT allocates ports
+async fn measure_port_delta(signaling_ip: &str, server_key: Option<&[u8]>) -> Option<i32> {
+    let mut ports = Vec::with_capacity(DELTA_SAMPLES);
+    for seq in 0..DELTA_SAMPLES {
+        let socket = net::bind_any(0).await.ok()?;
+        let primary = primary_addr(&socket, signaling_ip).await?;
+        ports.push(
+            probe(&socket, server_key, primary, seq as u8 + 1, 0)
+                .await?
+                .mapped
+                .port(),
+        );
+    }
+    log!(Debug, "Port prediction: mapped ports {:?}", ports);
+    consistent_delta(&ports)
+}
+
+/// How peers should punch us when we are behind a symmetric NAT.
+pub async fn port_hint(
+    signaling_ip: &str,
+    server_key: Option<&[u8]>,
+    strategy: SymmetricPunch,
+) -> PortHint {
+    if strategy == SymmetricPunch::Off {
+        return PortHint::None;
+    }
+    match measure_port_delta(signaling_ip, server_key).await {
+        Some(delta) => PortHint::Delta(delta),
+        None if strategy == SymmetricPunch::Birthday => PortHint::Random,
+        None => PortHint::None,
+    }
+}
+
+/// Where to punch a peer the server saw at `addr`: that address, then the
+/// ports its NAT is likely to allocate next, or random ones for a birthday peer.
+pub fn punch_targets(addr: SocketAddr, hint: PortHint) -> Vec<SocketAddr> {
+    let mut targets = vec![addr];
+    match hint {
+        PortHint::None => {}
+        PortHint::Delta(delta) => {
+            targets.extend((1..=PREDICT_SPAN).filter_map(|k| {
+                let port = addr.port() as i32 + delta * k;
+                u16::try_from(port)
+                    .ok()
+                    .filter(|p| *p != 0)
+                    .map(|p| SocketAddr::new(addr.ip(), p))
+            }));
+        }
+        PortHint::Random => {
+            let mut rng = rand::thread_rng();
+            targets.extend(
+                (0..BIRTHDAY_SPRAY)
+                    .map(|_| SocketAddr::new(addr.ip(), rng.gen_range(1024..=u16::MAX))),
+            );
+        }
+    }
+    targets
+}
@@ -1,397 +1,713 @@
 use std::collections::HashMap;
-use std::net::UdpSocket;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use crate::client::structures::{NatKind, PeerInfo, PunchSync, RelaySync};
-use crate::proto::control_text::{
-    MSG_DATA, MSG_HB, MSG_HOLE_PUNCH, MSG_NAT_PROBE, MSG_NAT_SEEN, MSG_PEER_TIMEOUT, MSG_PING,
-    MSG_REQUEST_RELAY, NAT_TYPE_CONE, NAT_TYPE_SYMMETRIC,
-};
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout};
+
+use crate::client::dtls::{DtlsPeer, DtlsRole};
+use crate::client::handlers::handle_datagram;
+use crate::client::ice;
+use crate::client::nat::punch_targets;
+use crate::client::structures::{ResumeState, SessionShared};
+use crate::log;
+use crate::net;
+use crate::proto::control::{self, ControlMessage, PortHint};
+use crate::proto::noise::{self, Initiator, MSG_INIT, MSG_RESPONSE, MSG_TRANSPORT, Transport};
+use crate::proto::packet;
 
 const PUNCH_INITIAL_SLEEP_MS: u64 = 150; //initial sleep between punches
 const PUNCH_MAX_SLEEP_MS: u64 = 1500; // max sleep value between punches
 const HEARTBEAT_SLEEP_SEC: u64 = 20; // sleep between heartbeats
+const SERVER_ANSWER_SEC: u64 = 3; // HB or RESUME unanswered for this long went unheard
 const RELAY_TICK_SEC: u64 = 15; // how often the relay does keepalive work
 const PEER_TIMEOUT_SEC: u64 = 60; //peer timeout if no PONG message in this time
 const CONNECT_GRACE_SEC: u64 = 12; // wait for connection for this time, after this, ask server for relay
-const NAT_DETECT_TOTAL_TIMEOUT_MS: u64 = 600; // maximum waiting time for server to respond to both probes
-const NAT_DETECT_POLL_SLEEP_MS: u64 = 20; // sleep between polls when socket is WouldBlock
+const DTLS_TICK_MS: u64 = 250; // how often handshakes are started and retransmitted
+const HANDSHAKE_ATTEMPTS: usize = 3; // Noise handshakes with the signaling server before giving up
+const HANDSHAKE_WAIT_MS: u64 = 1000;
 
-pub fn detect_nat_kind(socket: &UdpSocket, signaling_ip: &str) -> NatKind {
-    let addr1 = format!("{}:2131", signaling_ip);
-    let addr2 = format!("{}:2132", signaling_ip);
+pub const SIGNALING_PORT: u16 = 2131;
+pub const PROBE_PORT: u16 = 2132;
 
-    let _ = socket.send_to(format!("{MSG_NAT_PROBE} 1\n").as_bytes(), &addr1);
-    let _ = socket.send_to(format!("{MSG_NAT_PROBE} 2\n").as_bytes(), &addr2);
+pub(crate) async fn send_packet(
+    socket: &UdpSocket,
+    channel_id: u64,
+    src_peer_id: u32,
+    dst_peer_id: u32,
+    msg: &ControlMessage,
+    dst: SocketAddr,
+) -> std::io::Result<usize> {
+    let pkt = control::encode_packet(channel_id, src_peer_id, dst_peer_id, msg)
+        .map_err(std::io::Error::other)?;
+    net::send_to(socket, &pkt, dst).await
+}
+
+// the session socket, unless a birthday socket is what reached that peer
+pub(crate) fn socket_for(shared: &SessionShared, peer_id: u32) -> Arc<UdpSocket> {
+    let st = shared.state.lock().unwrap();
+    st.peers
+        .iter()
+        .find(|p| p.peer_id == peer_id)
+        .and_then(|p| p.via_socket)
+        .and_then(|i| shared.birthday_sockets.get(i))
+        .unwrap_or(&shared.socket)
+        .clone()
+}
+
+// everything for the server goes through the Noise session, if we have one
+pub(crate) async fn send_signaling(shared: &SessionShared, pkt: Vec<u8>) -> std::io::Result<usize> {
+    let pkt = match shared.signaling.lock().unwrap().as_mut() {
+        Some(transport) => noise::frame(MSG_TRANSPORT, &transport.seal(&pkt)),
+        None => pkt,
+    };
+    net::send_to(&shared.socket, &pkt, shared.signaling_addr).await
+}
+
+// client packets for the server carry no channel/peer addressing
+pub(crate) async fn send_server_control(
+    shared: &SessionShared,
+    msg: &ControlMessage,
+) -> std::io::Result<usize> {
+    let pkt = control::encode_packet(0, 0, 0, msg).map_err(std::io::Error::other)?;
+    send_signaling(shared, pkt).await
+}
 
-    let mut seen = Vec::new();
+/// Runs the Noise handshake with the server whose key we pinned, before the
+/// session's receive loop owns the socket.
+pub async fn signaling_handshake(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    server_key: &[u8],
+) -> std::io::Result<Transport> {
     let mut buf = [0u8; 256];
-    let deadline = Instant::now() + Duration::from_millis(NAT_DETECT_TOTAL_TIMEOUT_MS);
-
-    while seen.len() < 2 && Instant::now() < deadline {
-        match socket.recv_from(&mut buf) {
-            Ok((len, _src)) => {
-                let msg = String::from_utf8_lossy(&buf[..len]).to_string();
-                if msg.starts_with(&format!("{MSG_NAT_SEEN} ")) {
-                    if let Some(addr_str) = msg.split_whitespace().nth(1) {
-                        if let Ok(observed) = addr_str.parse::<std::net::SocketAddr>() {
-                            seen.push(observed);
-                        }
-                    }
-                }
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+        let (initiator, msg) = Initiator::start(server_key, &[])
+            .ok_or_else(|| std::io::Error::other("invalid signaling server key"))?;
+        net::send_to(socket, &noise::frame(MSG_INIT, &msg), server).await?;
+
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(HANDSHAKE_WAIT_MS);
+        while let Ok(Ok((len, from))) =
+            tokio::time::timeout_at(deadline, net::recv_from(socket, &mut buf)).await
+        {
+            if from != server {
+                continue;
             }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                std::thread::sleep(Duration::from_millis(NAT_DETECT_POLL_SLEEP_MS));
+            if let Some((hdr, payload)) = packet::decode(&buf[..len])
+                && hdr.kind == packet::Kind::Noise
+                && let Some((MSG_RESPONSE, body)) = noise::unframe(payload)
+                && let Some((_, transport)) = initiator.finish(body)
+            {
+                return Ok(transport);
             }
-            Err(_) => break,
         }
     }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "no Noise handshake response from the signaling server",
+    ))
+}
 
-    if seen.len() < 2 {
-        println!("NAT detection: insufficient responses, treating as Unknown");
-        return NatKind::Unknown;
-    }
-
-    let a = seen[0];
-    let b = seen[1];
-
-    if a.ip() != b.ip() {
-        println!("NAT detection: different public IPs, treating as Symmetric");
-        return NatKind::Symmetric;
+// from us to `dst_peer_id` (or BROADCAST via the server)
+pub(crate) async fn send_to_peer(
+    shared: &SessionShared,
+    msg: &ControlMessage,
+    dst_peer_id: u32,
+    dst: SocketAddr,
+) -> std::io::Result<usize> {
+    let (channel_id, my_peer_id) = shared.ids();
+    if dst == shared.signaling_addr {
+        let pkt = control::encode_packet(channel_id, my_peer_id, dst_peer_id, msg)
+            .map_err(std::io::Error::other)?;
+        return send_signaling(shared, pkt).await;
     }
+    send_packet(
+        &socket_for(shared, dst_peer_id),
+        channel_id,
+        my_peer_id,
+        dst_peer_id,
+        msg,
+        dst,
+    )
+    .await
+}
 
-    if a.port() == b.port() {
-        println!("NAT detection: {NAT_TYPE_CONE} (same addr on both ports): {a}",);
-        NatKind::Cone
+// DTLS and SRTP take the same path as DATA: through the server when either
+// side is server-relayed, otherwise straight to the peer
+fn media_route(shared: &SessionShared, peer_id: u32) -> Option<(Arc<UdpSocket>, SocketAddr)> {
+    let route = {
+        let st = shared.state.lock().unwrap();
+        let peer = st.peers.iter().find(|p| p.peer_id == peer_id)?;
+        if st.send_via_server || peer.use_server_relay {
+            shared.signaling_addr
+        } else {
+            peer.addr
+        }
+    };
+    let socket = if route == shared.signaling_addr {
+        Arc::clone(&shared.socket)
     } else {
-        println!("NAT detection: {NAT_TYPE_SYMMETRIC} (different ports): {a} vs {b}",);
-        NatKind::Symmetric
-    }
+        socket_for(shared, peer_id)
+    };
+    Some((socket, route))
 }
 
-fn heartbeat_loop(
-    socket: UdpSocket,
-    server_id: String,
-    channel: String,
-    user: String,
-    signaling_addr: String,
-) {
-    loop {
-        let hb = format!("{MSG_HB} {server_id} {channel} {user}");
-        let _ = socket.send_to(hb.as_bytes(), &signaling_addr);
-        thread::sleep(Duration::from_secs(HEARTBEAT_SLEEP_SEC));
+pub(crate) async fn send_dtls(shared: &SessionShared, peer_id: u32, datagrams: Vec<Vec<u8>>) {
+    if datagrams.is_empty() {
+        return;
+    }
+    let Some((socket, route)) = media_route(shared, peer_id) else {
+        return;
+    };
+
+    let (channel_id, my_peer_id) = shared.ids();
+    for datagram in datagrams {
+        let hdr = packet::Header::dtls(channel_id, my_peer_id, peer_id, datagram.len() as u16);
+        if let Err(e) = net::send_to(&socket, &packet::encode(hdr, &datagram), route).await {
+            log!(
+                Warn,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                peer_id = peer_id,
+                addr = route;
+                "Failed to send DTLS: {}",
+                e
+            );
+        }
     }
 }
 
-pub fn start_heartbeat(
-    socket: UdpSocket,
-    server_id: String,
-    channel: String,
-    user: String,
-    signaling_addr: String,
-) {
-    thread::spawn(move || {
-        heartbeat_loop(socket, server_id, channel, user, signaling_addr);
-    });
+// one already protected SRTP packet to one peer
+pub(crate) async fn send_srtp(
+    shared: &SessionShared,
+    peer_id: u32,
+    stream_id: u32,
+    srtp: &[u8],
+) -> std::io::Result<()> {
+    let Some((socket, route)) = media_route(shared, peer_id) else {
+        return Ok(());
+    };
+    let (channel_id, my_peer_id) = shared.ids();
+    let hdr = packet::Header::srtp(
+        channel_id,
+        my_peer_id,
+        peer_id,
+        stream_id,
+        srtp.len() as u16,
+    );
+    net::send_to(&socket, &packet::encode(hdr, srtp), route).await?;
+    Ok(())
 }
 
-fn hole_punching_loop(socket: UdpSocket, peers: Arc<Mutex<Vec<PeerInfo>>>, sync: PunchSync) {
-    let mut backoff: HashMap<String, u64> = HashMap::new(); //username -> ms
-
+// starts handshakes we are the active side of, retransmits stuck ones and
+// forgets associations of peers that are gone
+pub(crate) async fn dtls_loop(shared: Arc<SessionShared>) {
     loop {
-        // block if we are paused (sending via server) -> block here
-        {
-            let (lock, cvar) = &*sync;
-            let mut state = lock.lock().unwrap();
-
-            while state.paused {
-                //here the thread doesn't consume CPU
-                state = cvar.wait(state).unwrap();
-            }
+        sleep(Duration::from_millis(DTLS_TICK_MS)).await;
 
-            //here state.paused == false, we can punch
+        let (_, my_peer_id) = shared.ids();
+        if my_peer_id == 0 {
+            continue;
         }
 
+        let (known, reachable): (Vec<u32>, Vec<(u32, Vec<u8>)>) = {
+            let st = shared.state.lock().unwrap();
+            let reachable = st
+                .peers
+                .iter()
+                .filter(|p| p.connected && p.media_keys.is_none() && !p.fingerprint.is_empty())
+                .map(|p| (p.peer_id, p.fingerprint.clone()))
+                .collect();
+            (st.peers.iter().map(|p| p.peer_id).collect(), reachable)
+        };
+
+        shared
+            .srtp
+            .lock()
+            .unwrap()
+            .retain(|peer_id, _| known.contains(peer_id));
+
+        let mut outgoing = Vec::new();
         {
-            let guard = peers.lock().unwrap();
-            for p in guard.iter() {
-                if p.connected || p.use_server_relay {
-                    continue; //don't punch server-relayed peers
+            let mut dtls = shared.dtls.lock().unwrap();
+            dtls.retain(|peer_id, association| {
+                if association.timed_out() {
+                    log!(
+                        Info,
+                        server_id = &shared.config.server_id,
+                        channel = &shared.config.channel,
+                        user = &shared.config.user,
+                        peer_id = *peer_id;
+                        "DTLS handshake timed out"
+                    );
+                    return false;
                 }
+                known.contains(peer_id)
+            });
 
-                if let Err(e) = socket.send_to(MSG_HOLE_PUNCH.as_bytes(), p.addr) {
-                    eprintln!("Failed to send punch to {}: {}", p.addr, e);
-                } else {
-                    println!("Sent UDP punch to {} ({})", p.username, p.addr);
+            for (peer_id, fingerprint) in reachable {
+                if dtls.contains_key(&peer_id)
+                    || DtlsRole::for_pair(my_peer_id, peer_id) != DtlsRole::Active
+                {
+                    continue;
                 }
+                match DtlsPeer::new(&shared.identity, DtlsRole::Active, fingerprint)
+                    .and_then(|mut a| Ok((a.handle(None)?, a)))
+                {
+                    Ok((hello, association)) => {
+                        dtls.insert(peer_id, association);
+                        outgoing.push((peer_id, hello));
+                    }
+                    Err(e) => log!(
+                        Warn,
+                        server_id = &shared.config.server_id,
+                        channel = &shared.config.channel,
+                        user = &shared.config.user,
+                        peer_id = peer_id;
+                        "Failed to start DTLS: {}",
+                        e
+                    ),
+                }
+            }
 
-                let entry = backoff
-                    .entry(p.username.clone())
-                    .or_insert(PUNCH_INITIAL_SLEEP_MS);
-                *entry = (*entry).saturating_mul(2).min(PUNCH_MAX_SLEEP_MS);
+            let mut group = shared.group.lock().unwrap();
+            for (peer_id, association) in dtls.iter_mut() {
+                if let Some(flight) = association.retransmit() {
+                    outgoing.push((*peer_id, flight));
+                }
+                // our sender key, until the peer acknowledges it
+                let keys = group.exchange(*peer_id, association);
+                if !keys.is_empty() {
+                    outgoing.push((*peer_id, keys));
+                }
             }
         }
 
-        let sleep_ms = backoff
-            .values()
-            .min()
-            .copied()
-            .unwrap_or(PUNCH_INITIAL_SLEEP_MS);
-        thread::sleep(Duration::from_millis(sleep_ms));
+        for (peer_id, datagrams) in outgoing {
+            send_dtls(&shared, peer_id, datagrams).await;
+        }
     }
 }
 
-pub fn start_hole_punching(
-    socket: UdpSocket,
-    peers: Arc<Mutex<Vec<PeerInfo>>>,
-    punch_sync: PunchSync,
-) {
-    thread::spawn(move || {
-        hole_punching_loop(socket, peers, punch_sync);
-    });
+pub(crate) async fn recv_loop(shared: Arc<SessionShared>) {
+    let mut buf = [0u8; 2048];
+    loop {
+        match net::recv_from(&shared.socket, &mut buf).await {
+            Ok((len, src)) => handle_datagram(&shared, &buf[..len], src).await,
+            // ICMP errors from unreachable peers surface here on some platforms
+            Err(e) => log!(
+                Warn,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user;
+                "Error receiving: {}",
+                e
+            ),
+        }
+    }
 }
 
-fn handle_peer_timeout(
-    socket: &UdpSocket,
-    server_id: &str,
-    channel: &str,
-    peer: &PeerInfo,
-    signaling_addr: &str,
-) {
-    println!("Peer {} timeout - reporting to server", peer.username);
-    let _ = socket.send_to(
-        format!(
-            "{MSG_PEER_TIMEOUT} {} {} {}",
-            server_id, channel, peer.username
-        )
-        .as_bytes(),
-        &signaling_addr,
+// a birthday socket only carries peer traffic; the first one a peer reaches
+// becomes that peer's socket
+pub(crate) async fn birthday_recv_loop(shared: Arc<SessionShared>, index: usize) {
+    let socket = Arc::clone(&shared.birthday_sockets[index]);
+    let mut buf = [0u8; 2048];
+    loop {
+        let Ok((len, src)) = net::recv_from(&socket, &mut buf).await else {
+            continue;
+        };
+        if let Some((hdr, _)) = packet::decode(&buf[..len])
+            && hdr.src_peer_id != 0
+        {
+            let mut st = shared.state.lock().unwrap();
+            if let Some(peer) = st
+                .peers
+                .iter_mut()
+                .find(|p| p.peer_id == hdr.src_peer_id && p.via_socket.is_none())
+            {
+                log!(
+                    Debug,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer.peer_id,
+                    addr = src;
+                    "Birthday socket {} reached {}",
+                    index,
+                    peer.username
+                );
+                peer.via_socket = Some(index);
+            }
+        }
+        handle_datagram(&shared, &buf[..len], src).await;
+    }
+}
+
+// new Noise session with the server, set up while `recv_loop` owns the socket:
+// it finishes the handshake in `open_from_server`
+async fn renew_signaling(shared: &SessionShared, server_key: &[u8]) -> bool {
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+        let Some((initiator, msg)) = Initiator::start(server_key, &[]) else {
+            return false;
+        };
+        *shared.handshake.lock().unwrap() = Some(initiator);
+        let init = noise::frame(MSG_INIT, &msg);
+        let _ = net::send_to(&shared.socket, &init, shared.signaling_addr).await;
+        let wait = Duration::from_millis(HANDSHAKE_WAIT_MS);
+        if timeout(wait, shared.handshake_done.notified())
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+    }
+    *shared.handshake.lock().unwrap() = None;
+    false
+}
+
+// the server restarted or we moved to another address: take the session back
+// with the ids and nonce of its WELCOME, or CONNECT again if it is gone
+async fn resume(shared: &SessionShared) {
+    log!(
+        Info,
+        server_id = &shared.config.server_id,
+        channel = &shared.config.channel,
+        user = &shared.config.user;
+        "No answer from the signaling server, resuming the session"
     );
+    if let Some(key) = &shared.config.server_key
+        && !renew_signaling(shared, key).await
+    {
+        log!(
+            Warn,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "No Noise handshake response, retrying later"
+        );
+        return;
+    }
+
+    let msg = {
+        let mut st = shared.state.lock().unwrap();
+        st.resume = ResumeState::Sent;
+        ControlMessage::Resume {
+            channel_id: st.channel_id,
+            peer_id: st.my_peer_id,
+            nonce: st.session_nonce,
+        }
+    };
+    let _ = send_server_control(shared, &msg).await;
+    sleep(Duration::from_secs(SERVER_ANSWER_SEC)).await;
+
+    let refused = std::mem::take(&mut shared.state.lock().unwrap().resume) == ResumeState::Refused;
+    if refused {
+        log!(
+            Info,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "Session lost, sending CONNECT again"
+        );
+        let _ = send_server_control(shared, &shared.connect).await;
+    }
 }
 
-fn relay_main_loop(
-    socket: &UdpSocket,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    server_id: &str,
-    channel: &str,
-    signaling_addr: &str,
-    relay_sync: &RelaySync,
-) {
+fn heard_from_server_since(shared: &SessionShared, since: Instant) -> bool {
+    shared
+        .state
+        .lock()
+        .unwrap()
+        .server_seen
+        .is_some_and(|seen| seen >= since)
+}
+
+pub(crate) async fn heartbeat_loop(shared: Arc<SessionShared>) {
     loop {
-        // stop immediatly if deactivated
-        {
-            let (lock, _) = &**relay_sync;
-            if !lock.lock().unwrap().is_active {
-                break;
+        // the server ignores HB until we can echo the WELCOME nonce
+        let nonce = shared.state.lock().unwrap().session_nonce;
+        if nonce != 0 {
+            let hb = ControlMessage::Heartbeat {
+                server_id: shared.config.server_id.clone(),
+                channel: shared.config.channel.clone(),
+                user: shared.config.user.clone(),
+                nonce,
+            };
+            let sent = Instant::now();
+            let _ = send_server_control(&shared, &hb).await;
+
+            // the server answers HB with PONG
+            sleep(Duration::from_secs(SERVER_ANSWER_SEC)).await;
+            if !heard_from_server_since(&shared, sent) {
+                resume(&shared).await;
             }
         }
+        sleep(Duration::from_secs(HEARTBEAT_SLEEP_SEC)).await;
+    }
+}
 
-        let mut to_remove = Vec::new();
-        {
-            let mut guard = peers.lock().unwrap();
-            for (i, peer) in guard.iter_mut().enumerate() {
-                if peer.use_server_relay {
-                    continue;
-                }
+struct PunchTarget {
+    peer_id: u32,
+    username: String,
+    addr: SocketAddr, // as the server saw it
+    hint: PortHint,
+    connected: bool,
+    checks: Vec<(SocketAddr, u64)>, // due connectivity checks, best pair first
+    check_key: Vec<u8>,             // the peer's, see `ice::check_tag`
+    lan_first: bool,                // behind our NAT: leave its public mapping alone for now
+}
 
-                if peer.last_pong.elapsed() > Duration::from_secs(PEER_TIMEOUT_SEC) {
-                    handle_peer_timeout(socket, server_id, channel, peer, signaling_addr);
-                    to_remove.push(i);
-                } else {
-                    if let Err(e) = socket.send_to(MSG_PING.as_bytes(), peer.addr) {
-                        eprintln!("Failed to send PING to {}: {}", peer.addr, e);
-                    }
+pub(crate) async fn hole_punching_loop(shared: Arc<SessionShared>) {
+    let mut backoff: HashMap<u32, u64> = HashMap::new(); //peer_id -> ms
+    let mut paused = shared.punch_paused.subscribe();
+
+    loop {
+        // block if we are paused (sending via server)
+        if paused.wait_for(|p| !*p).await.is_err() {
+            return;
+        }
 
-                    if !peer.connected
-                        && peer.created_at.elapsed() > Duration::from_secs(CONNECT_GRACE_SEC)
-                        && !peer.relay_requested
+        // peers not reached yet, and peers with a better pair still being checked
+        let targets: Vec<PunchTarget> = {
+            let mut st = shared.state.lock().unwrap();
+            st.peers
+                .iter_mut()
+                .filter(|p| !p.use_server_relay) //don't punch server-relayed peers
+                .filter(|p| !p.connected || p.checks.pending())
+                .map(|p| PunchTarget {
+                    peer_id: p.peer_id,
+                    username: p.username.clone(),
+                    addr: p.server_addr,
+                    hint: p.port_hint,
+                    connected: p.connected,
+                    checks: p.checks.due(),
+                    check_key: p.check_key.clone(),
+                    lan_first: p.checks.lan_pending(),
+                })
+                .collect()
+        };
+
+        for target in targets {
+            let PunchTarget {
+                peer_id,
+                username,
+                addr,
+                hint,
+                connected,
+                checks,
+                check_key,
+                lan_first,
+            } = target;
+
+            // candidate pairs, best first; each answered CHECK proves one path
+            let (channel_id, my_peer_id) = shared.ids();
+            for (dst, txn) in checks {
+                let tag = ice::check_tag(&check_key, channel_id, my_peer_id, peer_id, txn, false);
+                let check = ControlMessage::Check { txn, tag };
+                if let Err(e) = send_to_peer(&shared, &check, peer_id, dst).await {
+                    log!(
+                        Warn,
+                        server_id = &shared.config.server_id,
+                        channel = &shared.config.channel,
+                        user = &shared.config.user,
+                        peer_id = peer_id,
+                        addr = dst;
+                        "Failed to send check: {}",
+                        e
+                    );
+                }
+            }
+
+            if lan_first {
+                log!(
+                    Trace,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer_id;
+                    "Checking private addresses of {} first (same public IP)",
+                    username
+                );
+            } else if !connected {
+                // symmetric peers: also the ports their NAT will likely pick for us
+                for dst in punch_targets(addr, hint) {
+                    if let Err(e) =
+                        send_to_peer(&shared, &ControlMessage::HolePunch, peer_id, dst).await
                     {
-                        println!(
-                            "Peer {} not connected after 10s - requesting server relay",
-                            peer.username
-                        );
-                        peer.relay_requested = true;
-                        let _ = socket.send_to(
-                            format!(
-                                "{MSG_REQUEST_RELAY} {} {} {}\n",
-                                server_id, channel, peer.username
-                            )
-                            .as_bytes(),
-                            signaling_addr,
+                        log!(
+                            Warn,
+                            server_id = &shared.config.server_id,
+                            channel = &shared.config.channel,
+                            user = &shared.config.user,
+                            peer_id = peer_id,
+                            addr = dst;
+                            "Failed to send punch: {}",
+                            e
                         );
                     }
                 }
+                // we are the birthday side: open one mapping per socket towards the peer
+                let (channel_id, my_peer_id) = shared.ids();
+                for socket in &shared.birthday_sockets {
+                    let _ = send_packet(
+                        socket,
+                        channel_id,
+                        my_peer_id,
+                        peer_id,
+                        &ControlMessage::HolePunch,
+                        addr,
+                    )
+                    .await;
+                }
+                log!(
+                    Trace,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer_id,
+                    addr = addr;
+                    "Sent UDP punch to {}",
+                    username
+                );
             }
 
-            for &idx in to_remove.iter().rev() {
-                guard.remove(idx);
-            }
+            let entry = backoff.entry(peer_id).or_insert(PUNCH_INITIAL_SLEEP_MS);
+            *entry = (*entry).saturating_mul(2).min(PUNCH_MAX_SLEEP_MS);
         }
-        // sleep up to 15s, but wake instantly if is_active flips
-        let (lock, cvar) = &**relay_sync;
-        let st = lock.lock().unwrap();
-        let _ = cvar
-            .wait_timeout(st, Duration::from_secs(RELAY_TICK_SEC))
-            .unwrap();
+
+        let sleep_ms = backoff
+            .values()
+            .min()
+            .copied()
+            .unwrap_or(PUNCH_INITIAL_SLEEP_MS);
+        sleep(Duration::from_millis(sleep_ms)).await;
     }
 }
 
-fn relay_keepalive_loop(
-    socket: UdpSocket,
-    peers: Arc<Mutex<Vec<PeerInfo>>>,
-    relay_started: Arc<Mutex<bool>>,
-    server_id: String,
-    channel: String,
-    signaling_addr: String,
-    relay_sync: RelaySync,
-) {
-    loop {
-        // wait until active
-        {
-            let (lock, cvar) = &*relay_sync;
-            let mut st = lock.lock().unwrap();
-            while !st.is_active {
-                st = cvar.wait(st).unwrap();
-            }
-        }
+async fn relay_tick(shared: &SessionShared) {
+    let mut timed_out = Vec::new();
+    let mut to_ping = Vec::new();
+    let mut to_request = Vec::new();
 
-        // mark started, only once
-        {
-            let mut started = relay_started.lock().unwrap();
-            if !*started {
-                *started = true;
-                println!("Starting relay keepalive thread.")
+    {
+        let mut st = shared.state.lock().unwrap();
+        st.peers.retain_mut(|peer| {
+            if peer.use_server_relay {
+                return true;
             }
-        }
 
-        // run main loop until deactivated
-        relay_main_loop(
-            &socket,
-            &peers,
-            &server_id,
-            &channel,
-            &signaling_addr,
-            &relay_sync,
-        );
+            if peer.last_pong.elapsed() > Duration::from_secs(PEER_TIMEOUT_SEC) {
+                timed_out.push((peer.peer_id, peer.username.clone()));
+                return false;
+            }
 
-        // mark stopped
-        {
-            let mut started = relay_started.lock().unwrap();
-            *started = false;
-            println!("Relay loop stopped.");
-        }
+            to_ping.push((peer.peer_id, peer.addr));
 
-        //go back to waiting
+            if !peer.connected
+                && peer.created_at.elapsed() > Duration::from_secs(CONNECT_GRACE_SEC)
+                && !peer.relay_requested
+            {
+                peer.relay_requested = true;
+                to_request.push((peer.peer_id, peer.username.clone()));
+            }
+            true
+        });
     }
-}
 
-pub fn start_relay_keepalive(
-    socket: UdpSocket,
-    peers: Arc<Mutex<Vec<PeerInfo>>>,
-    relay_started: Arc<Mutex<bool>>,
-    server_id: String,
-    channel: String,
-    signaling_addr: String,
-    relay_sync: RelaySync,
-) {
-    thread::spawn(move || {
-        relay_keepalive_loop(
-            socket,
-            peers,
-            relay_started,
-            server_id,
-            channel,
-            signaling_addr,
-            relay_sync,
+    let config = &shared.config;
+    let nonce = shared.state.lock().unwrap().session_nonce;
+
+    for (peer_id, username) in timed_out {
+        log!(
+            Info,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id;
+            "Peer {} timeout - reporting to server",
+            username
         );
-    });
-}
-
-fn handle_user_message(
-    socket: &UdpSocket,
-    peers: &Arc<Mutex<Vec<PeerInfo>>>,
-    username: &str,
-    message: &str,
-    send_via_server: bool,
-    signaling_addr: &str,
-    is_relay: &Arc<Mutex<bool>>,
-    channel_has_server_relays: &Arc<AtomicBool>,
-) {
-    let payload = format!("{MSG_DATA} {username} {message}\n");
-
-    // if i am simmetric, send via server
-    if send_via_server {
-        println!("Sending {MSG_DATA} via server relay: {message}");
-        let _ = socket.send_to(payload.as_bytes(), signaling_addr);
-        return;
+        let msg = ControlMessage::PeerTimeout {
+            server_id: config.server_id.clone(),
+            channel: config.channel.clone(),
+            user: username,
+            peer_id,
+            nonce,
+        };
+        let _ = send_server_control(shared, &msg).await;
     }
 
-    // we on DIRECT, send directly only to peers that are not server relayed
-    let peers_guard = peers.lock().unwrap();
-    for peer in peers_guard.iter().filter(|p| !p.use_server_relay) {
-        let _ = socket.send_to(payload.as_bytes(), peer.addr);
+    for (peer_id, addr) in to_ping {
+        if let Err(e) = send_to_peer(shared, &ControlMessage::Ping, peer_id, addr).await {
+            log!(
+                Warn,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user,
+                peer_id = peer_id,
+                addr = addr;
+                "Failed to send PING: {}",
+                e
+            );
+        }
     }
 
-    // if i am relay and channel has server relayed peers, mirror to server
-    // otherwise symmetric users can not receive my message
-    if *is_relay.lock().unwrap() && channel_has_server_relays.load(Ordering::Acquire) {
-        let _ = socket.send_to(payload.as_bytes(), signaling_addr);
+    for (peer_id, username) in to_request {
+        log!(
+            Info,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user,
+            peer_id = peer_id;
+            "Peer {} not connected after {}s - requesting server relay",
+            username,
+            CONNECT_GRACE_SEC
+        );
+        let msg = ControlMessage::RequestRelay {
+            server_id: config.server_id.clone(),
+            channel: config.channel.clone(),
+            user: username,
+            peer_id,
+            nonce,
+        };
+        let _ = send_server_control(shared, &msg).await;
     }
 }
 
-fn user_input_loop(
-    socket: UdpSocket,
-    peers: Arc<Mutex<Vec<PeerInfo>>>,
-    username: String,
-    send_via_server: Arc<AtomicBool>,
-    signaling_addr: String,
-    is_relay: Arc<Mutex<bool>>,
-    channel_has_server_relays: Arc<AtomicBool>,
-) {
-    use std::io::{self, BufRead};
-    let stdin = io::stdin();
-    for line in stdin.lock().lines() {
-        if let Ok(msg) = line {
-            let msg = msg.trim();
-            if msg.is_empty() {
-                continue;
+pub(crate) async fn relay_keepalive_loop(shared: Arc<SessionShared>) {
+    let mut active = shared.relay_active.subscribe();
+
+    loop {
+        // wait until active
+        if active.wait_for(|a| *a).await.is_err() {
+            return;
+        }
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "Starting relay keepalive loop."
+        );
+
+        // run until deactivated, waking instantly if is_active flips
+        loop {
+            relay_tick(&shared).await;
+
+            let _ = timeout(Duration::from_secs(RELAY_TICK_SEC), active.changed()).await;
+            if !*active.borrow() {
+                break;
             }
-            let s = send_via_server.load(Ordering::Acquire);
-            handle_user_message(
-                &socket,
-                &peers,
-                &username,
-                msg,
-                s,
-                &signaling_addr,
-                &is_relay,
-                &channel_has_server_relays,
-            );
         }
-    }
-}
 
-pub fn start_user_input(
-    socket: UdpSocket,
-    peers: Arc<Mutex<Vec<PeerInfo>>>,
-    username: String,
-    send_via_server: Arc<AtomicBool>,
-    signaling_addr: String,
-    is_relay: Arc<Mutex<bool>>,
-    channel_has_server_relays: Arc<AtomicBool>,
-) {
-    thread::spawn(move || {
-        user_input_loop(
-            socket,
-            peers,
-            username,
-            send_via_server,
-            signaling_addr,
-            is_relay,
-            channel_has_server_relays,
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "Relay loop stopped."
         );
-    });
+    }
 }
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::{
+    sync::{Mutex as AsyncMutex, Notify, broadcast, mpsc, watch},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    client::{
+        dtls::{DtlsIdentity, MediaKeys},
+        events::ClientEvent,
+        group::{self, GroupKeys},
+        ice,
+        nat::{BIRTHDAY_SOCKETS, detect_nat_kind, port_hint},
+        networking::{
+            SIGNALING_PORT, birthday_recv_loop, dtls_loop, heartbeat_loop, hole_punching_loop,
+            recv_loop, relay_keepalive_loop, send_server_control, send_srtp, send_to_peer,
+            signaling_handshake,
+        },
+        structures::{MediaFrame, NatKind, SessionConfig, SessionShared, SessionState},
+    },
+    log, net,
+    proto::{
+        control::{ControlMessage, PortHint},
+        packet::BROADCAST,
+    },
+};
+
+const SETUP_DEADLINE_MS: u64 = 1500; // how long connect() waits for the first MODE message
+const DATA_QUEUE_LEN: usize = 256; // received DATA payloads buffered before recv() drops them
+const EVENT_QUEUE_LEN: usize = 64;
+const MEDIA_QUEUE_LEN: usize = 256; // decrypted media frames buffered before recv_media() drops them
+const MEDIA_PAYLOAD_TYPE: u8 = 96; // first dynamic RTP payload type
+
+/// A joined voice channel: owns the UDP socket and the background tasks that
+/// keep the NAT mappings, relay role and peer list up to date.
+pub struct Session {
+    shared: Arc<SessionShared>,
+    data_rx: AsyncMutex<mpsc::Receiver<(u32, String, Vec<u8>)>>,
+    media_rx: AsyncMutex<mpsc::Receiver<MediaFrame>>,
+    first_events: Mutex<Option<broadcast::Receiver<ClientEvent>>>,
+    nat_kind: NatKind,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Session {
+    pub async fn connect(config: SessionConfig) -> std::io::Result<Session> {
+        let socket = Arc::new(net::bind_any(config.local_port).await?);
+
+        // NAT detection before CONNECT
+        let server_key = config.server_key.as_deref();
+        let (nat_kind, mapped) = detect_nat_kind(&socket, &config.signaling_ip, server_key).await;
+        log!(
+            Info,
+            server_id = &config.server_id,
+            channel = &config.channel,
+            user = &config.user;
+            "My NAT kind: {:?}",
+            nat_kind
+        );
+
+        // a symmetric NAT may still be punched if peers know where to aim
+        let hint = if nat_kind == NatKind::Symmetric {
+            port_hint(&config.signaling_ip, server_key, config.symmetric_punch).await
+        } else {
+            PortHint::None
+        };
+        let mut birthday_sockets = Vec::new();
+        if hint == PortHint::Random {
+            for _ in 0..BIRTHDAY_SOCKETS {
+                birthday_sockets.push(Arc::new(net::bind_any(0).await?));
+            }
+        }
+        if hint != PortHint::None {
+            log!(
+                Info,
+                server_id = &config.server_id,
+                channel = &config.channel,
+                user = &config.user;
+                "Symmetric NAT punching: {:?}",
+                hint
+            );
+        }
+
+        let signaling_addr = net::resolve(&socket, &config.signaling_ip, SIGNALING_PORT)
+            .await
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no address for signaling server",
+                )
+            })?;
+
+        let signaling = match server_key {
+            Some(key) => Some(signaling_handshake(&socket, signaling_addr, key).await?),
+            None => {
+                log!(
+                    Warn,
+                    server_id = &config.server_id,
+                    channel = &config.channel,
+                    user = &config.user;
+                    "Plain signaling: the server connection is not authenticated"
+                );
+                None
+            }
+        };
+
+        let identity = DtlsIdentity::generate().map_err(std::io::Error::other)?;
+
+        let candidates = ice::gather(&socket, mapped, signaling_addr);
+        for c in &candidates {
+            log!(
+                Debug,
+                server_id = &config.server_id,
+                channel = &config.channel,
+                user = &config.user,
+                addr = c.addr;
+                "Candidate {:?} (priority {})",
+                c.kind,
+                c.priority
+            );
+        }
+
+        let check_key = ice::check_key();
+        let connect = ControlMessage::Connect {
+            server_id: config.server_id.clone(),
+            channel: config.channel.clone(),
+            user: config.user.clone(),
+            nat_kind,
+            token: config.token.clone(),
+            port_hint: hint,
+            fingerprint: identity.fingerprint().to_vec(),
+            candidates: candidates.clone(),
+            check_key: check_key.clone(),
+        };
+
+        let (data_tx, data_rx) = mpsc::channel(DATA_QUEUE_LEN);
+        let (media_tx, media_rx) = mpsc::channel(MEDIA_QUEUE_LEN);
+        let (events, first_events) = broadcast::channel(EVENT_QUEUE_LEN);
+
+        let shared = Arc::new(SessionShared {
+            socket,
+            config,
+            signaling_addr,
+            state: Mutex::new(SessionState::default()),
+            punch_paused: watch::Sender::new(false),
+            relay_active: watch::Sender::new(false),
+            mode_seen: Notify::new(),
+            events,
+            data_tx,
+            birthday_sockets,
+            identity,
+            dtls: Mutex::new(HashMap::new()),
+            group: Mutex::new(GroupKeys::new()),
+            signaling: Mutex::new(signaling),
+            handshake: Mutex::new(None),
+            handshake_done: Notify::new(),
+            srtp: Mutex::new(HashMap::new()),
+            media_tx,
+            candidates,
+            check_key,
+            connect,
+        });
+
+        send_server_control(&shared, &shared.connect).await?;
+        log!(
+            Debug,
+            server_id = &shared.config.server_id,
+            channel = &shared.config.channel,
+            user = &shared.config.user;
+            "Sent CONNECT to signaling server"
+        );
+
+        let mut tasks = vec![
+            tokio::spawn(recv_loop(Arc::clone(&shared))),
+            tokio::spawn(heartbeat_loop(Arc::clone(&shared))),
+        ];
+
+        // setup phase: give the server a moment to tell us our role
+        if timeout(
+            Duration::from_millis(SETUP_DEADLINE_MS),
+            shared.mode_seen.notified(),
+        )
+        .await
+        .is_err()
+        {
+            log!(
+                Info,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user;
+                "No MODE from signaling server yet, continuing setup"
+            );
+        }
+
+        let rejected = shared.state.lock().unwrap().rejected.clone();
+        if let Some(reason) = rejected {
+            for task in &tasks {
+                task.abort();
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("signaling server rejected CONNECT: {reason}"),
+            ));
+        }
+
+        for index in 0..shared.birthday_sockets.len() {
+            tasks.push(tokio::spawn(birthday_recv_loop(Arc::clone(&shared), index)));
+        }
+
+        // start punching ONLY if NAT is not symmetric, or peers can predict our ports
+        if nat_kind != NatKind::Symmetric || hint != PortHint::None {
+            tasks.push(tokio::spawn(hole_punching_loop(Arc::clone(&shared))));
+        } else {
+            log!(
+                Info,
+                server_id = &shared.config.server_id,
+                channel = &shared.config.channel,
+                user = &shared.config.user;
+                "Symmetric NAT detected - skipping hole punching, relying on server relay."
+            );
+        }
+
+        tasks.push(tokio::spawn(relay_keepalive_loop(Arc::clone(&shared))));
+        tasks.push(tokio::spawn(dtls_loop(Arc::clone(&shared))));
+
+        Ok(Session {
+            shared,
+            data_rx: AsyncMutex::new(data_rx),
+            media_rx: AsyncMutex::new(media_rx),
+            first_events: Mutex::new(Some(first_events)),
+            nat_kind,
+            tasks,
+        })
+    }
+
+    pub fn nat_kind(&self) -> NatKind {
+        self.nat_kind
+    }
+
+    /// The first subscriber also receives the events emitted while connecting.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.first_events
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| self.shared.events.subscribe())
+    }
+
+    /// Sends `bytes` to every peer in the channel, directly or through the
+    /// relay/server depending on the current mode. The payload is sealed with
+    /// our sender key, only channel members that received it can read it.
+    pub async fn send(&self, bytes: &[u8]) -> std::io::Result<()> {
+        let shared = &self.shared;
+        let (channel_id, my_peer_id) = shared.ids();
+        let aad = group::aad(channel_id, my_peer_id, &shared.config.user);
+        let msg = ControlMessage::Data {
+            sender: shared.config.user.clone(),
+            payload: shared.group.lock().unwrap().seal(&aad, bytes),
+        };
+
+        let (send_via_server, mirror_to_server, targets) = {
+            let st = shared.state.lock().unwrap();
+            let targets: Vec<_> = st
+                .peers
+                .iter()
+                .filter(|p| !p.use_server_relay)
+                .map(|p| (p.peer_id, p.addr))
+                .collect();
+            (
+                st.send_via_server,
+                st.is_relay && st.channel_has_server_relays,
+                targets,
+            )
+        };
+
+        // if i am symmetric, send via server
+        if send_via_server {
+            send_to_peer(shared, &msg, BROADCAST, shared.signaling_addr).await?;
+            return Ok(());
+        }
+
+        // we are on DIRECT, send directly only to peers that are not server relayed
+        for (peer_id, addr) in targets {
+            if let Err(e) = send_to_peer(shared, &msg, peer_id, addr).await {
+                log!(
+                    Warn,
+                    server_id = &shared.config.server_id,
+                    channel = &shared.config.channel,
+                    user = &shared.config.user,
+                    peer_id = peer_id,
+                    addr = addr;
+                    "Failed to send data: {}",
+                    e
+                );
+            }
+        }
+
+        // if i am relay and channel has server relayed peers, mirror to server
+        // otherwise symmetric users can not receive my message
+        if mirror_to_server {
+            send_to_peer(shared, &msg, BROADCAST, shared.signaling_addr).await?;
+        }
+        Ok(())
+    }
+
+    /// SRTP keys agreed with `peer_id` over DTLS, once `ClientEvent::PeerSecured` was emitted.
+    pub fn media_keys(&self, peer_id: u32) -> Option<MediaKeys> {
+        let st = self.shared.state.lock().unwrap();
+        st.peers
+            .iter()
+            .find(|p| p.peer_id == peer_id)
+            .and_then(|p| p.media_keys.clone())
+    }
+
+    /// Encrypts `payload` as SRTP for every peer we finished DTLS with and sends
+    /// it to each of them, so relays and the server only carry ciphertext.
+    /// Peers still handshaking do not get the frame.
+    pub async fn send_media(
+        &self,
+        stream_id: u32,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        let packets: Vec<(u32, Vec<u8>)> = {
+            let mut srtp = self.shared.srtp.lock().unwrap();
+            srtp.iter_mut()
+                .map(|(peer_id, (sender, _))| {
+                    let packet = sender.protect(stream_id, MEDIA_PAYLOAD_TYPE, timestamp, payload);
+                    (*peer_id, packet)
+                })
+                .collect()
+        };
+
+        for (peer_id, packet) in packets {
+            if let Err(e) = send_srtp(&self.shared, peer_id, stream_id, &packet).await {
+                log!(
+                    Warn,
+                    server_id = &self.shared.config.server_id,
+                    channel = &self.shared.config.channel,
+                    user = &self.shared.config.user,
+                    peer_id = peer_id;
+                    "Failed to send media: {}",
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for the next decrypted media frame from a peer.
+    pub async fn recv_media(&self) -> Option<MediaFrame> {
+        self.media_rx.lock().await.recv().await
+    }
+
+    /// Waits for the next payload sent by a peer, as `(peer_id, username, payload)`.
+    pub async fn recv(&self) -> Option<(u32, String, Vec<u8>)> {
+        self.data_rx.lock().await.recv().await
+    }
+
+    /// Leaves the channel and stops every background task.
+    pub async fn close(&self) -> std::io::Result<()> {
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        let config = &self.shared.config;
+        let msg = ControlMessage::Disconnect {
+            server_id: config.server_id.clone(),
+            channel: config.channel.clone(),
+            user: config.user.clone(),
+        };
+        send_server_control(&self.shared, &msg).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
@@ -1,18 +1,33 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum NatKind {
-    Unknown,
-    Public,
-    Cone,
-    Symmetric,
-}
+use tokio::{
+    net::UdpSocket,
+    sync::{Notify, broadcast, mpsc, watch},
+};
+
+use crate::client::{
+    dtls::{DtlsIdentity, DtlsPeer, MediaKeys},
+    events::ClientEvent,
+    group::GroupKeys,
+    ice::{Candidate, Checklist},
+    nat::SymmetricPunch,
+};
+use crate::proto::{
+    control::ControlMessage,
+    noise::{Initiator, Transport},
+    srtp::{SrtpReceiver, SrtpSender},
+};
+
+pub use crate::proto::control::{NatKind, PortHint};
 
 #[derive(Clone, Debug)]
 pub struct PeerInfo {
-    pub addr: SocketAddr,
+    pub peer_id: u32,
+    pub addr: SocketAddr, //where we send, the best candidate pair that got through
+    pub server_addr: SocketAddr, //the peer's mapping as the server saw it
     pub last_pong: Instant,
     pub username: String,
     pub connected: bool,
@@ -20,18 +35,92 @@ pub struct PeerInfo {
     pub use_server_relay: bool, //server will carry traffic for this peer
     pub relay_requested: bool,  //we asked server once
     pub nat_kind: NatKind,
+    pub port_hint: PortHint, //how to punch the peer if it is behind a symmetric NAT
+    pub via_socket: Option<usize>, //birthday socket that reached this peer
+    pub fingerprint: Vec<u8>, //DTLS certificate digest announced by the server
+    pub check_key: Vec<u8>,  //the peer's key for checks, announced by the server
+    pub media_keys: Option<MediaKeys>, //set once the DTLS handshake with this peer finished
+    pub checks: Checklist,   //connectivity checks over the peer's candidates
 }
 
-#[derive(Debug)]
-pub struct PunchState {
-    pub paused: bool,
+/// One decrypted media frame, as delivered by `Session::recv_media`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaFrame {
+    pub peer_id: u32,
+    pub stream_id: u32,
+    pub timestamp: u32,
+    pub payload: Vec<u8>,
 }
 
-pub type PunchSync = Arc<(Mutex<PunchState>, Condvar)>;
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    pub signaling_ip: String,
+    pub server_id: String,
+    pub channel: String,
+    pub user: String,
+    pub local_port: u16,
+    pub token: Vec<u8>, // join token from proto::token, empty for open servers
+    pub symmetric_punch: SymmetricPunch,
+    pub server_key: Option<Vec<u8>>, // pinned Noise key of the server, None = plain signaling
+}
 
-#[derive(Debug)]
-pub struct RelayState {
-    pub is_active: bool, // = is_relay || channel_has_server_relays
+/// Where taking our session back after losing the server stands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResumeState {
+    #[default]
+    Idle,
+    Sent,
+    Refused, // the server no longer knows the session, CONNECT again
 }
 
-pub type RelaySync = Arc<(Mutex<RelayState>, Condvar)>;
+#[derive(Debug, Default)]
+pub struct SessionState {
+    pub peers: Vec<PeerInfo>,
+    pub is_relay: bool,
+    pub channel_has_server_relays: bool, //as relay, i should also mirror to server
+    pub send_via_server: bool,           //this client must send via server
+    pub channel_id: u64,
+    pub my_peer_id: u32,
+    pub session_nonce: u64, // from WELCOME, proves our session to the server
+    pub rejected: Option<String>, // reason the server refused our CONNECT
+    pub server_seen: Option<Instant>, // last control packet from the server
+    pub resume: ResumeState,
+}
+
+// Everything the background tasks of one session share
+pub(crate) struct SessionShared {
+    pub socket: Arc<UdpSocket>,
+    pub config: SessionConfig,
+    pub signaling_addr: SocketAddr,
+    pub state: Mutex<SessionState>,
+    pub punch_paused: watch::Sender<bool>,
+    pub relay_active: watch::Sender<bool>, // = is_relay || channel_has_server_relays
+    pub mode_seen: Notify,
+    pub events: broadcast::Sender<ClientEvent>,
+    pub data_tx: mpsc::Sender<(u32, String, Vec<u8>)>, // (peer_id, username, payload)
+    pub birthday_sockets: Vec<Arc<UdpSocket>>,         // extra mappings, PortHint::Random only
+    pub identity: DtlsIdentity,
+    pub dtls: Mutex<HashMap<u32, DtlsPeer>>, // peer_id -> association; lock after `state`
+    pub group: Mutex<GroupKeys>,             // sender keys for DATA; lock after `dtls`
+    pub signaling: Mutex<Option<Transport>>, // Noise session with the server, None when plain
+    pub handshake: Mutex<Option<Initiator>>, // renewing the Noise session; lock after `signaling`
+    pub handshake_done: Notify,
+    pub srtp: Mutex<HashMap<u32, (SrtpSender, SrtpReceiver)>>, // peer_id -> contexts; lock last
+    pub media_tx: mpsc::Sender<MediaFrame>,
+    pub candidates: Vec<Candidate>, // ours, as sent in CONNECT
+    pub check_key: Vec<u8>,         // ours, as sent in CONNECT; peers MAC their checks with it
+    pub connect: ControlMessage,    // sent again if the server lost our session
+}
+
+impl SessionShared {
+    pub fn emit(&self, event: ClientEvent) {
+        // no subscribers is fine, events are best effort
+        let _ = self.events.send(event);
+    }
+
+    // (channel_id, my_peer_id) as handed out in WELCOME, zero before that
+    pub fn ids(&self) -> (u64, u32) {
+        let st = self.state.lock().unwrap();
+        (st.channel_id, st.my_peer_id)
+    }
+}
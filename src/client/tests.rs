@@ -0,0 +1,1240 @@
+use crate::client::{
+    dtls::{DtlsIdentity, DtlsPeer, DtlsRole, SRTP_KEY_LEN, SRTP_SALT_LEN},
+    events::{ClientEvent, ConnectionPath},
+    group::{self, GroupKeys},
+    handlers::{handle_control, handle_datagram},
+    ice::{self, Candidate, CandidateKind, CheckState, Checklist},
+    nat::{
+        Filtering, Mapping, NatBehavior, classify_filtering, classify_mapping, consistent_delta,
+        punch_targets,
+    },
+    networking::send_to_peer,
+    structures::{MediaFrame, NatKind, ResumeState, SessionConfig, SessionShared, SessionState},
+};
+use crate::net;
+use crate::proto::{
+    control::{self, ControlMessage, PortHint},
+    noise::{self, Initiator, MSG_RESPONSE, MSG_TRANSPORT, Responder, StaticKeypair},
+    packet::{self, Header},
+    srtp::{SrtpReceiver, SrtpSender},
+};
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{Notify, broadcast, mpsc, watch};
+
+const SERVER: &str = "127.0.0.1:2131";
+
+async fn test_shared() -> (
+    Arc<SessionShared>,
+    broadcast::Receiver<ClientEvent>,
+    mpsc::Receiver<(u32, String, Vec<u8>)>,
+) {
+    let (shared, events_rx, data_rx, _) = test_shared_with_media().await;
+    (shared, events_rx, data_rx)
+}
+
+async fn test_shared_with_media() -> (
+    Arc<SessionShared>,
+    broadcast::Receiver<ClientEvent>,
+    mpsc::Receiver<(u32, String, Vec<u8>)>,
+    mpsc::Receiver<MediaFrame>,
+) {
+    let socket = Arc::new(net::bind_any(0).await.unwrap());
+    let (data_tx, data_rx) = mpsc::channel(8);
+    let (media_tx, media_rx) = mpsc::channel(8);
+    let (events, events_rx) = broadcast::channel(16);
+
+    let shared = Arc::new(SessionShared {
+        socket,
+        config: SessionConfig {
+            signaling_ip: "127.0.0.1".into(),
+            server_id: "s1".into(),
+            channel: "ch1".into(),
+            user: "me".into(),
+            local_port: 0,
+            token: Vec::new(),
+            symmetric_punch: Default::default(),
+            server_key: None,
+        },
+        signaling_addr: SERVER.parse().unwrap(),
+        state: Mutex::new(SessionState::default()),
+        punch_paused: watch::Sender::new(false),
+        relay_active: watch::Sender::new(false),
+        mode_seen: Notify::new(),
+        events,
+        data_tx,
+        birthday_sockets: Vec::new(),
+        identity: DtlsIdentity::generate().unwrap(),
+        dtls: Mutex::new(HashMap::new()),
+        group: Mutex::new(GroupKeys::new()),
+        signaling: Mutex::new(None),
+        handshake: Mutex::new(None),
+        handshake_done: Notify::new(),
+        srtp: Mutex::new(HashMap::new()),
+        media_tx,
+        candidates: Vec::new(),
+        check_key: vec![1; 16],
+        connect: ControlMessage::Connect {
+            server_id: "s1".into(),
+            channel: "ch1".into(),
+            user: "me".into(),
+            nat_kind: NatKind::Cone,
+            token: Vec::new(),
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        },
+    });
+
+    (shared, events_rx, data_rx, media_rx)
+}
+
+fn server() -> SocketAddr {
+    SERVER.parse().unwrap()
+}
+
+fn from_server() -> Header {
+    Header::control(0, 0, 0, 0)
+}
+
+fn from_peer(peer_id: u32) -> Header {
+    Header::control(77, peer_id, 3, 0)
+}
+
+async fn welcome(shared: &SessionShared) {
+    let msg = ControlMessage::Welcome {
+        channel_id: 77,
+        peer_id: 3,
+        nonce: 5,
+    };
+    handle_control(shared, &from_server(), &msg, server()).await;
+}
+
+#[tokio::test]
+async fn welcome_is_stored_and_announced() {
+    let (shared, mut events, _data) = test_shared().await;
+
+    let msg = ControlMessage::Welcome {
+        channel_id: 77,
+        peer_id: 3,
+        nonce: 5,
+    };
+    handle_control(&shared, &from_server(), &msg, server()).await;
+
+    assert_eq!(
+        events.try_recv().unwrap(),
+        ClientEvent::WelcomeReceived {
+            channel_id: 77,
+            peer_id: 3
+        }
+    );
+    let st = shared.state.lock().unwrap();
+    assert_eq!(st.channel_id, 77);
+    assert_eq!(st.my_peer_id, 3);
+}
+
+#[tokio::test]
+async fn punched_peer_is_added_then_connected_once() {
+    let (shared, mut events, _data) = test_shared().await;
+    let peer_addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+
+    let direct = ControlMessage::ModeDirect {
+        user: "peer".into(),
+        addr: peer_addr,
+        peer_id: 5,
+        port_hint: PortHint::None,
+        fingerprint: Vec::new(),
+        candidates: Vec::new(),
+        check_key: Vec::new(),
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    assert_eq!(
+        events.try_recv().unwrap(),
+        ClientEvent::PeerAdded {
+            peer_id: 5,
+            username: "peer".into(),
+            addr: peer_addr
+        }
+    );
+
+    handle_control(
+        &shared,
+        &from_peer(5),
+        &ControlMessage::HolePunch,
+        peer_addr,
+    )
+    .await;
+    assert_eq!(
+        events.try_recv().unwrap(),
+        ClientEvent::PeerConnected {
+            peer_id: 5,
+            username: "peer".into(),
+            addr: peer_addr,
+            via: ConnectionPath::Punch
+        }
+    );
+
+    handle_control(
+        &shared,
+        &from_peer(5),
+        &ControlMessage::HolePunch,
+        peer_addr,
+    )
+    .await;
+    assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn server_takeover_reports_relay_lost() {
+    let (shared, mut events, _data) = test_shared().await;
+    welcome(&shared).await;
+    let _ = events.try_recv();
+
+    handle_control(
+        &shared,
+        &from_server(),
+        &ControlMessage::ModeRelay,
+        server(),
+    )
+    .await;
+    handle_control(
+        &shared,
+        &from_server(),
+        &ControlMessage::ModeRelay,
+        server(),
+    )
+    .await;
+    assert_eq!(events.try_recv().unwrap(), ClientEvent::BecameRelay);
+    assert!(events.try_recv().is_err());
+
+    let takeover = ControlMessage::ModeServerRelay {
+        user: "me".into(),
+        peer_id: 3,
+        fingerprint: Vec::new(),
+    };
+    handle_control(&shared, &from_server(), &takeover, server()).await;
+    assert_eq!(events.try_recv().unwrap(), ClientEvent::RelayLost);
+    assert_eq!(
+        events.try_recv().unwrap(),
+        ClientEvent::ServerRelayEnabled {
+            peer_id: 3,
+            username: "me".into()
+        }
+    );
+    assert!(*shared.punch_paused.borrow());
+    assert!(shared.state.lock().unwrap().send_via_server);
+}
+
+#[tokio::test]
+async fn replaced_relay_steps_down() {
+    let (shared, mut events, _data) = test_shared().await;
+    welcome(&shared).await;
+    let _ = events.try_recv();
+
+    for msg in [ControlMessage::ModeRelay, ControlMessage::ModePeer] {
+        handle_control(&shared, &from_server(), &msg, server()).await;
+    }
+    assert_eq!(events.try_recv().unwrap(), ClientEvent::BecameRelay);
+    assert_eq!(events.try_recv().unwrap(), ClientEvent::RelayLost);
+    assert!(!shared.state.lock().unwrap().is_relay);
+    assert!(!*shared.relay_active.borrow());
+    assert!(!shared.state.lock().unwrap().send_via_server);
+}
+
+#[tokio::test]
+async fn server_relayed_data_connects_sender_and_is_delivered() {
+    let (shared, mut events, mut data) = test_shared().await;
+    let peer_addr: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+
+    let direct = ControlMessage::ModeDirect {
+        user: "peer".into(),
+        addr: peer_addr,
+        peer_id: 5,
+        port_hint: PortHint::None,
+        fingerprint: Vec::new(),
+        candidates: Vec::new(),
+        check_key: Vec::new(),
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    let _ = events.try_recv();
+
+    // DATA nobody sealed for us proves nothing about the peer
+    let forged = ControlMessage::Data {
+        sender: "peer".into(),
+        payload: vec![0; 48],
+    };
+    handle_control(&shared, &from_peer(5), &forged, server()).await;
+    assert!(events.try_recv().is_err());
+    assert!(!shared.state.lock().unwrap().peers[0].connected);
+
+    let mut peer_keys = GroupKeys::new();
+    exchange_sender_keys(&mut peer_keys, 5, &mut shared.group.lock().unwrap(), 0);
+    let data_msg = ControlMessage::Data {
+        sender: "peer".into(),
+        payload: peer_keys.seal(&group::aad(0, 5, "peer"), b"hi"),
+    };
+    handle_control(&shared, &from_peer(5), &data_msg, server()).await;
+
+    assert_eq!(
+        events.try_recv().unwrap(),
+        ClientEvent::PeerConnected {
+            peer_id: 5,
+            username: "peer".into(),
+            addr: peer_addr,
+            via: ConnectionPath::Server
+        }
+    );
+    assert_eq!(
+        data.try_recv().unwrap(),
+        (5, "peer".to_string(), b"hi".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn user_left_is_announced_for_known_peers_only() {
+    let (shared, mut events, _data) = test_shared().await;
+
+    let left = ControlMessage::UserLeft {
+        user: "peer".into(),
+        addr: None,
+        peer_id: 5,
+    };
+    handle_control(&shared, &from_server(), &left, server()).await;
+    assert!(events.try_recv().is_err());
+
+    let direct = ControlMessage::ModeDirect {
+        user: "peer".into(),
+        addr: "127.0.0.1:6003".parse().unwrap(),
+        peer_id: 5,
+        port_hint: PortHint::None,
+        fingerprint: Vec::new(),
+        candidates: Vec::new(),
+        check_key: Vec::new(),
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    let _ = events.try_recv();
+
+    handle_control(&shared, &from_server(), &left, server()).await;
+    assert_eq!(
+        events.try_recv().unwrap(),
+        ClientEvent::PeerLeft {
+            peer_id: 5,
+            username: "peer".into()
+        }
+    );
+}
+
+#[tokio::test]
+async fn same_named_peers_stay_distinct() {
+    let (shared, mut events, _data) = test_shared().await;
+    let addr_a: SocketAddr = "127.0.0.1:6004".parse().unwrap();
+    let addr_b: SocketAddr = "127.0.0.1:6005".parse().unwrap();
+
+    for (peer_id, addr) in [(5, addr_a), (6, addr_b)] {
+        let direct = ControlMessage::ModeDirect {
+            user: "twin".into(),
+            addr,
+            peer_id,
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        };
+        handle_control(&shared, &from_server(), &direct, server()).await;
+        let _ = events.try_recv();
+    }
+    assert_eq!(shared.state.lock().unwrap().peers.len(), 2);
+
+    // only peer 6 leaves
+    let left = ControlMessage::UserLeft {
+        user: "twin".into(),
+        addr: Some(addr_b),
+        peer_id: 6,
+    };
+    handle_control(&shared, &from_server(), &left, server()).await;
+
+    let st = shared.state.lock().unwrap();
+    assert_eq!(st.peers.len(), 1);
+    assert_eq!(st.peers[0].peer_id, 5);
+}
+
+#[tokio::test]
+async fn peer_address_change_keeps_its_identity() {
+    let (shared, mut events, _data) = test_shared().await;
+    let old_addr: SocketAddr = "127.0.0.1:6006".parse().unwrap();
+    let new_addr: SocketAddr = "127.0.0.1:6007".parse().unwrap();
+
+    for addr in [old_addr, new_addr] {
+        let direct = ControlMessage::ModeDirect {
+            user: "peer".into(),
+            addr,
+            peer_id: 5,
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        };
+        handle_control(&shared, &from_server(), &direct, server()).await;
+    }
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        ClientEvent::PeerAdded { peer_id: 5, .. }
+    ));
+    assert!(events.try_recv().is_err());
+
+    let st = shared.state.lock().unwrap();
+    assert_eq!(st.peers.len(), 1);
+    assert_eq!(st.peers[0].addr, new_addr);
+    assert!(!st.peers[0].connected);
+}
+
+#[test]
+fn mapping_is_classified_from_observed_addresses() {
+    let a: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+    let b: SocketAddr = "198.51.100.1:40001".parse().unwrap();
+    let c: SocketAddr = "198.51.100.1:40002".parse().unwrap();
+
+    assert_eq!(classify_mapping(a, a, None), Mapping::EndpointIndependent);
+    // same port towards both ports of the alternate address
+    assert_eq!(classify_mapping(a, b, Some(b)), Mapping::AddressDependent);
+    assert_eq!(
+        classify_mapping(a, b, Some(c)),
+        Mapping::AddressPortDependent
+    );
+    // no alternate address to tell the last two apart
+    assert_eq!(classify_mapping(a, b, None), Mapping::AddressPortDependent);
+}
+
+#[test]
+fn filtering_is_classified_from_change_requests() {
+    assert_eq!(
+        classify_filtering(Some(true), true),
+        Filtering::EndpointIndependent
+    );
+    assert_eq!(
+        classify_filtering(Some(false), true),
+        Filtering::AddressDependent
+    );
+    assert_eq!(classify_filtering(None, true), Filtering::AddressDependent);
+    assert_eq!(
+        classify_filtering(Some(false), false),
+        Filtering::AddressPortDependent
+    );
+}
+
+#[test]
+fn nat_behavior_maps_to_kind() {
+    let behavior = |no_nat, mapping, filtering| NatBehavior {
+        no_nat,
+        mapped: "198.51.100.1:40000".parse().unwrap(),
+        mapping,
+        filtering,
+    };
+    use Filtering as F;
+    use Mapping as M;
+
+    assert_eq!(
+        behavior(true, M::EndpointIndependent, F::AddressPortDependent).kind(),
+        NatKind::Public
+    );
+    assert_eq!(
+        behavior(false, M::EndpointIndependent, F::EndpointIndependent).kind(),
+        NatKind::Cone
+    );
+    assert_eq!(
+        behavior(false, M::EndpointIndependent, F::AddressDependent).kind(),
+        NatKind::RestrictedCone
+    );
+    assert_eq!(
+        behavior(false, M::EndpointIndependent, F::AddressPortDependent).kind(),
+        NatKind::PortRestrictedCone
+    );
+    assert_eq!(
+        behavior(false, M::AddressDependent, F::EndpointIndependent).kind(),
+        NatKind::Symmetric
+    );
+    assert_eq!(
+        behavior(false, M::AddressPortDependent, F::AddressPortDependent).kind(),
+        NatKind::Symmetric
+    );
+}
+
+#[test]
+fn port_delta_must_be_consistent_and_small() {
+    assert_eq!(consistent_delta(&[4000, 4001, 4002, 4003]), Some(1));
+    assert_eq!(consistent_delta(&[4010, 4006, 4002]), Some(-4));
+    assert_eq!(consistent_delta(&[4000, 4001, 4003]), None);
+    assert_eq!(consistent_delta(&[4000, 4000, 4000]), None);
+    assert_eq!(consistent_delta(&[4000, 9000, 14000]), None);
+    assert_eq!(consistent_delta(&[4000]), None);
+}
+
+#[test]
+fn punch_targets_follow_the_port_hint() {
+    let addr: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+    assert_eq!(punch_targets(addr, PortHint::None), vec![addr]);
+
+    let predicted = punch_targets(addr, PortHint::Delta(2));
+    assert_eq!(predicted[0], addr);
+    assert_eq!(predicted[1].port(), 40002);
+    assert!(predicted.iter().all(|a| a.ip() == addr.ip()));
+
+    // predictions never wrap past the port range
+    let high: SocketAddr = "198.51.100.7:65530".parse().unwrap();
+    assert!(
+        punch_targets(high, PortHint::Delta(4))
+            .iter()
+            .all(|a| a.port() >= 65530)
+    );
+
+    let random = punch_targets(addr, PortHint::Random);
+    assert!(random.len() > 1);
+    assert!(random[1..].iter().all(|a| a.port() >= 1024));
+}
+
+#[tokio::test]
+async fn symmetric_peer_is_reached_at_its_punched_address() {
+    let (shared, mut events, _data) = test_shared().await;
+    welcome(&shared).await;
+    let _ = events.try_recv();
+    let seen: SocketAddr = "127.0.0.1:6010".parse().unwrap();
+    let punched: SocketAddr = "127.0.0.1:6013".parse().unwrap();
+
+    for (peer_id, port_hint) in [(5, PortHint::Delta(3)), (6, PortHint::None)] {
+        let direct = ControlMessage::ModeDirect {
+            user: "peer".into(),
+            addr: seen,
+            peer_id,
+            port_hint,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: vec![2; 16],
+        };
+        handle_control(&shared, &from_server(), &direct, server()).await;
+        let _ = events.try_recv();
+    }
+    let addr_of = |id| {
+        let st = shared.state.lock().unwrap();
+        st.peers.iter().find(|p| p.peer_id == id).unwrap().addr
+    };
+
+    // anyone can send a plain punch in the peer's name, it moves nothing
+    handle_control(&shared, &from_peer(5), &ControlMessage::HolePunch, punched).await;
+    assert_eq!(addr_of(5), seen);
+    assert!(events.try_recv().is_err());
+
+    // an authenticated check does
+    for peer_id in [5, 6] {
+        let tag = ice::check_tag(&shared.check_key, 77, peer_id, 3, 1, false);
+        handle_control(
+            &shared,
+            &from_peer(peer_id),
+            &ControlMessage::Check { txn: 1, tag },
+            punched,
+        )
+        .await;
+    }
+    assert_eq!(addr_of(5), punched);
+    // without a hint the server-seen address stays authoritative
+    assert_eq!(addr_of(6), seen);
+}
+
+// shuttles datagrams between two associations until neither has anything to say
+fn pump(a: &mut DtlsPeer, b: &mut DtlsPeer) -> std::io::Result<()> {
+    let mut to_b = a.handle(None)?;
+    for _ in 0..16 {
+        let mut to_a = Vec::new();
+        for d in to_b.drain(..) {
+            to_a.extend(b.handle(Some(&d))?);
+        }
+        for d in to_a {
+            to_b.extend(a.handle(Some(&d))?);
+        }
+        if to_b.is_empty() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn dtls_handshake_exports_mirrored_srtp_keys() {
+    let alice = DtlsIdentity::generate().unwrap();
+    let bob = DtlsIdentity::generate().unwrap();
+    assert_eq!(DtlsRole::for_pair(1, 2), DtlsRole::Active);
+    assert_eq!(DtlsRole::for_pair(2, 1), DtlsRole::Passive);
+
+    let mut a = DtlsPeer::new(&alice, DtlsRole::Active, bob.fingerprint().to_vec()).unwrap();
+    let mut b = DtlsPeer::new(&bob, DtlsRole::Passive, alice.fingerprint().to_vec()).unwrap();
+    pump(&mut a, &mut b).unwrap();
+
+    let (ka, kb) = (a.keys().unwrap(), b.keys().unwrap());
+    assert_eq!(ka.local_key, kb.remote_key);
+    assert_eq!(ka.local_salt, kb.remote_salt);
+    assert_eq!(ka.remote_key, kb.local_key);
+    assert_ne!(ka.local_key, ka.remote_key);
+    assert_eq!(ka.local_key.len(), SRTP_KEY_LEN);
+    assert_eq!(ka.local_salt.len(), SRTP_SALT_LEN);
+    assert!(!a.timed_out() && a.retransmit().is_none());
+}
+
+#[test]
+fn dtls_handshake_fails_on_unexpected_fingerprint() {
+    let alice = DtlsIdentity::generate().unwrap();
+    let bob = DtlsIdentity::generate().unwrap();
+    let mallory = DtlsIdentity::generate().unwrap();
+
+    // alice was told to expect bob, but mallory answers
+    let mut a = DtlsPeer::new(&alice, DtlsRole::Active, bob.fingerprint().to_vec()).unwrap();
+    let mut m = DtlsPeer::new(&mallory, DtlsRole::Passive, alice.fingerprint().to_vec()).unwrap();
+    assert!(pump(&mut a, &mut m).is_err());
+    assert!(a.keys().is_none());
+}
+
+#[tokio::test]
+async fn dtls_from_unknown_peer_opens_no_association() {
+    let (shared, _events, _data) = test_shared().await;
+    welcome(&shared).await;
+
+    let mut hello = DtlsPeer::new(
+        &DtlsIdentity::generate().unwrap(),
+        DtlsRole::Active,
+        vec![0; 32],
+    )
+    .unwrap()
+    .handle(None)
+    .unwrap();
+    let hdr = Header::dtls(77, 1, 3, 0);
+    let pkt = crate::proto::packet::encode(hdr, &hello.remove(0));
+    handle_datagram(&shared, &pkt, "127.0.0.1:6020".parse().unwrap()).await;
+
+    assert!(shared.dtls.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn srtp_from_secured_peer_is_decrypted_once() {
+    let (shared, _events, _data, mut media) = test_shared_with_media().await;
+    let peer_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+    {
+        let mut st = shared.state.lock().unwrap();
+        st.channel_id = 9;
+        st.my_peer_id = 2;
+    }
+    let direct = ControlMessage::ModeDirect {
+        user: "bob".into(),
+        addr: peer_addr,
+        peer_id: 1,
+        port_hint: PortHint::None,
+        fingerprint: vec![1; 32],
+        candidates: Vec::new(),
+        check_key: Vec::new(),
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+
+    let (key, salt) = ([3u8; SRTP_KEY_LEN], [4u8; SRTP_SALT_LEN]);
+    shared.srtp.lock().unwrap().insert(
+        1,
+        (
+            SrtpSender::new(&[0; SRTP_KEY_LEN], &salt),
+            SrtpReceiver::new(&key, &salt),
+        ),
+    );
+    let mut bob = SrtpSender::new(&key, &salt);
+    let protected = bob.protect(7, 96, 480, b"frame");
+    let pkt = packet::encode(Header::srtp(9, 1, 2, 7, protected.len() as u16), &protected);
+
+    // relayed by the server, exactly as bob protected it
+    handle_datagram(&shared, &pkt, server()).await;
+    let frame = media.try_recv().unwrap();
+    assert_eq!(
+        frame,
+        MediaFrame {
+            peer_id: 1,
+            stream_id: 7,
+            timestamp: 480,
+            payload: b"frame".to_vec(),
+        }
+    );
+
+    // a replay, a claim to be someone without keys, a mismatched stream id
+    handle_datagram(&shared, &pkt, peer_addr).await;
+    let spoofed = packet::encode(Header::srtp(9, 3, 2, 7, protected.len() as u16), &protected);
+    handle_datagram(&shared, &spoofed, peer_addr).await;
+    let protected = bob.protect(7, 96, 960, b"frame");
+    let wrong = packet::encode(Header::srtp(9, 1, 2, 8, protected.len() as u16), &protected);
+    handle_datagram(&shared, &wrong, peer_addr).await;
+    assert!(media.try_recv().is_err());
+}
+
+// a DTLS association between two members, then their sender keys both ways
+fn exchange_sender_keys(a: &mut GroupKeys, a_id: u32, b: &mut GroupKeys, b_id: u32) {
+    let (ia, ib) = (
+        DtlsIdentity::generate().unwrap(),
+        DtlsIdentity::generate().unwrap(),
+    );
+    let mut da = DtlsPeer::new(&ia, DtlsRole::Active, ib.fingerprint().to_vec()).unwrap();
+    let mut db = DtlsPeer::new(&ib, DtlsRole::Passive, ia.fingerprint().to_vec()).unwrap();
+    pump(&mut da, &mut db).unwrap();
+
+    let mut to_b = a.exchange(b_id, &mut da);
+    for _ in 0..4 {
+        for d in to_b.drain(..) {
+            db.handle(Some(&d)).unwrap();
+        }
+        for d in b.exchange(a_id, &mut db) {
+            da.handle(Some(&d)).unwrap();
+        }
+        to_b = a.exchange(b_id, &mut da);
+    }
+}
+
+#[test]
+fn sender_keys_travel_over_dtls_and_rotate() {
+    let (mut alice, mut bob, mut carol) = (GroupKeys::new(), GroupKeys::new(), GroupKeys::new());
+    exchange_sender_keys(&mut alice, 1, &mut bob, 2);
+    exchange_sender_keys(&mut alice, 1, &mut carol, 3);
+
+    let aad = group::aad(9, 1, "alice");
+    let sealed = alice.seal(&aad, b"secret");
+    assert_eq!(bob.open(1, &aad, &sealed).unwrap(), b"secret");
+    assert_eq!(carol.open(1, &aad, &sealed).unwrap(), b"secret");
+    // a relay re-attributing the message, or tampering with it
+    assert!(bob.open(1, &group::aad(9, 3, "carol"), &sealed).is_none());
+    let mut tampered = sealed.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(bob.open(1, &aad, &tampered).is_none());
+
+    // carol left: only bob gets the next key
+    alice.remove_peer(3);
+    alice.rotate();
+    exchange_sender_keys(&mut alice, 1, &mut bob, 2);
+    let sealed = alice.seal(&aad, b"after");
+    assert_eq!(alice.key_id(), 2);
+    assert_eq!(bob.open(1, &aad, &sealed).unwrap(), b"after");
+    assert!(carol.open(1, &aad, &sealed).is_none());
+}
+
+#[tokio::test]
+async fn user_left_rotates_our_sender_key() {
+    let (shared, _events, _data) = test_shared().await;
+    let direct = ControlMessage::ModeDirect {
+        user: "peer".into(),
+        addr: "127.0.0.1:6010".parse().unwrap(),
+        peer_id: 5,
+        port_hint: PortHint::None,
+        fingerprint: Vec::new(),
+        candidates: Vec::new(),
+        check_key: Vec::new(),
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    assert_eq!(shared.group.lock().unwrap().key_id(), 1);
+
+    let left = ControlMessage::UserLeft {
+        user: "peer".into(),
+        addr: None,
+        peer_id: 5,
+    };
+    handle_control(&shared, &from_server(), &left, server()).await;
+    assert_eq!(shared.group.lock().unwrap().key_id(), 2);
+}
+
+#[tokio::test]
+async fn unsealed_server_control_is_ignored_once_secured() {
+    let (shared, mut events, _data) = test_shared().await;
+    let key = StaticKeypair::generate().unwrap();
+    let (initiator, init) = Initiator::start(key.public(), b"").unwrap();
+    let (_, responder) = Responder::read(&key, &init).unwrap();
+    let (reply, mut server_side) = responder.finish(b"").unwrap();
+    let (_, transport) = initiator.finish(&reply).unwrap();
+    *shared.signaling.lock().unwrap() = Some(transport);
+
+    let welcome = control::encode_packet(
+        0,
+        0,
+        0,
+        &ControlMessage::Welcome {
+            channel_id: 77,
+            peer_id: 3,
+            nonce: 5,
+        },
+    )
+    .unwrap();
+    handle_datagram(&shared, &welcome, server()).await;
+    assert!(events.try_recv().is_err());
+    assert_eq!(shared.state.lock().unwrap().my_peer_id, 0);
+
+    let sealed = noise::frame(MSG_TRANSPORT, &server_side.seal(&welcome));
+    handle_datagram(&shared, &sealed, server()).await;
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        ClientEvent::WelcomeReceived { peer_id: 3, .. }
+    ));
+}
+
+#[tokio::test]
+async fn renewed_signaling_session_replaces_the_old_one() {
+    let (shared, mut events, _data) = test_shared().await;
+    let key = StaticKeypair::generate().unwrap();
+    let (initiator, init) = Initiator::start(key.public(), b"").unwrap();
+    let (_, responder) = Responder::read(&key, &init).unwrap();
+    let (reply, mut old_server_side) = responder.finish(b"").unwrap();
+    let (_, transport) = initiator.finish(&reply).unwrap();
+    *shared.signaling.lock().unwrap() = Some(transport);
+
+    // the server restarted and answers a new handshake
+    let (initiator, init) = Initiator::start(key.public(), b"").unwrap();
+    *shared.handshake.lock().unwrap() = Some(initiator);
+    let (_, responder) = Responder::read(&key, &init).unwrap();
+    let (reply, mut server_side) = responder.finish(b"").unwrap();
+    handle_datagram(&shared, &noise::frame(MSG_RESPONSE, &reply), server()).await;
+    tokio::time::timeout(
+        std::time::Duration::from_secs(1),
+        shared.handshake_done.notified(),
+    )
+    .await
+    .unwrap();
+    assert!(shared.handshake.lock().unwrap().is_none());
+
+    let welcome = control::encode_packet(
+        0,
+        0,
+        0,
+        &ControlMessage::Welcome {
+            channel_id: 77,
+            peer_id: 3,
+            nonce: 5,
+        },
+    )
+    .unwrap();
+    let stale = noise::frame(MSG_TRANSPORT, &old_server_side.seal(&welcome));
+    handle_datagram(&shared, &stale, server()).await;
+    assert!(events.try_recv().is_err());
+
+    let sealed = noise::frame(MSG_TRANSPORT, &server_side.seal(&welcome));
+    handle_datagram(&shared, &sealed, server()).await;
+    assert!(matches!(
+        events.try_recv().unwrap(),
+        ClientEvent::WelcomeReceived { peer_id: 3, .. }
+    ));
+    assert!(shared.state.lock().unwrap().server_seen.is_some());
+}
+
+#[tokio::test]
+async fn refused_resume_is_not_a_refused_connect() {
+    let (shared, _events, _data) = test_shared().await;
+    welcome(&shared).await;
+
+    shared.state.lock().unwrap().resume = ResumeState::Sent;
+    let rejected = ControlMessage::Rejected {
+        reason: "unknown session".into(),
+    };
+    handle_control(&shared, &from_server(), &rejected, server()).await;
+    {
+        let st = shared.state.lock().unwrap();
+        assert_eq!(st.resume, ResumeState::Refused);
+        assert_eq!(st.rejected, None);
+    }
+
+    // a resumed session is welcomed again
+    shared.state.lock().unwrap().resume = ResumeState::Sent;
+    welcome(&shared).await;
+    assert_eq!(shared.state.lock().unwrap().resume, ResumeState::Idle);
+}
+
+#[tokio::test]
+async fn ipv6_peer_is_punched_and_connected() {
+    let (shared, mut events, _data) = test_shared().await;
+    welcome(&shared).await;
+    let _ = events.try_recv();
+    let peer = net::bind("::1".parse().unwrap(), 0).await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    let direct = ControlMessage::ModeDirect {
+        user: "peer".into(),
+        addr: peer_addr,
+        peer_id: 5,
+        port_hint: PortHint::Delta(2),
+        fingerprint: Vec::new(),
+        candidates: Vec::new(),
+        check_key: Vec::new(),
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    let _ = events.try_recv();
+    assert!(
+        punch_targets(peer_addr, PortHint::Delta(2))
+            .iter()
+            .all(|a| a.is_ipv6())
+    );
+
+    send_to_peer(&shared, &ControlMessage::HolePunch, 5, peer_addr)
+        .await
+        .unwrap();
+    let mut buf = [0u8; 256];
+    let (len, from) = net::recv_from(&peer, &mut buf).await.unwrap();
+    let (hdr, msg) = control::decode_packet(&buf[..len]).unwrap();
+    assert_eq!((hdr.src_peer_id, msg), (3, ControlMessage::HolePunch));
+    assert_eq!(from.port(), shared.socket.local_addr().unwrap().port());
+
+    let punch = control::encode_packet(77, 5, 3, &ControlMessage::HolePunch).unwrap();
+    handle_datagram(&shared, &punch, peer_addr).await;
+    assert_eq!(
+        events.try_recv().unwrap(),
+        ClientEvent::PeerConnected {
+            peer_id: 5,
+            username: "peer".into(),
+            addr: peer_addr,
+            via: ConnectionPath::Punch
+        }
+    );
+}
+
+#[test]
+fn candidates_are_gathered_best_first() {
+    let relay: SocketAddr = "203.0.113.1:2131".parse().unwrap();
+    let mapped: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+    let ips: Vec<std::net::IpAddr> = ["127.0.0.1", "192.168.1.20", "fe80::1", "2001:db8::5"]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+
+    let candidates = ice::collect_candidates(&ips, 4000, Some(mapped), relay, |_| true);
+    let kinds: Vec<_> = candidates.iter().map(|c| (c.kind, c.addr)).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (CandidateKind::Host, "192.168.1.20:4000".parse().unwrap()),
+            (CandidateKind::Host, "[2001:db8::5]:4000".parse().unwrap()),
+            (CandidateKind::ServerReflexive, mapped),
+            (CandidateKind::Relay, relay),
+        ]
+    );
+    assert!(candidates.windows(2).all(|w| w[0].priority > w[1].priority));
+
+    // without a NAT the mapping is one of our interfaces
+    let public =
+        ice::collect_candidates(&ips, 4000, Some(candidates[0].addr), relay, |a| a.is_ipv4());
+    assert_eq!(public.len(), 2);
+    assert!(
+        public
+            .iter()
+            .all(|c| c.kind != CandidateKind::ServerReflexive)
+    );
+
+    // a host with many interfaces still fits one CONNECT
+    let many: Vec<std::net::IpAddr> = (1..=40)
+        .map(|i| std::net::Ipv4Addr::new(10, 0, 0, i).into())
+        .collect();
+    let capped = ice::collect_candidates(&many, 4000, Some(mapped), relay, |_| true);
+    assert_eq!(capped.len(), control::MAX_CANDIDATES);
+    assert_eq!(capped[0].addr, "10.0.0.1:4000".parse().unwrap());
+    assert_eq!(capped.last().unwrap().kind, CandidateKind::Relay);
+}
+
+#[test]
+fn checklist_upgrades_to_the_best_pair_that_answers() {
+    let host: SocketAddr = "192.168.1.30:4000".parse().unwrap();
+    let srflx: SocketAddr = "198.51.100.9:40000".parse().unwrap();
+    let remote = [
+        Candidate {
+            kind: CandidateKind::ServerReflexive,
+            addr: srflx,
+            priority: ice::priority(CandidateKind::ServerReflexive, u16::MAX),
+        },
+        Candidate {
+            kind: CandidateKind::Host,
+            addr: host,
+            priority: ice::priority(CandidateKind::Host, u16::MAX),
+        },
+        Candidate {
+            kind: CandidateKind::Relay,
+            addr: "203.0.113.1:2131".parse().unwrap(),
+            priority: ice::priority(CandidateKind::Relay, u16::MAX),
+        },
+    ];
+    let mut checks = Checklist::new(&[], &remote, true);
+    assert_eq!(checks.pairs().len(), 2, "relay candidates are not checked");
+
+    let due = checks.due();
+    assert_eq!(
+        due.iter().map(|(a, _)| *a).collect::<Vec<_>>(),
+        [host, srflx]
+    );
+    let txn_of = |addr| due.iter().find(|(a, _)| *a == addr).unwrap().1;
+
+    assert_eq!(checks.succeeded(0xBAD, srflx), None);
+    assert_eq!(checks.succeeded(txn_of(srflx), srflx), Some(true));
+    assert_eq!(checks.selected(), Some(srflx));
+    // the host pair may still win, only it is checked again
+    assert!(checks.pending());
+    assert_eq!(checks.due(), vec![(host, txn_of(host))]);
+
+    assert_eq!(checks.succeeded(txn_of(host), host), Some(true));
+    assert_eq!(checks.selected(), Some(host));
+    assert!(!checks.pending());
+    assert!(checks.due().is_empty());
+    assert_eq!(checks.succeeded(txn_of(srflx), srflx), Some(false));
+}
+
+#[test]
+fn unanswered_pairs_fail_and_a_triggered_check_revives_them() {
+    let addr: SocketAddr = "198.51.100.9:40000".parse().unwrap();
+    let mut checks = Checklist::new(
+        &[],
+        &[Candidate {
+            kind: CandidateKind::ServerReflexive,
+            addr,
+            priority: ice::priority(CandidateKind::ServerReflexive, u16::MAX),
+        }],
+        false,
+    );
+    while !checks.due().is_empty() {}
+    assert!(!checks.pending());
+    assert_eq!(checks.pairs()[0].state, CheckState::Failed);
+
+    checks.triggered(addr);
+    assert_eq!(checks.due().len(), 1);
+
+    // a check from an address we did not know: a peer reflexive candidate
+    let prflx: SocketAddr = "198.51.100.9:40007".parse().unwrap();
+    checks.triggered(prflx);
+    assert!(
+        checks
+            .pairs()
+            .iter()
+            .any(|p| p.remote.addr == prflx && p.remote.kind == CandidateKind::PeerReflexive)
+    );
+}
+
+#[tokio::test]
+async fn lan_candidate_is_checked_and_selected() {
+    let (shared, mut events, _data) = test_shared().await;
+    welcome(&shared).await;
+    let _ = events.try_recv();
+    let peer = net::bind("127.0.0.1".parse().unwrap(), 0).await.unwrap();
+    let host = peer.local_addr().unwrap();
+    let seen: SocketAddr = "198.51.100.9:40000".parse().unwrap();
+
+    let direct = ControlMessage::ModeDirect {
+        user: "peer".into(),
+        addr: seen,
+        peer_id: 5,
+        port_hint: PortHint::None,
+        fingerprint: Vec::new(),
+        candidates: vec![Candidate {
+            kind: CandidateKind::Host,
+            addr: host,
+            priority: ice::priority(CandidateKind::Host, u16::MAX),
+        }],
+        check_key: vec![2; 16],
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    let _ = events.try_recv();
+
+    // the peer's check is answered on the same pair, MACed under its key
+    let tag = ice::check_tag(&shared.check_key, 77, 5, 3, 9, false);
+    handle_control(
+        &shared,
+        &from_peer(5),
+        &ControlMessage::Check { txn: 9, tag },
+        host,
+    )
+    .await;
+    let mut buf = [0u8; 256];
+    let (len, _) = net::recv_from(&peer, &mut buf).await.unwrap();
+    let (hdr, msg) = control::decode_packet(&buf[..len]).unwrap();
+    assert_eq!(
+        (hdr.src_peer_id, msg),
+        (
+            3,
+            ControlMessage::CheckOk {
+                txn: 9,
+                tag: ice::check_tag(&shared.check_key, 77, 3, 5, 9, true),
+            }
+        )
+    );
+    let _ = events.try_recv();
+
+    let txn = {
+        let mut st = shared.state.lock().unwrap();
+        let due = st.peers[0].checks.due();
+        assert_eq!(due[0].0, host, "the host pair is checked first");
+        due[0].1
+    };
+    handle_control(
+        &shared,
+        &from_peer(5),
+        &ControlMessage::CheckOk {
+            txn,
+            tag: ice::check_tag(&[2; 16], 77, 5, 3, txn, true),
+        },
+        host,
+    )
+    .await;
+    {
+        let st = shared.state.lock().unwrap();
+        assert_eq!(st.peers[0].addr, host);
+        assert_eq!(st.peers[0].server_addr, seen);
+        assert!(st.peers[0].connected);
+    }
+
+    // a repeated MODE DIRECT is not a move, the selected pair stays
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    assert_eq!(shared.state.lock().unwrap().peers[0].addr, host);
+}
+
+#[tokio::test]
+async fn checks_from_unknown_peers_are_not_answered() {
+    let (shared, _events, _data) = test_shared().await;
+    welcome(&shared).await;
+    let stranger = net::bind("127.0.0.1".parse().unwrap(), 0).await.unwrap();
+    let addr = stranger.local_addr().unwrap();
+
+    let tag = ice::check_tag(&shared.check_key, 77, 9, 3, 1, false);
+    handle_control(
+        &shared,
+        &from_peer(9),
+        &ControlMessage::Check { txn: 1, tag },
+        addr,
+    )
+    .await;
+    let mut buf = [0u8; 64];
+    assert!(
+        tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            net::recv_from(&stranger, &mut buf)
+        )
+        .await
+        .is_err()
+    );
+}
+
+#[tokio::test]
+async fn forged_checks_are_dropped() {
+    let (shared, mut events, _data) = test_shared().await;
+    welcome(&shared).await;
+    let _ = events.try_recv();
+    let attacker = net::bind("127.0.0.1".parse().unwrap(), 0).await.unwrap();
+    let spoofed = attacker.local_addr().unwrap();
+    let seen: SocketAddr = "198.51.100.9:40000".parse().unwrap();
+
+    let direct = ControlMessage::ModeDirect {
+        user: "peer".into(),
+        addr: seen,
+        peer_id: 5,
+        port_hint: PortHint::None,
+        fingerprint: Vec::new(),
+        candidates: Vec::new(),
+        check_key: vec![2; 16],
+    };
+    handle_control(&shared, &from_server(), &direct, server()).await;
+    let _ = events.try_recv();
+
+    // a check in the peer's name without its key: no answer, no learnt pair
+    let forged = ice::check_tag(&[9; 16], 77, 5, 3, 1, false);
+    handle_control(
+        &shared,
+        &from_peer(5),
+        &ControlMessage::Check {
+            txn: 1,
+            tag: forged,
+        },
+        spoofed,
+    )
+    .await;
+    let mut buf = [0u8; 64];
+    assert!(
+        tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            net::recv_from(&attacker, &mut buf)
+        )
+        .await
+        .is_err()
+    );
+
+    // nor does a forged answer select a pair
+    let txn = shared.state.lock().unwrap().peers[0].checks.due()[0].1;
+    handle_control(
+        &shared,
+        &from_peer(5),
+        &ControlMessage::CheckOk {
+            txn,
+            tag: ice::check_tag(&[9; 16], 77, 5, 3, txn, true),
+        },
+        seen,
+    )
+    .await;
+    let st = shared.state.lock().unwrap();
+    assert!(
+        st.peers[0]
+            .checks
+            .pairs()
+            .iter()
+            .all(|p| p.remote.kind != CandidateKind::PeerReflexive)
+    );
+    assert_eq!(st.peers[0].checks.selected(), None);
+    assert!(!st.peers[0].connected);
+}
+
+#[test]
+fn learnt_peer_reflexive_pairs_are_capped() {
+    let mut checks = Checklist::new(&[], &[], false);
+    for port in 40000..40100 {
+        checks.triggered(SocketAddr::from(([198, 51, 100, 9], port)));
+    }
+    let learnt = checks
+        .pairs()
+        .iter()
+        .filter(|p| p.remote.kind == CandidateKind::PeerReflexive)
+        .count();
+    assert_eq!(learnt, 4);
+    assert_eq!(
+        checks.succeeded(1, SocketAddr::from(([198, 51, 100, 9], 50000))),
+        None
+    );
+}
+
+#[test]
+fn peer_behind_our_public_ip_is_checked_on_the_lan_first() {
+    let public: SocketAddr = "198.51.100.9:40000".parse().unwrap();
+    let host: SocketAddr = "192.168.1.30:4000".parse().unwrap();
+    let candidate = |kind, addr| Candidate {
+        kind,
+        addr,
+        priority: ice::priority(kind, u16::MAX),
+    };
+    let local = [
+        candidate(CandidateKind::Host, "192.168.1.20:4000".parse().unwrap()),
+        candidate(
+            CandidateKind::ServerReflexive,
+            "198.51.100.9:40100".parse().unwrap(),
+        ),
+    ];
+    let remote = [
+        candidate(CandidateKind::Host, host),
+        candidate(CandidateKind::ServerReflexive, public),
+    ];
+
+    let mut checks = Checklist::new(&local, &remote, true);
+    assert!(checks.same_nat());
+    // the public mapping only joins once the private address had its chance
+    let mut rounds = 0;
+    while checks.lan_pending() {
+        assert_eq!(
+            checks.due().iter().map(|(a, _)| *a).collect::<Vec<_>>(),
+            [host]
+        );
+        rounds += 1;
+    }
+    assert!(rounds > 0);
+    assert!(checks.due().iter().any(|(a, _)| *a == public));
+
+    // elsewhere both are checked from the start
+    let mut elsewhere = Checklist::new(&local[..1], &remote, true);
+    assert!(!elsewhere.same_nat());
+    assert_eq!(elsewhere.due().len(), 2);
+}
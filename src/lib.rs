@@ -1,3 +1,5 @@
 pub mod client;
+pub mod log;
+pub mod net;
 pub mod proto;
 pub mod signaling;
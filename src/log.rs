@@ -0,0 +1,383 @@
+use std::{
+    fmt::{self, Write},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        PoisonError, RwLock,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+// Both binaries log through `log!`. A line has a level, a target (the module it
+// was logged from, without the crate name) and the session fields it is about;
+// a filter picks the level per target.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    /// Every retransmit, punch and keepalive.
+    Trace,
+}
+
+impl LogLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!(
+                "expected error, warn, info, debug or trace, got {s:?}"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// A default level and levels for targets, written `info,signaling::turn=debug`.
+/// A target covers its submodules; the longest matching one wins.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    level: LogLevel,
+    targets: Vec<(String, LogLevel)>, // longest first
+}
+
+impl LogFilter {
+    pub fn new(level: LogLevel, targets: impl IntoIterator<Item = (String, LogLevel)>) -> Self {
+        let mut targets: Vec<_> = targets.into_iter().collect();
+        targets.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        targets.dedup_by(|a, b| a.0 == b.0);
+        Self { level, targets }
+    }
+
+    pub fn level(&self) -> LogLevel {
+        self.level
+    }
+
+    pub fn targets(&self) -> &[(String, LogLevel)] {
+        &self.targets
+    }
+
+    pub fn level_for(&self, target: &str) -> LogLevel {
+        self.targets
+            .iter()
+            .find(|(t, _)| {
+                target
+                    .strip_prefix(t.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.level, |(_, level)| *level)
+    }
+
+    // the most verbose level any target gets
+    fn max(&self) -> LogLevel {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, LogLevel::max)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut level = LogLevel::default();
+        let mut targets = Vec::new();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, l)) if !target.is_empty() => {
+                    targets.push((target.to_string(), l.parse()?))
+                }
+                Some(_) => return Err(format!("missing target in {directive:?}")),
+                None => level = directive.parse()?,
+            }
+        }
+        Ok(Self::new(level, targets))
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.level.as_str())?;
+        for (target, level) in &self.targets {
+            write!(f, ",{target}={}", level.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// What a line is about, set through the `key = value` part of the macros.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fields<'a> {
+    pub server_id: Option<&'a str>,
+    pub channel: Option<&'a str>,
+    pub user: Option<&'a str>,
+    pub peer_id: Option<u32>,
+    pub addr: Option<SocketAddr>,
+}
+
+impl<'a> Fields<'a> {
+    pub fn server_id(mut self, server_id: &'a str) -> Self {
+        self.server_id = Some(server_id);
+        self
+    }
+
+    pub fn channel(mut self, channel: &'a str) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub fn user(mut self, user: &'a str) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    pub fn peer_id(mut self, peer_id: u32) -> Self {
+        self.peer_id = Some(peer_id);
+        self
+    }
+
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static FILTER: RwLock<LogFilter> = RwLock::new(LogFilter {
+    level: LogLevel::Info,
+    targets: Vec::new(),
+});
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_filter(filter: LogFilter) {
+    let mut current = FILTER.write().unwrap_or_else(PoisonError::into_inner);
+    MAX_LEVEL.store(filter.max() as u8, Ordering::Relaxed);
+    *current = filter;
+}
+
+pub fn filter() -> LogFilter {
+    FILTER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+// a module_path!() without the crate name, other targets as they are
+fn target(target: &str) -> &str {
+    target.strip_prefix("od_nat_piercer::").unwrap_or(target)
+}
+
+pub fn enabled(level: LogLevel, target: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    let filter = FILTER.read().unwrap_or_else(PoisonError::into_inner);
+    level <= filter.level_for(self::target(target))
+}
+
+pub fn write(level: LogLevel, target: &str, fields: &Fields, args: fmt::Arguments) {
+    let format = if JSON.load(Ordering::Relaxed) {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    };
+    let line = format_line(format, level, self::target(target), fields, args);
+    match level {
+        LogLevel::Error | LogLevel::Warn => eprintln!("{line}"),
+        _ => println!("{line}"),
+    }
+}
+
+fn json_string(out: &mut String, s: &str) {
+    let _ = write!(out, "{}", serde_json::Value::from(s));
+}
+
+fn format_line(
+    format: LogFormat,
+    level: LogLevel,
+    target: &str,
+    fields: &Fields,
+    args: fmt::Arguments,
+) -> String {
+    let mut out = String::new();
+    let strings = [
+        ("server_id", fields.server_id),
+        ("channel", fields.channel),
+        ("user", fields.user),
+    ];
+    match format {
+        LogFormat::Text => {
+            let level = level.as_str().to_uppercase();
+            let _ = write!(out, "{level:<5} {target}: {args}");
+            for (key, value) in strings.iter().filter_map(|(k, v)| Some((k, (*v)?))) {
+                let _ = write!(out, " {key}={value:?}");
+            }
+            if let Some(peer_id) = fields.peer_id {
+                let _ = write!(out, " peer_id={peer_id}");
+            }
+            if let Some(addr) = fields.addr {
+                let _ = write!(out, " addr={addr}");
+            }
+        }
+        LogFormat::Json => {
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let _ = write!(
+                out,
+                "{{\"ts\":{}.{:03},\"level\":\"{}\",\"target\":",
+                ts / 1000,
+                ts % 1000,
+                level.as_str()
+            );
+            json_string(&mut out, target);
+            out.push_str(",\"message\":");
+            json_string(&mut out, &args.to_string());
+            for (key, value) in strings.iter().filter_map(|(k, v)| Some((k, (*v)?))) {
+                let _ = write!(out, ",\"{key}\":");
+                json_string(&mut out, value);
+            }
+            if let Some(peer_id) = fields.peer_id {
+                let _ = write!(out, ",\"peer_id\":{peer_id}");
+            }
+            if let Some(addr) = fields.addr {
+                let _ = write!(out, ",\"addr\":\"{addr}\"");
+            }
+            out.push('}');
+        }
+    }
+    out
+}
+
+/// `log!(Warn, channel = name, addr = src; "...", args)` prints if the filter
+/// allows the level for the target; the fields before `;` are optional. The
+/// target is the calling module unless given first, as in
+/// `log!(target: "signaling::turn", Debug, "...")`.
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:ident, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if $crate::log::enabled($crate::log::LogLevel::$level, $target) {
+            $crate::log::write(
+                $crate::log::LogLevel::$level,
+                $target,
+                &$crate::log::Fields::default()$(.$key($value))+,
+                format_args!($($arg)+),
+            );
+        }
+    };
+    (target: $target:expr, $level:ident, $($arg:tt)+) => {
+        if $crate::log::enabled($crate::log::LogLevel::$level, $target) {
+            $crate::log::write(
+                $crate::log::LogLevel::$level,
+                $target,
+                &$crate::log::Fields::default(),
+                format_args!($($arg)+),
+            );
+        }
+    };
+    ($level:ident, $($arg:tt)+) => {
+        $crate::log!(target: module_path!(), $level, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_picks_the_longest_matching_target() {
+        let filter: LogFilter = "warn, client=info ,client::networking=trace"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level_for("signaling::turn"), LogLevel::Warn);
+        assert_eq!(filter.level_for("client::handlers"), LogLevel::Info);
+        assert_eq!(filter.level_for("client::networking"), LogLevel::Trace);
+        // a prefix of the name is not a parent module
+        assert_eq!(filter.level_for("clients"), LogLevel::Warn);
+        assert_eq!(filter.max(), LogLevel::Trace);
+        assert_eq!(
+            filter.to_string(),
+            "warn,client::networking=trace,client=info"
+        );
+        assert_eq!(filter.to_string().parse::<LogFilter>().unwrap(), filter);
+
+        assert!("loud".parse::<LogFilter>().is_err());
+        assert!("info,=debug".parse::<LogFilter>().is_err());
+        assert!("info,client=loud".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn lines_carry_their_fields() {
+        let addr: SocketAddr = "198.51.100.7:2131".parse().unwrap();
+        let fields = Fields::default()
+            .server_id("srv")
+            .channel("gen\"eral")
+            .peer_id(3)
+            .addr(addr);
+        let text = format_line(
+            LogFormat::Text,
+            LogLevel::Info,
+            "signaling::admin",
+            &fields,
+            format_args!("kicked {}", "bob"),
+        );
+        assert_eq!(
+            text,
+            "INFO  signaling::admin: kicked bob server_id=\"srv\" channel=\"gen\\\"eral\" \
+             peer_id=3 addr=198.51.100.7:2131"
+        );
+
+        let json = format_line(
+            LogFormat::Json,
+            LogLevel::Warn,
+            "signaling::admin",
+            &fields,
+            format_args!("line\nbreak"),
+        );
+        let (_, rest) = json.split_once(",\"level\"").unwrap();
+        assert_eq!(
+            rest,
+            ":\"warn\",\"target\":\"signaling::admin\",\"message\":\"line\\nbreak\",\
+             \"server_id\":\"srv\",\"channel\":\"gen\\\"eral\",\"peer_id\":3,\
+             \"addr\":\"198.51.100.7:2131\"}"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+// Sockets bound to `::` serve both families: IPv4 peers show up as
+// v4-mapped addresses (::ffff:a.b.c.d). Everything above this module only
+// ever sees plain IPv4 addresses, the mapping happens on the way in and out.
+
+/// Binds a UDP socket to `ip`; the unspecified IPv6 address gives a
+/// dual-stack socket.
+pub async fn bind(ip: IpAddr, port: u16) -> io::Result<UdpSocket> {
+    let addr = SocketAddr::new(ip, port);
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// A dual-stack socket on `port`, or an IPv4 one where the host has no IPv6.
+pub async fn bind_any(port: u16) -> io::Result<UdpSocket> {
+    match bind(Ipv6Addr::UNSPECIFIED.into(), port).await {
+        Ok(socket) => Ok(socket),
+        Err(_) => bind([0, 0, 0, 0].into(), port).await,
+    }
+}
+
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Whether `socket` can send to `dst` at all.
+pub fn can_reach(socket: &UdpSocket, dst: SocketAddr) -> bool {
+    match socket.local_addr() {
+        Ok(local) => local.is_ipv6() || dst.is_ipv4(),
+        Err(_) => false,
+    }
+}
+
+// what `dst` has to look like for the family `socket` was bound with
+fn wire_addr(socket: &UdpSocket, dst: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), dst.ip()) {
+        (Ok(local), IpAddr::V4(ip)) if local.is_ipv6() => {
+            SocketAddr::new(ip.to_ipv6_mapped().into(), dst.port())
+        }
+        _ => dst,
+    }
+}
+
+pub async fn send_to(socket: &UdpSocket, buf: &[u8], dst: SocketAddr) -> io::Result<usize> {
+    if !can_reach(socket, dst) {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no IPv6 on this socket for {dst}"),
+        ));
+    }
+    socket.send_to(buf, wire_addr(socket, dst)).await
+}
+
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    let (len, src) = socket.recv_from(buf).await?;
+    Ok((len, canonical(src)))
+}
+
+/// Resolves `host` (a name, an IPv4 or IPv6 literal, bracketed or not) to
+/// an address `socket` can reach.
+pub async fn resolve(socket: &UdpSocket, host: &str, port: u16) -> Option<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    tokio::net::lookup_host((host, port))
+        .await
+        .ok()?
+        .map(canonical)
+        .find(|addr| can_reach(socket, *addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_addresses_become_plain_ipv4() {
+        let mapped: SocketAddr = "[::ffff:198.51.100.7]:2131".parse().unwrap();
+        assert_eq!(canonical(mapped), "198.51.100.7:2131".parse().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:2131".parse().unwrap();
+        assert_eq!(canonical(v6), v6);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_socket_talks_to_both_families() {
+        let server = bind_any(0).await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let mut buf = [0u8; 16];
+
+        for (client_ip, server_ip) in [("127.0.0.1", "127.0.0.1"), ("::1", "::1")] {
+            let client = bind(client_ip.parse().unwrap(), 0).await.unwrap();
+            let server_addr = SocketAddr::new(server_ip.parse().unwrap(), port);
+            send_to(&client, b"ping", server_addr).await.unwrap();
+
+            let (len, src) = recv_from(&server, &mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"ping");
+            assert_eq!(src, client.local_addr().unwrap());
+
+            send_to(&server, b"pong", src).await.unwrap();
+            let (len, _) = recv_from(&client, &mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"pong");
+        }
+
+        let v4_only = bind([127, 0, 0, 1].into(), 0).await.unwrap();
+        assert!(!can_reach(&v4_only, "[::1]:2131".parse().unwrap()));
+        assert!(
+            send_to(&v4_only, b"x", "[::1]:2131".parse().unwrap())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn hosts_resolve_with_or_without_brackets() {
+        let socket = bind_any(0).await.unwrap();
+        for host in ["::1", "[::1]"] {
+            assert_eq!(
+                resolve(&socket, host, 2131).await,
+                Some("[::1]:2131".parse().unwrap())
+            );
+        }
+        assert_eq!(
+            resolve(&socket, "127.0.0.1", 2131).await,
+            Some("127.0.0.1:2131".parse().unwrap())
+        );
+
+        let v4_only = bind([0, 0, 0, 0].into(), 0).await.unwrap();
+        assert_eq!(resolve(&v4_only, "::1", 2131).await, None);
+    }
+}
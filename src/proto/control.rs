@@ -0,0 +1,1092 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::proto::packet::{self, Header, Kind};
+
+pub const CONTROL_VERSION: u8 = 2;
+
+const TAG_CONNECT: u8 = 1;
+const TAG_DISCONNECT: u8 = 2;
+const TAG_HEARTBEAT: u8 = 3;
+const TAG_PEER_TIMEOUT: u8 = 4;
+const TAG_REQUEST_RELAY: u8 = 5;
+const TAG_NAT_PROBE: u8 = 6;
+const TAG_NAT_SEEN: u8 = 7;
+const TAG_WELCOME: u8 = 8;
+const TAG_MODE_RELAY: u8 = 9;
+const TAG_MODE_DIRECT: u8 = 10;
+const TAG_MODE_SERVER_RELAY: u8 = 11;
+const TAG_USER_LEFT: u8 = 12;
+const TAG_DATA: u8 = 13;
+const TAG_PING: u8 = 14;
+const TAG_PONG: u8 = 15;
+const TAG_HOLE_PUNCH: u8 = 16;
+const TAG_REJECTED: u8 = 17;
+const TAG_CHECK: u8 = 18;
+const TAG_CHECK_OK: u8 = 19;
+const TAG_RESUME: u8 = 20;
+const TAG_MODE_PEER: u8 = 21;
+
+// NAT_PROBE flags: ask the server to answer from its other port and/or address
+pub const PROBE_CHANGE_PORT: u8 = 0x01;
+pub const PROBE_CHANGE_IP: u8 = 0x02;
+
+const HINT_NONE: u8 = 0;
+const HINT_DELTA: u8 = 1;
+const HINT_RANDOM: u8 = 2;
+
+/// Candidates one message carries per peer; `client::ice` gathers no more.
+pub const MAX_CANDIDATES: usize = 16;
+
+/// Length of the MAC on CHECK and CHECK_OK, see `client::ice::check_tag`.
+pub const CHECK_TAG_LEN: usize = 16;
+
+/// A field, or a whole message, longer than the wire allows; it is refused
+/// rather than cut short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TooLong {
+    /// Bytes that do not fit a u16 length.
+    Bytes(usize),
+    /// More than `MAX_CANDIDATES` candidates.
+    Candidates(usize),
+}
+
+impl std::fmt::Display for TooLong {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TooLong::Bytes(n) => write!(f, "{n} bytes do not fit a u16 length"),
+            TooLong::Candidates(n) => write!(f, "{n} candidates, at most {MAX_CANDIDATES} fit"),
+        }
+    }
+}
+
+impl std::error::Error for TooLong {}
+
+const ADDR_NONE: u8 = 0;
+const ADDR_V4: u8 = 4;
+const ADDR_V6: u8 = 6;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatKind {
+    Unknown = 0,
+    Public = 1,
+    Cone = 2, // endpoint-independent mapping and filtering
+    Symmetric = 3,
+    RestrictedCone = 4, // endpoint-independent mapping, address-dependent filtering
+    PortRestrictedCone = 5, // endpoint-independent mapping, address and port-dependent filtering
+}
+
+impl NatKind {
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => NatKind::Public,
+            2 => NatKind::Cone,
+            3 => NatKind::Symmetric,
+            4 => NatKind::RestrictedCone,
+            5 => NatKind::PortRestrictedCone,
+            _ => NatKind::Unknown,
+        }
+    }
+}
+
+/// How peers can reach a symmetric NAT whose mapping changes per destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PortHint {
+    /// Not punchable, use the server relay.
+    #[default]
+    None,
+    /// New mappings are allocated `delta` ports apart: punch the predicted ports.
+    Delta(i32),
+    /// Unpredictable ports: the peer opens many mappings, punch random ports (birthday).
+    Random,
+}
+
+/// Where a candidate address comes from (RFC 8445 section 5.1.1).
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CandidateKind {
+    Host = 0,            // an address of one of the peer's interfaces
+    ServerReflexive = 1, // the peer's public mapping, as the signaling server saw it
+    PeerReflexive = 2,   // a mapping learnt from a connectivity check
+    Relay = 3,           // the signaling server, relaying by peer id
+}
+
+impl CandidateKind {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(CandidateKind::Host),
+            1 => Some(CandidateKind::ServerReflexive),
+            2 => Some(CandidateKind::PeerReflexive),
+            3 => Some(CandidateKind::Relay),
+            _ => None,
+        }
+    }
+}
+
+/// One address a peer may be reachable at, see `client::ice`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: SocketAddr,
+    pub priority: u32,
+}
+
+/// One signaling exchange between a client, the signaling server or another peer.
+///
+/// Every variant is carried as the payload of a `Kind::Control` packet:
+/// `[CONTROL_VERSION][tag][fields...]`. Strings are length-prefixed UTF-8 and
+/// addresses carry their family, so names with spaces and IPv6 literals survive.
+/// Decoders ignore bytes after the known fields but need every field they know,
+/// so a message from an older sender is unreadable: adding fields bumps
+/// `CONTROL_VERSION`, and both ends must speak the same one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+    Connect {
+        server_id: String,
+        channel: String,
+        user: String,
+        nat_kind: NatKind,
+        token: Vec<u8>, // proto::token, empty when the server runs without a secret
+        port_hint: PortHint,
+        fingerprint: Vec<u8>, // SHA-256 of the DTLS certificate, empty without DTLS
+        candidates: Vec<Candidate>, // gathered by the client, forwarded in MODE DIRECT
+        check_key: Vec<u8>, // peers MAC connectivity checks to us with it, forwarded in MODE DIRECT
+    },
+    Disconnect {
+        server_id: String,
+        channel: String,
+        user: String,
+    },
+    // `nonce` echoes the one handed out in WELCOME
+    Heartbeat {
+        server_id: String,
+        channel: String,
+        user: String,
+        nonce: u64,
+    },
+    PeerTimeout {
+        server_id: String,
+        channel: String,
+        user: String,
+        peer_id: u32,
+        nonce: u64,
+    },
+    RequestRelay {
+        server_id: String,
+        channel: String,
+        user: String,
+        peer_id: u32,
+        nonce: u64,
+    },
+    NatProbe {
+        seq: u8,
+        flags: u8, // PROBE_CHANGE_*
+    },
+    // `other` is the server's alternate address (RFC 5780 OTHER-ADDRESS), if it has one
+    NatSeen {
+        addr: SocketAddr,
+        seq: u8,
+        other: Option<SocketAddr>,
+    },
+    Welcome {
+        channel_id: u64,
+        peer_id: u32,
+        nonce: u64,
+    },
+    ModeRelay,
+    // server -> a relay another user replaced: stop forwarding for the channel
+    ModePeer,
+    ModeDirect {
+        user: String,
+        addr: SocketAddr,
+        peer_id: u32,
+        port_hint: PortHint,
+        fingerprint: Vec<u8>,
+        candidates: Vec<Candidate>,
+        check_key: Vec<u8>,
+    },
+    ModeServerRelay {
+        user: String,
+        peer_id: u32,
+        fingerprint: Vec<u8>,
+    },
+    UserLeft {
+        user: String,
+        addr: Option<SocketAddr>,
+        peer_id: u32,
+    },
+    Data {
+        sender: String,
+        payload: Vec<u8>,
+    },
+    Ping,
+    Pong,
+    HolePunch,
+    // server -> client: CONNECT refused, e.g. a bad or expired token
+    Rejected {
+        reason: String,
+    },
+    // peer <-> peer connectivity check on one candidate pair, answered with CHECK_OK;
+    // both carry a MAC under the check key of the peer answering
+    Check {
+        txn: u64,
+        tag: [u8; CHECK_TAG_LEN],
+    },
+    CheckOk {
+        txn: u64,
+        tag: [u8; CHECK_TAG_LEN],
+    },
+    // client -> server: take back a session the server kept across a restart or
+    // that moved to a new address; `nonce` is the one from WELCOME
+    Resume {
+        channel_id: u64,
+        peer_id: u32,
+        nonce: u64,
+    },
+}
+
+impl ControlMessage {
+    /// Short upper-case name used in logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlMessage::Connect { .. } => "CONNECT",
+            ControlMessage::Disconnect { .. } => "DISCONNECT",
+            ControlMessage::Heartbeat { .. } => "HB",
+            ControlMessage::PeerTimeout { .. } => "PEER_TIMEOUT",
+            ControlMessage::RequestRelay { .. } => "REQUEST_RELAY",
+            ControlMessage::NatProbe { .. } => "NAT_PROBE",
+            ControlMessage::NatSeen { .. } => "NAT_SEEN",
+            ControlMessage::Welcome { .. } => "WELCOME",
+            ControlMessage::ModeRelay => "MODE RELAY",
+            ControlMessage::ModePeer => "MODE PEER",
+            ControlMessage::ModeDirect { .. } => "MODE DIRECT",
+            ControlMessage::ModeServerRelay { .. } => "MODE SERVER_RELAY",
+            ControlMessage::UserLeft { .. } => "USER_LEFT",
+            ControlMessage::Data { .. } => "DATA",
+            ControlMessage::Ping => "PING",
+            ControlMessage::Pong => "PONG",
+            ControlMessage::HolePunch => "HOLE_PUNCH",
+            ControlMessage::Rejected { .. } => "REJECTED",
+            ControlMessage::Check { .. } => "CHECK",
+            ControlMessage::CheckOk { .. } => "CHECK_OK",
+            ControlMessage::Resume { .. } => "RESUME",
+        }
+    }
+
+    pub fn is_mode(&self) -> bool {
+        matches!(
+            self,
+            ControlMessage::ModeRelay
+                | ControlMessage::ModePeer
+                | ControlMessage::ModeDirect { .. }
+                | ControlMessage::ModeServerRelay { .. }
+        )
+    }
+
+    /// Fails on a field longer than its u16 length prefix allows.
+    pub fn encode(&self) -> Result<Vec<u8>, TooLong> {
+        let mut w = Writer::default();
+        w.u8(CONTROL_VERSION);
+
+        match self {
+            ControlMessage::Connect {
+                server_id,
+                channel,
+                user,
+                nat_kind,
+                token,
+                port_hint,
+                fingerprint,
+                candidates,
+                check_key,
+            } => {
+                w.u8(TAG_CONNECT);
+                w.session(server_id, channel, user)?;
+                w.u8(*nat_kind as u8);
+                w.bytes(token)?;
+                w.port_hint(*port_hint);
+                w.bytes(fingerprint)?;
+                w.candidates(candidates)?;
+                w.bytes(check_key)?;
+            }
+            ControlMessage::Disconnect {
+                server_id,
+                channel,
+                user,
+            } => {
+                w.u8(TAG_DISCONNECT);
+                w.session(server_id, channel, user)?;
+            }
+            ControlMessage::Heartbeat {
+                server_id,
+                channel,
+                user,
+                nonce,
+            } => {
+                w.u8(TAG_HEARTBEAT);
+                w.session(server_id, channel, user)?;
+                w.u64(*nonce);
+            }
+            ControlMessage::PeerTimeout {
+                server_id,
+                channel,
+                user,
+                peer_id,
+                nonce,
+            } => {
+                w.u8(TAG_PEER_TIMEOUT);
+                w.session(server_id, channel, user)?;
+                w.u32(*peer_id);
+                w.u64(*nonce);
+            }
+            ControlMessage::RequestRelay {
+                server_id,
+                channel,
+                user,
+                peer_id,
+                nonce,
+            } => {
+                w.u8(TAG_REQUEST_RELAY);
+                w.session(server_id, channel, user)?;
+                w.u32(*peer_id);
+                w.u64(*nonce);
+            }
+            ControlMessage::NatProbe { seq, flags } => {
+                w.u8(TAG_NAT_PROBE);
+                w.u8(*seq);
+                w.u8(*flags);
+            }
+            ControlMessage::NatSeen { addr, seq, other } => {
+                w.u8(TAG_NAT_SEEN);
+                w.addr(Some(*addr));
+                w.u8(*seq);
+                w.addr(*other);
+            }
+            ControlMessage::Welcome {
+                channel_id,
+                peer_id,
+                nonce,
+            } => {
+                w.u8(TAG_WELCOME);
+                w.u64(*channel_id);
+                w.u32(*peer_id);
+                w.u64(*nonce);
+            }
+            ControlMessage::ModeRelay => w.u8(TAG_MODE_RELAY),
+            ControlMessage::ModePeer => w.u8(TAG_MODE_PEER),
+            ControlMessage::ModeDirect {
+                user,
+                addr,
+                peer_id,
+                port_hint,
+                fingerprint,
+                candidates,
+                check_key,
+            } => {
+                w.u8(TAG_MODE_DIRECT);
+                w.str(user)?;
+                w.addr(Some(*addr));
+                w.u32(*peer_id);
+                w.port_hint(*port_hint);
+                w.bytes(fingerprint)?;
+                w.candidates(candidates)?;
+                w.bytes(check_key)?;
+            }
+            ControlMessage::ModeServerRelay {
+                user,
+                peer_id,
+                fingerprint,
+            } => {
+                w.u8(TAG_MODE_SERVER_RELAY);
+                w.str(user)?;
+                w.u32(*peer_id);
+                w.bytes(fingerprint)?;
+            }
+            ControlMessage::UserLeft {
+                user,
+                addr,
+                peer_id,
+            } => {
+                w.u8(TAG_USER_LEFT);
+                w.str(user)?;
+                w.addr(*addr);
+                w.u32(*peer_id);
+            }
+            ControlMessage::Data { sender, payload } => {
+                w.u8(TAG_DATA);
+                w.str(sender)?;
+                w.bytes(payload)?;
+            }
+            ControlMessage::Ping => w.u8(TAG_PING),
+            ControlMessage::Pong => w.u8(TAG_PONG),
+            ControlMessage::HolePunch => w.u8(TAG_HOLE_PUNCH),
+            ControlMessage::Rejected { reason } => {
+                w.u8(TAG_REJECTED);
+                w.str(reason)?;
+            }
+            ControlMessage::Check { txn, tag } => {
+                w.u8(TAG_CHECK);
+                w.u64(*txn);
+                w.buf.extend_from_slice(tag);
+            }
+            ControlMessage::CheckOk { txn, tag } => {
+                w.u8(TAG_CHECK_OK);
+                w.u64(*txn);
+                w.buf.extend_from_slice(tag);
+            }
+            ControlMessage::Resume {
+                channel_id,
+                peer_id,
+                nonce,
+            } => {
+                w.u8(TAG_RESUME);
+                w.u64(*channel_id);
+                w.u32(*peer_id);
+                w.u64(*nonce);
+            }
+        }
+
+        Ok(w.buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader { buf, pos: 0 };
+        if r.u8()? != CONTROL_VERSION {
+            return None;
+        }
+
+        let msg = match r.u8()? {
+            TAG_CONNECT => {
+                let (server_id, channel, user) = r.session()?;
+                ControlMessage::Connect {
+                    server_id,
+                    channel,
+                    user,
+                    nat_kind: NatKind::from_u8(r.u8()?),
+                    token: r.bytes()?.to_vec(),
+                    port_hint: r.port_hint()?,
+                    fingerprint: r.bytes()?.to_vec(),
+                    candidates: r.candidates()?,
+                    check_key: r.bytes()?.to_vec(),
+                }
+            }
+            TAG_DISCONNECT => {
+                let (server_id, channel, user) = r.session()?;
+                ControlMessage::Disconnect {
+                    server_id,
+                    channel,
+                    user,
+                }
+            }
+            TAG_HEARTBEAT => {
+                let (server_id, channel, user) = r.session()?;
+                ControlMessage::Heartbeat {
+                    server_id,
+                    channel,
+                    user,
+                    nonce: r.u64()?,
+                }
+            }
+            TAG_PEER_TIMEOUT => {
+                let (server_id, channel, user) = r.session()?;
+                ControlMessage::PeerTimeout {
+                    server_id,
+                    channel,
+                    user,
+                    peer_id: r.u32()?,
+                    nonce: r.u64()?,
+                }
+            }
+            TAG_REQUEST_RELAY => {
+                let (server_id, channel, user) = r.session()?;
+                ControlMessage::RequestRelay {
+                    server_id,
+                    channel,
+                    user,
+                    peer_id: r.u32()?,
+                    nonce: r.u64()?,
+                }
+            }
+            TAG_NAT_PROBE => ControlMessage::NatProbe {
+                seq: r.u8()?,
+                flags: r.u8()?,
+            },
+            TAG_NAT_SEEN => ControlMessage::NatSeen {
+                addr: r.addr()??,
+                seq: r.u8()?,
+                other: r.addr()?,
+            },
+            TAG_WELCOME => ControlMessage::Welcome {
+                channel_id: r.u64()?,
+                peer_id: r.u32()?,
+                nonce: r.u64()?,
+            },
+            TAG_MODE_RELAY => ControlMessage::ModeRelay,
+            TAG_MODE_PEER => ControlMessage::ModePeer,
+            TAG_MODE_DIRECT => ControlMessage::ModeDirect {
+                user: r.str()?,
+                addr: r.addr()??,
+                peer_id: r.u32()?,
+                port_hint: r.port_hint()?,
+                fingerprint: r.bytes()?.to_vec(),
+                candidates: r.candidates()?,
+                check_key: r.bytes()?.to_vec(),
+            },
+            TAG_MODE_SERVER_RELAY => ControlMessage::ModeServerRelay {
+                user: r.str()?,
+                peer_id: r.u32()?,
+                fingerprint: r.bytes()?.to_vec(),
+            },
+            TAG_USER_LEFT => ControlMessage::UserLeft {
+                user: r.str()?,
+                addr: r.addr()?,
+                peer_id: r.u32()?,
+            },
+            TAG_DATA => ControlMessage::Data {
+                sender: r.str()?,
+                payload: r.bytes()?.to_vec(),
+            },
+            TAG_PING => ControlMessage::Ping,
+            TAG_PONG => ControlMessage::Pong,
+            TAG_HOLE_PUNCH => ControlMessage::HolePunch,
+            TAG_REJECTED => ControlMessage::Rejected { reason: r.str()? },
+            TAG_CHECK => ControlMessage::Check {
+                txn: r.u64()?,
+                tag: r.take(CHECK_TAG_LEN)?.try_into().ok()?,
+            },
+            TAG_CHECK_OK => ControlMessage::CheckOk {
+                txn: r.u64()?,
+                tag: r.take(CHECK_TAG_LEN)?.try_into().ok()?,
+            },
+            TAG_RESUME => ControlMessage::Resume {
+                channel_id: r.u64()?,
+                peer_id: r.u32()?,
+                nonce: r.u64()?,
+            },
+            _ => return None,
+        };
+
+        Some(msg)
+    }
+}
+
+/// Wraps `msg` in a `Kind::Control` packet ready to be sent on the wire.
+pub fn encode_packet(
+    channel_id: u64,
+    src_peer_id: u32,
+    dst_peer_id: u32,
+    msg: &ControlMessage,
+) -> Result<Vec<u8>, TooLong> {
+    let payload = msg.encode()?;
+    let len = payload
+        .len()
+        .try_into()
+        .map_err(|_| TooLong::Bytes(payload.len()))?;
+    let hdr = Header::control(channel_id, src_peer_id, dst_peer_id, len);
+    Ok(packet::encode(hdr, &payload))
+}
+
+/// Decodes a datagram as a `Kind::Control` packet carrying a `ControlMessage`.
+pub fn decode_packet(buf: &[u8]) -> Option<(Header, ControlMessage)> {
+    let (hdr, payload) = packet::decode(buf)?;
+    if hdr.kind != Kind::Control {
+        return None;
+    }
+    Some((hdr, ControlMessage::decode(payload)?))
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> Result<(), TooLong> {
+        let len: u16 = v.len().try_into().map_err(|_| TooLong::Bytes(v.len()))?;
+        self.u16(len);
+        self.buf.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn str(&mut self, v: &str) -> Result<(), TooLong> {
+        self.bytes(v.as_bytes())
+    }
+
+    fn session(&mut self, server_id: &str, channel: &str, user: &str) -> Result<(), TooLong> {
+        self.str(server_id)?;
+        self.str(channel)?;
+        self.str(user)
+    }
+
+    fn port_hint(&mut self, hint: PortHint) {
+        match hint {
+            PortHint::None => self.u8(HINT_NONE),
+            PortHint::Delta(d) => {
+                self.u8(HINT_DELTA);
+                self.u32(d as u32);
+            }
+            PortHint::Random => self.u8(HINT_RANDOM),
+        }
+    }
+
+    fn addr(&mut self, addr: Option<SocketAddr>) {
+        match addr {
+            None => self.u8(ADDR_NONE),
+            Some(SocketAddr::V4(a)) => {
+                self.u8(ADDR_V4);
+                self.buf.extend_from_slice(&a.ip().octets());
+                self.u16(a.port());
+            }
+            Some(SocketAddr::V6(a)) => {
+                self.u8(ADDR_V6);
+                self.buf.extend_from_slice(&a.ip().octets());
+                self.u16(a.port());
+            }
+        }
+    }
+
+    fn candidates(&mut self, candidates: &[Candidate]) -> Result<(), TooLong> {
+        if candidates.len() > MAX_CANDIDATES {
+            return Err(TooLong::Candidates(candidates.len()));
+        }
+        self.u8(candidates.len() as u8);
+        for c in candidates {
+            self.u8(c.kind as u8);
+            self.u32(c.priority);
+            self.addr(Some(c.addr));
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let out = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(out)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Option<String> {
+        std::str::from_utf8(self.bytes()?).ok().map(str::to_string)
+    }
+
+    fn session(&mut self) -> Option<(String, String, String)> {
+        Some((self.str()?, self.str()?, self.str()?))
+    }
+
+    fn port_hint(&mut self) -> Option<PortHint> {
+        Some(match self.u8()? {
+            HINT_NONE => PortHint::None,
+            HINT_DELTA => PortHint::Delta(self.u32()? as i32),
+            HINT_RANDOM => PortHint::Random,
+            _ => return None,
+        })
+    }
+
+    // Outer Option: malformed input, inner Option: no address was sent.
+    fn addr(&mut self) -> Option<Option<SocketAddr>> {
+        let ip = match self.u8()? {
+            ADDR_NONE => return Some(None),
+            ADDR_V4 => {
+                let o: [u8; 4] = self.take(4)?.try_into().ok()?;
+                IpAddr::V4(Ipv4Addr::from(o))
+            }
+            ADDR_V6 => {
+                let o: [u8; 16] = self.take(16)?.try_into().ok()?;
+                IpAddr::V6(Ipv6Addr::from(o))
+            }
+            _ => return None,
+        };
+        Some(Some(SocketAddr::new(ip, self.u16()?)))
+    }
+
+    fn candidates(&mut self) -> Option<Vec<Candidate>> {
+        let count = self.u8()? as usize;
+        if count > MAX_CANDIDATES {
+            return None;
+        }
+        let mut out = Vec::with_capacity(count);
+        for _ in 0..count {
+            out.push(Candidate {
+                kind: CandidateKind::from_u8(self.u8()?)?,
+                priority: self.u32()?,
+                addr: self.addr()??,
+            });
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::packet::BROADCAST;
+
+    fn roundtrip(msg: ControlMessage) {
+        let buf = msg.encode().unwrap();
+        assert_eq!(ControlMessage::decode(&buf), Some(msg));
+    }
+
+    #[test]
+    fn every_variant_roundtrips() {
+        let v4: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:2131".parse().unwrap();
+
+        roundtrip(ControlMessage::Connect {
+            server_id: "s1".into(),
+            channel: "general".into(),
+            user: "name".into(),
+            nat_kind: NatKind::Symmetric,
+            token: vec![1; 40],
+            port_hint: PortHint::Delta(-2),
+            fingerprint: vec![2; 32],
+            candidates: vec![
+                Candidate {
+                    kind: CandidateKind::Host,
+                    addr: "192.168.1.20:40000".parse().unwrap(),
+                    priority: 2_130_706_431,
+                },
+                Candidate {
+                    kind: CandidateKind::Relay,
+                    addr: v6,
+                    priority: 255,
+                },
+            ],
+            check_key: vec![4; 16],
+        });
+        roundtrip(ControlMessage::Disconnect {
+            server_id: "s1".into(),
+            channel: "general".into(),
+            user: "name".into(),
+        });
+        roundtrip(ControlMessage::Heartbeat {
+            server_id: "s1".into(),
+            channel: "general".into(),
+            user: "name".into(),
+            nonce: 0xDEAD_BEEF,
+        });
+        roundtrip(ControlMessage::PeerTimeout {
+            server_id: "s1".into(),
+            channel: "general".into(),
+            user: "name".into(),
+            peer_id: 5,
+            nonce: 1,
+        });
+        roundtrip(ControlMessage::RequestRelay {
+            server_id: "s1".into(),
+            channel: "general".into(),
+            user: "name".into(),
+            peer_id: 6,
+            nonce: u64::MAX,
+        });
+        roundtrip(ControlMessage::NatProbe {
+            seq: 2,
+            flags: PROBE_CHANGE_IP | PROBE_CHANGE_PORT,
+        });
+        roundtrip(ControlMessage::NatSeen {
+            addr: v6,
+            seq: 2,
+            other: None,
+        });
+        roundtrip(ControlMessage::NatSeen {
+            addr: v4,
+            seq: 3,
+            other: Some(v4),
+        });
+        roundtrip(ControlMessage::Welcome {
+            channel_id: u64::MAX - 1,
+            peer_id: 42,
+            nonce: 99,
+        });
+        roundtrip(ControlMessage::ModeRelay);
+        roundtrip(ControlMessage::ModePeer);
+        roundtrip(ControlMessage::ModeDirect {
+            user: "name".into(),
+            addr: v4,
+            peer_id: 7,
+            port_hint: PortHint::Random,
+            fingerprint: vec![3; 32],
+            candidates: vec![Candidate {
+                kind: CandidateKind::ServerReflexive,
+                addr: v4,
+                priority: 1_694_498_815,
+            }],
+            check_key: vec![5; 16],
+        });
+        roundtrip(ControlMessage::ModeServerRelay {
+            user: "name".into(),
+            peer_id: 7,
+            fingerprint: Vec::new(),
+        });
+        roundtrip(ControlMessage::UserLeft {
+            user: "name".into(),
+            addr: None,
+            peer_id: 7,
+        });
+        roundtrip(ControlMessage::UserLeft {
+            user: "name".into(),
+            addr: Some(v6),
+            peer_id: u32::MAX - 1,
+        });
+        roundtrip(ControlMessage::Data {
+            sender: "name".into(),
+            payload: vec![0, 159, 146, 150, 255],
+        });
+        roundtrip(ControlMessage::Ping);
+        roundtrip(ControlMessage::Pong);
+        roundtrip(ControlMessage::HolePunch);
+        roundtrip(ControlMessage::Rejected {
+            reason: "token expired".into(),
+        });
+        roundtrip(ControlMessage::Check {
+            txn: 1,
+            tag: [6; CHECK_TAG_LEN],
+        });
+        roundtrip(ControlMessage::CheckOk {
+            txn: u64::MAX,
+            tag: [7; CHECK_TAG_LEN],
+        });
+        roundtrip(ControlMessage::Resume {
+            channel_id: u64::MAX,
+            peer_id: 3,
+            nonce: 0x0123_4567_89ab_cdef,
+        });
+    }
+
+    #[test]
+    fn ipv6_addresses_survive_in_every_message() {
+        let v6: SocketAddr = "[2001:db8:0:1::7]:40000".parse().unwrap();
+        let link_local: SocketAddr = "[fe80::1]:2131".parse().unwrap();
+
+        for addr in [v6, link_local] {
+            roundtrip(ControlMessage::NatSeen {
+                addr,
+                seq: 1,
+                other: Some(addr),
+            });
+            roundtrip(ControlMessage::ModeDirect {
+                user: "name".into(),
+                addr,
+                peer_id: 1,
+                port_hint: PortHint::Delta(1),
+                fingerprint: Vec::new(),
+                candidates: vec![Candidate {
+                    kind: CandidateKind::Host,
+                    addr,
+                    priority: 1,
+                }],
+                check_key: Vec::new(),
+            });
+            roundtrip(ControlMessage::UserLeft {
+                user: "name".into(),
+                addr: Some(addr),
+                peer_id: 1,
+            });
+        }
+    }
+
+    #[test]
+    fn usernames_with_spaces_survive() {
+        roundtrip(ControlMessage::ModeDirect {
+            user: "first  last ".into(),
+            addr: "10.0.0.1:4000".parse().unwrap(),
+            peer_id: 1,
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        });
+    }
+
+    #[test]
+    fn decode_rejects_bad_version() {
+        let mut buf = ControlMessage::Ping.encode().unwrap();
+        buf[0] = CONTROL_VERSION + 1;
+        assert!(ControlMessage::decode(&buf).is_none());
+    }
+
+    #[test]
+    fn decode_rejects_unknown_tag() {
+        assert!(ControlMessage::decode(&[CONTROL_VERSION, 0xEE]).is_none());
+    }
+
+    #[test]
+    fn decode_rejects_truncated_fields() {
+        let buf = ControlMessage::ModeDirect {
+            user: "name".into(),
+            addr: "[2001:db8::1]:2131".parse().unwrap(),
+            peer_id: 4,
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        }
+        .encode()
+        .unwrap();
+        for len in 0..buf.len() {
+            assert!(ControlMessage::decode(&buf[..len]).is_none(), "len {len}");
+        }
+    }
+
+    #[test]
+    fn encode_refuses_fields_longer_than_a_u16() {
+        let msg = ControlMessage::Data {
+            sender: "alice".into(),
+            payload: vec![0; 70_000],
+        };
+        assert_eq!(msg.encode(), Err(TooLong::Bytes(70_000)));
+        assert_eq!(
+            encode_packet(1, 2, BROADCAST, &msg),
+            Err(TooLong::Bytes(70_000))
+        );
+
+        let msg = ControlMessage::Data {
+            sender: "alice".into(),
+            payload: vec![0; u16::MAX as usize],
+        };
+        assert!(msg.encode().is_ok());
+    }
+
+    #[test]
+    fn encode_refuses_too_many_candidates() {
+        let connect = |count: usize| ControlMessage::Connect {
+            server_id: "s1".into(),
+            channel: "c1".into(),
+            user: "alice".into(),
+            nat_kind: NatKind::Cone,
+            token: Vec::new(),
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: (0..count)
+                .map(|i| Candidate {
+                    kind: CandidateKind::Host,
+                    addr: SocketAddr::new(Ipv4Addr::new(10, 0, 0, i as u8).into(), 4000),
+                    priority: i as u32,
+                })
+                .collect(),
+            check_key: Vec::new(),
+        };
+        roundtrip(connect(MAX_CANDIDATES));
+        assert_eq!(
+            connect(MAX_CANDIDATES + 1).encode(),
+            Err(TooLong::Candidates(MAX_CANDIDATES + 1))
+        );
+
+        // nor does a decoder take more than it would send: repeat the first
+        // candidate (kind, priority, v4 address) and count it
+        let mut buf = connect(MAX_CANDIDATES).encode().unwrap();
+        let count = buf.iter().position(|b| *b == MAX_CANDIDATES as u8).unwrap();
+        buf[count] += 1;
+        let first = buf[count + 1..count + 13].to_vec();
+        buf.splice(count + 1..count + 1, first);
+        assert_eq!(ControlMessage::decode(&buf), None);
+    }
+
+    #[test]
+    fn decode_ignores_trailing_fields() {
+        let mut buf = ControlMessage::Welcome {
+            channel_id: 1,
+            peer_id: 2,
+            nonce: 3,
+        }
+        .encode()
+        .unwrap();
+        buf.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(
+            ControlMessage::decode(&buf),
+            Some(ControlMessage::Welcome {
+                channel_id: 1,
+                peer_id: 2,
+                nonce: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn packet_roundtrip_keeps_header() {
+        let msg = ControlMessage::Welcome {
+            channel_id: 9,
+            peer_id: 3,
+            nonce: 4,
+        };
+        let buf = encode_packet(9, 0, BROADCAST, &msg).unwrap();
+        let (hdr, decoded) = decode_packet(&buf).expect("decode failed");
+        assert_eq!(hdr.kind, Kind::Control);
+        assert_eq!(hdr.channel_id, 9);
+        assert_eq!(hdr.dst_peer_id, BROADCAST);
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn decode_packet_rejects_plain_text() {
+        assert!(decode_packet(b"MODE DIRECT name 10.0.0.1:4000\n").is_none());
+    }
+
+    #[test]
+    fn same_name_peers_differ_by_peer_id() {
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let a = ControlMessage::ModeDirect {
+            user: "name".into(),
+            addr,
+            peer_id: 1,
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        };
+        let b = ControlMessage::ModeDirect {
+            user: "name".into(),
+            addr,
+            peer_id: 2,
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        };
+        assert_ne!(a.encode().unwrap(), b.encode().unwrap());
+        roundtrip(b);
+    }
+}
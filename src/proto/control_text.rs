@@ -1,26 +0,0 @@
-pub const MSG_WELCOME: &str = "WELCOME";
-pub const MSG_CONTROL: &str = "CONTROL";
-
-pub const MSG_CONNECT: &str = "CONNECT";
-pub const MSG_DISCONNECT: &str = "DISCONNECT";
-pub const MSG_HB: &str = "HB";
-pub const MSG_NAT_PROBE: &str = "NAT_PROBE";
-pub const MSG_NAT_SEEN: &str = "NAT_SEEN";
-pub const MSG_PEER_TIMEOUT: &str = "PEER_TIMEOUT";
-pub const MSG_REQUEST_RELAY: &str = "REQUEST_RELAY";
-pub const MSG_DATA: &str = "DATA";
-
-pub const NAT_TYPE_SYMMETRIC: &str = "SYMMETRIC";
-pub const NAT_TYPE_CONE: &str = "CONE";
-pub const NAT_TYPE_PUBLIC: &str = "PUBLIC";
-pub const NAT_TYPE_UNKNOWN: &str = "UNKNOWN";
-
-pub const MSG_MODE: &str = "MODE";
-pub const MSG_RELAY: &str = "RELAY";
-pub const MSG_DIRECT: &str = "DIRECT";
-pub const MSG_SERVER_RELAY: &str = "SERVER_RELAY";
-
-pub const MSG_USER_LEFT: &str = "USER_LEFT";
-pub const MSG_PING: &str = "PING";
-pub const MSG_PONG: &str = "PONG";
-pub const MSG_HOLE_PUNCH: &str = "HOLE_PUNCH";
@@ -1,2 +1,6 @@
-pub mod control_text;
+pub mod control;
+pub mod noise;
 pub mod packet;
+pub mod srtp;
+pub mod stun;
+pub mod token;
@@ -0,0 +1,405 @@
+use hmac::{Hmac, Mac};
+use openssl::{
+    derive::Deriver,
+    error::ErrorStack,
+    pkey::{Id, PKey, Private},
+    symm::{Cipher, decrypt_aead, encrypt_aead},
+};
+use sha2::{Digest, Sha256};
+
+use crate::proto::packet::{self, Header};
+
+// Noise_NK: the client knows the server's static key up front and proves
+// nothing about itself, the server proves it holds the pinned key.
+//   <- s
+//   ...
+//   -> e, es
+//   <- e, ee
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_NK_25519_ChaChaPoly_SHA256";
+
+pub const KEY_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+const REPLAY_WINDOW: u64 = 64;
+
+// first payload byte of a Kind::Noise packet
+pub const MSG_INIT: u8 = 1;
+pub const MSG_RESPONSE: u8 = 2;
+pub const MSG_TRANSPORT: u8 = 3;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The server's long-term X25519 key; clients pin `public()`.
+pub struct StaticKeypair {
+    private: PKey<Private>,
+    public: [u8; KEY_LEN],
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Result<Self, ErrorStack> {
+        Self::from_pkey(PKey::generate_x25519()?)
+    }
+
+    pub fn from_private(bytes: &[u8]) -> Result<Self, ErrorStack> {
+        Self::from_pkey(PKey::private_key_from_raw_bytes(bytes, Id::X25519)?)
+    }
+
+    fn from_pkey(private: PKey<Private>) -> Result<Self, ErrorStack> {
+        let mut public = [0u8; KEY_LEN];
+        public.copy_from_slice(&private.raw_public_key()?);
+        Ok(Self { private, public })
+    }
+
+    pub fn public(&self) -> &[u8; KEY_LEN] {
+        &self.public
+    }
+
+    pub fn private_bytes(&self) -> Result<Vec<u8>, ErrorStack> {
+        self.private.raw_private_key()
+    }
+}
+
+fn ephemeral() -> Option<StaticKeypair> {
+    StaticKeypair::generate().ok()
+}
+
+fn dh(private: &PKey<Private>, public: &[u8]) -> Option<Vec<u8>> {
+    let public = PKey::public_key_from_raw_bytes(public, Id::X25519).ok()?;
+    let mut deriver = Deriver::new(private).ok()?;
+    deriver.set_peer(&public).ok()?;
+    deriver.derive_to_vec().ok()
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; KEY_LEN] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn hkdf(ck: &[u8], ikm: &[u8]) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+    let temp = hmac(ck, &[ikm]);
+    let first = hmac(&temp, &[&[1]]);
+    let second = hmac(&temp, &[&first, &[2]]);
+    (first, second)
+}
+
+fn nonce(n: u64) -> [u8; 12] {
+    let mut out = [0u8; 12];
+    out[4..].copy_from_slice(&n.to_le_bytes());
+    out
+}
+
+fn seal(key: &[u8], n: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut tag = [0u8; TAG_LEN];
+    let mut out = encrypt_aead(
+        Cipher::chacha20_poly1305(),
+        key,
+        Some(&nonce(n)),
+        ad,
+        plaintext,
+        &mut tag,
+    )
+    .expect("ChaCha20-Poly1305 with a valid key and nonce");
+    out.extend_from_slice(&tag);
+    out
+}
+
+fn open(key: &[u8], n: u64, ad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    let split = sealed.len().checked_sub(TAG_LEN)?;
+    let (ciphertext, tag) = sealed.split_at(split);
+    decrypt_aead(
+        Cipher::chacha20_poly1305(),
+        key,
+        Some(&nonce(n)),
+        ad,
+        ciphertext,
+        tag,
+    )
+    .ok()
+}
+
+#[derive(Clone)]
+struct SymmetricState {
+    ck: [u8; KEY_LEN],
+    h: [u8; KEY_LEN],
+    k: Option<[u8; KEY_LEN]>,
+    n: u64,
+}
+
+impl SymmetricState {
+    // the responder's static key is the pre-message both sides hash first
+    fn new(responder_static: &[u8]) -> Self {
+        let mut state = Self {
+            ck: *PROTOCOL_NAME,
+            h: *PROTOCOL_NAME,
+            k: None,
+            n: 0,
+        };
+        state.mix_hash(&[]); // empty prologue
+        state.mix_hash(responder_static);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = Sha256::new()
+            .chain_update(self.h)
+            .chain_update(data)
+            .finalize()
+            .into();
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let (ck, k) = hkdf(&self.ck, ikm);
+        self.ck = ck;
+        self.k = Some(k);
+        self.n = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match self.k {
+            Some(k) => seal(&k, self.n, &self.h, plaintext),
+            None => plaintext.to_vec(),
+        };
+        self.n += 1;
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = match self.k {
+            Some(k) => open(&k, self.n, &self.h, ciphertext)?,
+            None => ciphertext.to_vec(),
+        };
+        self.n += 1;
+        self.mix_hash(ciphertext);
+        Some(plaintext)
+    }
+
+    // (initiator -> responder, responder -> initiator)
+    fn split(&self) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+        hkdf(&self.ck, &[])
+    }
+}
+
+/// The client half of a handshake that was sent and awaits the response.
+pub struct Initiator {
+    state: SymmetricState,
+    e: StaticKeypair,
+}
+
+impl Initiator {
+    /// Builds `-> e, es` for the server whose public key was pinned; `payload`
+    /// travels encrypted, only the holder of that key can read it.
+    pub fn start(server_public: &[u8], payload: &[u8]) -> Option<(Self, Vec<u8>)> {
+        let mut state = SymmetricState::new(server_public);
+        let e = ephemeral()?;
+        state.mix_hash(e.public());
+        state.mix_key(&dh(&e.private, server_public)?);
+
+        let mut msg = e.public().to_vec();
+        msg.extend(state.encrypt_and_hash(payload));
+        Some((Self { state, e }, msg))
+    }
+
+    /// Reads `<- e, ee`. `None` if the reply was not made by the pinned
+    /// server for this handshake, in which case another reply may still be tried.
+    pub fn finish(&self, msg: &[u8]) -> Option<(Vec<u8>, Transport)> {
+        if msg.len() < KEY_LEN + TAG_LEN {
+            return None;
+        }
+        let (re, rest) = msg.split_at(KEY_LEN);
+        let mut state = self.state.clone();
+        state.mix_hash(re);
+        state.mix_key(&dh(&self.e.private, re)?);
+        let payload = state.decrypt_and_hash(rest)?;
+
+        let (to_responder, to_initiator) = state.split();
+        Some((payload, Transport::new(to_responder, to_initiator)))
+    }
+}
+
+/// The server half after reading a valid `-> e, es`.
+pub struct Responder {
+    state: SymmetricState,
+    re: [u8; KEY_LEN],
+}
+
+impl Responder {
+    /// Reads `-> e, es` with our static key, returning its payload.
+    pub fn read(key: &StaticKeypair, msg: &[u8]) -> Option<(Vec<u8>, Self)> {
+        if msg.len() < KEY_LEN + TAG_LEN {
+            return None;
+        }
+        let (re, rest) = msg.split_at(KEY_LEN);
+        let mut state = SymmetricState::new(key.public());
+        state.mix_hash(re);
+        state.mix_key(&dh(&key.private, re)?);
+        let payload = state.decrypt_and_hash(rest)?;
+        Some((
+            payload,
+            Self {
+                state,
+                re: re.try_into().ok()?,
+            },
+        ))
+    }
+
+    /// Builds `<- e, ee` carrying `payload`.
+    pub fn finish(mut self, payload: &[u8]) -> Option<(Vec<u8>, Transport)> {
+        let e = ephemeral()?;
+        self.state.mix_hash(e.public());
+        self.state.mix_key(&dh(&e.private, &self.re)?);
+
+        let mut msg = e.public().to_vec();
+        msg.extend(self.state.encrypt_and_hash(payload));
+        let (to_responder, to_initiator) = self.state.split();
+        Some((msg, Transport::new(to_initiator, to_responder)))
+    }
+}
+
+/// An established session. Datagrams may be lost or reordered, so every
+/// message carries its nonce and a replay window replaces Noise's strict order.
+pub struct Transport {
+    send_key: [u8; KEY_LEN],
+    recv_key: [u8; KEY_LEN],
+    next: u64,
+    highest: Option<u64>,
+    window: u64,
+}
+
+impl Transport {
+    fn new(send_key: [u8; KEY_LEN], recv_key: [u8; KEY_LEN]) -> Self {
+        Self {
+            send_key,
+            recv_key,
+            next: 0,
+            highest: None,
+            window: 0,
+        }
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let n = self.next;
+        self.next += 1;
+        let mut out = n.to_be_bytes().to_vec();
+        out.extend(seal(&self.send_key, n, &[], plaintext));
+        out
+    }
+
+    pub fn open(&mut self, msg: &[u8]) -> Option<Vec<u8>> {
+        let (n, sealed) = msg.split_at_checked(8)?;
+        let n = u64::from_be_bytes(n.try_into().ok()?);
+        if let Some(highest) = self.highest
+            && n <= highest
+        {
+            let delta = highest - n;
+            if delta >= REPLAY_WINDOW || self.window & (1 << delta) != 0 {
+                return None;
+            }
+        }
+
+        let plaintext = open(&self.recv_key, n, &[], sealed)?;
+        match self.highest {
+            Some(highest) if n <= highest => self.window |= 1 << (highest - n),
+            Some(highest) => {
+                let shift = n - highest;
+                self.window = if shift >= REPLAY_WINDOW {
+                    1
+                } else {
+                    (self.window << shift) | 1
+                };
+                self.highest = Some(n);
+            }
+            None => {
+                self.window = 1;
+                self.highest = Some(n);
+            }
+        }
+        Some(plaintext)
+    }
+}
+
+/// Wraps one handshake or transport message in a `Kind::Noise` packet.
+pub fn frame(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + body.len());
+    payload.push(msg_type);
+    payload.extend_from_slice(body);
+    packet::encode(Header::noise(payload.len() as u16), &payload)
+}
+
+/// `(msg_type, body)` of a `Kind::Noise` packet's payload.
+pub fn unframe(payload: &[u8]) -> Option<(u8, &[u8])> {
+    let (&msg_type, body) = payload.split_first()?;
+    Some((msg_type, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(server: &StaticKeypair) -> (Transport, Transport) {
+        let (initiator, msg1) = Initiator::start(server.public(), b"hello").unwrap();
+        let (payload, responder) = Responder::read(server, &msg1).unwrap();
+        assert_eq!(payload, b"hello");
+        let (msg2, server_side) = responder.finish(b"welcome").unwrap();
+        let (payload, client_side) = initiator.finish(&msg2).unwrap();
+        assert_eq!(payload, b"welcome");
+        (client_side, server_side)
+    }
+
+    #[test]
+    fn handshake_hides_payloads_and_derives_matching_transports() {
+        let server = StaticKeypair::generate().unwrap();
+        let (initiator, msg1) = Initiator::start(server.public(), b"secret probe").unwrap();
+        assert!(!msg1.windows(12).any(|w| w == b"secret probe"));
+        drop(initiator);
+
+        let (mut client, mut server_side) = handshake(&server);
+        let sealed = client.seal(b"CONNECT");
+        assert_eq!(server_side.open(&sealed).unwrap(), b"CONNECT");
+        let sealed = server_side.seal(b"WELCOME");
+        assert_eq!(client.open(&sealed).unwrap(), b"WELCOME");
+    }
+
+    #[test]
+    fn only_the_pinned_server_can_answer() {
+        let server = StaticKeypair::generate().unwrap();
+        let impostor = StaticKeypair::generate().unwrap();
+        let (initiator, msg1) = Initiator::start(server.public(), b"").unwrap();
+
+        // the impostor cannot read the first message, nor forge a reply
+        assert!(Responder::read(&impostor, &msg1).is_none());
+        let (_, decoy) = Initiator::start(impostor.public(), b"").unwrap();
+        let (_, responder) = Responder::read(&impostor, &decoy).unwrap();
+        let (forged, _) = responder.finish(b"").unwrap();
+        assert!(initiator.finish(&forged).is_none());
+
+        let (_, responder) = Responder::read(&server, &msg1).unwrap();
+        let (msg2, _) = responder.finish(b"").unwrap();
+        assert!(initiator.finish(&msg2).is_some());
+    }
+
+    #[test]
+    fn transport_rejects_replays_and_tampering() {
+        let server = StaticKeypair::generate().unwrap();
+        let (mut client, mut server_side) = handshake(&server);
+
+        let first = client.seal(b"a");
+        let second = client.seal(b"b");
+        assert_eq!(server_side.open(&second).unwrap(), b"b");
+        assert_eq!(server_side.open(&first).unwrap(), b"a");
+        assert!(server_side.open(&first).is_none());
+
+        let mut tampered = client.seal(b"c");
+        tampered[9] ^= 1;
+        assert!(server_side.open(&tampered).is_none());
+    }
+
+    #[test]
+    fn static_key_roundtrips_through_private_bytes() {
+        let key = StaticKeypair::generate().unwrap();
+        let again = StaticKeypair::from_private(&key.private_bytes().unwrap()).unwrap();
+        assert_eq!(key.public(), again.public());
+    }
+}
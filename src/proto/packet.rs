@@ -8,6 +8,7 @@ pub enum Kind {
     Control = 1,
     Dtls = 2,
     Srtp = 3,
+    Noise = 4,
 }
 
 impl Kind {
@@ -16,6 +17,7 @@ impl Kind {
             1 => Some(Kind::Control),
             2 => Some(Kind::Dtls),
             3 => Some(Kind::Srtp),
+            4 => Some(Kind::Noise),
             _ => None,
         }
     }
@@ -47,6 +49,35 @@ impl Header {
         }
     }
 
+    pub fn dtls(channel_id: u64, src_peer_id: u32, dst_peer_id: u32, payload_len: u16) -> Self {
+        Self {
+            kind: Kind::Dtls,
+            ..Self::control(channel_id, src_peer_id, dst_peer_id, payload_len)
+        }
+    }
+
+    pub fn srtp(
+        channel_id: u64,
+        src_peer_id: u32,
+        dst_peer_id: u32,
+        stream_id: u32,
+        payload_len: u16,
+    ) -> Self {
+        Self {
+            kind: Kind::Srtp,
+            stream_id,
+            ..Self::control(channel_id, src_peer_id, dst_peer_id, payload_len)
+        }
+    }
+
+    // client <-> signaling server only, addressing lives in the sealed packet
+    pub fn noise(payload_len: u16) -> Self {
+        Self {
+            kind: Kind::Noise,
+            ..Self::control(0, 0, 0, payload_len)
+        }
+    }
+
     pub fn welcome(channel_id: u64, dst_peer_id: u32, payload_len: u16) -> Self {
         Self::control(channel_id, 0, dst_peer_id, payload_len)
     }
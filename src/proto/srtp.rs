@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use aes::Aes128;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type HmacSha1 = Hmac<Sha1>;
+
+pub const MASTER_KEY_LEN: usize = 16;
+pub const MASTER_SALT_LEN: usize = 14;
+pub const RTP_HEADER_LEN: usize = 12;
+pub const TAG_LEN: usize = 10; // HMAC-SHA1 truncated to 80 bits
+const AUTH_KEY_LEN: usize = 20;
+const REPLAY_WINDOW: u64 = 64;
+
+// RFC 3711 4.3.1 key derivation labels
+const LABEL_CIPHER: u8 = 0x00;
+const LABEL_AUTH: u8 = 0x01;
+const LABEL_SALT: u8 = 0x02;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SrtpError {
+    Malformed,
+    BadTag,
+    Replayed,
+}
+
+impl std::fmt::Display for SrtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            SrtpError::Malformed => "malformed SRTP packet",
+            SrtpError::BadTag => "bad SRTP authentication tag",
+            SrtpError::Replayed => "replayed or too old SRTP packet",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// The fixed 12-byte RTP header, without CSRCs or extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtpHeader {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    pub fn encode(&self) -> [u8; RTP_HEADER_LEN] {
+        let mut out = [0u8; RTP_HEADER_LEN];
+        out[0] = 0x80; // version 2, no padding, extension or CSRCs
+        out[1] = (self.marker as u8) << 7 | (self.payload_type & 0x7F);
+        out[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        out[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        out[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        out
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < RTP_HEADER_LEN || buf[0] != 0x80 {
+            return None;
+        }
+        Some(Self {
+            marker: buf[1] & 0x80 != 0,
+            payload_type: buf[1] & 0x7F,
+            sequence: u16::from_be_bytes([buf[2], buf[3]]),
+            timestamp: u32::from_be_bytes(buf[4..8].try_into().ok()?),
+            ssrc: u32::from_be_bytes(buf[8..12].try_into().ok()?),
+        })
+    }
+}
+
+// AES-CM as a PRF over the master key (key derivation rate 0)
+fn derive(master_key: &[u8], master_salt: &[u8], label: u8, out: &mut [u8]) {
+    let mut iv = [0u8; 16];
+    iv[..MASTER_SALT_LEN].copy_from_slice(master_salt);
+    iv[7] ^= label;
+    out.fill(0);
+    Aes128Ctr::new(master_key.into(), &iv.into()).apply_keystream(out);
+}
+
+#[derive(Clone)]
+struct SessionKeys {
+    cipher: [u8; MASTER_KEY_LEN],
+    auth: [u8; AUTH_KEY_LEN],
+    salt: [u8; MASTER_SALT_LEN],
+}
+
+impl SessionKeys {
+    fn derive(master_key: &[u8], master_salt: &[u8]) -> Self {
+        assert_eq!(master_key.len(), MASTER_KEY_LEN, "SRTP master key length");
+        assert_eq!(
+            master_salt.len(),
+            MASTER_SALT_LEN,
+            "SRTP master salt length"
+        );
+
+        let mut keys = Self {
+            cipher: [0; MASTER_KEY_LEN],
+            auth: [0; AUTH_KEY_LEN],
+            salt: [0; MASTER_SALT_LEN],
+        };
+        derive(master_key, master_salt, LABEL_CIPHER, &mut keys.cipher);
+        derive(master_key, master_salt, LABEL_AUTH, &mut keys.auth);
+        derive(master_key, master_salt, LABEL_SALT, &mut keys.salt);
+        keys
+    }
+
+    // IV = (salt * 2^16) XOR (SSRC * 2^64) XOR (index * 2^16)
+    fn apply_keystream(&self, ssrc: u32, index: u64, payload: &mut [u8]) {
+        let mut iv = [0u8; 16];
+        iv[..MASTER_SALT_LEN].copy_from_slice(&self.salt);
+        for (b, s) in iv[4..8].iter_mut().zip(ssrc.to_be_bytes()) {
+            *b ^= s;
+        }
+        for (b, i) in iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]) {
+            *b ^= i;
+        }
+        Aes128Ctr::new((&self.cipher).into(), &iv.into()).apply_keystream(payload);
+    }
+
+    fn mac(&self, authenticated: &[u8], roc: u32) -> HmacSha1 {
+        let mut mac = HmacSha1::new_from_slice(&self.auth).expect("HMAC accepts any key length");
+        mac.update(authenticated);
+        mac.update(&roc.to_be_bytes());
+        mac
+    }
+}
+
+/// Outbound SRTP for one peer: numbers, encrypts and authenticates our frames.
+pub struct SrtpSender {
+    keys: SessionKeys,
+    streams: HashMap<u32, u64>, // ssrc -> index (ROC << 16 | SEQ) of the next packet
+}
+
+impl SrtpSender {
+    pub fn new(master_key: &[u8], master_salt: &[u8]) -> Self {
+        Self {
+            keys: SessionKeys::derive(master_key, master_salt),
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn protect(
+        &mut self,
+        ssrc: u32,
+        payload_type: u8,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        // a random first sequence number, as RFC 3550 asks; the ROC starts at zero
+        let next = self
+            .streams
+            .entry(ssrc)
+            .or_insert_with(|| rand::random::<u16>() as u64);
+        let index = *next;
+        *next += 1;
+
+        let header = RtpHeader {
+            marker: false,
+            payload_type,
+            sequence: index as u16,
+            timestamp,
+            ssrc,
+        };
+        let mut out = header.encode().to_vec();
+        out.extend_from_slice(payload);
+        self.keys
+            .apply_keystream(ssrc, index, &mut out[RTP_HEADER_LEN..]);
+
+        let tag = self.keys.mac(&out, (index >> 16) as u32).finalize();
+        out.extend_from_slice(&tag.into_bytes()[..TAG_LEN]);
+        out
+    }
+}
+
+// highest authenticated index and which of the REPLAY_WINDOW before it were seen
+#[derive(Clone, Copy)]
+struct ReplayState {
+    highest: u64,
+    window: u64,
+}
+
+impl ReplayState {
+    // RFC 3711 3.3.1: the index is the one closest to the highest seen so far
+    fn estimate(&self, seq: u16) -> u64 {
+        let roc = self.highest >> 16;
+        let s_l = self.highest as u16;
+        let v = if s_l < 0x8000 {
+            if seq > s_l && seq - s_l > 0x8000 {
+                roc.saturating_sub(1) // nothing precedes the first rollover
+            } else {
+                roc
+            }
+        } else if s_l - 0x8000 > seq {
+            roc + 1
+        } else {
+            roc
+        };
+        (v << 16) | seq as u64
+    }
+
+    fn check(&self, index: u64) -> Result<(), SrtpError> {
+        if index > self.highest {
+            return Ok(());
+        }
+        let delta = self.highest - index;
+        if delta >= REPLAY_WINDOW || self.window & (1 << delta) != 0 {
+            return Err(SrtpError::Replayed);
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, index: u64) {
+        if index > self.highest {
+            let shift = index - self.highest;
+            self.window = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.window << shift
+            };
+            self.window |= 1;
+            self.highest = index;
+        } else {
+            self.window |= 1 << (self.highest - index);
+        }
+    }
+}
+
+/// Inbound SRTP from one peer: authenticates, rejects replays, decrypts.
+pub struct SrtpReceiver {
+    keys: SessionKeys,
+    streams: HashMap<u32, ReplayState>,
+}
+
+impl SrtpReceiver {
+    pub fn new(master_key: &[u8], master_salt: &[u8]) -> Self {
+        Self {
+            keys: SessionKeys::derive(master_key, master_salt),
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn unprotect(&mut self, packet: &[u8]) -> Result<(RtpHeader, Vec<u8>), SrtpError> {
+        if packet.len() < RTP_HEADER_LEN + TAG_LEN {
+            return Err(SrtpError::Malformed);
+        }
+        let header = RtpHeader::decode(packet).ok_or(SrtpError::Malformed)?;
+        let (authenticated, tag) = packet.split_at(packet.len() - TAG_LEN);
+
+        let state = self.streams.get(&header.ssrc).copied();
+        let index = match state {
+            Some(state) => {
+                let index = state.estimate(header.sequence);
+                state.check(index)?;
+                index
+            }
+            // the first packet of a stream starts at ROC 0
+            None => header.sequence as u64,
+        };
+
+        self.keys
+            .mac(authenticated, (index >> 16) as u32)
+            .verify_truncated_left(tag)
+            .map_err(|_| SrtpError::BadTag)?;
+
+        let mut state = state.unwrap_or(ReplayState {
+            highest: index,
+            window: 0,
+        });
+        state.accept(index);
+        self.streams.insert(header.ssrc, state);
+
+        let mut payload = authenticated[RTP_HEADER_LEN..].to_vec();
+        self.keys.apply_keystream(header.ssrc, index, &mut payload);
+        Ok((header, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        crate::proto::token::from_hex(s).unwrap()
+    }
+
+    const KEY: [u8; MASTER_KEY_LEN] = [7; MASTER_KEY_LEN];
+    const SALT: [u8; MASTER_SALT_LEN] = [9; MASTER_SALT_LEN];
+
+    #[test]
+    fn key_derivation_matches_rfc3711() {
+        // RFC 3711 B.3
+        let keys = SessionKeys::derive(
+            &hex("E1F97A0D3E018BE0D64FA32C06DE4139"),
+            &hex("0EC675AD498AFEEBB6960B3AABE6"),
+        );
+        assert_eq!(
+            keys.cipher.to_vec(),
+            hex("C61E7A93744F39EE10734AFE3FF7A087")
+        );
+        assert_eq!(keys.salt.to_vec(), hex("30CBBC08863D8C85D49DB34A9AE1"));
+        assert_eq!(
+            keys.auth.to_vec(),
+            hex("CEBE321F6FF7716B6FD4AB49AF256A156D38BAA4")
+        );
+    }
+
+    #[test]
+    fn protected_frames_roundtrip_and_hide_the_payload() {
+        let mut tx = SrtpSender::new(&KEY, &SALT);
+        let mut rx = SrtpReceiver::new(&KEY, &SALT);
+
+        for n in 0..3u32 {
+            let packet = tx.protect(5, 96, 160 * n, b"voice frame");
+            assert_eq!(packet.len(), RTP_HEADER_LEN + 11 + TAG_LEN);
+            assert!(!packet.windows(11).any(|w| w == b"voice frame"));
+
+            let (header, payload) = rx.unprotect(&packet).unwrap();
+            assert_eq!(header.ssrc, 5);
+            assert_eq!(header.timestamp, 160 * n);
+            assert_eq!(payload, b"voice frame");
+        }
+    }
+
+    #[test]
+    fn tampered_or_foreign_frames_are_rejected() {
+        let mut tx = SrtpSender::new(&KEY, &SALT);
+        let mut rx = SrtpReceiver::new(&KEY, &SALT);
+        let mut other = SrtpReceiver::new(&[8; MASTER_KEY_LEN], &SALT);
+
+        let mut packet = tx.protect(1, 96, 0, b"abc");
+        assert_eq!(other.unprotect(&packet), Err(SrtpError::BadTag));
+        packet[RTP_HEADER_LEN] ^= 1;
+        assert_eq!(rx.unprotect(&packet), Err(SrtpError::BadTag));
+        assert_eq!(rx.unprotect(&packet[..5]), Err(SrtpError::Malformed));
+    }
+
+    #[test]
+    fn replays_and_stale_frames_are_rejected() {
+        let mut tx = SrtpSender::new(&KEY, &SALT);
+        let mut rx = SrtpReceiver::new(&KEY, &SALT);
+
+        let packets: Vec<_> = (0..70).map(|_| tx.protect(1, 96, 0, b"x")).collect();
+        rx.unprotect(&packets[0]).unwrap();
+        assert_eq!(rx.unprotect(&packets[0]), Err(SrtpError::Replayed));
+
+        // reordering inside the window is fine, once
+        rx.unprotect(&packets[69]).unwrap();
+        rx.unprotect(&packets[10]).unwrap();
+        assert_eq!(rx.unprotect(&packets[10]), Err(SrtpError::Replayed));
+        // 68 behind the newest is outside the window
+        assert_eq!(rx.unprotect(&packets[1]), Err(SrtpError::Replayed));
+    }
+
+    #[test]
+    fn rollover_counter_follows_sequence_wrap() {
+        let mut tx = SrtpSender::new(&KEY, &SALT);
+        let mut rx = SrtpReceiver::new(&KEY, &SALT);
+        tx.streams.insert(3, 0xFFFE);
+
+        for _ in 0..4 {
+            let packet = tx.protect(3, 96, 0, b"wrap");
+            assert_eq!(rx.unprotect(&packet).unwrap().1, b"wrap");
+        }
+        assert_eq!(rx.streams[&3].highest, 0x1_0001);
+    }
+
+    #[test]
+    fn index_estimate_picks_the_closest_rollover() {
+        let at = |highest| ReplayState { highest, window: 1 };
+        assert_eq!(at(0x1_0010).estimate(0x0011), 0x1_0011);
+        assert_eq!(at(0x1_0010).estimate(0xFFF0), 0x0_FFF0);
+        assert_eq!(at(0x1_FFF0).estimate(0x0002), 0x2_0002);
+        assert_eq!(at(0x1_0005).estimate(0xFFFF), 0x0_FFFF);
+        assert_eq!(at(0x0_0005).estimate(0xFFFF), 0x0_FFFF);
+    }
+}
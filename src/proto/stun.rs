@@ -0,0 +1,515 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use hmac::{Hmac, Mac};
+use openssl::hash::{MessageDigest, hash};
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const HEADER_LEN: usize = 20;
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+const INTEGRITY_LEN: usize = 20;
+
+// methods (RFC 8489, RFC 8656)
+pub const BINDING: u16 = 0x001;
+pub const ALLOCATE: u16 = 0x003;
+pub const REFRESH: u16 = 0x004;
+pub const SEND: u16 = 0x006;
+pub const DATA: u16 = 0x007;
+pub const CREATE_PERMISSION: u16 = 0x008;
+pub const CHANNEL_BIND: u16 = 0x009;
+
+// attributes
+pub const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+pub const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+pub const ATTR_USERNAME: u16 = 0x0006;
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+pub const ATTR_ERROR_CODE: u16 = 0x0009;
+pub const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+pub const ATTR_CHANNEL_NUMBER: u16 = 0x000C;
+pub const ATTR_LIFETIME: u16 = 0x000D;
+pub const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+pub const ATTR_DATA: u16 = 0x0013;
+pub const ATTR_REALM: u16 = 0x0014;
+pub const ATTR_NONCE: u16 = 0x0015;
+pub const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+pub const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+pub const ATTR_SOFTWARE: u16 = 0x8022;
+pub const ATTR_FINGERPRINT: u16 = 0x8028;
+pub const ATTR_RESPONSE_ORIGIN: u16 = 0x802B;
+pub const ATTR_OTHER_ADDRESS: u16 = 0x802C;
+
+// CHANGE-REQUEST flags (RFC 5780 section 7.2)
+pub const CHANGE_IP: u32 = 0x04;
+pub const CHANGE_PORT: u32 = 0x02;
+
+pub const TRANSPORT_UDP: u8 = 17;
+
+// ChannelData messages (RFC 8656 section 12) start with a channel number in this range
+pub const CHANNEL_MIN: u16 = 0x4000;
+pub const CHANNEL_MAX: u16 = 0x4FFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    Request,
+    Indication,
+    Success,
+    Error,
+}
+
+impl Class {
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0b00,
+            Class::Indication => 0b01,
+            Class::Success => 0b10,
+            Class::Error => 0b11,
+        }
+    }
+
+    fn from_bits(bits: u16) -> Self {
+        match bits {
+            0b00 => Class::Request,
+            0b01 => Class::Indication,
+            0b10 => Class::Success,
+            _ => Class::Error,
+        }
+    }
+}
+
+// the class bits sit between the method bits: M11..M7 C1 M6..M4 C0 M3..M0
+fn message_type(method: u16, class: Class) -> u16 {
+    let c = class.bits();
+    (method & 0x000F)
+        | ((method & 0x0070) << 1)
+        | ((method & 0x0F80) << 2)
+        | ((c & 1) << 4)
+        | ((c & 2) << 7)
+}
+
+fn split_type(t: u16) -> (u16, Class) {
+    let method = (t & 0x000F) | ((t >> 1) & 0x0070) | ((t >> 2) & 0x0F80);
+    let class = ((t >> 4) & 1) | ((t >> 7) & 2);
+    (method, Class::from_bits(class))
+}
+
+/// A STUN message (RFC 8489) as the Binding and TURN servers use it.
+///
+/// Attributes are kept raw and in order; MESSAGE-INTEGRITY and FINGERPRINT
+/// are added by `encode` and checked separately with `check_integrity`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub method: u16,
+    pub class: Class,
+    pub txn: [u8; 12],
+    pub attrs: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    pub fn new(method: u16, class: Class, txn: [u8; 12]) -> Self {
+        Self {
+            method,
+            class,
+            txn,
+            attrs: Vec::new(),
+        }
+    }
+
+    pub fn request(method: u16) -> Self {
+        Self::new(method, Class::Request, rand::random())
+    }
+
+    /// A response to (or indication matching) this message, same method and transaction.
+    pub fn reply(&self, class: Class) -> Self {
+        Self::new(self.method, class, self.txn)
+    }
+
+    pub fn add(&mut self, attr: u16, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.attrs.push((attr, value.into()));
+        self
+    }
+
+    pub fn attr(&self, attr: u16) -> Option<&[u8]> {
+        self.attrs
+            .iter()
+            .find(|(t, _)| *t == attr)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn str_attr(&self, attr: u16) -> Option<&str> {
+        std::str::from_utf8(self.attr(attr)?).ok()
+    }
+
+    pub fn u32_attr(&self, attr: u16) -> Option<u32> {
+        Some(u32::from_be_bytes(
+            self.attr(attr)?.get(..4)?.try_into().ok()?,
+        ))
+    }
+
+    pub fn add_xor_addr(&mut self, attr: u16, addr: SocketAddr) -> &mut Self {
+        let value = xor_addr(addr, &self.txn);
+        self.add(attr, value)
+    }
+
+    pub fn xor_addr(&self, attr: u16) -> Option<SocketAddr> {
+        parse_xor_addr(self.attr(attr)?, &self.txn)
+    }
+
+    /// Every address of a repeatable attribute, e.g. XOR-PEER-ADDRESS in CreatePermission.
+    pub fn xor_addrs(&self, attr: u16) -> Vec<SocketAddr> {
+        self.attrs
+            .iter()
+            .filter(|(t, _)| *t == attr)
+            .filter_map(|(_, v)| parse_xor_addr(v, &self.txn))
+            .collect()
+    }
+
+    /// Adds an address in the plain MAPPED-ADDRESS format, as RFC 5780 uses
+    /// for OTHER-ADDRESS and RESPONSE-ORIGIN.
+    pub fn add_addr(&mut self, attr: u16, addr: SocketAddr) -> &mut Self {
+        let mut value = vec![0, if addr.is_ipv4() { 1 } else { 2 }];
+        value.extend_from_slice(&addr.port().to_be_bytes());
+        match addr.ip() {
+            IpAddr::V4(ip) => value.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => value.extend_from_slice(&ip.octets()),
+        }
+        self.add(attr, value)
+    }
+
+    pub fn addr(&self, attr: u16) -> Option<SocketAddr> {
+        let v = self.attr(attr)?;
+        let port = u16::from_be_bytes(v.get(2..4)?.try_into().ok()?);
+        let ip = match *v.get(1)? {
+            1 => IpAddr::V4(<[u8; 4]>::try_from(v.get(4..8)?).ok()?.into()),
+            2 => IpAddr::V6(<[u8; 16]>::try_from(v.get(4..20)?).ok()?.into()),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    pub fn add_error(&mut self, code: u16, reason: &str) -> &mut Self {
+        let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        self.add(ATTR_ERROR_CODE, value)
+    }
+
+    pub fn error_code(&self) -> Option<u16> {
+        let v = self.attr(ATTR_ERROR_CODE)?;
+        Some(*v.get(2)? as u16 * 100 + *v.get(3)? as u16)
+    }
+
+    /// Serializes the message, signed with `integrity` (the long-term key) if
+    /// given, and always ending with a FINGERPRINT.
+    pub fn encode(&self, integrity: Option<&[u8]>) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + 64);
+        out.extend_from_slice(&message_type(self.method, self.class).to_be_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        out.extend_from_slice(&self.txn);
+        for (attr, value) in &self.attrs {
+            push_attr(&mut out, *attr, value);
+        }
+
+        if let Some(key) = integrity {
+            // the length covers MESSAGE-INTEGRITY itself, but not what follows it
+            set_len(&mut out, 4 + INTEGRITY_LEN);
+            let mut mac = HmacSha1::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(&out);
+            let tag = mac.finalize().into_bytes();
+            push_attr(&mut out, ATTR_MESSAGE_INTEGRITY, &tag);
+        }
+
+        set_len(&mut out, 8);
+        let crc = crc32(&out) ^ FINGERPRINT_XOR;
+        push_attr(&mut out, ATTR_FINGERPRINT, &crc.to_be_bytes());
+        set_len(&mut out, 0);
+        out
+    }
+
+    /// Parses a STUN message; a FINGERPRINT, if present, must match.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if !is_stun(buf) {
+            return None;
+        }
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let body = buf.get(HEADER_LEN..HEADER_LEN + len)?;
+        let (method, class) = split_type(u16::from_be_bytes([buf[0], buf[1]]));
+        let mut msg = Message::new(method, class, buf[8..20].try_into().ok()?);
+
+        let mut pos = 0;
+        while pos < body.len() {
+            let attr = u16::from_be_bytes(body.get(pos..pos + 2)?.try_into().ok()?);
+            let len = u16::from_be_bytes(body.get(pos + 2..pos + 4)?.try_into().ok()?) as usize;
+            let value = body.get(pos + 4..pos + 4 + len)?;
+            if attr == ATTR_FINGERPRINT {
+                let start = HEADER_LEN + pos;
+                let mut covered = buf[..start].to_vec();
+                covered[2..4].copy_from_slice(&((pos + 8) as u16).to_be_bytes());
+                if value != (crc32(&covered) ^ FINGERPRINT_XOR).to_be_bytes() {
+                    return None;
+                }
+            }
+            msg.attrs.push((attr, value.to_vec()));
+            pos += 4 + len.next_multiple_of(4);
+        }
+        Some(msg)
+    }
+}
+
+/// A Binding request, asking to be answered from the other address and/or
+/// port like RFC 5780 CHANGE-REQUEST.
+pub fn binding_request(change_ip: bool, change_port: bool) -> Message {
+    let mut msg = Message::request(BINDING);
+    if change_ip || change_port {
+        let flags =
+            if change_ip { CHANGE_IP } else { 0 } | if change_port { CHANGE_PORT } else { 0 };
+        msg.add(ATTR_CHANGE_REQUEST, flags.to_be_bytes());
+    }
+    msg
+}
+
+/// `(change_ip, change_port)` asked for by a Binding request.
+pub fn change_request(msg: &Message) -> (bool, bool) {
+    let flags = msg.u32_attr(ATTR_CHANGE_REQUEST).unwrap_or(0);
+    (flags & CHANGE_IP != 0, flags & CHANGE_PORT != 0)
+}
+
+/// The mapped address and OTHER-ADDRESS of a Binding success response; the
+/// plain MAPPED-ADDRESS is accepted from servers predating RFC 5389.
+pub fn binding_response(msg: &Message) -> Option<(SocketAddr, Option<SocketAddr>)> {
+    if msg.method != BINDING || msg.class != Class::Success {
+        return None;
+    }
+    let mapped = msg
+        .xor_addr(ATTR_XOR_MAPPED_ADDRESS)
+        .or_else(|| msg.addr(ATTR_MAPPED_ADDRESS))?;
+    Some((mapped, msg.addr(ATTR_OTHER_ADDRESS)))
+}
+
+/// Whether `buf` looks like STUN: top bits clear, magic cookie in place.
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_LEN
+        && buf[0] & 0xC0 == 0
+        && buf[4..8] == MAGIC_COOKIE.to_be_bytes()
+        && u16::from_be_bytes([buf[2], buf[3]]).is_multiple_of(4)
+}
+
+/// Checks the MESSAGE-INTEGRITY of a received message against `key`.
+pub fn check_integrity(buf: &[u8], key: &[u8]) -> bool {
+    let Some(offset) = integrity_offset(buf) else {
+        return false;
+    };
+    let mut covered = buf[..offset].to_vec();
+    let len = (offset - HEADER_LEN + 4 + INTEGRITY_LEN) as u16;
+    covered[2..4].copy_from_slice(&len.to_be_bytes());
+
+    let mut mac = HmacSha1::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(&covered);
+    mac.verify_slice(&buf[offset + 4..offset + 4 + INTEGRITY_LEN])
+        .is_ok()
+}
+
+// where the MESSAGE-INTEGRITY attribute starts, if the message has one
+fn integrity_offset(buf: &[u8]) -> Option<usize> {
+    let end = HEADER_LEN + u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as usize;
+    let mut pos = HEADER_LEN;
+    while pos + 4 <= end.min(buf.len()) {
+        let attr = u16::from_be_bytes([buf[pos], buf[pos + 1]]);
+        let len = u16::from_be_bytes([buf[pos + 2], buf[pos + 3]]) as usize;
+        if attr == ATTR_MESSAGE_INTEGRITY {
+            return (len == INTEGRITY_LEN && pos + 4 + len <= buf.len()).then_some(pos);
+        }
+        pos += 4 + len.next_multiple_of(4);
+    }
+    None
+}
+
+/// The long-term credential key (RFC 8489 section 9.2.2): MD5(username:realm:password).
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> Vec<u8> {
+    let input = format!("{username}:{realm}:{password}");
+    hash(MessageDigest::md5(), input.as_bytes())
+        .map(|d| d.to_vec())
+        .unwrap_or_default()
+}
+
+/// A ChannelData message carrying `data` on `channel`.
+pub fn channel_data(channel: u16, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + data.len());
+    out.extend_from_slice(&channel.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+/// `(channel, data)` of a ChannelData message.
+pub fn parse_channel_data(buf: &[u8]) -> Option<(u16, &[u8])> {
+    let channel = u16::from_be_bytes(buf.get(..2)?.try_into().ok()?);
+    if !(CHANNEL_MIN..=CHANNEL_MAX).contains(&channel) {
+        return None;
+    }
+    let len = u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as usize;
+    Some((channel, buf.get(4..4 + len)?))
+}
+
+fn push_attr(out: &mut Vec<u8>, attr: u16, value: &[u8]) {
+    out.extend_from_slice(&attr.to_be_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+// header length field: the attributes so far plus `extra` bytes to come
+fn set_len(out: &mut [u8], extra: usize) {
+    let len = (out.len() - HEADER_LEN + extra) as u16;
+    out[2..4].copy_from_slice(&len.to_be_bytes());
+}
+
+fn xor_addr(addr: SocketAddr, txn: &[u8; 12]) -> Vec<u8> {
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut out = vec![0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(1);
+            out.extend_from_slice(&port.to_be_bytes());
+            out.extend_from_slice(&(u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            out.push(2);
+            out.extend_from_slice(&port.to_be_bytes());
+            let mask = xor_mask(txn);
+            out.extend(ip.octets().iter().zip(mask).map(|(a, m)| a ^ m));
+        }
+    }
+    out
+}
+
+fn parse_xor_addr(v: &[u8], txn: &[u8; 12]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes(v.get(2..4)?.try_into().ok()?) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match *v.get(1)? {
+        1 => {
+            let raw = u32::from_be_bytes(v.get(4..8)?.try_into().ok()?);
+            IpAddr::V4(Ipv4Addr::from(raw ^ MAGIC_COOKIE))
+        }
+        2 => {
+            let mut octets: [u8; 16] = v.get(4..20)?.try_into().ok()?;
+            for (o, m) in octets.iter_mut().zip(xor_mask(txn)) {
+                *o ^= m;
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// IPv6 addresses are XORed with the cookie followed by the transaction id
+fn xor_mask(txn: &[u8; 12]) -> [u8; 16] {
+    let mut mask = [0u8; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(txn);
+    mask
+}
+
+// CRC-32 (ISO-HDLC), as FINGERPRINT uses it
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_the_reference_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn message_types_follow_the_rfc_layout() {
+        // Binding request, Binding success, Allocate error, Data indication
+        assert_eq!(message_type(BINDING, Class::Request), 0x0001);
+        assert_eq!(message_type(BINDING, Class::Success), 0x0101);
+        assert_eq!(message_type(ALLOCATE, Class::Error), 0x0113);
+        assert_eq!(message_type(DATA, Class::Indication), 0x0017);
+        for t in [0x0001, 0x0101, 0x0113, 0x0017, 0x0009, 0x0118] {
+            let (method, class) = split_type(t);
+            assert_eq!(message_type(method, class), t);
+        }
+    }
+
+    #[test]
+    fn messages_roundtrip_with_addresses_of_both_families() {
+        let v4: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:2131".parse().unwrap();
+        let mut msg = Message::request(CREATE_PERMISSION);
+        msg.add_xor_addr(ATTR_XOR_PEER_ADDRESS, v4)
+            .add_xor_addr(ATTR_XOR_PEER_ADDRESS, v6)
+            .add(ATTR_USERNAME, "odd length");
+
+        let decoded = Message::decode(&msg.encode(None)).unwrap();
+        assert_eq!(decoded.method, CREATE_PERMISSION);
+        assert_eq!(decoded.class, Class::Request);
+        assert_eq!(decoded.xor_addrs(ATTR_XOR_PEER_ADDRESS), vec![v4, v6]);
+        assert_eq!(decoded.str_attr(ATTR_USERNAME), Some("odd length"));
+    }
+
+    #[test]
+    fn integrity_and_fingerprint_catch_changes() {
+        let key = long_term_key("user", "realm", "pass");
+        let mut msg = Message::request(ALLOCATE);
+        msg.add(ATTR_REQUESTED_TRANSPORT, vec![TRANSPORT_UDP, 0, 0, 0]);
+        let buf = msg.encode(Some(&key));
+
+        assert!(check_integrity(&buf, &key));
+        assert!(!check_integrity(
+            &buf,
+            &long_term_key("user", "realm", "other")
+        ));
+        assert!(!check_integrity(&msg.encode(None), &key));
+
+        let mut tampered = buf.clone();
+        tampered[HEADER_LEN + 4] ^= 1;
+        assert!(Message::decode(&tampered).is_none(), "fingerprint mismatch");
+    }
+
+    #[test]
+    fn binding_carries_change_request_and_other_address() {
+        let req = Message::decode(&binding_request(true, false).encode(None)).unwrap();
+        assert_eq!(change_request(&req), (true, false));
+        assert_eq!(change_request(&Message::request(BINDING)), (false, false));
+
+        let mapped: SocketAddr = "203.0.113.9:51000".parse().unwrap();
+        let other: SocketAddr = "[2001:db8::2]:2132".parse().unwrap();
+        let mut reply = req.reply(Class::Success);
+        reply
+            .add_xor_addr(ATTR_XOR_MAPPED_ADDRESS, mapped)
+            .add_addr(ATTR_OTHER_ADDRESS, other);
+        let reply = Message::decode(&reply.encode(None)).unwrap();
+        assert_eq!(binding_response(&reply), Some((mapped, Some(other))));
+        assert_eq!(binding_response(&req), None);
+    }
+
+    #[test]
+    fn non_stun_is_not_parsed() {
+        assert!(!is_stun(b"ODNP\x01\x01"));
+        assert!(Message::decode(&[0u8; 20]).is_none());
+        assert_eq!(
+            parse_channel_data(&channel_data(0x4001, b"hi")),
+            Some((0x4001, &b"hi"[..]))
+        );
+        assert_eq!(parse_channel_data(&channel_data(0x3FFF, b"hi")), None);
+    }
+}
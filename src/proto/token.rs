@@ -0,0 +1,205 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAC_LEN: usize = 32;
+pub const TOKEN_LEN: usize = 8 + MAC_LEN;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    Missing,
+    Malformed,
+    Expired,
+    BadSignature,
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            TokenError::Missing => "missing token",
+            TokenError::Malformed => "malformed token",
+            TokenError::Expired => "token expired",
+            TokenError::BadSignature => "bad token signature",
+        };
+        f.write_str(reason)
+    }
+}
+
+fn mac(secret: &[u8], server_id: &str, channel: &str, user: &str, expires_at: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    // length prefixes so ("ab", "c") and ("a", "bc") sign differently
+    for field in [server_id, channel, user] {
+        mac.update(&(field.len() as u32).to_le_bytes());
+        mac.update(field.as_bytes());
+    }
+    mac.update(&expires_at.to_le_bytes());
+    mac
+}
+
+/// Signs a join token for `user` in `server_id`/`channel`, valid until
+/// `expires_at` (unix seconds). Layout: `[expires_at u64 LE][HMAC-SHA256]`.
+pub fn sign(secret: &[u8], server_id: &str, channel: &str, user: &str, expires_at: u64) -> Vec<u8> {
+    let tag = mac(secret, server_id, channel, user, expires_at).finalize();
+    let mut out = expires_at.to_le_bytes().to_vec();
+    out.extend_from_slice(&tag.into_bytes());
+    out
+}
+
+/// Checks a token presented in CONNECT against the claims it was sent with.
+pub fn verify(
+    secret: &[u8],
+    token: &[u8],
+    server_id: &str,
+    channel: &str,
+    user: &str,
+    now: u64,
+) -> Result<(), TokenError> {
+    if token.is_empty() {
+        return Err(TokenError::Missing);
+    }
+    if token.len() != TOKEN_LEN {
+        return Err(TokenError::Malformed);
+    }
+
+    let (expiry, tag) = token.split_at(8);
+    let expires_at = u64::from_le_bytes(expiry.try_into().unwrap());
+
+    // constant-time compare, checked before expiry so forged tokens learn nothing
+    mac(secret, server_id, channel, user, expires_at)
+        .verify_slice(tag)
+        .map_err(|_| TokenError::BadSignature)?;
+
+    if now > expires_at {
+        return Err(TokenError::Expired);
+    }
+    Ok(())
+}
+
+/// TURN long-term credentials for a join token: the username carries the
+/// claims (`expires_at:server_id:channel:user`) so the server can sign the
+/// token again, and the hex token is the password. `None` if `token` is not
+/// a token.
+pub fn turn_credentials(
+    server_id: &str,
+    channel: &str,
+    user: &str,
+    token: &[u8],
+) -> Option<(String, String)> {
+    if token.len() != TOKEN_LEN || server_id.contains(':') || channel.contains(':') {
+        return None;
+    }
+    let expires_at = u64::from_le_bytes(token[..8].try_into().unwrap());
+    Some((
+        format!("{expires_at}:{server_id}:{channel}:{user}"),
+        to_hex(token),
+    ))
+}
+
+/// `(expires_at, server_id, channel, user)` of a TURN username.
+pub fn parse_turn_username(username: &str) -> Option<(u64, &str, &str, &str)> {
+    let mut parts = username.splitn(4, ':');
+    let expires_at = parts.next()?.parse().ok()?;
+    Some((expires_at, parts.next()?, parts.next()?, parts.next()?))
+}
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"test secret";
+
+    #[test]
+    fn signed_token_verifies() {
+        let t = sign(SECRET, "s1", "general", "alice", 1000);
+        assert_eq!(t.len(), TOKEN_LEN);
+        assert_eq!(verify(SECRET, &t, "s1", "general", "alice", 999), Ok(()));
+        assert_eq!(verify(SECRET, &t, "s1", "general", "alice", 1000), Ok(()));
+    }
+
+    #[test]
+    fn token_is_bound_to_its_claims() {
+        let t = sign(SECRET, "s1", "general", "alice", 1000);
+        for (sid, ch, user) in [
+            ("s2", "general", "alice"),
+            ("s1", "random", "alice"),
+            ("s1", "general", "mallory"),
+            ("s1general", "", "alice"),
+        ] {
+            assert_eq!(
+                verify(SECRET, &t, sid, ch, user, 0),
+                Err(TokenError::BadSignature)
+            );
+        }
+        assert_eq!(
+            verify(b"other secret", &t, "s1", "general", "alice", 0),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn expired_or_tampered_tokens_are_rejected() {
+        let mut t = sign(SECRET, "s1", "general", "alice", 1000);
+        assert_eq!(
+            verify(SECRET, &t, "s1", "general", "alice", 1001),
+            Err(TokenError::Expired)
+        );
+
+        // pushing the expiry forward breaks the signature
+        t[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            verify(SECRET, &t, "s1", "general", "alice", 0),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            verify(SECRET, &[], "s1", "general", "alice", 0),
+            Err(TokenError::Missing)
+        );
+        assert_eq!(
+            verify(SECRET, &t[..20], "s1", "general", "alice", 0),
+            Err(TokenError::Malformed)
+        );
+    }
+
+    #[test]
+    fn turn_credentials_carry_the_claims() {
+        let t = sign(SECRET, "s1", "general", "al:ice", 1000);
+        let (username, password) = turn_credentials("s1", "general", "al:ice", &t).unwrap();
+        assert_eq!(password, to_hex(&t));
+        assert_eq!(
+            parse_turn_username(&username),
+            Some((1000, "s1", "general", "al:ice"))
+        );
+        assert_eq!(turn_credentials("s:1", "general", "alice", &t), None);
+        assert_eq!(parse_turn_username("soon:s1:general:alice"), None);
+    }
+
+    #[test]
+    fn hex_roundtrips() {
+        let t = sign(SECRET, "s1", "general", "alice", 1000);
+        assert_eq!(from_hex(&to_hex(&t)), Some(t));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
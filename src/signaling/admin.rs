@@ -0,0 +1,436 @@
+use std::{collections::BTreeMap, future::Future, net::SocketAddr, str::FromStr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use warp::{
+    Filter, Rejection, Reply,
+    http::StatusCode,
+    reply::{Response, json, with_status},
+};
+
+use crate::{
+    log,
+    proto::control::ControlMessage,
+    signaling::{
+        handlers::{
+            notifications::{
+                demote_old_relay, handle_disconnect_notifications, notify_new_relay_about_peers,
+                notify_peers_about_new_relay, promote_new_relay,
+            },
+            utils::update_relay_after_departure,
+        },
+        state::ServerState,
+        structures::{Channel, User},
+        utils::{Outbox, flush_outbox, queue_control},
+    },
+};
+
+#[derive(Serialize)]
+struct ChannelSummary {
+    name: String,
+    channel_id: u64,
+    users: usize,
+    relay: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct ServerSummary {
+    id: String,
+    channels: Vec<ChannelSummary>,
+}
+
+#[derive(Serialize)]
+struct UserDetail {
+    name: String,
+    peer_id: u32,
+    addr: SocketAddr,
+    nat_kind: String,
+    server_relay: bool,
+    last_pong_secs: u64,
+}
+
+#[derive(Serialize)]
+struct ChannelDetail {
+    server_id: String,
+    name: String,
+    channel_id: u64,
+    relay: Option<u32>,
+    users: Vec<UserDetail>,
+}
+
+/// Body of the kick and relay actions.
+#[derive(Deserialize)]
+struct PeerRequest {
+    peer_id: u32,
+}
+
+/// Body and answer of `/log`, a filter like `info,signaling::turn=debug`.
+#[derive(Deserialize, Serialize)]
+struct LogLevels {
+    level: String,
+}
+
+/// A percent-decoded path segment; server ids and channel names may hold
+/// anything a client put in its CONNECT.
+struct Segment(String);
+
+impl FromStr for Segment {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let hex = |b: Option<u8>| b.and_then(|b| (b as char).to_digit(16)).ok_or(());
+        let mut out = Vec::with_capacity(s.len());
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'%' {
+                out.push((hex(bytes.next())? << 4 | hex(bytes.next())?) as u8);
+            } else {
+                out.push(b);
+            }
+        }
+        String::from_utf8(out).map(Segment).map_err(|_| ())
+    }
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+// what the handlers share: the state and the socket users are told changes on
+struct Admin {
+    state: Arc<ServerState>,
+    socket: Arc<UdpSocket>,
+}
+
+fn message(status: StatusCode, text: &str) -> Response {
+    with_status(json(&BTreeMap::from([("message", text)])), status).into_response()
+}
+
+// same work for every byte, a wrong guess learns nothing from the timing
+fn token_matches(header: Option<&str>, token: &str) -> bool {
+    let Some(given) = header.and_then(|h| h.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn recover(err: Rejection) -> Result<Response, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        return Ok(message(
+            StatusCode::UNAUTHORIZED,
+            "missing or bad bearer token",
+        ));
+    }
+    Err(err)
+}
+
+/// The admin API over `state`; actions notify users through `socket`.
+///
+/// - `GET /servers`: every server id with its channels
+/// - `GET /servers/{id}/channels/{name}`: users, peer ids, NAT kinds, relay
+/// - `POST /servers/{id}/channels/{name}/kick` `{"peer_id": n}`: drops a user
+/// - `POST /servers/{id}/channels/{name}/relay` `{"peer_id": n}`: makes a user the relay
+/// - `DELETE /servers/{id}/channels/{name}`: closes the channel
+/// - `GET /log`, `PUT /log` `{"level": "info,signaling::turn=debug"}`: the log filter
+pub fn routes(
+    state: Arc<ServerState>,
+    socket: Arc<UdpSocket>,
+    token: String,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let admin = Arc::new(Admin { state, socket });
+    let admin = warp::any().map(move || Arc::clone(&admin));
+    let token = Arc::new(token);
+    let auth = warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = Arc::clone(&token);
+            async move {
+                if token_matches(header.as_deref(), &token) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one();
+
+    let servers = warp::get()
+        .and(warp::path!("servers"))
+        .and(admin.clone())
+        .map(|admin: Arc<Admin>| list_servers(&admin.state));
+    let channel = warp::get()
+        .and(warp::path!("servers" / Segment / "channels" / Segment))
+        .and(admin.clone())
+        .map(|Segment(sid), Segment(name), admin: Arc<Admin>| {
+            show_channel(&admin.state, &sid, &name)
+        });
+    let kick = warp::post()
+        .and(warp::path!(
+            "servers" / Segment / "channels" / Segment / "kick"
+        ))
+        .and(warp::body::json())
+        .and(admin.clone())
+        .then(|Segment(sid), Segment(name), req: PeerRequest, admin| {
+            kick(admin, sid, name, req.peer_id)
+        });
+    let relay = warp::post()
+        .and(warp::path!(
+            "servers" / Segment / "channels" / Segment / "relay"
+        ))
+        .and(warp::body::json())
+        .and(admin.clone())
+        .then(|Segment(sid), Segment(name), req: PeerRequest, admin| {
+            force_relay(admin, sid, name, req.peer_id)
+        });
+    let delete = warp::delete()
+        .and(warp::path!("servers" / Segment / "channels" / Segment))
+        .and(admin)
+        .then(|Segment(sid), Segment(name), admin| delete_channel(sid, name, admin));
+    let show_log = warp::get().and(warp::path!("log")).map(show_log);
+    let set_log = warp::put()
+        .and(warp::path!("log"))
+        .and(warp::body::json())
+        .map(set_log);
+
+    let api = servers
+        .or(channel)
+        .unify()
+        .or(kick)
+        .unify()
+        .or(relay)
+        .unify()
+        .or(delete)
+        .unify()
+        .or(show_log)
+        .unify()
+        .or(set_log)
+        .unify();
+    auth.and(api).recover(recover).unify()
+}
+
+/// Binds the admin API on `listen`, returning the bound address and the
+/// server future to spawn.
+pub fn bind(
+    listen: SocketAddr,
+    token: String,
+    socket: Arc<UdpSocket>,
+    state: Arc<ServerState>,
+) -> Result<(SocketAddr, impl Future<Output = ()>), warp::Error> {
+    warp::serve(routes(state, socket, token)).try_bind_ephemeral(listen)
+}
+
+fn show_log() -> Response {
+    json(&LogLevels {
+        level: log::filter().to_string(),
+    })
+    .into_response()
+}
+
+fn set_log(req: LogLevels) -> Response {
+    match req.level.parse() {
+        Ok(filter) => {
+            log::set_filter(filter);
+            log!(Info, "Admin set the log level to {}", log::filter());
+            show_log()
+        }
+        Err(e) => message(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+fn list_servers(state: &ServerState) -> Response {
+    let mut servers: BTreeMap<String, Vec<ChannelSummary>> = BTreeMap::new();
+    state.for_each_channel(|sid, name, channel| {
+        servers
+            .entry(sid.to_string())
+            .or_default()
+            .push(ChannelSummary {
+                name: name.to_string(),
+                channel_id: channel.channel_id,
+                users: channel.users.len(),
+                relay: channel.relay,
+            });
+    });
+    let servers: Vec<ServerSummary> = servers
+        .into_iter()
+        .map(|(id, mut channels)| {
+            channels.sort_by(|a, b| a.name.cmp(&b.name));
+            ServerSummary { id, channels }
+        })
+        .collect();
+    json(&servers).into_response()
+}
+
+fn user_detail(user: &User) -> UserDetail {
+    UserDetail {
+        name: user.name.clone(),
+        peer_id: user.peer_id,
+        addr: user.addr,
+        nat_kind: format!("{:?}", user.nat_kind),
+        server_relay: user.needs_server_relay,
+        last_pong_secs: user.last_pong.elapsed().as_secs(),
+    }
+}
+
+fn show_channel(state: &ServerState, sid: &str, name: &str) -> Response {
+    let detail = state.with_channel(sid, name, |channel| ChannelDetail {
+        server_id: sid.to_string(),
+        name: name.to_string(),
+        channel_id: channel.channel_id,
+        relay: channel.relay,
+        users: channel.users.iter().map(user_detail).collect(),
+    });
+    match detail {
+        Some(detail) => json(&detail).into_response(),
+        None => message(StatusCode::NOT_FOUND, "no such channel"),
+    }
+}
+
+// a replica only changes through its owner, see `cluster`
+fn served_elsewhere(state: &ServerState, sid: &str, name: &str) -> Option<Response> {
+    let owner = state.owner(sid, name)?;
+    let text = format!("channel is served by instance {owner}");
+    Some(message(StatusCode::CONFLICT, &text))
+}
+
+// like a DISCONNECT from the user, who is also told it was kicked
+async fn kick(admin: Arc<Admin>, sid: String, name: String, peer_id: u32) -> Response {
+    let state = &admin.state;
+    if let Some(response) = served_elsewhere(state, &sid, &name) {
+        return response;
+    }
+    let mut outbox = Outbox::new();
+    let kicked = state.with_channel(&sid, &name, |channel| {
+        let pos = channel.users.iter().position(|u| u.peer_id == peer_id)?;
+        let leaving = channel.users.remove(pos);
+        let was_relay = channel.relay == Some(peer_id);
+        state.unindex_peer(leaving.addr, &sid, &name);
+
+        let lone_user_addr = update_relay_after_departure(channel, was_relay);
+        handle_disconnect_notifications(channel, was_relay, &leaving, lone_user_addr, &mut outbox);
+        queue_control(
+            &mut outbox,
+            &ControlMessage::Rejected {
+                reason: "kicked by the server admin".into(),
+            },
+            leaving.addr,
+        );
+        Some(leaving)
+    });
+
+    let Some(kicked) = kicked.flatten() else {
+        return message(StatusCode::NOT_FOUND, "no such channel or peer");
+    };
+    log!(
+        Info,
+        server_id = &sid,
+        channel = &name,
+        user = &kicked.name,
+        peer_id = peer_id,
+        addr = kicked.addr;
+        "Admin kicked {}",
+        kicked.name
+    );
+    flush_outbox(&admin.socket, state, outbox).await;
+    state.remove_empty_channels(&[(sid, name)]);
+    json(&user_detail(&kicked)).into_response()
+}
+
+fn elect(
+    channel: &mut Channel,
+    peer_id: u32,
+    outbox: &mut Outbox,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Some(relay) = channel.users.iter().find(|u| u.peer_id == peer_id).cloned() else {
+        return Err((StatusCode::NOT_FOUND, "no such peer"));
+    };
+    if relay.relay_rank().is_none() {
+        return Err((
+            StatusCode::CONFLICT,
+            "peer cannot be reached directly and cannot relay",
+        ));
+    }
+    if channel.relay == Some(peer_id) {
+        return Ok(());
+    }
+
+    // announced like a re-election, and the relay it replaces steps down;
+    // it sticks while the user stays eligible
+    if let Some(old) = channel
+        .users
+        .iter()
+        .find(|u| Some(u.peer_id) == channel.relay)
+    {
+        demote_old_relay(outbox, old);
+    }
+    let peers: Vec<User> = channel
+        .users
+        .iter()
+        .filter(|u| u.peer_id != peer_id)
+        .cloned()
+        .collect();
+    promote_new_relay(outbox, &relay);
+    notify_peers_about_new_relay(outbox, &relay, &peers);
+    notify_new_relay_about_peers(outbox, &relay, &peers);
+    channel.relay = Some(peer_id);
+    Ok(())
+}
+
+async fn force_relay(admin: Arc<Admin>, sid: String, name: String, peer_id: u32) -> Response {
+    if let Some(response) = served_elsewhere(&admin.state, &sid, &name) {
+        return response;
+    }
+    let mut outbox = Outbox::new();
+    let result = admin
+        .state
+        .with_channel(&sid, &name, |channel| elect(channel, peer_id, &mut outbox));
+    match result {
+        None => message(StatusCode::NOT_FOUND, "no such channel"),
+        Some(Err((status, text))) => message(status, text),
+        Some(Ok(())) => {
+            log!(
+                Info,
+                server_id = &sid,
+                channel = &name,
+                peer_id = peer_id;
+                "Admin made #{} relay",
+                peer_id
+            );
+            flush_outbox(&admin.socket, &admin.state, outbox).await;
+            show_channel(&admin.state, &sid, &name)
+        }
+    }
+}
+
+// every user is told the channel is gone, as if its CONNECT had been refused
+async fn delete_channel(sid: String, name: String, admin: Arc<Admin>) -> Response {
+    if let Some(response) = served_elsewhere(&admin.state, &sid, &name) {
+        return response;
+    }
+    let Some(channel) = admin.state.remove_channel(&sid, &name) else {
+        return message(StatusCode::NOT_FOUND, "no such channel");
+    };
+    let mut outbox = Outbox::new();
+    for user in &channel.users {
+        queue_control(
+            &mut outbox,
+            &ControlMessage::Rejected {
+                reason: "channel closed by the server admin".into(),
+            },
+            user.addr,
+        );
+    }
+    log!(
+        Info,
+        server_id = &sid,
+        channel = &name;
+        "Admin closed the channel ({} users)",
+        channel.users.len()
+    );
+    flush_outbox(&admin.socket, &admin.state, outbox).await;
+    with_status(warp::reply(), StatusCode::NO_CONTENT).into_response()
+}
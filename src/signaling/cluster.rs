@@ -0,0 +1,767 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+use sha2::{Digest, Sha256};
+use tokio::net::UdpSocket;
+
+use crate::{
+    log, net,
+    proto::control,
+    signaling::{
+        handlers::handle_message,
+        snapshot::ChannelSnapshot,
+        state::ServerState,
+        store::{ChannelStore, MemoryStore},
+        structures::Channel,
+    },
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAGIC: &[u8; 4] = b"ODNC";
+const SALT_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+/// Bytes of a channel each envelope carries, which keeps it under a common MTU.
+const PART_LEN: usize = 1024;
+/// Parts a channel may take; a larger one is not replicated.
+const MAX_PARTS: usize = 256;
+// the largest UDP payload
+const MAX_DATAGRAM: usize = 65507;
+// envelopes older than this (or from a clock this far ahead) are dropped
+const MAX_AGE_MS: u64 = 5000;
+// how far behind the newest sequence number of a sender one may arrive
+const REPLAY_WINDOW: u64 = 64;
+/// How often changed channels are sent to the other instances.
+const REPLICATE_EVERY: Duration = Duration::from_millis(100);
+/// Every channel is sent this often, changed or not, so lost updates heal.
+const RESYNC_EVERY: Duration = Duration::from_secs(5);
+/// A replica its owner stopped refreshing is dropped after this long.
+const REPLICA_TTL: Duration = Duration::from_secs(15);
+
+const KIND_DELIVER: u8 = 0;
+const KIND_CLIENT: u8 = 1;
+const KIND_CHANNEL: u8 = 2;
+const KIND_GONE: u8 = 3;
+
+/// What instances send each other.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// A packet for the user at `dst`, who talks to the receiving instance.
+    /// Control packets are sealed there, in the user's Noise session; media
+    /// goes out as it is.
+    Deliver {
+        dst: SocketAddr,
+        sealed: bool,
+        packet: Vec<u8>,
+    },
+    /// A control packet the user at `src` sent to another instance, for the
+    /// owner of its channel to handle.
+    Client { src: SocketAddr, packet: Vec<u8> },
+    /// The owner's view of one of its channels.
+    Channel(ChannelSnapshot),
+    /// The owner closed the channel.
+    Gone { server_id: String, channel: String },
+}
+
+/// An authenticated message, see `Cluster::open`.
+#[derive(Debug, PartialEq)]
+pub struct Envelope {
+    pub from: SocketAddr,
+    pub seq: u64,
+    pub message: Message,
+}
+
+// the newest sequence number seen from a sender and which of the ones just
+// before it arrived (bit n is `top - n`)
+#[derive(Default)]
+struct ReplayWindow {
+    top: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, seq: u64) -> bool {
+        if seq > self.top {
+            let shift = seq - self.top;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.top = seq;
+            return true;
+        }
+        let age = self.top - seq;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+struct Replica {
+    owner: SocketAddr,
+    seq: u64,
+    refreshed: Instant,
+}
+
+// the parts of a channel received so far
+struct Partial {
+    parts: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+tokio::task_local! {
+    // the instance that passed on the message being handled, and its sender
+    static VIA: (SocketAddr, SocketAddr);
+}
+
+/// The instance the message being handled was passed on by, `None` if its
+/// sender talks to this instance directly.
+pub fn via() -> Option<SocketAddr> {
+    VIA.try_with(|(node, _)| *node).ok()
+}
+
+/// The instance to answer `dst` through, if it sent the message being handled
+/// through another one.
+pub fn via_for(dst: SocketAddr) -> Option<SocketAddr> {
+    VIA.try_with(|(node, src)| (*src == dst).then_some(*node))
+        .ok()
+        .flatten()
+}
+
+/// Several instances serving the same channels. Every channel is owned by one
+/// instance, picked from the configured nodes by rendezvous hashing, which
+/// keeps it in memory like a single server would. The other instances pass
+/// their users' control messages for it on to the owner, and keep a replica
+/// the owner refreshes, to route media and relayed DATA without asking it.
+/// Packets for a user go out through the instance it talks to, its `home`.
+///
+/// Envelope: `ODNC`, sender node, salt, sequence number, then kind, sent at
+/// (unix ms) and body sealed with ChaCha20-Poly1305 (the header is authenticated
+/// along). The key is derived from the secret and the salt, which is drawn
+/// anew every run, and the nonce is the sequence number. Every sender's
+/// sequence numbers only grow, a replayed envelope is dropped. A channel is
+/// sent in parts of `PART_LEN` bytes under consecutive sequence numbers.
+pub struct Cluster {
+    socket: Arc<UdpSocket>,
+    node: SocketAddr,
+    nodes: Vec<SocketAddr>,
+    secret: Vec<u8>,
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+    next_seq: AtomicU64,
+    windows: Mutex<HashMap<SocketAddr, ReplayWindow>>,
+    partials: Mutex<HashMap<(SocketAddr, u64), Partial>>,
+    replicas: Mutex<HashMap<(String, String), Replica>>,
+}
+
+// the key a run of an instance seals under
+fn derive_key(secret: &[u8], salt: &[u8]) -> [u8; 32] {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(b"ODNC key");
+    mac.update(salt);
+    mac.finalize().into_bytes().into()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn nonce(seq: u64) -> [u8; 12] {
+    let mut out = [0u8; 12];
+    out[4..].copy_from_slice(&seq.to_be_bytes());
+    out
+}
+
+fn put_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+// reads the front of a body, `None` once it runs short
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn addr(&mut self) -> Option<SocketAddr> {
+        let ip: IpAddr = match self.u8()? {
+            4 => Ipv4Addr::from(<[u8; 4]>::try_from(self.take(4)?).ok()?).into(),
+            6 => Ipv6Addr::from(<[u8; 16]>::try_from(self.take(16)?).ok()?).into(),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, self.u16()?))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.u16()?;
+        String::from_utf8(self.take(len as usize)?.to_vec()).ok()
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+}
+
+impl Cluster {
+    /// `node` is how the other instances reach `socket`, `nodes` every instance
+    /// of the cluster (this one is added if missing).
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        node: SocketAddr,
+        mut nodes: Vec<SocketAddr>,
+        secret: Vec<u8>,
+    ) -> Self {
+        nodes.push(node);
+        nodes.sort();
+        nodes.dedup();
+        let salt: [u8; SALT_LEN] = rand::random();
+        let key = derive_key(&secret, &salt);
+        Self {
+            socket,
+            node,
+            nodes,
+            secret,
+            salt,
+            key,
+            // a restarted instance must not fall behind its last run's window
+            next_seq: AtomicU64::new(now_ms() * 1000),
+            windows: Mutex::default(),
+            partials: Mutex::default(),
+            replicas: Mutex::default(),
+        }
+    }
+
+    pub fn node(&self) -> SocketAddr {
+        self.node
+    }
+
+    /// The instance that owns a channel, the same on every instance that was
+    /// given the same nodes.
+    pub fn owner_of(&self, server_id: &str, channel: &str) -> SocketAddr {
+        let score = |node: &SocketAddr| {
+            let digest = Sha256::new()
+                .chain_update(node.to_string())
+                .chain_update([0])
+                .chain_update(server_id)
+                .chain_update([0])
+                .chain_update(channel)
+                .finalize();
+            u64::from_be_bytes(digest[..8].try_into().unwrap())
+        };
+        *self
+            .nodes
+            .iter()
+            .max_by_key(|node| score(node))
+            .expect("a cluster has at least this node")
+    }
+
+    /// The datagrams carrying `message` under the next sequence numbers, as
+    /// sent at `sent_ms`: one, or one per part of a channel.
+    pub fn seal(&self, message: &Message, sent_ms: u64) -> io::Result<Vec<Vec<u8>>> {
+        let mut body = Vec::new();
+        let bodies = match message {
+            Message::Deliver {
+                dst,
+                sealed,
+                packet,
+            } => {
+                put_addr(&mut body, *dst);
+                body.push(*sealed as u8);
+                body.extend_from_slice(packet);
+                vec![(KIND_DELIVER, body)]
+            }
+            Message::Client { src, packet } => {
+                put_addr(&mut body, *src);
+                body.extend_from_slice(packet);
+                vec![(KIND_CLIENT, body)]
+            }
+            Message::Channel(snapshot) => {
+                let json = serde_json::to_vec(snapshot)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let parts = json.chunks(PART_LEN);
+                if parts.len() > MAX_PARTS {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} bytes take more than {} parts", json.len(), MAX_PARTS),
+                    ));
+                }
+                let count = parts.len() as u16;
+                parts
+                    .enumerate()
+                    .map(|(i, chunk)| {
+                        let mut body = (i as u16).to_be_bytes().to_vec();
+                        body.extend_from_slice(&count.to_be_bytes());
+                        body.extend_from_slice(chunk);
+                        (KIND_CHANNEL, body)
+                    })
+                    .collect()
+            }
+            Message::Gone { server_id, channel } => {
+                put_str(&mut body, server_id);
+                put_str(&mut body, channel);
+                vec![(KIND_GONE, body)]
+            }
+        };
+        let first = self
+            .next_seq
+            .fetch_add(bodies.len() as u64, Ordering::Relaxed);
+        bodies
+            .into_iter()
+            .zip(first..)
+            .map(|((kind, body), seq)| self.seal_one(kind, seq, sent_ms, &body))
+            .collect()
+    }
+
+    fn seal_one(&self, kind: u8, seq: u64, sent_ms: u64, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = MAGIC.to_vec();
+        put_addr(&mut out, self.node);
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&seq.to_be_bytes());
+        let mut plaintext = vec![kind];
+        plaintext.extend_from_slice(&sent_ms.to_be_bytes());
+        plaintext.extend_from_slice(body);
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &self.key,
+            Some(&nonce(seq)),
+            &out,
+            &plaintext,
+            &mut tag,
+        )
+        .map_err(io::Error::other)?;
+        out.extend_from_slice(&ciphertext);
+        out.extend_from_slice(&tag);
+        if out.len() > MAX_DATAGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("a {}-byte envelope does not fit a datagram", out.len()),
+            ));
+        }
+        Ok(out)
+    }
+
+    /// The envelope in `datagram`, if it opens under the secret, it is fresh
+    /// and it was not seen before. A part of a channel gives `None` until the
+    /// last one is in, then the whole channel under the first part's number.
+    pub fn open(&self, datagram: &[u8]) -> Option<Envelope> {
+        let mut r = Reader(datagram);
+        if r.take(MAGIC.len())? != MAGIC {
+            return None;
+        }
+        let from = r.addr()?;
+        let salt = r.take(SALT_LEN)?;
+        let seq = r.u64()?;
+        let (header, sealed) = datagram.split_at(datagram.len() - r.0.len());
+        let (ciphertext, tag) = sealed.split_at(sealed.len().checked_sub(TAG_LEN)?);
+        let plaintext = decrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &derive_key(&self.secret, salt),
+            Some(&nonce(seq)),
+            header,
+            ciphertext,
+            tag,
+        )
+        .ok()?;
+
+        let mut r = Reader(&plaintext);
+        let kind = r.u8()?;
+        if now_ms().abs_diff(r.u64()?) > MAX_AGE_MS {
+            return None;
+        }
+        let message = match kind {
+            KIND_DELIVER => Message::Deliver {
+                dst: r.addr()?,
+                sealed: r.u8()? != 0,
+                packet: r.rest().to_vec(),
+            },
+            KIND_CLIENT => Message::Client {
+                src: r.addr()?,
+                packet: r.rest().to_vec(),
+            },
+            KIND_CHANNEL => {
+                let (part, parts) = (r.u16()?, r.u16()?);
+                if part >= parts || usize::from(parts) > MAX_PARTS {
+                    return None;
+                }
+                let first = seq.checked_sub(u64::from(part))?;
+                if !self.accept(from, seq) {
+                    return None;
+                }
+                let json = self.reassemble((from, first), part, parts, r.rest())?;
+                let snapshot = match serde_json::from_slice(&json) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        log!(Warn, addr = from; "Dropping an unreadable channel: {}", e);
+                        return None;
+                    }
+                };
+                return Some(Envelope {
+                    from,
+                    seq: first,
+                    message: Message::Channel(snapshot),
+                });
+            }
+            KIND_GONE => Message::Gone {
+                server_id: r.str()?,
+                channel: r.str()?,
+            },
+            _ => return None,
+        };
+
+        // checked last, a malformed envelope must not move the window
+        if !self.accept(from, seq) {
+            return None;
+        }
+        Some(Envelope { from, seq, message })
+    }
+
+    fn accept(&self, from: SocketAddr, seq: u64) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        windows.entry(from).or_default().accept(seq)
+    }
+
+    // the channel once every part of it came in; one missing is sent again
+    // when the channel changes or at the next resync
+    fn reassemble(
+        &self,
+        key: (SocketAddr, u64),
+        part: u16,
+        parts: u16,
+        bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        if parts == 1 {
+            return Some(bytes.to_vec());
+        }
+        let mut partials = self.partials.lock().unwrap();
+        partials.retain(|_, p| p.started.elapsed() < Duration::from_millis(MAX_AGE_MS));
+        let partial = partials.entry(key).or_insert_with(|| Partial {
+            parts: vec![None; usize::from(parts)],
+            started: Instant::now(),
+        });
+        if partial.parts.len() != usize::from(parts) {
+            return None;
+        }
+        partial.parts[usize::from(part)] = Some(bytes.to_vec());
+        if partial.parts.iter().any(Option::is_none) {
+            return None;
+        }
+        let partial = partials.remove(&key)?;
+        Some(partial.parts.into_iter().flatten().flatten().collect())
+    }
+
+    pub async fn send(&self, to: SocketAddr, message: &Message) -> io::Result<usize> {
+        let mut sent = 0;
+        for datagram in self.seal(message, now_ms())? {
+            sent += net::send_to(&self.socket, &datagram, to).await?;
+        }
+        Ok(sent)
+    }
+
+    // to every other instance; a send that fails is logged, one that cannot
+    // be sealed is returned
+    async fn broadcast(&self, message: &Message) -> io::Result<()> {
+        let datagrams = self.seal(message, now_ms())?;
+        for node in self.nodes.iter().filter(|n| **n != self.node) {
+            for datagram in &datagrams {
+                if let Err(e) = net::send_to(&self.socket, datagram, *node).await {
+                    log!(Warn, addr = *node; "Failed to replicate to {}: {}", node, e);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // whether an update from `owner` is newer than the replica held
+    fn refresh_replica(&self, key: (String, String), owner: SocketAddr, seq: u64) -> bool {
+        let mut replicas = self.replicas.lock().unwrap();
+        if replicas
+            .get(&key)
+            .is_some_and(|r| r.owner == owner && r.seq > seq)
+        {
+            return false;
+        }
+        replicas.insert(
+            key,
+            Replica {
+                owner,
+                seq,
+                refreshed: Instant::now(),
+            },
+        );
+        true
+    }
+
+    fn expired_replicas(&self) -> Vec<(String, String)> {
+        let mut replicas = self.replicas.lock().unwrap();
+        let expired: Vec<_> = replicas
+            .iter()
+            .filter(|(_, r)| r.refreshed.elapsed() > REPLICA_TTL)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            replicas.remove(key);
+        }
+        expired
+    }
+}
+
+/// The directory of a cluster instance: a `MemoryStore` that also holds the
+/// replicas of channels other instances own, which `for_each` and
+/// `channel_count` leave to their owners.
+pub struct ClusterStore {
+    channels: MemoryStore,
+    cluster: Cluster,
+}
+
+impl ClusterStore {
+    pub fn new(cluster: Cluster) -> Self {
+        Self {
+            channels: MemoryStore::default(),
+            cluster,
+        }
+    }
+
+    fn owns(&self, server_id: &str, channel: &str) -> bool {
+        self.cluster.owner_of(server_id, channel) == self.cluster.node
+    }
+}
+
+impl ChannelStore for ClusterStore {
+    fn update(
+        &self,
+        server_id: &str,
+        channel: &str,
+        create: bool,
+        f: &mut dyn FnMut(&mut Channel),
+    ) -> bool {
+        self.channels.update(server_id, channel, create, f)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &str, &mut Channel)) {
+        self.channels.for_each(&mut |sid, name, ch| {
+            if self.owns(sid, name) {
+                f(sid, name, ch);
+            }
+        });
+    }
+
+    fn remove(&self, server_id: &str, channel: &str, only_if_empty: bool) -> Option<Channel> {
+        self.channels.remove(server_id, channel, only_if_empty)
+    }
+
+    fn insert(&self, server_id: &str, channel: &str, ch: Channel) -> Option<Channel> {
+        self.channels.insert(server_id, channel, ch)
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channels.count_where(|sid, name| self.owns(sid, name))
+    }
+
+    fn owner(&self, server_id: &str, channel: &str) -> Option<SocketAddr> {
+        let owner = self.cluster.owner_of(server_id, channel);
+        (owner != self.cluster.node).then_some(owner)
+    }
+
+    fn cluster(&self) -> Option<&Cluster> {
+        Some(&self.cluster)
+    }
+}
+
+// does what another instance asked for
+async fn receive(envelope: Envelope, socket: &Arc<UdpSocket>, state: &Arc<ServerState>) {
+    let Some(cluster) = state.cluster() else {
+        return;
+    };
+    let from = envelope.from;
+    match envelope.message {
+        // no routing here: a stale home must not bounce it back
+        Message::Deliver {
+            dst,
+            sealed,
+            packet,
+        } => {
+            let packet = if sealed {
+                state.outbound(dst, packet)
+            } else {
+                Some(packet)
+            };
+            if let Some(packet) = packet
+                && let Err(e) = net::send_to(socket, &packet, dst).await
+            {
+                log!(Warn, addr = dst; "Failed to deliver for {}: {}", from, e);
+            }
+        }
+        Message::Client { src, packet } => {
+            let Some((hdr, msg)) = control::decode_packet(&packet) else {
+                return;
+            };
+            let handled = handle_message(msg, &hdr, src, Arc::clone(socket), Arc::clone(state));
+            VIA.scope((from, src), handled).await;
+        }
+        Message::Channel(snapshot) => {
+            let (sid, name) = (snapshot.server_id.clone(), snapshot.name.clone());
+            if cluster.owner_of(&sid, &name) != from {
+                log!(
+                    Warn,
+                    server_id = &sid,
+                    channel = &name;
+                    "{} claims a channel it does not own, are the nodes the same everywhere?",
+                    from
+                );
+            } else if cluster.refresh_replica((sid, name), from, envelope.seq) {
+                state.apply_replica(from, &snapshot);
+            }
+        }
+        Message::Gone { server_id, channel } => {
+            if cluster.owner_of(&server_id, &channel) == from {
+                cluster
+                    .replicas
+                    .lock()
+                    .unwrap()
+                    .remove(&(server_id.clone(), channel.clone()));
+                state.remove_channel(&server_id, &channel);
+            }
+        }
+    }
+}
+
+// sends the channels this instance owns to the others as they change, and
+// drops replicas whose owner went quiet
+async fn replicate(state: Arc<ServerState>) {
+    let Some(cluster) = state.cluster() else {
+        return;
+    };
+    let mut sent: HashMap<(String, String), ChannelSnapshot> = HashMap::new();
+    let mut resynced = Instant::now();
+    let mut interval = tokio::time::interval(REPLICATE_EVERY);
+    loop {
+        interval.tick().await;
+        let mut current = HashMap::new();
+        state.for_each_channel(|sid, name, channel| {
+            let key = (sid.to_string(), name.to_string());
+            current.insert(key, ChannelSnapshot::capture(sid, name, channel));
+        });
+
+        let resync = resynced.elapsed() >= RESYNC_EVERY;
+        if resync {
+            resynced = Instant::now();
+        }
+        for (key, snapshot) in &current {
+            if (resync || sent.get(key) != Some(snapshot))
+                && let Err(e) = cluster.broadcast(&Message::Channel(snapshot.clone())).await
+            {
+                log!(
+                    Warn,
+                    server_id = &key.0,
+                    channel = &key.1;
+                    "Not replicating the channel: {}",
+                    e
+                );
+            }
+        }
+        for (server_id, channel) in sent.into_keys().filter(|k| !current.contains_key(k)) {
+            let gone = Message::Gone {
+                server_id: server_id.clone(),
+                channel: channel.clone(),
+            };
+            if let Err(e) = cluster.broadcast(&gone).await {
+                log!(
+                    Warn,
+                    server_id = &server_id,
+                    channel = &channel;
+                    "Not telling the others the channel is gone: {}",
+                    e
+                );
+            }
+        }
+        sent = current;
+
+        for (server_id, channel) in cluster.expired_replicas() {
+            log!(
+                Info,
+                server_id = &server_id,
+                channel = &channel;
+                "Dropping the replica, its owner went quiet"
+            );
+            state.remove_channel(&server_id, &channel);
+        }
+    }
+}
+
+/// Handles what the other instances send, answering users through `socket`
+/// (the one clients talk to), and keeps them up to date on this instance's
+/// channels. Runs until the cluster socket fails.
+pub async fn serve(socket: Arc<UdpSocket>, state: Arc<ServerState>) {
+    let Some(cluster) = state.cluster() else {
+        return;
+    };
+    tokio::spawn(replicate(Arc::clone(&state)));
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (len, src) = match net::recv_from(&cluster.socket, &mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log!(Error, "Cluster socket failed: {}", e);
+                return;
+            }
+        };
+        match cluster.open(&buf[..len]) {
+            Some(envelope) => receive(envelope, &socket, &state).await,
+            None => {
+                log!(Debug, addr = src; "Bad or replayed cluster envelope, or part of a channel")
+            }
+        }
+    }
+}
@@ -0,0 +1,580 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+pub use crate::log::{LogFilter, LogFormat, LogLevel};
+use crate::proto::{noise::KEY_LEN, token};
+
+pub const DEFAULT_PORT: u16 = 2131;
+
+/// Everything `signaling_server` can be told at startup. Values come from the
+/// defaults, then the TOML file, then `ODNP_*` environment variables, then
+/// command-line flags; later sources win.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub network: NetworkConfig,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub security: SecurityConfig,
+    pub turn: TurnConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+    pub snapshot: SnapshotConfig,
+    pub cluster: ClusterConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Address both sockets bind to; `::` (the default) serves IPv4 and IPv6.
+    pub bind: IpAddr,
+    /// Second address for the RFC 5780 style NAT tests, needs an explicit `bind`.
+    pub alt_ip: Option<IpAddr>,
+    pub port: u16,
+    /// Second port NAT probes may be answered from, `port + 1` if unset.
+    pub probe_port: Option<u16>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            bind: Ipv6Addr::UNSPECIFIED.into(),
+            alt_ip: None,
+            port: DEFAULT_PORT,
+            probe_port: None,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn probe_port(&self) -> u16 {
+        self.probe_port.unwrap_or(self.port.wrapping_add(1))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// How often relays are pinged and silent users looked for.
+    pub heartbeat_secs: u64,
+    /// A user not heard from for this long is dropped from its channel.
+    pub user_timeout_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            heartbeat_secs: 20,
+            user_timeout_secs: 40,
+        }
+    }
+}
+
+/// Caps on what clients may create; `None` is unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_channels: Option<usize>,
+    pub max_users_per_channel: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// Secret join tokens are signed with, see `proto::token`.
+    pub join_secret: Option<String>,
+    /// Hex X25519 private key of the Noise signaling sessions.
+    pub signaling_key: Option<String>,
+    pub plain_signaling: bool,
+}
+
+/// The TURN (RFC 8656) relay for third-party WebRTC stacks, off unless `port`
+/// is set. Credentials are derived from join tokens, see `token::turn_credentials`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TurnConfig {
+    pub port: Option<u16>,
+    /// Address relayed transport addresses are allocated on, `bind` if unset.
+    pub relay_ip: Option<IpAddr>,
+    pub realm: String,
+    pub max_allocations: Option<usize>,
+    /// Peer addresses one allocation may hold permissions for.
+    pub max_permissions: Option<usize>,
+    /// Bytes one allocation may relay per second, both directions together.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Loopback, private and link-local peers that may be relayed to anyway.
+    pub allowed_peers: Vec<IpRange>,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        Self {
+            port: None,
+            relay_ip: None,
+            realm: "od-nat-piercer".to_string(),
+            max_allocations: None,
+            max_permissions: None,
+            max_bytes_per_sec: None,
+            allowed_peers: Vec::new(),
+        }
+    }
+}
+
+/// An address block written `10.0.0.0/8`, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let bits = |prefix: u8, len: u32| len - prefix as u32;
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) ^ u32::from(ip))
+                .checked_shr(bits(self.prefix, 32))
+                .is_none_or(|rest| rest == 0),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net) ^ u128::from(ip))
+                .checked_shr(bits(self.prefix, 128))
+                .is_none_or(|rest| rest == 0),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("expected an address or address/prefix, got {s:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(p) => p
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("prefix of {s:?} must be 0 to {max}"))?,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The admin HTTP API, off unless `listen` is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub listen: Option<SocketAddr>,
+    /// Bearer token every request must carry.
+    pub token: Option<String>,
+}
+
+/// Prometheus metrics at `GET /metrics`, off unless `listen` is set. There is
+/// no authentication, keep it on an address only the scraper reaches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    /// Levels for single modules, e.g. `"signaling::turn" = "debug"`.
+    pub targets: BTreeMap<String, LogLevel>,
+    pub format: LogFormat,
+}
+
+impl LogConfig {
+    pub fn filter(&self) -> LogFilter {
+        LogFilter::new(self.level, self.targets.clone())
+    }
+}
+
+/// Periodic snapshots of the channel directory, off unless `path` is set. The
+/// file is read back on startup so clients can RESUME their sessions; it holds
+/// their session nonces, keep it private.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    pub path: Option<PathBuf>,
+    pub interval_secs: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval_secs: 30,
+        }
+    }
+}
+
+/// Several instances serving the same channels, off unless `listen` is set.
+/// Each channel is owned by one of `nodes`, the others keep a replica and
+/// forward packets for each other's users over `listen`, see `signaling::cluster`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub listen: Option<SocketAddr>,
+    /// How the other instances reach `listen`, needed if it is a wildcard.
+    pub advertise: Option<SocketAddr>,
+    /// Every instance of the cluster, as it is known there; the same list
+    /// everywhere (this instance may be left out).
+    pub nodes: Vec<SocketAddr>,
+    /// Secret the forwarded packets are authenticated with, the same everywhere.
+    pub secret: Option<String>,
+}
+
+impl ClusterConfig {
+    /// The address this instance is known by in the cluster.
+    pub fn node(&self) -> Option<SocketAddr> {
+        self.advertise.or(self.listen)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    /// A flag or environment variable that is unknown or has a bad value.
+    Override(String),
+    Invalid(String),
+    /// `--help` was asked for.
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Override(e) | ConfigError::Invalid(e) => f.write_str(e),
+            ConfigError::Help => f.write_str(USAGE),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub const USAGE: &str = "\
+Usage: signaling_server [--config <file.toml>] [--bind <ip>] [--alt-ip <ip>]
+                        [--port <port>] [--probe-port <port>]
+                        [--heartbeat-secs <n>] [--user-timeout-secs <n>]
+                        [--max-channels <n>] [--max-users-per-channel <n>]
+                        [--plain-signaling] [--turn-port <port>]
+                        [--turn-relay-ip <ip>] [--admin-listen <ip:port>]
+                        [--metrics-listen <ip:port>]
+                        [--log-level <level>[,<module>=<level>...]]
+                        [--log-format text|json]
+                        [--snapshot-path <file>] [--snapshot-interval-secs <n>]
+                        [--cluster-listen <ip:port>]
+                        [--cluster-advertise <ip:port>]
+                        [--cluster-nodes <ip:port>[,<ip:port>...]]
+
+Secrets are not taken on the command line: set ODNP_JOIN_SECRET,
+ODNP_SIGNALING_KEY, ODNP_ADMIN_TOKEN and ODNP_CLUSTER_SECRET, or put them
+in the [security], [admin] and [cluster] sections of the file.";
+
+// environment variable -> setting; NAT_PIERCER_PORT is the name the standard uses
+const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("ODNP_PRIMARY_IP", "bind"),
+    ("ODNP_ALT_IP", "alt-ip"),
+    ("NAT_PIERCER_PORT", "port"),
+    ("ODNP_PROBE_PORT", "probe-port"),
+    ("ODNP_HEARTBEAT_SECS", "heartbeat-secs"),
+    ("ODNP_USER_TIMEOUT_SECS", "user-timeout-secs"),
+    ("ODNP_MAX_CHANNELS", "max-channels"),
+    ("ODNP_MAX_USERS_PER_CHANNEL", "max-users-per-channel"),
+    ("ODNP_JOIN_SECRET", "join-secret"),
+    ("ODNP_SIGNALING_KEY", "signaling-key"),
+    ("ODNP_PLAIN_SIGNALING", "plain-signaling"),
+    ("ODNP_TURN_PORT", "turn-port"),
+    ("ODNP_TURN_RELAY_IP", "turn-relay-ip"),
+    ("ODNP_ADMIN_LISTEN", "admin-listen"),
+    ("ODNP_ADMIN_TOKEN", "admin-token"),
+    ("ODNP_METRICS_LISTEN", "metrics-listen"),
+    ("ODNP_LOG_LEVEL", "log-level"),
+    ("ODNP_LOG_FORMAT", "log-format"),
+    ("ODNP_SNAPSHOT_PATH", "snapshot-path"),
+    ("ODNP_SNAPSHOT_INTERVAL_SECS", "snapshot-interval-secs"),
+    ("ODNP_CLUSTER_LISTEN", "cluster-listen"),
+    ("ODNP_CLUSTER_ADVERTISE", "cluster-advertise"),
+    ("ODNP_CLUSTER_NODES", "cluster-nodes"),
+    ("ODNP_CLUSTER_SECRET", "cluster-secret"),
+];
+
+// settings that must not show up in `ps`
+const SECRET_SETTINGS: &[&str] = &[
+    "join-secret",
+    "signaling-key",
+    "admin-token",
+    "cluster-secret",
+];
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Override(format!("{name}: invalid value {value:?}")))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, ConfigError> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(ConfigError::Override(format!(
+            "{name}: expected true/false or 1/0, got {value:?}"
+        ))),
+    }
+}
+
+impl ServerConfig {
+    pub fn from_toml(path: impl Into<PathBuf>) -> Result<Self, ConfigError> {
+        let path = path.into();
+        let text =
+            std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path, e.message().to_string()))
+    }
+
+    /// Builds the configuration from `args` (without the program name) and the
+    /// environment, then validates it.
+    pub fn load(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut flags = Vec::new();
+        let mut file = env("ODNP_CONFIG").map(PathBuf::from);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(ConfigError::Override(format!(
+                    "unexpected argument {arg:?}"
+                )));
+            };
+            if name == "help" {
+                return Err(ConfigError::Help);
+            }
+            if SECRET_SETTINGS.contains(&name) {
+                return Err(ConfigError::Override(format!(
+                    "--{name} is not accepted on the command line, use the environment or the config file"
+                )));
+            }
+            let value = match name {
+                "plain-signaling" => "true".to_string(),
+                _ => args
+                    .next()
+                    .ok_or_else(|| ConfigError::Override(format!("--{name} needs a value")))?,
+            };
+            match name {
+                "config" => file = Some(value.into()),
+                _ => flags.push((format!("--{name}"), name.to_string(), value)),
+            }
+        }
+
+        let mut config = match file {
+            Some(path) => Self::from_toml(path)?,
+            None => Self::default(),
+        };
+        for (var, setting) in ENV_OVERRIDES {
+            if let Some(value) = env(var).filter(|v| !v.is_empty()) {
+                config.set(var, setting, &value)?;
+            }
+        }
+        for (flag, setting, value) in flags {
+            config.set(&flag, &setting, &value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    // `name` is what the value came from, for error messages
+    fn set(&mut self, name: &str, setting: &str, value: &str) -> Result<(), ConfigError> {
+        match setting {
+            "bind" => self.network.bind = parse(name, value)?,
+            "alt-ip" => self.network.alt_ip = Some(parse(name, value)?),
+            "port" => self.network.port = parse(name, value)?,
+            "probe-port" => self.network.probe_port = Some(parse(name, value)?),
+            "heartbeat-secs" => self.timeouts.heartbeat_secs = parse(name, value)?,
+            "user-timeout-secs" => self.timeouts.user_timeout_secs = parse(name, value)?,
+            "max-channels" => self.limits.max_channels = Some(parse(name, value)?),
+            "max-users-per-channel" => {
+                self.limits.max_users_per_channel = Some(parse(name, value)?)
+            }
+            "join-secret" => self.security.join_secret = Some(value.to_string()),
+            "signaling-key" => self.security.signaling_key = Some(value.to_string()),
+            "plain-signaling" => self.security.plain_signaling = parse_bool(name, value)?,
+            "turn-port" => self.turn.port = Some(parse(name, value)?),
+            "turn-relay-ip" => self.turn.relay_ip = Some(parse(name, value)?),
+            "admin-listen" => self.admin.listen = Some(parse(name, value)?),
+            "admin-token" => self.admin.token = Some(value.to_string()),
+            "metrics-listen" => self.metrics.listen = Some(parse(name, value)?),
+            "log-level" => {
+                let filter: LogFilter = value
+                    .parse()
+                    .map_err(|e| ConfigError::Override(format!("{name}: {e}")))?;
+                self.log.level = filter.level();
+                self.log.targets.extend(filter.targets().iter().cloned());
+            }
+            "log-format" => {
+                self.log.format = match value {
+                    "text" => LogFormat::Text,
+                    "json" => LogFormat::Json,
+                    _ => {
+                        return Err(ConfigError::Override(format!(
+                            "{name}: expected text or json, got {value:?}"
+                        )));
+                    }
+                }
+            }
+            "snapshot-path" => self.snapshot.path = Some(value.into()),
+            "snapshot-interval-secs" => self.snapshot.interval_secs = parse(name, value)?,
+            "cluster-listen" => self.cluster.listen = Some(parse(name, value)?),
+            "cluster-advertise" => self.cluster.advertise = Some(parse(name, value)?),
+            "cluster-nodes" => {
+                self.cluster.nodes = value
+                    .split(',')
+                    .map(|node| parse(name, node.trim()))
+                    .collect::<Result<_, _>>()?;
+            }
+            "cluster-secret" => self.cluster.secret = Some(value.to_string()),
+            _ => return Err(ConfigError::Override(format!("unknown option {name}"))),
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+        let net = &self.network;
+
+        if net.port == 0 || net.probe_port() == 0 {
+            return invalid("port and probe_port must not be 0".into());
+        }
+        if net.port == net.probe_port() {
+            return invalid(format!("port and probe_port are both {}", net.port));
+        }
+        if let Some(alt) = net.alt_ip {
+            if net.bind.is_unspecified() {
+                return invalid("alt_ip needs an explicit bind address".into());
+            }
+            if alt == net.bind || alt.is_ipv4() != net.bind.is_ipv4() {
+                return invalid(format!(
+                    "alt_ip {alt} must be a different address of the same family as bind {}",
+                    net.bind
+                ));
+            }
+        }
+
+        let t = &self.timeouts;
+        if t.heartbeat_secs == 0 {
+            return invalid("heartbeat_secs must be at least 1".into());
+        }
+        // users only answer when pinged, a shorter timeout drops them all
+        if t.user_timeout_secs <= t.heartbeat_secs {
+            return invalid(format!(
+                "user_timeout_secs ({}) must be longer than heartbeat_secs ({})",
+                t.user_timeout_secs, t.heartbeat_secs
+            ));
+        }
+
+        if self.limits.max_channels == Some(0) || self.limits.max_users_per_channel == Some(0) {
+            return invalid("limits must be at least 1, leave them out for no limit".into());
+        }
+
+        if self.security.join_secret.as_deref() == Some("") {
+            return invalid("join_secret must not be empty".into());
+        }
+        if let Some(key) = &self.security.signaling_key
+            && token::from_hex(key).is_none_or(|k| k.len() != KEY_LEN)
+        {
+            return invalid(format!(
+                "signaling_key must be {} hex characters (see signaling_key)",
+                KEY_LEN * 2
+            ));
+        }
+
+        let turn = &self.turn;
+        if let Some(port) = turn.port {
+            if port == 0 || port == net.port || port == net.probe_port() {
+                return invalid(format!(
+                    "turn port {port} must be non-zero and differ from port and probe_port"
+                ));
+            }
+            // TURN passwords are derived from join tokens
+            if self.security.join_secret.is_none() {
+                return invalid("turn needs a join_secret".into());
+            }
+            if turn.relay_ip.unwrap_or(net.bind).is_unspecified() {
+                return invalid("turn needs relay_ip or an explicit bind address".into());
+            }
+            if turn.max_allocations == Some(0)
+                || turn.max_permissions == Some(0)
+                || turn.max_bytes_per_sec == Some(0)
+            {
+                return invalid(
+                    "turn limits must be at least 1, leave them out for no limit".into(),
+                );
+            }
+        }
+
+        if self.admin.token.as_deref() == Some("") {
+            return invalid("admin token must not be empty".into());
+        }
+        if self.admin.listen.is_some() && self.admin.token.is_none() {
+            return invalid("the admin API needs a token (ODNP_ADMIN_TOKEN)".into());
+        }
+        if let (Some(admin), Some(metrics)) = (self.admin.listen, self.metrics.listen)
+            && admin == metrics
+        {
+            return invalid(format!("admin and metrics both listen on {admin}"));
+        }
+
+        if self.snapshot.interval_secs == 0 {
+            return invalid("snapshot interval_secs must be at least 1".into());
+        }
+
+        let cluster = &self.cluster;
+        if cluster.secret.as_deref() == Some("") {
+            return invalid("cluster secret must not be empty".into());
+        }
+        if let Some(listen) = cluster.listen {
+            if cluster.nodes.is_empty() {
+                return invalid("the cluster needs its nodes".into());
+            }
+            if cluster.secret.is_none() {
+                return invalid("the cluster needs a secret (ODNP_CLUSTER_SECRET)".into());
+            }
+            if cluster
+                .node()
+                .is_some_and(|node| node.ip().is_unspecified())
+            {
+                return invalid(format!(
+                    "cluster listen {listen} is a wildcard, set advertise"
+                ));
+            }
+        } else if !cluster.nodes.is_empty() || cluster.advertise.is_some() {
+            return invalid("cluster nodes and advertise need cluster listen".into());
+        }
+        Ok(())
+    }
+}
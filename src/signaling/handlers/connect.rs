@@ -1,90 +1,181 @@
 use crate::{
-    proto::control_text::{MSG_WELCOME, NAT_TYPE_CONE, NAT_TYPE_PUBLIC, NAT_TYPE_SYMMETRIC},
+    log,
+    proto::{
+        control::ControlMessage,
+        token::{self, TokenError},
+    },
     signaling::{
-        structures::{NatKind, ServerMap},
-        utils::generate_channel_id,
+        cluster,
+        state::{PeerLocation, ServerState},
+        structures::{Candidate, NatKind, PortHint, User},
+        utils::{Outbox, flush_outbox, mode_direct, queue_control, send_control},
     },
 };
 
 use crate::proto::packet::{self, Header};
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::UdpSocket, sync::Mutex};
+use tokio::net::UdpSocket;
 
 use super::{
     notifications::handle_connect_notifications,
     utils::{add_new_user, remove_user_from_other_channels, update_existing_user},
 };
 
-async fn send_welcome(socket: &Arc<UdpSocket>, dst: SocketAddr, channel_id: u64, peer_id: u32) {
-    let payload = format!("{MSG_WELCOME} to cid:{channel_id} with pid:{peer_id}\n");
-    let hdr = Header::welcome(channel_id, peer_id, payload.len() as u16);
+pub fn welcome_packet(channel_id: u64, user: &User) -> Vec<u8> {
+    let payload = ControlMessage::Welcome {
+        channel_id,
+        peer_id: user.peer_id,
+        nonce: user.nonce,
+    }
+    .encode()
+    .expect("WELCOME has no variable-length fields");
+    let hdr = Header::welcome(channel_id, user.peer_id, payload.len() as u16);
 
-    let pkt = packet::encode(hdr, payload.as_bytes());
-    let _ = socket.send_to(&pkt, dst).await;
+    packet::encode(hdr, &payload)
+}
+
+// without a configured secret the server stays open and tokens are ignored
+pub fn check_join_token(
+    state: &ServerState,
+    server_id: &str,
+    channel_name: &str,
+    user_name: &str,
+    join_token: &[u8],
+) -> Result<(), TokenError> {
+    let Some(secret) = state.join_secret() else {
+        return Ok(());
+    };
+    token::verify(
+        secret,
+        join_token,
+        server_id,
+        channel_name,
+        user_name,
+        token::now_secs(),
+    )
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_connect_message(
-    parts: &[&str],
+    server_id: String,
+    channel_name: String,
+    user_name: String,
+    nat_kind: NatKind,
+    port_hint: PortHint,
+    fingerprint: Vec<u8>,
+    candidates: Vec<Candidate>,
+    check_key: Vec<u8>,
+    join_token: &[u8],
     src: SocketAddr,
     socket: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
+    state: Arc<ServerState>,
 ) {
-    let server_id = parts[1].to_string();
-    let channel_name = parts[2].to_string();
-    let user_name = parts[3].to_string();
     let src_addr = src;
 
-    let nat_kind = if parts.len() >= 5 {
-        match parts[4] {
-            NAT_TYPE_SYMMETRIC => NatKind::Symmetric,
-            NAT_TYPE_CONE => NatKind::Cone,
-            NAT_TYPE_PUBLIC => NatKind::Public,
-            _ => NatKind::Unknown,
-        }
-    } else {
-        NatKind::Unknown
+    let reject = async |reason: String| {
+        log!(
+            Info,
+            server_id = &server_id,
+            channel = &channel_name,
+            user = &user_name,
+            addr = src_addr;
+            "Rejecting CONNECT: {}",
+            reason
+        );
+        let reply = ControlMessage::Rejected { reason };
+        let _ = send_control(&socket, &state, &reply, src_addr).await;
     };
 
-    let (users_to_notify, is_new_user, peer_id, channel_id) = {
-        let mut st = state.lock().await;
-
-        remove_user_from_other_channels(&mut st, &server_id, &channel_name, &user_name, src_addr)
-            .await;
+    if let Err(e) = check_join_token(&state, &server_id, &channel_name, &user_name, join_token) {
+        reject(e.to_string()).await;
+        return;
+    }
 
-        let channels = st.entry(server_id.clone()).or_default();
-        let channel = channels.entry(channel_name.clone()).or_default();
+    let limits = state.limits();
+    if let Some(max) = limits.max_channels
+        && state
+            .with_channel(&server_id, &channel_name, |_| ())
+            .is_none()
+        && state.channel_count() >= max
+    {
+        reject("too many channels".into()).await;
+        return;
+    }
 
-        if channel.channel_id == 0 {
-            channel.channel_id = generate_channel_id();
-        }
+    remove_user_from_other_channels(&state, &server_id, &channel_name, src_addr);
 
+    let mut outbox = Outbox::new();
+    let mut full = false;
+    state.with_channel_or_create(&server_id, &channel_name, |channel| {
         let channel_id = channel.channel_id;
 
-        if let Some((updated_channel, is_new, peer_id)) =
-            update_existing_user(channel, &user_name, src_addr).await
+        if let Some(peer_id) = update_existing_user(channel, &user_name, src_addr) {
+            // a repeated CONNECT keeps the session, and with it the nonce
+            let Some(user) = channel.users.iter_mut().find(|u| u.peer_id == peer_id) else {
+                return;
+            };
+            user.home = cluster::via();
+            outbox.push((src_addr, welcome_packet(channel_id, user)));
+
+            // a restarted client brings a new certificate and check key, peers
+            // must pin them again; new interfaces mean new candidates to check
+            if user.fingerprint != fingerprint
+                || user.candidates != candidates
+                || user.check_key != check_key
+            {
+                user.fingerprint = fingerprint;
+                user.candidates = candidates;
+                user.check_key = check_key;
+                let user = user.clone();
+                for other in channel.users.iter().filter(|u| u.peer_id != peer_id) {
+                    queue_control(&mut outbox, &mode_direct(&user, other.addr), other.addr);
+                }
+            }
+            return;
+        }
+
+        if limits
+            .max_users_per_channel
+            .is_some_and(|max| channel.users.len() >= max)
         {
-            (updated_channel, is_new, peer_id, channel_id)
-        } else {
-            let (update_channel, peer_id) =
-                add_new_user(channel, &user_name, src_addr, &socket, nat_kind).await;
-            (update_channel, true, peer_id, channel_id)
+            full = true;
+            return;
         }
-    };
 
-    send_welcome(&socket, src_addr, channel_id, peer_id).await;
+        // a second user with the same name is a different peer; a stale session
+        // of a reconnecting user ages out through the heartbeat
+        let peer_id = add_new_user(
+            channel,
+            &user_name,
+            src_addr,
+            &mut outbox,
+            nat_kind,
+            port_hint,
+            fingerprint,
+            candidates,
+            check_key,
+        );
+        let last = channel.users.len() - 1;
+        channel.users[last].home = cluster::via();
+        let new_user = channel.users[last].clone();
+        // WELCOME goes first so the client knows its peer_id before any MODE
+        outbox.insert(0, (src_addr, welcome_packet(channel_id, &new_user)));
+        state.index_peer(
+            src_addr,
+            PeerLocation {
+                server_id: server_id.clone(),
+                channel: channel_name.clone(),
+                peer_id,
+            },
+        );
+
+        handle_connect_notifications(channel, &new_user, &mut outbox);
+    });
 
-    if !is_new_user {
+    if full {
+        // an empty channel was never full, so this one is not left behind empty
+        reject("channel is full".into()).await;
         return;
     }
-
-    handle_connect_notifications(
-        &server_id,
-        &channel_name,
-        &user_name,
-        src_addr,
-        users_to_notify,
-        socket,
-        state,
-    )
-    .await;
+    flush_outbox(&socket, &state, outbox).await;
 }
@@ -1,34 +1,57 @@
-use crate::signaling::structures::ServerMap;
+use crate::log;
+use crate::signaling::{
+    state::ServerState,
+    utils::{Outbox, flush_outbox},
+};
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::UdpSocket, sync::Mutex};
+use tokio::net::UdpSocket;
 
-use super::{notifications::handle_disconnect_notifications, utils::handle_user_removal};
+use super::{
+    notifications::handle_disconnect_notifications,
+    utils::{find_and_remove_user, update_relay_after_departure},
+};
 
 pub async fn handle_disconnect_message(
-    parts: &[&str],
+    server_id: String,
+    channel_name: String,
+    user_name: String,
     src: SocketAddr,
     socket: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
+    state: Arc<ServerState>,
 ) {
-    let server_id = parts[1].to_string();
-    let channel_name = parts[2].to_string();
-    let user_name = parts[3].to_string();
     let src_addr = src;
+    let mut outbox = Outbox::new();
 
     //We'll only remove the user if the src matches the stored addr for that username
-    let (remaining_users, was_relay, leaving_user_addr, lone_user_addr) =
-        handle_user_removal(&state, &server_id, &channel_name, &user_name, src_addr).await;
+    state.with_channel(&server_id, &channel_name, |channel| {
+        let Some((was_relay, leaving)) = find_and_remove_user(channel, &user_name, src_addr) else {
+            log!(
+                Info,
+                server_id = &server_id,
+                channel = &channel_name,
+                user = &user_name,
+                addr = src_addr;
+                "Ignoring DISCONNECT (no matching session)"
+            );
+            return;
+        };
 
-    handle_disconnect_notifications(
-        remaining_users,
-        was_relay,
-        leaving_user_addr,
-        lone_user_addr,
-        &user_name,
-        socket,
-        &Arc::clone(&state),
-        server_id,
-        channel_name,
-    )
-    .await;
+        state.unindex_peer(src_addr, &server_id, &channel_name);
+        let lone_user_addr = update_relay_after_departure(channel, was_relay);
+
+        log!(
+            Info,
+            server_id = &server_id,
+            channel = &channel_name,
+            user = &user_name,
+            peer_id = leaving.peer_id,
+            addr = src_addr;
+            "User {} left",
+            user_name
+        );
+
+        handle_disconnect_notifications(channel, was_relay, &leaving, lone_user_addr, &mut outbox);
+    });
+
+    flush_outbox(&socket, &state, outbox).await;
 }
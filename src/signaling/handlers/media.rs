@@ -0,0 +1,83 @@
+use crate::{
+    log,
+    proto::packet::{BROADCAST, Header},
+    signaling::{
+        metrics::{self, Relayed},
+        state::ServerState,
+        structures::Channel,
+        utils::send_media,
+    },
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::UdpSocket;
+
+// Where a DTLS/SRTP packet from `src` goes, or None if `src` does not own
+// `src_peer_id` in the header's channel
+pub fn media_targets(channel: &Channel, hdr: &Header, src: SocketAddr) -> Option<Vec<SocketAddr>> {
+    if channel.channel_id != hdr.channel_id {
+        return None;
+    }
+    if !channel
+        .users
+        .iter()
+        .any(|u| u.addr == src && u.peer_id == hdr.src_peer_id)
+    {
+        return None;
+    }
+
+    let targets = channel
+        .users
+        .iter()
+        .filter(|u| u.addr != src)
+        .filter(|u| hdr.dst_peer_id == BROADCAST || u.peer_id == hdr.dst_peer_id)
+        .map(|u| u.addr)
+        .collect();
+    Some(targets)
+}
+
+pub async fn handle_media(
+    hdr: &Header,
+    raw: &[u8],
+    src: SocketAddr,
+    socket: Arc<UdpSocket>,
+    state: Arc<ServerState>,
+) {
+    let targets = state
+        .locate(src)
+        .filter(|loc| loc.peer_id == hdr.src_peer_id)
+        .and_then(|loc| {
+            state.with_channel(&loc.server_id, &loc.channel, |channel| {
+                media_targets(channel, hdr, src)
+            })
+        })
+        .flatten();
+
+    let Some(targets) = targets else {
+        log!(
+            Debug,
+            peer_id = hdr.src_peer_id,
+            addr = src;
+            "Dropping {:?}: not a peer of channel {}",
+            hdr.kind,
+            hdr.channel_id
+        );
+        return;
+    };
+
+    let kind = Relayed::media(hdr.kind);
+    if let Some(kind) = kind
+        && !targets.is_empty()
+    {
+        metrics::RELAYED_PACKET_SIZE[kind].observe(raw.len() as f64);
+    }
+    for addr in targets {
+        match send_media(&socket, &state, raw, addr).await {
+            Ok(_) => {
+                if let Some(kind) = kind {
+                    metrics::RELAYED_BYTES[kind].add(raw.len() as u64);
+                }
+            }
+            Err(e) => log!(Warn, addr = addr; "Failed to forward {:?}: {}", hdr.kind, e),
+        }
+    }
+}
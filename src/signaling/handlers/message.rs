@@ -1,68 +1,302 @@
-use crate::proto::control_text::{
-    MSG_CONNECT, MSG_DATA, MSG_DISCONNECT, MSG_HB, MSG_NAT_PROBE, MSG_NAT_SEEN, MSG_PEER_TIMEOUT,
-    MSG_PONG, MSG_REQUEST_RELAY,
+use crate::log;
+use crate::proto::{
+    control::{self, ControlMessage},
+    packet::{self, HEADER_LEN, Header, Kind},
+};
+use crate::signaling::{
+    cluster::{self, Message},
+    metrics,
+    state::ServerState,
+    utils::send_control,
 };
-use crate::signaling::structures::ServerMap;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::UdpSocket, sync::Mutex};
+use tokio::{net::UdpSocket, task::JoinHandle};
 
 use super::{
     connect::handle_connect_message,
     disconnect::handle_disconnect_message,
     heartbeat::{handle_heartbeat, handle_pong},
+    media::handle_media,
     notifications::handle_peer_timeout,
     request_relay::{handle_data_from_client, handle_relay_request},
+    resume::handle_resume,
+    utils::authenticate_sender,
 };
 
-pub async fn handle_message(
-    msg: String,
+/// Handles `buf` on a task of its own, so a channel that is slow to handle
+/// does not hold up the datagrams of other channels behind it; each channel
+/// stays consistent under its own lock.
+pub fn spawn_packet(
+    buf: Vec<u8>,
+    src: SocketAddr,
+    socket: Arc<UdpSocket>,
+    state: Arc<ServerState>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move { handle_packet(&buf, src, socket, state).await })
+}
+
+// CONTROL goes to handle_message, DTLS/SRTP is forwarded by header
+pub async fn handle_packet(
+    buf: &[u8],
     src: SocketAddr,
     socket: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
+    state: Arc<ServerState>,
 ) {
-    if msg.starts_with(MSG_NAT_PROBE) {
-        let reply = format!("{MSG_NAT_SEEN} {src}\n");
-        let _ = socket.send_to(reply.as_bytes(), src).await;
+    let Some((hdr, payload)) = packet::decode(buf) else {
+        metrics::MALFORMED_PACKETS.inc();
+        log!(
+            Debug,
+            addr = src;
+            "Unknown/Bad packet ({} bytes)",
+            buf.len()
+        );
         return;
+    };
+
+    match hdr.kind {
+        Kind::Control => match ControlMessage::decode(payload) {
+            Some(msg) => handle_message(msg, &hdr, src, socket, state).await,
+            None => {
+                metrics::MALFORMED_PACKETS.inc();
+                log!(Debug, addr = src; "Bad CONTROL payload");
+            }
+        },
+        Kind::Dtls | Kind::Srtp => {
+            let raw = &buf[..HEADER_LEN + payload.len()];
+            handle_media(&hdr, raw, src, socket, state).await;
+        }
+        // sessions are unwrapped by ServerState::inbound before this
+        Kind::Noise => log!(Debug, addr = src; "Unexpected Noise packet"),
+    }
+}
+
+// every message after CONNECT must come from an address that holds an
+// authenticated session in the channel it names, echoing that session's nonce
+// when the message carries one; `relay_only` further limits it to the relay
+fn bound_to_session(
+    state: &ServerState,
+    msg: &ControlMessage,
+    server_id: &str,
+    channel: &str,
+    src: SocketAddr,
+    nonce: Option<u64>,
+    relay_only: bool,
+) -> bool {
+    let verdict = if state.session_in(src, server_id, channel).is_none() {
+        Err("no session")
+    } else {
+        state
+            .with_channel(server_id, channel, |ch| {
+                let Some(nonce) = nonce else {
+                    return Ok(());
+                };
+                let Some(sender) = authenticate_sender(ch, src, nonce) else {
+                    return Err("bad nonce");
+                };
+                if relay_only && ch.relay != Some(sender.peer_id) {
+                    return Err("not the relay");
+                }
+                Ok(())
+            })
+            .unwrap_or(Err("no session"))
+    };
+
+    match verdict {
+        Ok(()) => true,
+        Err(reason) => {
+            log!(
+                Info,
+                server_id = server_id,
+                channel = channel,
+                addr = src;
+                "Rejecting {}: {}",
+                msg.name(),
+                reason
+            );
+            false
+        }
     }
+}
 
-    let parts: Vec<&str> = msg.trim().split_whitespace().collect();
+// the instance owning the channel `msg` changes, if that is not this one;
+// DATA only needs the relay, which a replica knows as well
+fn owner_for(state: &ServerState, msg: &ControlMessage, src: SocketAddr) -> Option<SocketAddr> {
+    match msg {
+        ControlMessage::Connect {
+            server_id, channel, ..
+        }
+        | ControlMessage::Disconnect {
+            server_id, channel, ..
+        }
+        | ControlMessage::Heartbeat {
+            server_id, channel, ..
+        }
+        | ControlMessage::RequestRelay {
+            server_id, channel, ..
+        }
+        | ControlMessage::PeerTimeout {
+            server_id, channel, ..
+        } => state.owner(server_id, channel),
+        ControlMessage::Resume { channel_id, .. } => {
+            let (sid, name) = state.find_channel(*channel_id)?;
+            state.owner(&sid, &name)
+        }
+        ControlMessage::Pong => {
+            let loc = state.locate(src)?;
+            state.owner(&loc.server_id, &loc.channel)
+        }
+        _ => None,
+    }
+}
 
-    if msg.trim() == MSG_PONG {
-        handle_pong(src, state).await;
+pub async fn handle_message(
+    msg: ControlMessage,
+    hdr: &Header,
+    src: SocketAddr,
+    socket: Arc<UdpSocket>,
+    state: Arc<ServerState>,
+) {
+    // the owner checks the session itself, it holds the channel
+    if cluster::via().is_none()
+        && let Some(owner) = owner_for(&state, &msg, src)
+        && let Some(cluster) = state.cluster()
+    {
+        // it was decoded from a datagram, so it fits one again
+        let Ok(packet) =
+            control::encode_packet(hdr.channel_id, hdr.src_peer_id, hdr.dst_peer_id, &msg)
+        else {
+            return;
+        };
+        if let Err(e) = cluster.send(owner, &Message::Client { src, packet }).await {
+            log!(Warn, addr = src; "Failed to pass {} on to {}: {}", msg.name(), owner, e);
+        }
         return;
     }
 
-    if parts.len() >= 4 && parts[0] == MSG_HB {
-        handle_heartbeat(&parts, src, state).await;
+    let binding = match &msg {
+        ControlMessage::Disconnect {
+            server_id, channel, ..
+        } => Some((server_id, channel, None, false)),
+        ControlMessage::Heartbeat {
+            server_id,
+            channel,
+            nonce,
+            ..
+        }
+        | ControlMessage::RequestRelay {
+            server_id,
+            channel,
+            nonce,
+            ..
+        } => Some((server_id, channel, Some(*nonce), false)),
+        // only the relay pings peers, so only it can tell one timed out
+        ControlMessage::PeerTimeout {
+            server_id,
+            channel,
+            nonce,
+            ..
+        } => Some((server_id, channel, Some(*nonce), true)),
+        _ => None,
+    };
+    if let Some((server_id, channel, nonce, relay_only)) = binding
+        && !bound_to_session(&state, &msg, server_id, channel, src, nonce, relay_only)
+    {
         return;
     }
 
-    if parts.len() >= 1 {
-        match parts[0] {
-            MSG_CONNECT if parts.len() >= 4 => {
-                handle_connect_message(&parts, src, socket, state).await;
-            }
+    match msg {
+        // probes that ask for another address/port are answered by ProbeSockets
+        ControlMessage::NatProbe { seq, .. } => {
+            let seen = ControlMessage::NatSeen {
+                addr: src,
+                seq,
+                other: None,
+            };
+            let _ = send_control(&socket, &state, &seen, src).await;
+        }
 
-            MSG_DISCONNECT if parts.len() >= 4 => {
-                handle_disconnect_message(&parts, src, socket, state).await;
-            }
+        ControlMessage::Pong => handle_pong(src, &state),
 
-            MSG_PEER_TIMEOUT if parts.len() >= 4 => {
-                handle_peer_timeout(&parts, src, socket, state).await;
-            }
+        ControlMessage::Heartbeat {
+            server_id,
+            channel,
+            user,
+            ..
+        } => {
+            handle_heartbeat(&server_id, &channel, &user, src, &state);
+            // the answer tells the client the server still knows its session
+            let _ = send_control(&socket, &state, &ControlMessage::Pong, src).await;
+        }
 
-            MSG_REQUEST_RELAY if parts.len() >= 4 => {
-                handle_relay_request(&parts, src, socket, state).await;
-            }
+        ControlMessage::Connect {
+            server_id,
+            channel,
+            user,
+            nat_kind,
+            token,
+            port_hint,
+            fingerprint,
+            candidates,
+            check_key,
+        } => {
+            handle_connect_message(
+                server_id,
+                channel,
+                user,
+                nat_kind,
+                port_hint,
+                fingerprint,
+                candidates,
+                check_key,
+                &token,
+                src,
+                socket,
+                state,
+            )
+            .await;
+        }
 
-            MSG_DATA if parts.len() >= 3 => {
-                handle_data_from_client(&msg, src, socket, state).await;
-            }
+        ControlMessage::Resume {
+            channel_id,
+            peer_id,
+            nonce,
+        } => {
+            handle_resume(channel_id, peer_id, nonce, src, socket, state).await;
+        }
 
-            _ => {
-                println!("Unknown/Bad packet from {}: {}", src, msg.trim());
-            }
+        ControlMessage::Disconnect {
+            server_id,
+            channel,
+            user,
+        } => {
+            handle_disconnect_message(server_id, channel, user, src, socket, state).await;
+        }
+
+        ControlMessage::PeerTimeout {
+            server_id,
+            channel,
+            peer_id,
+            ..
+        } => {
+            handle_peer_timeout(server_id, channel, peer_id, src, socket, state).await;
+        }
+
+        ControlMessage::RequestRelay {
+            server_id,
+            channel,
+            peer_id,
+            ..
+        } => {
+            handle_relay_request(&server_id, &channel, peer_id, src, socket, state).await;
+        }
+
+        ControlMessage::Data { .. } => {
+            handle_data_from_client(&msg, hdr, src, socket, state).await;
+        }
+
+        _ => {
+            metrics::MALFORMED_PACKETS.inc();
+            log!(Debug, addr = src; "Unknown/Bad packet: {}", msg.name());
         }
     }
 }
@@ -1,9 +1,11 @@
 pub mod connect;
 pub mod disconnect;
 pub mod heartbeat;
+pub mod media;
 pub mod message;
 pub mod notifications;
 pub mod request_relay;
+pub mod resume;
 pub mod utils;
 
-pub use message::handle_message;
+pub use message::{handle_message, handle_packet, spawn_packet};
@@ -1,381 +1,250 @@
 use crate::{
-    proto::control_text::{MSG_DIRECT, MSG_MODE, MSG_RELAY, MSG_SERVER_RELAY, MSG_USER_LEFT},
-    signaling::structures::{Channel, NatKind, ServerMap, User},
+    log,
+    proto::control::ControlMessage,
+    signaling::{
+        metrics,
+        state::ServerState,
+        structures::{Channel, User},
+        utils::{Outbox, flush_outbox, mode_direct, mode_server_relay, queue_control, user_left},
+    },
 };
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::UdpSocket, sync::Mutex};
-
-fn pick_eligible_relay(channel: &Channel) -> Option<User> {
-    // Primul user care NU e in need_server_relay (adica nu are NAT symmetric)
-    channel
-        .users
-        .iter()
-        .find(|u| !u.needs_server_relay && !matches!(u.nat_kind, NatKind::Symmetric))
-        .cloned()
-}
+use tokio::net::UdpSocket;
 
-pub async fn mark_relay_in_channel(
-    state: &Arc<Mutex<ServerMap>>,
-    server_id: &str,
-    channel_name: &str,
-    relay_user: &User,
-) -> bool {
-    let mut st = state.lock().await;
-    if let Some(channels) = st.get_mut(server_id) {
-        if let Some(channel) = channels.get_mut(channel_name) {
-            if channel.relay.as_deref() == Some(&relay_user.name) {
-                return false;
-            }
-            channel.relay = Some(relay_user.name.clone());
-            return true;
-        }
+use super::utils::pick_eligible_relay;
+
+pub fn mark_relay_in_channel(channel: &mut Channel, relay_user: &User) -> bool {
+    if channel.relay == Some(relay_user.peer_id) {
+        return false;
     }
-    false
+    channel.relay = Some(relay_user.peer_id);
+    true
 }
 
-pub async fn notify_relay_about_peers(socket: &Arc<UdpSocket>, relay_user: &User, peers: &[User]) {
+pub fn notify_relay_about_peers(outbox: &mut Outbox, relay_user: &User, peers: &[User]) {
     for peer in peers {
-        let msg = format!("{} {} {} {}\n", MSG_MODE, MSG_DIRECT, peer.name, peer.addr);
-        if let Err(e) = socket.send_to(msg.as_bytes(), relay_user.addr).await {
-            eprintln!(
-                "Failed to notify relay {} about {}: {}",
-                relay_user.name, peer.name, e
-            );
-        }
+        let msg = mode_direct(peer, relay_user.addr);
+        queue_control(outbox, &msg, relay_user.addr);
     }
 }
 
-pub async fn notify_peers_about_relay(socket: &Arc<UdpSocket>, relay_user: &User, peers: &[User]) {
+pub fn notify_peers_about_relay(outbox: &mut Outbox, relay_user: &User, peers: &[User]) {
     for peer in peers {
-        let msg = format!(
-            "{} {} {} {}\n",
-            MSG_MODE, MSG_DIRECT, relay_user.name, relay_user.addr
-        );
-        if let Err(e) = socket.send_to(msg.as_bytes(), peer.addr).await {
-            eprintln!(
-                "Failed to notify {} about new relay{}: {}",
-                peer.name, relay_user.name, e
-            );
-        }
+        queue_control(outbox, &mode_direct(relay_user, peer.addr), peer.addr);
     }
 }
 
-pub async fn send_relay_mode_to_relay(socket: &Arc<UdpSocket>, relay_user: &User) {
-    let reply = format!("{MSG_MODE} {MSG_RELAY}\n");
-    if let Err(e) = socket.send_to(reply.as_bytes(), relay_user.addr).await {
-        eprintln!("Failed to send RELAY mode to {}: {}", relay_user.name, e);
-    }
+pub fn send_relay_mode_to_relay(outbox: &mut Outbox, relay_user: &User) {
+    queue_control(outbox, &ControlMessage::ModeRelay, relay_user.addr);
 }
 
-pub async fn notify_existing_users_about_new_user(
-    socket: &Arc<UdpSocket>,
-    users: &[User],
-    new_user_name: &str,
-    new_user_addr: SocketAddr,
-) {
+pub fn notify_existing_users_about_new_user(outbox: &mut Outbox, users: &[User], new_user: &User) {
     for user in users.iter() {
-        if user.addr != new_user_addr {
-            let msg_to_existing =
-                format!("{MSG_MODE} {MSG_DIRECT} {new_user_name} {new_user_addr}\n");
-            if let Err(e) = socket.send_to(msg_to_existing.as_bytes(), user.addr).await {
-                eprintln!("Failed to notify {}: {}", user.name, e);
-            }
-
-            let msg_to_new = format!("{} {} {} {}\n", MSG_MODE, MSG_DIRECT, user.name, user.addr);
-            if let Err(e) = socket.send_to(msg_to_new.as_bytes(), new_user_addr).await {
-                eprintln!("Failed to notify new user: {}", e);
-            }
+        if user.peer_id != new_user.peer_id {
+            let msg_to_existing = mode_direct(new_user, user.addr);
+            queue_control(outbox, &msg_to_existing, user.addr);
+
+            let msg_to_new = mode_direct(user, new_user.addr);
+            queue_control(outbox, &msg_to_new, new_user.addr);
         }
     }
 }
 
-pub async fn promote_new_relay(socket: &Arc<UdpSocket>, new_relay: &User) {
-    if let Err(e) = socket
-        .send_to(
-            format!("{MSG_MODE} {MSG_RELAY}\n").as_bytes(),
-            new_relay.addr,
-        )
-        .await
-    {
-        eprintln!(
-            "Failed to notify {} about promotion to relay: {}",
-            new_relay.name, e
-        );
-    }
+pub fn promote_new_relay(outbox: &mut Outbox, new_relay: &User) {
+    queue_control(outbox, &ControlMessage::ModeRelay, new_relay.addr);
+}
+
+pub fn demote_old_relay(outbox: &mut Outbox, old_relay: &User) {
+    queue_control(outbox, &ControlMessage::ModePeer, old_relay.addr);
 }
 
-pub async fn notify_lone_user(socket: &Arc<UdpSocket>, lone_user_addr: Option<SocketAddr>) {
+pub fn notify_lone_user(outbox: &mut Outbox, lone_user_addr: Option<SocketAddr>) {
     if let Some(lone_user_addr) = lone_user_addr {
-        let reply = format!("{MSG_MODE} {MSG_RELAY}\n");
-        if let Err(e) = socket.send_to(reply.as_bytes(), lone_user_addr).await {
-            eprintln!("Failed to notify lone user: {}", e);
-        }
+        queue_control(outbox, &ControlMessage::ModeRelay, lone_user_addr);
     }
 }
 
-pub async fn notify_peers_about_new_relay(
-    socket: &Arc<UdpSocket>,
-    new_relay: &User,
-    peers: &[User],
-) {
+pub fn notify_peers_about_new_relay(outbox: &mut Outbox, new_relay: &User, peers: &[User]) {
     for user in peers {
-        let msg = format!(
-            "{} {} {} {}\n",
-            MSG_MODE, MSG_DIRECT, new_relay.name, new_relay.addr
-        );
-        if let Err(e) = socket.send_to(msg.as_bytes(), user.addr).await {
-            eprintln!("Failed to notify {} about new relay: {}", user.name, e);
-        }
+        queue_control(outbox, &mode_direct(new_relay, user.addr), user.addr);
     }
 }
 
-pub async fn notify_new_relay_about_peers(
-    socket: &Arc<UdpSocket>,
-    new_relay: &User,
-    peers: &[User],
-) {
+pub fn notify_new_relay_about_peers(outbox: &mut Outbox, new_relay: &User, peers: &[User]) {
     for user in peers {
-        let msg = format!("{} {} {} {}\n", MSG_MODE, MSG_DIRECT, user.name, user.addr);
-        if let Err(e) = socket.send_to(msg.as_bytes(), new_relay.addr).await {
-            eprintln!(
-                "Failed to notify {} about connected users: {}",
-                new_relay.name, e
-            );
-        }
+        let msg = mode_direct(user, new_relay.addr);
+        queue_control(outbox, &msg, new_relay.addr);
     }
 }
 
-pub async fn notify_all_about_departure(
-    socket: &Arc<UdpSocket>,
-    remaining_users: Vec<User>,
-    user_name: &str,
-    leaving_user_addr: Option<SocketAddr>,
-) {
+pub fn notify_all_about_departure(outbox: &mut Outbox, remaining_users: &[User], leaving: &User) {
+    let departure_msg = user_left(leaving);
     for user in remaining_users {
-        let departure_msg = format!(
-            "{} {} {}\n",
-            MSG_USER_LEFT,
-            user_name,
-            leaving_user_addr
-                .map(|a| a.to_string())
-                .unwrap_or_else(|| "0.0.0.0:0".into())
-        );
-        if let Err(e) = socket.send_to(departure_msg.as_bytes(), user.addr).await {
-            eprintln!("Failed to notify {} about departure: {}", user.name, e);
+        queue_control(outbox, &departure_msg, user.addr);
+    }
+}
+
+// every user is told that the server relays for every user
+fn announce_server_relay_for_all(outbox: &mut Outbox, channel: &mut Channel) {
+    for u in &channel.users {
+        let notify = mode_server_relay(u);
+        for usr in &channel.users {
+            queue_control(outbox, &notify, usr.addr);
         }
     }
+
+    // mark in state that there is no user relay
+    channel.relay = None;
 }
 
-pub async fn handle_connect_notifications(
-    server_id: &str,
-    channel_name: &str,
-    user_name: &str,
-    src_addr: SocketAddr,
-    users_to_notify: Channel,
-    socket: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
-) {
-    if users_to_notify.users.len() > 1 {
-        handle_multiple_users_scenario(server_id, channel_name, &users_to_notify, &socket, &state)
-            .await;
+pub fn handle_connect_notifications(channel: &mut Channel, new_user: &User, outbox: &mut Outbox) {
+    if channel.users.len() > 1 {
+        handle_multiple_users_scenario(channel, outbox);
     } else {
-        handle_single_user_scenario(&socket, &users_to_notify, user_name, src_addr).await;
+        handle_single_user_scenario(channel, new_user, outbox);
     }
 }
 
-pub async fn handle_multiple_users_scenario(
-    server_id: &str,
-    channel_name: &str,
-    users_to_notify: &Channel,
-    socket: &Arc<UdpSocket>,
-    state: &Arc<Mutex<ServerMap>>,
-) {
-    if let Some(relay_user) = pick_eligible_relay(users_to_notify) {
-        let mut relay_peers: Vec<User> = Vec::new();
-        let mut symmetric_peers: Vec<User> = Vec::new();
-
-        for u in users_to_notify.users.iter() {
-            if u.name == relay_user.name {
-                continue;
-            }
-
-            if u.needs_server_relay {
-                symmetric_peers.push(u.clone());
-            } else {
-                relay_peers.push(u.clone());
-            }
+pub fn handle_multiple_users_scenario(channel: &mut Channel, outbox: &mut Outbox) {
+    let Some(relay_user) = pick_eligible_relay(channel) else {
+        // no eligible user -> no user gets promoted to relay, let server as relay
+        announce_server_relay_for_all(outbox, channel);
+        return;
+    };
+
+    let mut relay_peers: Vec<User> = Vec::new();
+    let mut symmetric_peers: Vec<User> = Vec::new();
+
+    for u in channel.users.iter() {
+        if u.peer_id == relay_user.peer_id {
+            continue;
         }
 
-        // 1) mark relay for channel
-        let changed = mark_relay_in_channel(state, server_id, channel_name, &relay_user).await;
+        if u.needs_server_relay {
+            symmetric_peers.push(u.clone());
+        } else {
+            relay_peers.push(u.clone());
+        }
+    }
 
-        // 2) notify relay about DIRECT peers
-        notify_relay_about_peers(socket, &relay_user, &relay_peers).await;
+    // 1) mark relay for channel
+    let changed = mark_relay_in_channel(channel, &relay_user);
 
-        // 3) notify DIRECT peers about relay
-        notify_peers_about_relay(socket, &relay_user, &relay_peers).await;
+    // 2) notify relay about DIRECT peers
+    notify_relay_about_peers(outbox, &relay_user, &relay_peers);
 
-        // 4) relay receives MODE RELAY
-        if changed {
-            send_relay_mode_to_relay(socket, &relay_user).await;
-        }
+    // 3) notify DIRECT peers about relay
+    notify_peers_about_relay(outbox, &relay_user, &relay_peers);
 
-        // 5) SYMMETRIC peers: only get MODE SERVER_RELAY
-        for symmetric in symmetric_peers.iter() {
-            let msg = format!("{} {} {}\n", MSG_MODE, MSG_SERVER_RELAY, symmetric.name);
+    // 4) relay receives MODE RELAY
+    if changed {
+        send_relay_mode_to_relay(outbox, &relay_user);
+    }
 
-            //1) send to symmetric user (to send via server)
-            let _ = socket.send_to(msg.as_bytes(), symmetric.addr).await;
+    // 5) SYMMETRIC peers: only get MODE SERVER_RELAY
+    for symmetric in symmetric_peers.iter() {
+        let msg = mode_server_relay(symmetric);
 
-            //2) send to relay (to mark peer as peer.use_server_relay)
-            if relay_user.addr != symmetric.addr {
-                let _ = socket.send_to(msg.as_bytes(), relay_user.addr).await;
-            }
-        }
-    } else {
-        // no eligible user -> no user gets promoted to relay, let server as relay
-        // announce that server will be relay for every user in the channel
-        for u in &users_to_notify.users {
-            let notify = format!("{} {} {}\n", MSG_MODE, MSG_SERVER_RELAY, u.name);
-            for usr in &users_to_notify.users {
-                let _ = socket.send_to(notify.as_bytes(), usr.addr).await;
-            }
-        }
+        //1) send to symmetric user (to send via server)
+        queue_control(outbox, &msg, symmetric.addr);
 
-        // mark in state that there is no user relay
-        let mut st = state.lock().await;
-        if let Some(chans) = st.get_mut(server_id) {
-            if let Some(ch) = chans.get_mut(channel_name) {
-                ch.relay = None;
-            }
+        //2) send to relay (to mark peer as peer.use_server_relay)
+        if relay_user.addr != symmetric.addr {
+            queue_control(outbox, &msg, relay_user.addr);
         }
     }
 }
 
-pub async fn handle_single_user_scenario(
-    socket: &Arc<UdpSocket>,
-    users_to_notify: &Channel,
-    user_name: &str,
-    src_addr: SocketAddr,
-) {
-    notify_existing_users_about_new_user(socket, &users_to_notify.users, user_name, src_addr).await;
+pub fn handle_single_user_scenario(channel: &Channel, new_user: &User, outbox: &mut Outbox) {
+    notify_existing_users_about_new_user(outbox, &channel.users, new_user);
 }
 
-pub async fn handle_relay_transition(
-    socket: &Arc<UdpSocket>,
-    was_relay: bool,
-    state: &Arc<Mutex<ServerMap>>,
-    server_id: &str,
-    channel_name: &str,
-) {
+pub fn handle_relay_transition(channel: &mut Channel, was_relay: bool, outbox: &mut Outbox) {
     if !was_relay {
         return;
     }
 
-    //luam canalul curent ca sa vedem users + need_server_relay
-    let channel_opt = {
-        let st = state.lock().await;
-        st.get(server_id)
-            .and_then(|chans| chans.get(channel_name))
+    //alegem un nou relay eligibil
+    if let Some(new_relay) = pick_eligible_relay(channel) {
+        let peers: Vec<User> = channel
+            .users
+            .iter()
+            .filter(|u| u.peer_id != new_relay.peer_id)
             .cloned()
-    };
+            .collect();
 
-    if let Some(channel) = channel_opt {
-        //alegem un nou relay eligibil
-        if let Some(new_relay) = pick_eligible_relay(&channel) {
-            let peers: Vec<User> = channel
-                .users
-                .iter()
-                .cloned()
-                .filter(|u| u.name != new_relay.name)
-                .collect();
-
-            promote_new_relay(socket, &new_relay).await;
-            notify_peers_about_new_relay(socket, &new_relay, &peers).await;
-            notify_new_relay_about_peers(socket, &new_relay, &peers).await;
-
-            //actualizam relay in state
-            let mut st = state.lock().await;
-            if let Some(chans) = st.get_mut(server_id) {
-                if let Some(ch) = chans.get_mut(channel_name) {
-                    ch.relay = Some(new_relay.name.clone());
-                }
-            }
-        } else {
-            //no eligible user -> server remains relay, announce SERVER_RELAY for all peers
-            for u in &channel.users {
-                let notify = format!("{} {} {}\n", MSG_MODE, MSG_SERVER_RELAY, u.name);
-                for usr in &channel.users {
-                    let _ = socket.send_to(notify.as_bytes(), usr.addr).await;
-                }
-            }
-
-            let mut st = state.lock().await;
-            if let Some(chans) = st.get_mut(server_id) {
-                if let Some(ch) = chans.get_mut(channel_name) {
-                    ch.relay = None;
-                }
-            }
-        }
+        promote_new_relay(outbox, &new_relay);
+        notify_peers_about_new_relay(outbox, &new_relay, &peers);
+        notify_new_relay_about_peers(outbox, &new_relay, &peers);
+
+        //actualizam relay in state
+        channel.relay = Some(new_relay.peer_id);
+    } else {
+        //no eligible user -> server remains relay, announce SERVER_RELAY for all peers
+        announce_server_relay_for_all(outbox, channel);
     }
 }
 
-pub async fn handle_disconnect_notifications(
-    remaining_users: Vec<User>,
+pub fn handle_disconnect_notifications(
+    channel: &mut Channel,
     was_relay: bool,
-    leaving_user_addr: Option<SocketAddr>,
+    leaving: &User,
     lone_user_addr: Option<SocketAddr>,
-    user_name: &str,
-    socket: Arc<UdpSocket>,
-    state: &Arc<Mutex<ServerMap>>,
-    server_id: String,
-    channel_name: String,
+    outbox: &mut Outbox,
 ) {
+    // the relay before `update_relay_after_departure` cleared it
+    let before = if was_relay {
+        Some(leaving.peer_id)
+    } else {
+        channel.relay
+    };
     if lone_user_addr.is_some() {
-        notify_lone_user(&socket, lone_user_addr).await;
+        notify_lone_user(outbox, lone_user_addr);
     } else {
-        handle_relay_transition(&socket, was_relay, &state, &server_id, &channel_name).await;
+        handle_relay_transition(channel, was_relay, outbox);
     }
-    notify_all_about_departure(&socket, remaining_users, user_name, leaving_user_addr).await;
+    // counted like a relay timeout in the heartbeat
+    if channel.relay != before && !channel.users.is_empty() {
+        metrics::RELAY_REELECTIONS.inc();
+    }
+    notify_all_about_departure(outbox, &channel.users, leaving);
 }
 
 pub async fn handle_peer_timeout(
-    parts: &[&str],
-    _src: SocketAddr,
+    server_id: String,
+    channel_name: String,
+    peer_id: u32,
+    src: SocketAddr,
     socket: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
+    state: Arc<ServerState>,
 ) {
-    let server_id = parts[1].to_string();
-    let channel_name = parts[2].to_string();
-    let peer_user = parts[3].to_string();
-
-    let remaining = {
-        let mut st = state.lock().await;
-        if let Some(channels) = st.get_mut(&server_id) {
-            if let Some(channel) = channels.get_mut(&channel_name) {
-                //removing by name
-                channel.users.retain(|u| u.name != peer_user);
-
-                //update relay if needed
-                if channel.users.len() == 1 {
-                    channel.relay = Some(channel.users[0].name.clone());
-                }
-            }
+    let mut outbox = Outbox::new();
+    state.with_channel(&server_id, &channel_name, |channel| {
+        let Some(pos) = channel.users.iter().position(|u| u.peer_id == peer_id) else {
+            return;
+        };
+        // the relay pings its peers, it cannot miss its own pongs
+        if channel.users[pos].addr == src {
+            log!(
+                Info,
+                server_id = &server_id,
+                channel = &channel_name,
+                peer_id = peer_id,
+                addr = src;
+                "Ignoring PEER_TIMEOUT about the reporter itself"
+            );
+            return;
         }
+        let gone = channel.users.remove(pos);
+        state.unindex_peer(gone.addr, &server_id, &channel_name);
 
-        st.get(&server_id)
-            .and_then(|channels| channels.get(&channel_name))
-            .cloned()
-    };
-
-    if let Some(channel) = remaining {
-        for u in channel.users {
-            let _ = socket
-                .send_to(
-                    format!("{MSG_USER_LEFT} {peer_user} 0.0.0.0:0\n").as_bytes(),
-                    u.addr,
-                )
-                .await;
+        //update relay if needed
+        if channel.users.len() == 1 {
+            channel.relay = Some(channel.users[0].peer_id);
         }
-    }
+
+        notify_all_about_departure(&mut outbox, &channel.users, &gone);
+    });
+
+    flush_outbox(&socket, &state, outbox).await;
 }
@@ -1,117 +1,116 @@
 use crate::{
-    proto::control_text::{MSG_MODE, MSG_SERVER_RELAY},
-    signaling::structures::ServerMap,
+    proto::{
+        control::{self, ControlMessage},
+        packet::Header,
+    },
+    signaling::{
+        metrics::{self, Relayed},
+        state::ServerState,
+        structures::Channel,
+        utils::{Outbox, flush_outbox, mode_server_relay, queue_control, send_sealed},
+    },
 };
 use std::{net::SocketAddr, sync::Arc};
-use tokio::{net::UdpSocket, sync::Mutex};
+use tokio::net::UdpSocket;
 
 pub async fn handle_relay_request(
-    parts: &[&str],
+    server_id: &str,
+    channel_name: &str,
+    peer_id: u32,
     _src: SocketAddr,
     socket: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
+    state: Arc<ServerState>,
 ) {
-    // Relay request <server_id> <channel> <username>
-    if parts.len() < 4 {
-        return;
-    }
-    let server_id = parts[1];
-    let channel_name = parts[2];
-    let username = parts[3];
+    let mut outbox = Outbox::new();
+    state.with_channel(server_id, channel_name, |channel| {
+        // mark this user that he needs server relay
+        let Some(user) = channel.users.iter_mut().find(|u| u.peer_id == peer_id) else {
+            return;
+        };
+        user.needs_server_relay = true;
 
-    let mut st = state.lock().await;
-    if let Some(channels) = st.get_mut(server_id) {
-        if let Some(channel) = channels.get_mut(channel_name) {
-            // mark this user that he needs server relay
-            if let Some(user) = channel.users.iter_mut().find(|u| u.name == username) {
-                user.needs_server_relay = true;
-            }
-            // notify all users that the server will forward for the user that needs a relay
-            let notify = format!("{MSG_MODE} {MSG_SERVER_RELAY} {username}\n");
-            for u in channel.users.iter() {
-                let _ = socket.send_to(notify.as_bytes(), u.addr).await;
-            }
+        // notify all users that the server will forward for the user that needs a relay
+        let notify = mode_server_relay(user);
+        for u in channel.users.iter() {
+            queue_control(&mut outbox, &notify, u.addr);
         }
+    });
+
+    flush_outbox(&socket, &state, outbox).await;
+}
+
+// who should receive DATA from `src` that originated at peer `origin` (the
+// header's src_peer_id, which differs from the sender's when a relay mirrors it)
+fn data_targets(channel: &Channel, origin: u32, src: SocketAddr) -> Vec<SocketAddr> {
+    let relay_addr = channel
+        .relay
+        .and_then(|r| channel.users.iter().find(|u| u.peer_id == r))
+        .map(|u| u.addr);
+
+    // DATA from (or mirrored by) the RELAY -> deliver only to peers that need server relay
+    if relay_addr == Some(src) {
+        return channel
+            .users
+            .iter()
+            .filter(|peer| peer.peer_id != origin && peer.needs_server_relay)
+            .map(|peer| peer.addr)
+            .collect();
     }
+
+    //find the sender in this channel by (addr, peer_id)
+    let Some(sender) = channel
+        .users
+        .iter()
+        .find(|u| u.addr == src && u.peer_id == origin)
+    else {
+        return Vec::new();
+    };
+
+    // If sender is symmetric (needs server relay) -> deliver to everyone else
+    if sender.needs_server_relay {
+        return channel
+            .users
+            .iter()
+            .filter(|peer| peer.addr != src)
+            .map(|peer| peer.addr)
+            .collect();
+    }
+
+    //otherwise (unexpected normal client sent to server?)
+    // forward to the relay only
+    relay_addr.into_iter().collect()
 }
 
 pub async fn handle_data_from_client(
-    raw: &str,
+    msg: &ControlMessage,
+    hdr: &Header,
     src: SocketAddr,
     socket: Arc<UdpSocket>,
-    state: Arc<Mutex<ServerMap>>,
+    state: Arc<ServerState>,
 ) {
-    // expect "DATA <sender> <payload>"
-    let parts: Vec<&str> = raw.splitn(3, ' ').collect();
-    if parts.len() < 3 {
+    let Some(loc) = state.locate(src) else {
         return;
-    }
-    let sender_name = parts[1];
-
-    let mut st = state.lock().await;
-
-    for (_sid, channels) in st.iter_mut() {
-        for (_cname, channel) in channels.iter_mut() {
-            // mirrored DATA from RELAY -> deliver to peers that need server relay
-            if let Some(relay_name) = &channel.relay {
-                if let Some(relay_user) = channel.users.iter().find(|u| &u.name == relay_name) {
-                    if relay_user.addr == src {
-                        for peer in channel.users.iter() {
-                            if peer.name != sender_name && peer.needs_server_relay {
-                                let _ = socket.send_to(raw.as_bytes(), peer.addr).await;
-                            }
-                        }
-                        return;
-                    }
-                }
-            }
-
-            //find the sender in this channel by (addr, name)
-            if let Some(sender_index) = channel
-                .users
-                .iter()
-                .position(|u| u.addr == src && u.name == sender_name)
-            {
-                //who is relay
-                let sender_is_relay = channel
-                    .relay
-                    .as_deref()
-                    .map(|r| r == sender_name)
-                    .unwrap_or(false);
-
-                let sender_needs_server_relay = channel.users[sender_index].needs_server_relay;
+    };
+    let targets = state
+        .with_channel(&loc.server_id, &loc.channel, |channel| {
+            data_targets(channel, hdr.src_peer_id, src)
+        })
+        .unwrap_or_default();
 
-                //if sender is relay -> deliver only to need_server_relay users
-                if sender_is_relay {
-                    for peer in channel.users.iter() {
-                        if peer.name != sender_name && peer.needs_server_relay {
-                            let _ = socket.send_to(raw.as_bytes(), peer.addr).await;
-                        }
-                    }
-                    return;
-                }
-
-                // If sender is symmetric (needs server relay) -> deliver to everyone else
-                if sender_needs_server_relay {
-                    for peer in channel.users.iter() {
-                        if peer.addr != src {
-                            let _ = socket.send_to(raw.as_bytes(), peer.addr).await;
-                        }
-                    }
-                    return;
-                }
-
-                //otherwise (unexpected normal client sent to server?)
-                // forward to the relay only
-                if let Some(relay_name) = &channel.relay {
-                    if let Some(relay_user) = channel.users.iter().find(|u| &u.name == relay_name) {
-                        if relay_user.addr != src {
-                            let _ = socket.send_to(raw.as_bytes(), relay_user.addr).await;
-                        }
-                    }
-                }
-                return;
-            }
+    // forwarded with the original header so receivers see who spoke
+    let Ok(raw) = control::encode_packet(hdr.channel_id, hdr.src_peer_id, hdr.dst_peer_id, msg)
+    else {
+        return;
+    };
+    if !targets.is_empty() {
+        metrics::RELAYED_PACKET_SIZE[Relayed::Data].observe(raw.len() as f64);
+    }
+    for addr in targets {
+        if send_sealed(&socket, &state, raw.clone(), addr)
+            .await
+            .is_ok()
+        {
+            metrics::RELAYED_BYTES[Relayed::Data].add(raw.len() as u64);
         }
     }
 }
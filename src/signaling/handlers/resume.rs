@@ -0,0 +1,126 @@
+use crate::{
+    log,
+    proto::control::ControlMessage,
+    signaling::{
+        cluster,
+        state::{PeerLocation, ServerState},
+        utils::{Outbox, flush_outbox, mode_direct, queue_control, send_control},
+    },
+};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+use tokio::net::UdpSocket;
+
+use super::{
+    connect::welcome_packet,
+    notifications::handle_disconnect_notifications,
+    utils::{remove_user_from_other_channels, update_relay_after_departure},
+};
+
+// RESUME takes a session back by the ids and nonce of its WELCOME, from
+// whatever address the client now has; peers learn a changed address as if the
+// user had reconnected. Unknown sessions are refused, the client then CONNECTs.
+pub async fn handle_resume(
+    channel_id: u64,
+    peer_id: u32,
+    nonce: u64,
+    src: SocketAddr,
+    socket: Arc<UdpSocket>,
+    state: Arc<ServerState>,
+) {
+    let mut outbox = Outbox::new();
+    let resumed = state.find_channel(channel_id).and_then(|(sid, name)| {
+        let known = state
+            .with_channel(&sid, &name, |channel| {
+                channel
+                    .users
+                    .iter()
+                    .any(|u| u.peer_id == peer_id && u.nonce == nonce)
+            })
+            .unwrap_or(false);
+        if !known {
+            return None;
+        }
+        // `src` is about to point at this session, drop the one it held elsewhere
+        remove_user_from_other_channels(&state, &sid, &name, src);
+
+        state
+            .with_channel(&sid, &name, |channel| {
+                // or in this channel, under another peer id
+                if let Some(pos) = channel
+                    .users
+                    .iter()
+                    .position(|u| u.addr == src && u.peer_id != peer_id)
+                {
+                    let stale = channel.users.remove(pos);
+                    let was_relay = channel.relay == Some(stale.peer_id);
+                    let lone_user_addr = update_relay_after_departure(channel, was_relay);
+                    handle_disconnect_notifications(
+                        channel,
+                        was_relay,
+                        &stale,
+                        lone_user_addr,
+                        &mut outbox,
+                    );
+                }
+                let user = channel
+                    .users
+                    .iter_mut()
+                    .find(|u| u.peer_id == peer_id && u.nonce == nonce)?;
+                user.last_pong = Instant::now();
+                // in a cluster the client may now talk to another instance
+                user.home = cluster::via();
+                let moved_from = std::mem::replace(&mut user.addr, src);
+                outbox.push((src, welcome_packet(channel_id, user)));
+
+                let user = user.clone();
+                state.index_peer(
+                    src,
+                    PeerLocation {
+                        server_id: sid.clone(),
+                        channel: name.clone(),
+                        peer_id,
+                    },
+                );
+                if moved_from != src {
+                    state.unindex_peer(moved_from, &sid, &name);
+                    for other in channel.users.iter().filter(|u| u.peer_id != peer_id) {
+                        queue_control(&mut outbox, &mode_direct(&user, other.addr), other.addr);
+                    }
+                }
+                Some((user.name, moved_from))
+            })
+            .flatten()
+            .map(|(user, moved_from)| (sid, name, user, moved_from))
+    });
+
+    let Some((sid, name, user, moved_from)) = resumed else {
+        log!(
+            Info,
+            peer_id = peer_id,
+            addr = src;
+            "Rejecting RESUME: unknown session"
+        );
+        let reply = ControlMessage::Rejected {
+            reason: "unknown session".into(),
+        };
+        let _ = send_control(&socket, &state, &reply, src).await;
+        return;
+    };
+
+    log!(
+        Info,
+        server_id = &sid,
+        channel = &name,
+        user = &user,
+        peer_id = peer_id,
+        addr = src;
+        "User {} resumed its session{}",
+        user,
+        if moved_from == src {
+            String::new()
+        } else {
+            format!(" (was {moved_from})")
+        }
+    );
+    flush_outbox(&socket, &state, outbox).await;
+}
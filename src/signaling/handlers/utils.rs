@@ -1,144 +1,132 @@
 use crate::{
-    proto::control_text::{MSG_MODE, MSG_RELAY, MSG_SERVER_RELAY},
-    signaling::structures::{Channel, NatKind, ServerMap, User},
+    log,
+    proto::control::ControlMessage,
+    signaling::{
+        state::ServerState,
+        structures::{Candidate, Channel, NatKind, PortHint, User},
+        utils::{Outbox, mode_server_relay, queue_control},
+    },
 };
-use std::{net::SocketAddr, sync::Arc, time::Instant};
-use tokio::{net::UdpSocket, sync::Mutex};
+use std::{net::SocketAddr, time::Instant};
 
-pub async fn get_remaining_users(
-    state: &Arc<Mutex<ServerMap>>,
-    server_id: &str,
-    channel_name: &str,
-) -> Vec<User> {
-    let st = state.lock().await;
-    st.get(server_id)
-        .and_then(|channels| channels.get(channel_name))
-        .map(|channel| channel.users.clone())
-        .unwrap_or_default()
-}
-
-pub async fn update_existing_user(
+pub fn update_existing_user(
     channel: &mut Channel,
     user_name: &str,
     src_addr: SocketAddr,
-) -> Option<(Channel, bool, u32)> {
+) -> Option<u32> {
     //check if user already present with same addr
-    if let Some(existing) = channel
+    let existing = channel
         .users
         .iter_mut()
-        .find(|u| u.name == user_name && u.addr == src_addr)
-    {
-        existing.last_pong = Instant::now();
-        let peer_id = existing.peer_id;
-        Some((channel.clone(), false, peer_id))
-    } else {
-        None
-    }
+        .find(|u| u.name == user_name && u.addr == src_addr)?;
+    existing.last_pong = Instant::now();
+    Some(existing.peer_id)
 }
 
-pub async fn remove_old_user_sessions(
-    channel: &mut Channel,
-    user_name: &str,
-    src_addr: SocketAddr,
-) {
+// the user at `src` whose session nonce matches, i.e. the one WELCOME was sent to
+pub fn authenticate_sender(channel: &Channel, src: SocketAddr, nonce: u64) -> Option<&User> {
     channel
         .users
-        .retain(|u| !(u.name == user_name && u.addr != src_addr));
+        .iter()
+        .find(|u| u.addr == src && u.nonce == nonce)
+}
+
+// keeps the current relay while it is eligible, otherwise elects the best-ranked user
+pub fn pick_eligible_relay(channel: &Channel) -> Option<User> {
+    let current = channel
+        .relay
+        .and_then(|r| channel.users.iter().find(|u| u.peer_id == r))
+        .filter(|u| u.relay_rank().is_some());
+
+    current
+        .or_else(|| {
+            channel
+                .users
+                .iter()
+                .filter_map(|u| Some((u.relay_rank()?, u)))
+                .min_by_key(|(rank, _)| *rank)
+                .map(|(_, u)| u)
+        })
+        .cloned()
 }
 
-pub async fn handle_lone_user_scenario(channel: &mut Channel, socket: &Arc<UdpSocket>) {
+pub fn handle_lone_user_scenario(channel: &mut Channel, outbox: &mut Outbox) {
     if channel.relay.is_none() && channel.users.len() == 1 {
         let u = &channel.users[0];
-        match u.nat_kind {
-            NatKind::Symmetric => {
-                let reply = format!(
-                    "{} {} {}\n",
-                    MSG_MODE, MSG_SERVER_RELAY, channel.users[0].name
-                );
-                if let Err(e) = socket.send_to(reply.as_bytes(), u.addr).await {
-                    eprintln!("Failed to notify lone user about server relay: {}", e);
-                }
-                channel.relay = None;
-            }
-
-            _ => {
-                if let Err(e) = socket
-                    .send_to(format!("{MSG_MODE} {MSG_RELAY}\n").as_bytes(), u.addr)
-                    .await
-                {
-                    eprintln!("Failed to notify lone user about relay mode: {}", e);
-                }
-                channel.relay = Some(u.name.clone());
-            }
+        if u.relay_rank().is_some() {
+            queue_control(outbox, &ControlMessage::ModeRelay, u.addr);
+            channel.relay = Some(u.peer_id);
+        } else if u.needs_server_relay {
+            let reply = mode_server_relay(u);
+            queue_control(outbox, &reply, u.addr);
+            channel.relay = None;
         }
+        // a punchable symmetric user waits for the next peer to punch
     }
 }
 
-pub async fn add_new_user(
+#[allow(clippy::too_many_arguments)]
+pub fn add_new_user(
     channel: &mut Channel,
     user_name: &str,
     src_addr: SocketAddr,
-    socket: &Arc<UdpSocket>,
+    outbox: &mut Outbox,
     nat_kind: NatKind,
-) -> (Channel, u32) {
-    //Remove old user sessions with same name but different address
-    remove_old_user_sessions(channel, user_name, src_addr).await;
-
+    port_hint: PortHint,
+    fingerprint: Vec<u8>,
+    candidates: Vec<Candidate>,
+    check_key: Vec<u8>,
+) -> u32 {
     let peer_id = channel.next_peer_id;
     channel.next_peer_id += 1;
 
-    let new_user = User::new(user_name, src_addr, nat_kind, peer_id);
+    let new_user = User::new(user_name, src_addr, nat_kind, peer_id)
+        .with_port_hint(port_hint)
+        .with_fingerprint(fingerprint)
+        .with_candidates(candidates)
+        .with_check_key(check_key);
     channel.users.push(new_user);
 
-    handle_lone_user_scenario(channel, socket).await;
+    handle_lone_user_scenario(channel, outbox);
 
-    println!("User {} joined from {}", user_name, src_addr);
+    log!(
+        Info,
+        user = user_name,
+        peer_id = peer_id,
+        addr = src_addr;
+        "User {} joined",
+        user_name
+    );
 
-    (channel.clone(), peer_id)
+    peer_id
 }
 
-pub async fn find_and_remove_user(
+pub fn find_and_remove_user(
     channel: &mut Channel,
     user_name: &str,
     src_addr: SocketAddr,
-) -> Option<(bool, SocketAddr)> {
-    if let Some(pos) = channel
+) -> Option<(bool, User)> {
+    let pos = channel
         .users
         .iter()
-        .position(|u| u.name == user_name && u.addr == src_addr)
-    {
-        //check if leaving user was relay
-        let was_relay = channel
-            .relay
-            .as_ref()
-            .map(|r| r == &user_name)
-            .unwrap_or(false);
-
-        let leaving_user_addr = channel.users[pos].addr;
-        channel.users.remove(pos);
-
-        Some((was_relay, leaving_user_addr))
-    } else {
-        None
-    }
+        .position(|u| u.name == user_name && u.addr == src_addr)?;
+
+    let leaving = channel.users.remove(pos);
+
+    //check if leaving user was relay
+    let was_relay = channel.relay == Some(leaving.peer_id);
+    Some((was_relay, leaving))
 }
 
-pub async fn update_relay_after_departure(
-    channel: &mut Channel,
-    was_relay: bool,
-) -> Option<SocketAddr> {
+pub fn update_relay_after_departure(channel: &mut Channel, was_relay: bool) -> Option<SocketAddr> {
     let mut lone_user_addr = None;
 
     if was_relay {
-        if !channel.users.is_empty() {
-            let new_relay = channel.users[0].name.clone();
-            channel.relay = Some(new_relay);
-        } else {
-            channel.relay = None;
-        }
+        // with several users left, handle_relay_transition elects the next relay
+        channel.relay = None;
 
         if channel.users.len() == 1 {
-            channel.relay = Some(channel.users[0].name.clone());
+            channel.relay = Some(channel.users[0].peer_id);
             lone_user_addr = Some(channel.users[0].addr);
         }
     }
@@ -146,62 +134,26 @@ pub async fn update_relay_after_departure(
     lone_user_addr
 }
 
-pub async fn handle_user_removal(
-    state: &Arc<Mutex<ServerMap>>,
-    server_id: &str,
-    channel_name: &str,
-    user_name: &str,
-    src_addr: SocketAddr,
-) -> (Vec<User>, bool, Option<SocketAddr>, Option<SocketAddr>) {
-    let mut st = state.lock().await;
-    let mut was_relay = false;
-    let mut leaving_user_addr = None;
-    let mut lone_user_addr = None;
-
-    if let Some(channels) = st.get_mut(server_id) {
-        if let Some(channel) = channels.get_mut(channel_name) {
-            if let Some((found_was_relay, found_leaving_addr)) =
-                find_and_remove_user(channel, user_name, src_addr).await
-            {
-                was_relay = found_was_relay;
-                leaving_user_addr = Some(found_leaving_addr);
-
-                lone_user_addr = update_relay_after_departure(channel, was_relay).await;
-
-                println!("User {} left {}-{}", user_name, server_id, channel_name);
-            } else {
-                println!(
-                    "Ignoring DISCONNECT for {} from {} (no matching session)",
-                    user_name, src_addr
-                );
-            }
-        }
-    }
-
-    let remaining_users = get_remaining_users(&state, server_id, channel_name).await;
-    (
-        remaining_users,
-        was_relay,
-        leaving_user_addr,
-        lone_user_addr,
-    )
-}
-
-pub async fn remove_user_from_other_channels(
-    st: &mut ServerMap,
+// an address sits in one channel at a time: drop its session elsewhere
+pub fn remove_user_from_other_channels(
+    state: &ServerState,
     server_id: &str,
     channel_name: &str,
-    user_name: &str,
     src_addr: SocketAddr,
 ) {
-    if let Some(channels) = st.get_mut(server_id) {
-        for (cname, ch) in channels.iter_mut() {
-            if cname == channel_name {
-                continue;
-            }
-
-            ch.users
-                .retain(|u| !(u.name == user_name && u.addr == src_addr));
-        }
+    let Some(loc) = state.locate(src_addr) else {
+        return;
+    };
+    // a replica is not ours to change, its owner times the user out
+    if (loc.server_id == server_id && loc.channel == channel_name)
+        || state.owner(&loc.server_id, &loc.channel).is_some()
+    {
+        return;
     }
+
+    state.with_channel(&loc.server_id, &loc.channel, |ch| {
+        ch.users
+            .retain(|u| !(u.peer_id == loc.peer_id && u.addr == src_addr))
+    });
+    state.unindex_peer(src_addr, &loc.server_id, &loc.channel);
 }
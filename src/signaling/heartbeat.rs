@@ -1,90 +1,85 @@
 use crate::{
-    proto::control_text::{
-        MSG_DIRECT, MSG_MODE, MSG_PING, MSG_RELAY, MSG_SERVER_RELAY, MSG_USER_LEFT,
-    },
+    log,
+    proto::control::ControlMessage,
     signaling::{
-        structures::{Channel, ServerMap, User},
-        utils::cleanup_and_notify_iter,
+        config::Timeouts,
+        handlers::utils::pick_eligible_relay,
+        metrics,
+        state::ServerState,
+        structures::User,
+        utils::{
+            cleanup_and_notify_iter, control_packet, mode_direct, mode_server_relay, send_sealed,
+            user_left,
+        },
     },
 };
 use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::{net::UdpSocket, sync::Mutex};
+use tokio::net::UdpSocket;
 
-fn pick_eligible_relay(channel: &Channel) -> Option<User> {
-    // First user that is NOT in need_server_relay (meaning he doesn't have Symmetric NAT)
-    channel
-        .users
-        .iter()
-        .find(|u| !u.needs_server_relay)
-        .cloned()
+// queues `msg` for `addrs`; one that cannot be encoded is logged and dropped
+fn notify(
+    notifications: &mut Vec<(Vec<SocketAddr>, Vec<u8>)>,
+    addrs: Vec<SocketAddr>,
+    msg: &ControlMessage,
+) {
+    match control_packet(msg) {
+        Ok(pkt) => notifications.push((addrs, pkt)),
+        Err(e) => log!(Warn, "Not sending {}: {}", msg.name(), e),
+    }
 }
 
 fn handle_relay_timeout(
     channel: &mut crate::signaling::structures::Channel,
     notifications: &mut Vec<(Vec<SocketAddr>, Vec<u8>)>,
 ) {
+    let before = channel.relay;
     if let Some(new_relay_user) = pick_eligible_relay(channel) {
         //setting the new relay in channel
-        channel.relay = Some(new_relay_user.name.clone());
+        channel.relay = Some(new_relay_user.peer_id);
 
         //1) Send MODE RELAY just to the new relay
-        notifications.push((
+        notify(
+            notifications,
             vec![new_relay_user.addr],
-            format!("{MSG_MODE} {MSG_RELAY}\n").as_bytes().to_vec(),
-        ));
+            &ControlMessage::ModeRelay,
+        );
 
-        //2) Send to every peer: "MODE DIRECT <relay_name> <relay_addr>"
-        let peers_addrs: Vec<SocketAddr> = channel
+        //2) Send to every peer: MODE DIRECT <relay_name> <relay_addr>
+        for peer in channel
             .users
             .iter()
-            .filter(|u| u.name != new_relay_user.name)
-            .map(|u| u.addr)
-            .collect();
-        if !peers_addrs.is_empty() {
-            let mut payload = Vec::new();
-            for _peer in channel
-                .users
-                .iter()
-                .filter(|u| u.name != new_relay_user.name)
-            {
-                let m = format!(
-                    "{} {} {} {}\n",
-                    MSG_MODE, MSG_DIRECT, new_relay_user.name, new_relay_user.addr
-                );
-                payload.extend_from_slice(m.as_bytes());
-            }
-            notifications.push((peers_addrs, payload));
+            .filter(|u| u.peer_id != new_relay_user.peer_id)
+        {
+            let msg = mode_direct(&new_relay_user, peer.addr);
+            notify(notifications, vec![peer.addr], &msg);
         }
 
-        // 3) Send new relay info about all other peers: "MODE DIRECT <peer> <addr>"
-        let mut peers_to_new_msg = Vec::new();
+        // 3) Send new relay info about all other peers: MODE DIRECT <peer> <addr>
         for peer in channel
             .users
             .iter()
-            .filter(|u| u.name != new_relay_user.name)
+            .filter(|u| u.peer_id != new_relay_user.peer_id)
         {
-            let m = format!("{} {} {} {}\n", MSG_MODE, MSG_DIRECT, peer.name, peer.addr);
-            peers_to_new_msg.extend_from_slice(m.as_bytes());
-        }
-        if !peers_to_new_msg.is_empty() {
-            notifications.push((vec![new_relay_user.addr], peers_to_new_msg));
+            let msg = mode_direct(peer, new_relay_user.addr);
+            notify(notifications, vec![new_relay_user.addr], &msg);
         }
     } else {
         //No eligible user -> no RELAY
         // we just keep the server as relay (send MODE SERVER_RELAY <user> to all users)
         channel.relay = None;
         if !channel.users.is_empty() {
-            let mut payload = Vec::new();
-            for u in &channel.users {
-                let m = format!("{} {} {}\n", MSG_MODE, MSG_SERVER_RELAY, u.name);
-                payload.extend_from_slice(m.as_bytes());
-            }
-
             //send to all users from channel
             let all_addrs: Vec<SocketAddr> = channel.users.iter().map(|u| u.addr).collect();
-            notifications.push((all_addrs, payload));
+            for u in &channel.users {
+                let msg = mode_server_relay(u);
+                notify(notifications, all_addrs.clone(), &msg);
+            }
         }
     }
+    // nobody is left to relay for in an emptied channel
+    if channel.relay != before && !channel.users.is_empty() {
+        metrics::RELAY_REELECTIONS.inc();
+    }
 }
 
 fn handle_timed_out_user(
@@ -92,47 +87,51 @@ fn handle_timed_out_user(
     channel_name: &str,
     channel: &mut crate::signaling::structures::Channel,
     user_index: usize,
-    user_name: &str,
-    user_addr: SocketAddr,
+    user: &User,
     notifications: &mut Vec<(Vec<SocketAddr>, Vec<u8>)>,
 ) {
-    println!(
-        "User {} timed out from {}-{}",
-        user_name, server_id, channel_name
+    metrics::USER_TIMEOUTS.inc();
+    log!(
+        Info,
+        server_id = server_id,
+        channel = channel_name,
+        user = &user.name,
+        peer_id = user.peer_id,
+        addr = user.addr;
+        "User {} timed out",
+        user.name
     );
 
-    let msg = format!("{MSG_USER_LEFT} {user_name} {user_addr}\n");
+    let msg = user_left(user);
     let peers_to_notify: Vec<SocketAddr> = channel
         .users
         .iter()
-        .filter(|u| u.addr != user_addr)
+        .filter(|u| u.peer_id != user.peer_id)
         .map(|u| u.addr)
         .collect();
 
     if !peers_to_notify.is_empty() {
-        notifications.push((peers_to_notify, msg.as_bytes().to_vec()));
+        notify(notifications, peers_to_notify, &msg);
     }
 
-    let was_relay = channel
-        .relay
-        .as_ref()
-        .map(|r| r == &user_name)
-        .unwrap_or(false);
+    let was_relay = channel.relay == Some(user.peer_id);
 
     channel.users.remove(user_index);
 
     if was_relay {
         handle_relay_timeout(channel, notifications);
     } else if channel.users.len() == 1 {
-        channel.relay = Some(channel.users[0].name.clone());
-        notifications.push((
+        channel.relay = Some(channel.users[0].peer_id);
+        notify(
+            notifications,
             vec![channel.users[0].addr],
-            format!("{MSG_MODE} {MSG_RELAY}\n").as_bytes().to_vec(),
-        ));
+            &ControlMessage::ModeRelay,
+        );
     }
 }
 
 fn process_channel_heartbeat(
+    user_timeout: Duration,
     server_id: &str,
     channel_name: &str,
     channel: &mut crate::signaling::structures::Channel,
@@ -145,14 +144,13 @@ fn process_channel_heartbeat(
     while i < channel.users.len() {
         let user_clone = &channel.users[i].clone();
 
-        if user_clone.last_pong.elapsed() > Duration::from_secs(40) {
+        if user_clone.last_pong.elapsed() > user_timeout {
             handle_timed_out_user(
                 server_id,
                 channel_name,
                 channel,
                 i,
-                &user_clone.name,
-                user_clone.addr,
+                user_clone,
                 notifications,
             );
         } else {
@@ -163,20 +161,20 @@ fn process_channel_heartbeat(
     if channel.users.len() == 1 {
         //one lone user, if he's eligible, we keep him as relay, otherwise the server remains relay
         let solo = channel.users[0].clone();
-        let is_eligible = !solo.needs_server_relay;
+        let is_eligible = solo.relay_rank().is_some();
 
         if is_eligible {
             pings.push(solo.addr);
-            channel.relay = Some(solo.name.clone());
+            channel.relay = Some(solo.peer_id);
         } else {
             //not eligible, don't promote him as relay, keep server as relay
             channel.relay = None;
 
-            //announce it
-            notifications.push((
-                vec![solo.addr],
-                format!("{} {} {}\n", MSG_MODE, MSG_SERVER_RELAY, solo.name).into_bytes(),
-            ));
+            //announce it, unless he still waits to be punched
+            if solo.needs_server_relay {
+                let msg = mode_server_relay(&solo);
+                notify(notifications, vec![solo.addr], &msg);
+            }
         }
     }
 
@@ -185,85 +183,90 @@ fn process_channel_heartbeat(
     }
 }
 
-async fn collect_heartbeat_data(
-    state: Arc<Mutex<ServerMap>>,
-) -> (
+type HeartbeatData = (
     Vec<SocketAddr>,
     Vec<(String, String)>,
     Vec<(Vec<SocketAddr>, Vec<u8>)>,
-) {
-    let mut st = state.lock().await;
+);
+
+pub(crate) fn collect_heartbeat_data(state: &ServerState, user_timeout: Duration) -> HeartbeatData {
     let mut pings = Vec::new();
     let mut cleanup = Vec::new();
     let mut notifications: Vec<(Vec<SocketAddr>, Vec<u8>)> = Vec::new();
+    let mut gone: Vec<(SocketAddr, String, String)> = Vec::new();
 
-    let server_ids: Vec<String> = st.keys().cloned().collect();
-    for sid in server_ids.iter() {
-        if let Some(channels) = st.get_mut(sid) {
-            let channel_names: Vec<String> = channels.keys().cloned().collect();
-            for cname in channel_names.iter() {
-                if let Some(channel) = channels.get_mut(cname) {
-                    process_channel_heartbeat(
-                        sid,
-                        cname,
-                        channel,
-                        &mut pings,
-                        &mut cleanup,
-                        &mut notifications,
-                    );
-                }
+    state.for_each_channel(|sid, cname, channel| {
+        let before: Vec<SocketAddr> = channel.users.iter().map(|u| u.addr).collect();
+
+        process_channel_heartbeat(
+            user_timeout,
+            sid,
+            cname,
+            channel,
+            &mut pings,
+            &mut cleanup,
+            &mut notifications,
+        );
+
+        for addr in before {
+            if !channel.users.iter().any(|u| u.addr == addr) {
+                gone.push((addr, sid.to_string(), cname.to_string()));
             }
         }
+    });
+
+    for (addr, sid, cname) in gone {
+        state.unindex_peer(addr, &sid, &cname);
     }
     (pings, cleanup, notifications)
 }
 
-async fn send_pings(socket: Arc<UdpSocket>, to_ping: Vec<SocketAddr>) {
+async fn send_pings(socket: &UdpSocket, state: &ServerState, to_ping: Vec<SocketAddr>) {
+    let ping = control_packet(&ControlMessage::Ping).expect("PING has no fields");
     for addr in to_ping {
-        if let Err(e) = socket.send_to(MSG_PING.as_bytes(), addr).await {
-            eprintln!("Failed to send {MSG_PING}: {e}");
+        if let Err(e) = send_sealed(socket, state, ping.clone(), addr).await {
+            log!(Warn, addr = addr; "Failed to send PING: {e}");
         }
     }
 }
 
-async fn send_notifications(socket: Arc<UdpSocket>, notify_msgs: Vec<(Vec<SocketAddr>, Vec<u8>)>) {
+async fn send_notifications(
+    socket: &UdpSocket,
+    state: &ServerState,
+    notify_msgs: Vec<(Vec<SocketAddr>, Vec<u8>)>,
+) {
     //send notifications (USER_LEFT, MODE RELAY, MODE DIRECT messages}
-    for (peers_to_notify, payload) in cleanup_and_notify_iter(notify_msgs.into_iter()) {
+    for (peers_to_notify, payload) in cleanup_and_notify_iter(notify_msgs) {
         for addr in peers_to_notify {
-            if let Err(e) = socket.send_to(&payload, addr).await {
-                eprintln!("Failed to send heartbeat notification to {}: {}", addr, e);
-            }
-        }
-    }
-}
-
-async fn cleanup_empty_channels(state: Arc<Mutex<ServerMap>>, to_cleanup: Vec<(String, String)>) {
-    if !to_cleanup.is_empty() {
-        let mut st = state.lock().await;
-        for (sid, cname) in to_cleanup.into_iter() {
-            if let Some(chans) = st.get_mut(&sid) {
-                chans.remove(&cname);
-                if chans.is_empty() {
-                    st.remove(&sid);
-                }
+            if let Err(e) = send_sealed(socket, state, payload.clone(), addr).await {
+                log!(
+                    Warn,
+                    addr = addr;
+                    "Failed to send heartbeat notification: {}",
+                    e
+                );
             }
         }
     }
 }
 
-pub fn start_heartbeat(socket: Arc<UdpSocket>, state: Arc<Mutex<ServerMap>>) {
+pub fn start_heartbeat(socket: Arc<UdpSocket>, state: Arc<ServerState>, timeouts: Timeouts) {
+    let user_timeout = Duration::from_secs(timeouts.user_timeout_secs);
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(20));
+        let mut interval = tokio::time::interval(Duration::from_secs(timeouts.heartbeat_secs));
         loop {
             interval.tick().await;
 
-            let (to_ping, to_cleanup, notify_msgs) = collect_heartbeat_data(state.clone()).await;
+            let started = std::time::Instant::now();
+            let (to_ping, to_cleanup, notify_msgs) = collect_heartbeat_data(&state, user_timeout);
+            metrics::HEARTBEAT_SECONDS.observe(started.elapsed().as_secs_f64());
 
-            send_pings(socket.clone(), to_ping).await;
+            send_pings(&socket, &state, to_ping).await;
 
-            send_notifications(socket.clone(), notify_msgs).await;
+            send_notifications(&socket, &state, notify_msgs).await;
 
-            cleanup_empty_channels(state.clone(), to_cleanup).await;
+            state.remove_empty_channels(&to_cleanup);
+            state.prune_signaling_sessions();
         }
     });
 }
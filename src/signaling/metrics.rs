@@ -0,0 +1,270 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    net::SocketAddr,
+    ops::Index,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use warp::{Filter, Reply, http::header::CONTENT_TYPE};
+
+use crate::proto::packet::Kind;
+use crate::signaling::state::ServerState;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A Prometheus histogram with fixed upper bounds; the `+Inf` bucket is `count`.
+pub struct Histogram<const N: usize> {
+    bounds: [f64; N],
+    buckets: [AtomicU64; N], // not cumulative, summed up when rendered
+    count: AtomicU64,
+    sum: AtomicU64, // f64 bits
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(bounds: [f64; N]) -> Self {
+        Self {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        self.render_series(out, name, "");
+    }
+
+    // the samples alone, `labels` like `kind="data"` or empty
+    fn render_series(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let count = self.count();
+        let sum = f64::from_bits(self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}");
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(
+            out,
+            "{name}_sum{labels} {sum}\n{name}_count{labels} {count}"
+        );
+    }
+}
+
+/// What the server relayed, the `kind` label of the relay metrics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relayed {
+    Data,
+    Dtls,
+    Srtp,
+}
+
+impl Relayed {
+    const ALL: [Relayed; 3] = [Relayed::Data, Relayed::Dtls, Relayed::Srtp];
+
+    /// The kind of a media packet `handle_media` forwards.
+    pub fn media(kind: Kind) -> Option<Self> {
+        match kind {
+            Kind::Dtls => Some(Relayed::Dtls),
+            Kind::Srtp => Some(Relayed::Srtp),
+            Kind::Control | Kind::Noise => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Relayed::Data => "data",
+            Relayed::Dtls => "dtls",
+            Relayed::Srtp => "srtp",
+        }
+    }
+}
+
+/// One metric per `Relayed` kind.
+pub struct ByKind<T>([T; 3]);
+
+impl<T> Index<Relayed> for ByKind<T> {
+    type Output = T;
+
+    fn index(&self, kind: Relayed) -> &T {
+        &self.0[kind as usize]
+    }
+}
+
+impl ByKind<Counter> {
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for kind in Relayed::ALL {
+            let _ = writeln!(
+                out,
+                "{name}{{kind=\"{}\"}} {}",
+                kind.label(),
+                self[kind].get()
+            );
+        }
+    }
+}
+
+impl<const N: usize> ByKind<Histogram<N>> {
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for kind in Relayed::ALL {
+            self[kind].render_series(out, name, &format!("kind=\"{}\"", kind.label()));
+        }
+    }
+}
+
+/// Bytes the server forwarded for users (DATA in `handle_data_from_client`,
+/// DTLS and SRTP in `handle_media`), counted once per receiver.
+pub static RELAYED_BYTES: ByKind<Counter> = ByKind([const { Counter::new() }; 3]);
+/// Users dropped by the heartbeat for not answering pings.
+pub static USER_TIMEOUTS: Counter = Counter::new();
+/// A relay left or timed out and the channel got a new one (or the server).
+pub static RELAY_REELECTIONS: Counter = Counter::new();
+/// Datagrams that were neither a known packet nor a message the server handles.
+pub static MALFORMED_PACKETS: Counter = Counter::new();
+/// Sizes of the packets the server relays.
+pub static RELAYED_PACKET_SIZE: ByKind<Histogram<7>> =
+    ByKind([const { Histogram::new([64.0, 128.0, 256.0, 512.0, 1024.0, 1280.0, 1500.0]) }; 3]);
+/// How long one heartbeat pass over every channel takes.
+pub static HEARTBEAT_SECONDS: Histogram<6> =
+    Histogram::new([0.0001, 0.0005, 0.001, 0.005, 0.01, 0.1]);
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+    );
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    );
+}
+
+/// Every metric in the Prometheus text format; gauges are read from `state`.
+pub fn render(state: &ServerState) -> String {
+    let (mut channels, mut users, mut relayed_users) = (0, 0, 0);
+    state.for_each_channel(|_, _, channel| {
+        channels += 1;
+        users += channel.users.len();
+        relayed_users += channel
+            .users
+            .iter()
+            .filter(|u| u.needs_server_relay)
+            .count();
+    });
+
+    let mut out = String::new();
+    gauge(&mut out, "odnp_channels", "Open channels.", channels);
+    gauge(&mut out, "odnp_users", "Users in all channels.", users);
+    gauge(
+        &mut out,
+        "odnp_server_relay_users",
+        "Users whose traffic the server relays.",
+        relayed_users,
+    );
+    RELAYED_BYTES.render(
+        &mut out,
+        "odnp_relayed_bytes_total",
+        "Bytes of DATA, DTLS and SRTP forwarded for users.",
+    );
+    counter(
+        &mut out,
+        "odnp_user_timeouts_total",
+        "Users dropped after missing heartbeats.",
+        USER_TIMEOUTS.get(),
+    );
+    counter(
+        &mut out,
+        "odnp_relay_reelections_total",
+        "Relays replaced after leaving or timing out.",
+        RELAY_REELECTIONS.get(),
+    );
+    counter(
+        &mut out,
+        "odnp_malformed_packets_total",
+        "Unknown or malformed packets received.",
+        MALFORMED_PACKETS.get(),
+    );
+    RELAYED_PACKET_SIZE.render(
+        &mut out,
+        "odnp_relayed_packet_bytes",
+        "Size of relayed packets.",
+    );
+    HEARTBEAT_SECONDS.render(
+        &mut out,
+        "odnp_heartbeat_seconds",
+        "Duration of a heartbeat pass.",
+    );
+    out
+}
+
+/// Binds `GET /metrics` on `listen`, returning the bound address and the
+/// server future to spawn.
+pub fn bind(
+    listen: SocketAddr,
+    state: Arc<ServerState>,
+) -> Result<(SocketAddr, impl Future<Output = ()>), warp::Error> {
+    let route = warp::get().and(warp::path!("metrics")).map(move || {
+        warp::reply::with_header(render(&state), CONTENT_TYPE, "text/plain; version=0.0.4")
+            .into_response()
+    });
+    warp::serve(route).try_bind_ephemeral(listen)
+}
@@ -1,9 +1,19 @@
+pub mod admin;
+pub mod cluster;
+pub mod config;
 pub mod handlers;
 pub mod heartbeat;
+pub mod metrics;
+pub mod probe;
+pub mod secure;
+pub mod snapshot;
+pub mod state;
+pub mod store;
 pub mod structures;
+pub mod turn;
 pub mod utils;
 
-pub use handlers::handle_message;
+pub use handlers::{handle_message, handle_packet, spawn_packet};
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,181 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    log, net,
+    proto::{
+        control::{ControlMessage, PROBE_CHANGE_IP, PROBE_CHANGE_PORT},
+        noise::{self, MSG_RESPONSE, Responder},
+        stun::{self, Class, Message},
+    },
+    signaling::utils::control_packet,
+};
+
+/// Which of the server's sockets a probe arrived on: `alternate` address or
+/// not, main (0) or probe (1) port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProbeSlot {
+    pub alternate: bool,
+    pub port: usize,
+}
+
+impl ProbeSlot {
+    pub const MAIN: ProbeSlot = ProbeSlot {
+        alternate: false,
+        port: 0,
+    };
+
+    pub const ALL: [ProbeSlot; 4] = [
+        ProbeSlot::MAIN,
+        ProbeSlot {
+            alternate: false,
+            port: 1,
+        },
+        ProbeSlot {
+            alternate: true,
+            port: 0,
+        },
+        ProbeSlot {
+            alternate: true,
+            port: 1,
+        },
+    ];
+}
+
+/// The sockets NAT probes can be answered from, RFC 5780 style: two ports on
+/// the primary address and, if configured, the same two ports on an alternate
+/// address. Without an alternate address clients can still tell port-dependent
+/// behaviour apart, but not address-dependent one.
+pub struct ProbeSockets {
+    primary: [Arc<UdpSocket>; 2],
+    alternate: Option<([Arc<UdpSocket>; 2], IpAddr)>,
+}
+
+impl ProbeSockets {
+    pub fn new(primary: [Arc<UdpSocket>; 2]) -> Self {
+        Self {
+            primary,
+            alternate: None,
+        }
+    }
+
+    pub fn with_alternate(
+        primary: [Arc<UdpSocket>; 2],
+        alternate: [Arc<UdpSocket>; 2],
+        ip: IpAddr,
+    ) -> Self {
+        Self {
+            primary,
+            alternate: Some((alternate, ip)),
+        }
+    }
+
+    // what clients probe for the address-dependent tests: alternate ip, probe port
+    pub fn other_address(&self) -> Option<SocketAddr> {
+        let (sockets, ip) = self.alternate.as_ref()?;
+        let port = sockets[1].local_addr().ok()?.port();
+        Some(SocketAddr::new(*ip, port))
+    }
+
+    pub fn socket(&self, slot: ProbeSlot) -> Option<&Arc<UdpSocket>> {
+        if slot.alternate {
+            self.alternate.as_ref().map(|(s, _)| &s[slot.port])
+        } else {
+            Some(&self.primary[slot.port])
+        }
+    }
+
+    /// The socket that should answer a probe with `flags` that arrived on `slot`,
+    /// `None` if it asks for an address this server does not have.
+    pub fn reply_socket(&self, slot: ProbeSlot, flags: u8) -> Option<&Arc<UdpSocket>> {
+        let alternate = slot.alternate ^ (flags & PROBE_CHANGE_IP != 0);
+        let port = if flags & PROBE_CHANGE_PORT != 0 {
+            1 - slot.port
+        } else {
+            slot.port
+        };
+        self.socket(ProbeSlot { alternate, port })
+    }
+
+    /// Answers a probe; one that came as a one-shot Noise handshake is
+    /// answered inside the handshake response, from whichever socket replies.
+    pub async fn answer(
+        &self,
+        slot: ProbeSlot,
+        seq: u8,
+        flags: u8,
+        src: SocketAddr,
+        responder: Option<Responder>,
+    ) {
+        let Some(socket) = self.reply_socket(slot, flags) else {
+            log!(
+                Info,
+                addr = src;
+                "NAT_PROBE asks for an alternate address we do not have"
+            );
+            return;
+        };
+        let reply = control_packet(&ControlMessage::NatSeen {
+            addr: src,
+            seq,
+            other: self.other_address(),
+        })
+        .expect("NAT_SEEN has no variable-length fields");
+        let reply = match responder {
+            Some(responder) => match responder.finish(&reply) {
+                Some((msg, _)) => noise::frame(MSG_RESPONSE, &msg),
+                None => return,
+            },
+            None => reply,
+        };
+        if let Err(e) = net::send_to(socket, &reply, src).await {
+            log!(Warn, addr = src; "Failed to answer NAT_PROBE: {}", e);
+        }
+    }
+
+    /// Answers a STUN Binding request (RFC 5389) the way `answer` does a
+    /// probe: XOR-MAPPED-ADDRESS, OTHER-ADDRESS and CHANGE-REQUEST (RFC 5780).
+    pub async fn answer_binding(&self, slot: ProbeSlot, req: &Message, src: SocketAddr) {
+        let (change_ip, change_port) = stun::change_request(req);
+        let mut flags = 0;
+        if change_ip {
+            flags |= PROBE_CHANGE_IP;
+        }
+        if change_port {
+            flags |= PROBE_CHANGE_PORT;
+        }
+
+        let (socket, mut reply) = match self.reply_socket(slot, flags) {
+            Some(socket) => {
+                let mut reply = req.reply(Class::Success);
+                reply.add_xor_addr(stun::ATTR_XOR_MAPPED_ADDRESS, src);
+                if let Some(other) = self.other_address() {
+                    reply.add_addr(stun::ATTR_OTHER_ADDRESS, other);
+                }
+                if let Ok(origin) = socket.local_addr()
+                    && !origin.ip().is_unspecified()
+                {
+                    reply.add_addr(stun::ATTR_RESPONSE_ORIGIN, origin);
+                }
+                (socket, reply)
+            }
+            // RFC 5780: a server without an alternate address does not know CHANGE-REQUEST
+            None => {
+                let mut reply = req.reply(Class::Error);
+                reply.add_error(420, "Unknown Attribute").add(
+                    stun::ATTR_UNKNOWN_ATTRIBUTES,
+                    stun::ATTR_CHANGE_REQUEST.to_be_bytes(),
+                );
+                (self.socket(slot).unwrap_or(&self.primary[0]), reply)
+            }
+        };
+        reply.add(stun::ATTR_SOFTWARE, "od-nat-piercer");
+        if let Err(e) = net::send_to(socket, &reply.encode(None), src).await {
+            log!(Warn, addr = src; "Failed to answer STUN Binding: {}", e);
+        }
+    }
+}
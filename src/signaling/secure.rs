@@ -0,0 +1,151 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::proto::{
+    noise::{
+        self, KEY_LEN, MSG_INIT, MSG_RESPONSE, MSG_TRANSPORT, Responder, StaticKeypair, Transport,
+    },
+    packet::{self, Kind},
+};
+
+const UNUSED_SESSION_SEC: u64 = 60; // a handshake never followed by CONNECT is forgotten after this
+
+/// What a received datagram is once the signaling security layer looked at it.
+pub enum Inbound<'a> {
+    /// Handle it like any packet (decrypted, if it came through a session).
+    Packet(Cow<'a, [u8]>),
+    /// A handshake response to send straight back.
+    Reply(Vec<u8>),
+    /// A one-shot handshake carrying a packet (NAT probes); the answer goes back through `responder`.
+    OneShot {
+        packet: Vec<u8>,
+        responder: Responder,
+    },
+    Drop(&'static str),
+}
+
+struct Session {
+    current: Option<Transport>,
+    pending: Option<Transport>, // from a newer handshake, promoted once the client uses it
+    since: Instant,
+}
+
+/// Noise sessions between the server and its clients, keyed by client address.
+pub struct SecureSignaling {
+    key: StaticKeypair,
+    allow_plain: bool,
+    sessions: Mutex<HashMap<SocketAddr, Session>>,
+}
+
+impl SecureSignaling {
+    pub fn new(key: StaticKeypair, allow_plain: bool) -> Self {
+        Self {
+            key,
+            allow_plain,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8; KEY_LEN] {
+        self.key.public()
+    }
+
+    pub fn inbound<'a>(&self, pkt: &'a [u8], src: SocketAddr) -> Inbound<'a> {
+        let Some((hdr, payload)) = packet::decode(pkt) else {
+            return Inbound::Packet(Cow::Borrowed(pkt));
+        };
+        match hdr.kind {
+            // already protected end to end, forwarded by header
+            Kind::Dtls | Kind::Srtp => Inbound::Packet(Cow::Borrowed(pkt)),
+            Kind::Control if !self.allow_plain => Inbound::Drop("plain signaling is disabled"),
+            // only an established session counts: anyone can start a handshake
+            // in the name of a plain client
+            Kind::Control if self.secured(src) => {
+                Inbound::Drop("plain packet from a secured address")
+            }
+            Kind::Control => Inbound::Packet(Cow::Borrowed(pkt)),
+            Kind::Noise => match noise::unframe(payload) {
+                Some((MSG_INIT, body)) => self.handshake(body, src),
+                Some((MSG_TRANSPORT, body)) => self.open(body, src),
+                _ => Inbound::Drop("unexpected Noise message"),
+            },
+        }
+    }
+
+    fn secured(&self, src: SocketAddr) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&src)
+            .is_some_and(|s| s.current.is_some())
+    }
+
+    fn handshake(&self, body: &[u8], src: SocketAddr) -> Inbound<'static> {
+        let Some((packet, responder)) = Responder::read(&self.key, body) else {
+            return Inbound::Drop("bad handshake");
+        };
+        if !packet.is_empty() {
+            return Inbound::OneShot { packet, responder };
+        }
+        let Some((reply, transport)) = responder.finish(&[]) else {
+            return Inbound::Drop("bad handshake");
+        };
+
+        // the old session stays until the new one is used, so a spoofed
+        // handshake cannot cut a client off
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.entry(src).or_insert_with(|| Session {
+            current: None,
+            pending: None,
+            since: Instant::now(),
+        });
+        session.pending = Some(transport);
+        Inbound::Reply(noise::frame(MSG_RESPONSE, &reply))
+    }
+
+    fn open(&self, body: &[u8], src: SocketAddr) -> Inbound<'static> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(&src) else {
+            return Inbound::Drop("no session");
+        };
+        if let Some(plain) = session.current.as_mut().and_then(|t| t.open(body)) {
+            return Inbound::Packet(Cow::Owned(plain));
+        }
+        if let Some(mut pending) = session.pending.take() {
+            if let Some(plain) = pending.open(body) {
+                session.current = Some(pending);
+                session.since = Instant::now();
+                return Inbound::Packet(Cow::Owned(plain));
+            }
+            session.pending = Some(pending);
+        }
+        Inbound::Drop("not sealed for this session")
+    }
+
+    /// `pkt` as it may go to `dst`: sealed for its session, as is if plain
+    /// signaling is allowed, or `None`.
+    pub fn outbound(&self, dst: SocketAddr, pkt: Vec<u8>) -> Option<Vec<u8>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(&dst).and_then(|s| s.current.as_mut()) {
+            Some(transport) => Some(noise::frame(MSG_TRANSPORT, &transport.seal(&pkt))),
+            None if self.allow_plain => Some(pkt),
+            None => None,
+        }
+    }
+
+    /// Forgets sessions that have been unused for a while and fail `in_use`.
+    pub fn prune(&self, in_use: impl Fn(SocketAddr) -> bool) {
+        self.sessions.lock().unwrap().retain(|addr, session| {
+            session.since.elapsed() < Duration::from_secs(UNUSED_SESSION_SEC) || in_use(*addr)
+        });
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    log,
+    proto::{
+        control::{Candidate, CandidateKind, NatKind, PortHint},
+        token,
+    },
+    signaling::{
+        state::ServerState,
+        structures::{Channel, User},
+    },
+};
+
+/// The channel directory as written to disk, so a restarted server keeps every
+/// `channel_id`, peer id and relay choice and clients can RESUME their sessions.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChannelSnapshot {
+    pub server_id: String,
+    pub name: String,
+    pub channel_id: u64,
+    pub next_peer_id: u32,
+    pub relay: Option<u32>,
+    pub users: Vec<UserSnapshot>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UserSnapshot {
+    pub peer_id: u32,
+    pub name: String,
+    pub addr: SocketAddr,
+    pub nat_kind: u8,
+    pub server_relay: bool,
+    pub nonce: u64, // what RESUME must echo, keep the file private
+    pub port_hint: PortHintSnapshot,
+    pub fingerprint: String, // hex
+    pub candidates: Vec<CandidateSnapshot>,
+    #[serde(default)]
+    pub check_key: String, // hex, like the nonce it must stay private
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortHintSnapshot {
+    None,
+    Delta(i32),
+    Random,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CandidateSnapshot {
+    pub kind: u8,
+    pub addr: SocketAddr,
+    pub priority: u32,
+}
+
+impl From<PortHint> for PortHintSnapshot {
+    fn from(hint: PortHint) -> Self {
+        match hint {
+            PortHint::None => PortHintSnapshot::None,
+            PortHint::Delta(delta) => PortHintSnapshot::Delta(delta),
+            PortHint::Random => PortHintSnapshot::Random,
+        }
+    }
+}
+
+impl From<&PortHintSnapshot> for PortHint {
+    fn from(hint: &PortHintSnapshot) -> Self {
+        match hint {
+            PortHintSnapshot::None => PortHint::None,
+            PortHintSnapshot::Delta(delta) => PortHint::Delta(*delta),
+            PortHintSnapshot::Random => PortHint::Random,
+        }
+    }
+}
+
+impl UserSnapshot {
+    fn capture(user: &User) -> Self {
+        Self {
+            peer_id: user.peer_id,
+            name: user.name.clone(),
+            addr: user.addr,
+            nat_kind: user.nat_kind as u8,
+            server_relay: user.needs_server_relay,
+            nonce: user.nonce,
+            port_hint: user.port_hint.into(),
+            fingerprint: token::to_hex(&user.fingerprint),
+            candidates: user
+                .candidates
+                .iter()
+                .map(|c| CandidateSnapshot {
+                    kind: c.kind as u8,
+                    addr: c.addr,
+                    priority: c.priority,
+                })
+                .collect(),
+            check_key: token::to_hex(&user.check_key),
+            home: user.home,
+        }
+    }
+
+    // the user is given a full timeout to come back before it is dropped
+    fn restore(&self) -> User {
+        User {
+            peer_id: self.peer_id,
+            name: self.name.clone(),
+            addr: self.addr,
+            last_pong: Instant::now(),
+            needs_server_relay: self.server_relay,
+            nat_kind: NatKind::from_u8(self.nat_kind),
+            nonce: self.nonce,
+            port_hint: (&self.port_hint).into(),
+            fingerprint: token::from_hex(&self.fingerprint).unwrap_or_default(),
+            candidates: self
+                .candidates
+                .iter()
+                .filter_map(|c| {
+                    Some(Candidate {
+                        kind: CandidateKind::from_u8(c.kind)?,
+                        addr: c.addr,
+                        priority: c.priority,
+                    })
+                })
+                .collect(),
+            check_key: token::from_hex(&self.check_key).unwrap_or_default(),
+            home: self.home,
+        }
+    }
+}
+
+impl ChannelSnapshot {
+    pub fn capture(server_id: &str, name: &str, channel: &Channel) -> Self {
+        Self {
+            server_id: server_id.to_string(),
+            name: name.to_string(),
+            channel_id: channel.channel_id,
+            next_peer_id: channel.next_peer_id,
+            relay: channel.relay,
+            users: channel.users.iter().map(UserSnapshot::capture).collect(),
+        }
+    }
+
+    pub fn restore(&self) -> Channel {
+        Channel {
+            channel_id: self.channel_id,
+            next_peer_id: self.next_peer_id,
+            users: self.users.iter().map(UserSnapshot::restore).collect(),
+            relay: self.relay,
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn capture(state: &ServerState) -> Self {
+        let mut channels = Vec::new();
+        state.for_each_channel(|sid, name, channel| {
+            channels.push(ChannelSnapshot::capture(sid, name, channel));
+        });
+        channels.sort_by(|a, b| (&a.server_id, &a.name).cmp(&(&b.server_id, &b.name)));
+        Self { channels }
+    }
+
+    /// Puts every channel back into `state`, returning how many there were.
+    pub fn restore(&self, state: &ServerState) -> usize {
+        for ch in &self.channels {
+            state.restore_channel(&ch.server_id, &ch.name, ch.restore());
+        }
+        self.channels.len()
+    }
+}
+
+/// Reads the snapshot at `path`, `None` if there is none yet.
+pub async fn load(path: &Path) -> std::io::Result<Option<Snapshot>> {
+    let text = match tokio::fs::read(path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_slice(&text)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Writes the directory of `state` to `path`. The file is replaced in one
+/// rename, a crash while writing leaves the previous snapshot. It holds the
+/// session nonces, so on unix only the owner may read it.
+pub async fn save(state: &ServerState, path: &Path) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(&Snapshot::capture(state))?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    // a leftover from a crash keeps its mode, so it is never reused
+    if let Err(e) = tokio::fs::remove_file(&tmp).await
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        return Err(e);
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    file.write_all(&json).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await
+}
+
+pub fn start_snapshots(state: Arc<ServerState>, path: PathBuf, interval_secs: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        interval.tick().await; // the first tick is immediate, the state was just loaded
+        loop {
+            interval.tick().await;
+            if let Err(e) = save(&state, &path).await {
+                log!(Warn, "Failed to write snapshot {}: {}", path.display(), e);
+            }
+        }
+    });
+}
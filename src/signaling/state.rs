@@ -0,0 +1,305 @@
+use std::{collections::HashMap, net::SocketAddr, sync::RwLock};
+
+use crate::proto::noise::StaticKeypair;
+use crate::signaling::{
+    cluster::{self, Cluster, ClusterStore},
+    config::Limits,
+    secure::{Inbound, SecureSignaling},
+    snapshot::ChannelSnapshot,
+    store::{ChannelStore, MemoryStore},
+    structures::Channel,
+};
+
+/// Where a client address currently lives.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerLocation {
+    pub server_id: String,
+    pub channel: String,
+    pub peer_id: u32,
+}
+
+/// Shared signaling state.
+///
+/// The channel directory lives in a `ChannelStore`, `MemoryStore` unless the
+/// instance is part of a cluster. `by_addr` resolves a source address to its
+/// channel and `by_id` a `channel_id` to its name, both without scanning. Lock
+/// order is store -> channel -> index.
+///
+/// An address only enters `by_addr` through an accepted CONNECT, so it doubles
+/// as the record of authenticated sessions. In a cluster the store also holds
+/// replicas of channels other instances own, and their users are indexed like
+/// local ones (see `cluster`).
+pub struct ServerState {
+    store: Box<dyn ChannelStore>,
+    by_addr: RwLock<HashMap<SocketAddr, PeerLocation>>,
+    by_id: RwLock<HashMap<u64, (String, String)>>,
+    join_secret: Option<Vec<u8>>,
+    secure: Option<SecureSignaling>,
+    limits: Limits,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self {
+            store: Box::new(MemoryStore::default()),
+            by_addr: RwLock::default(),
+            by_id: RwLock::default(),
+            join_secret: None,
+            secure: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl ServerState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requires every CONNECT to carry a token signed with `secret` (see `proto::token`).
+    pub fn with_join_secret(secret: Vec<u8>) -> Self {
+        Self {
+            join_secret: Some(secret),
+            ..Self::default()
+        }
+    }
+
+    pub fn join_secret(&self) -> Option<&[u8]> {
+        self.join_secret.as_deref()
+    }
+
+    /// Seals signaling in Noise sessions under `key`; plain clients are only
+    /// served if `allow_plain` is set. Without this the server speaks plain only.
+    pub fn with_secure_signaling(mut self, key: StaticKeypair, allow_plain: bool) -> Self {
+        self.secure = Some(SecureSignaling::new(key, allow_plain));
+        self
+    }
+
+    /// Caps the number of channels and users per channel, see `handle_connect_message`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Keeps the channel directory in `store` instead of a `MemoryStore`.
+    pub fn with_store(mut self, store: impl ChannelStore + 'static) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    /// Serves channels together with the other instances of `cluster`, see
+    /// `ClusterStore`.
+    pub fn with_cluster(self, cluster: Cluster) -> Self {
+        self.with_store(ClusterStore::new(cluster))
+    }
+
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.store.cluster()
+    }
+
+    /// The instance owning the channel, if that is not this one.
+    pub fn owner(&self, server_id: &str, channel: &str) -> Option<SocketAddr> {
+        self.store.owner(server_id, channel)
+    }
+
+    /// The instance `dst` talks to, if that is not this one. A reply to a
+    /// message another instance passed on goes back through it.
+    pub fn remote_home(&self, dst: SocketAddr) -> Option<(&Cluster, SocketAddr)> {
+        let cluster = self.cluster()?;
+        let home = match self.locate(dst) {
+            Some(l) => self
+                .with_channel(&l.server_id, &l.channel, |ch| {
+                    ch.users.iter().find(|u| u.addr == dst).and_then(|u| u.home)
+                })
+                .flatten(),
+            None => cluster::via_for(dst),
+        };
+        home.filter(|home| *home != cluster.node())
+            .map(|home| (cluster, home))
+    }
+
+    /// Takes in the owner's view of a channel: its users' `home` is rewritten
+    /// from the owner's point of view to this instance's, and addresses that
+    /// left are forgotten.
+    pub fn apply_replica(&self, owner: SocketAddr, snapshot: &ChannelSnapshot) {
+        let node = self.cluster().map(Cluster::node);
+        let (sid, name) = (&snapshot.server_id, &snapshot.name);
+        let mut channel = snapshot.restore();
+        for user in &mut channel.users {
+            user.home = match user.home {
+                None => Some(owner),
+                home if home == node => None,
+                home => home,
+            };
+        }
+        let gone: Vec<SocketAddr> = self
+            .with_channel(sid, name, |ch| {
+                ch.users.iter().map(|u| u.addr).collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|addr| !channel.users.iter().any(|u| u.addr == *addr))
+            .collect();
+        self.restore_channel(sid, name, channel);
+        for addr in gone {
+            self.unindex_peer(addr, sid, name);
+        }
+    }
+
+    pub fn secure(&self) -> Option<&SecureSignaling> {
+        self.secure.as_ref()
+    }
+
+    /// Unwraps a received datagram, see `SecureSignaling::inbound`.
+    pub fn inbound<'a>(&self, pkt: &'a [u8], src: SocketAddr) -> Inbound<'a> {
+        match &self.secure {
+            Some(secure) => secure.inbound(pkt, src),
+            None => Inbound::Packet(pkt.into()),
+        }
+    }
+
+    /// Seals a control packet for `dst`, `None` if it must not be sent.
+    pub fn outbound(&self, dst: SocketAddr, pkt: Vec<u8>) -> Option<Vec<u8>> {
+        match &self.secure {
+            Some(secure) => secure.outbound(dst, pkt),
+            None => Some(pkt),
+        }
+    }
+
+    /// Drops Noise sessions of addresses that never joined or have left.
+    pub fn prune_signaling_sessions(&self) {
+        if let Some(secure) = &self.secure {
+            secure.prune(|addr| self.locate(addr).is_some());
+        }
+    }
+
+    /// Runs `f` on the channel if it exists.
+    pub fn with_channel<R>(
+        &self,
+        server_id: &str,
+        channel: &str,
+        f: impl FnOnce(&mut Channel) -> R,
+    ) -> Option<R> {
+        let mut f = Some(f);
+        let mut out = None;
+        self.store.update(server_id, channel, false, &mut |ch| {
+            out = f.take().map(|f| f(ch));
+        });
+        out
+    }
+
+    /// Runs `f` on the channel, creating it (with a fresh `channel_id`) first if needed.
+    pub fn with_channel_or_create<R>(
+        &self,
+        server_id: &str,
+        channel: &str,
+        f: impl FnOnce(&mut Channel) -> R,
+    ) -> R {
+        let mut f = Some(f);
+        let mut out = None;
+        self.store.update(server_id, channel, true, &mut |ch| {
+            // a channel the store just created is not indexed yet
+            if !self.by_id.read().unwrap().contains_key(&ch.channel_id) {
+                self.by_id
+                    .write()
+                    .unwrap()
+                    .insert(ch.channel_id, (server_id.to_string(), channel.to_string()));
+            }
+            out = f.take().map(|f| f(ch));
+        });
+        out.expect("the store creates missing channels")
+    }
+
+    /// Runs `f` on every channel this instance owns (replicas are left to
+    /// their owners), one channel lock at a time.
+    pub fn for_each_channel(&self, mut f: impl FnMut(&str, &str, &mut Channel)) {
+        self.store.for_each(&mut f);
+    }
+
+    /// Puts back a channel kept across a restart (see `snapshot`), replacing any
+    /// channel of that name, and indexes its users' addresses.
+    pub fn restore_channel(&self, server_id: &str, name: &str, channel: Channel) {
+        for user in &channel.users {
+            self.index_peer(
+                user.addr,
+                PeerLocation {
+                    server_id: server_id.to_string(),
+                    channel: name.to_string(),
+                    peer_id: user.peer_id,
+                },
+            );
+        }
+        let channel_id = channel.channel_id;
+        let replaced = self.store.insert(server_id, name, channel);
+        let mut by_id = self.by_id.write().unwrap();
+        if let Some(replaced) = replaced {
+            by_id.remove(&replaced.channel_id);
+        }
+        by_id.insert(channel_id, (server_id.to_string(), name.to_string()));
+    }
+
+    /// The server id and name of the channel handed out as `channel_id`.
+    pub fn find_channel(&self, channel_id: u64) -> Option<(String, String)> {
+        self.by_id.read().unwrap().get(&channel_id).cloned()
+    }
+
+    /// Drops the listed channels if they are still empty, and servers left without channels.
+    pub fn remove_empty_channels(&self, keys: &[(String, String)]) {
+        for (sid, cname) in keys {
+            if let Some(removed) = self.store.remove(sid, cname, true) {
+                self.by_id.write().unwrap().remove(&removed.channel_id);
+            }
+        }
+    }
+
+    /// Removes a channel outright, forgetting the sessions of its users, and
+    /// returns what it held.
+    pub fn remove_channel(&self, server_id: &str, channel: &str) -> Option<Channel> {
+        let removed = self.store.remove(server_id, channel, false)?;
+        self.by_id.write().unwrap().remove(&removed.channel_id);
+        for user in &removed.users {
+            self.unindex_peer(user.addr, server_id, channel);
+        }
+        Some(removed)
+    }
+
+    pub fn locate(&self, addr: SocketAddr) -> Option<PeerLocation> {
+        self.by_addr.read().unwrap().get(&addr).cloned()
+    }
+
+    /// The session `addr` holds in `server_id`/`channel`, if any.
+    pub fn session_in(
+        &self,
+        addr: SocketAddr,
+        server_id: &str,
+        channel: &str,
+    ) -> Option<PeerLocation> {
+        self.locate(addr)
+            .filter(|l| l.server_id == server_id && l.channel == channel)
+    }
+
+    pub fn index_peer(&self, addr: SocketAddr, location: PeerLocation) {
+        self.by_addr.write().unwrap().insert(addr, location);
+    }
+
+    /// Forgets `addr`, but only while it still points at the given channel
+    /// (the address may have rejoined elsewhere meanwhile).
+    pub fn unindex_peer(&self, addr: SocketAddr, server_id: &str, channel: &str) {
+        let mut by_addr = self.by_addr.write().unwrap();
+        if by_addr
+            .get(&addr)
+            .is_some_and(|l| l.server_id == server_id && l.channel == channel)
+        {
+            by_addr.remove(&addr);
+        }
+    }
+
+    /// Channels this instance owns.
+    pub fn channel_count(&self) -> usize {
+        self.store.channel_count()
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::signaling::{cluster::Cluster, structures::Channel, utils::generate_channel_id};
+
+/// Where the channel directory lives. `ServerState` goes through it for every
+/// channel access and keeps the address and `channel_id` indexes itself.
+///
+/// `f` runs while the channel is locked and must not call back into the store.
+pub trait ChannelStore: Send + Sync {
+    /// Runs `f` on the channel, creating it (with a fresh `channel_id`) first
+    /// if `create` is set. False if there was no channel.
+    fn update(
+        &self,
+        server_id: &str,
+        channel: &str,
+        create: bool,
+        f: &mut dyn FnMut(&mut Channel),
+    ) -> bool;
+
+    /// Runs `f` on every channel this instance owns, one channel lock at a time.
+    fn for_each(&self, f: &mut dyn FnMut(&str, &str, &mut Channel));
+
+    /// Takes the channel out, if it is there (and empty, with `only_if_empty`).
+    fn remove(&self, server_id: &str, channel: &str, only_if_empty: bool) -> Option<Channel>;
+
+    /// Puts in a channel, returning the one of that name it replaced.
+    fn insert(&self, server_id: &str, channel: &str, ch: Channel) -> Option<Channel>;
+
+    /// Channels this instance owns.
+    fn channel_count(&self) -> usize;
+
+    /// The instance owning the channel, if that is not this one.
+    fn owner(&self, _server_id: &str, _channel: &str) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The cluster the channels are shared over, if any.
+    fn cluster(&self) -> Option<&Cluster> {
+        None
+    }
+}
+
+// server_id -> channel name -> channel
+type Directory = HashMap<String, HashMap<String, Arc<Mutex<Channel>>>>;
+
+/// The directory of a single instance, and the default store.
+///
+/// The directory lock only guards the server -> channel map and is never held
+/// across an `.await`; every channel has its own lock, so traffic for different
+/// channels never contends.
+#[derive(Default)]
+pub struct MemoryStore {
+    servers: RwLock<Directory>,
+}
+
+impl MemoryStore {
+    /// How many channels `keep` accepts, without locking any of them.
+    pub fn count_where(&self, keep: impl Fn(&str, &str) -> bool) -> usize {
+        let servers = self.servers.read().unwrap();
+        servers
+            .iter()
+            .flat_map(|(sid, channels)| channels.keys().map(move |name| (sid, name)))
+            .filter(|(sid, name)| keep(sid, name))
+            .count()
+    }
+}
+
+impl ChannelStore for MemoryStore {
+    fn update(
+        &self,
+        server_id: &str,
+        channel: &str,
+        create: bool,
+        f: &mut dyn FnMut(&mut Channel),
+    ) -> bool {
+        {
+            let servers = self.servers.read().unwrap();
+            if let Some(ch) = servers.get(server_id).and_then(|c| c.get(channel)) {
+                f(&mut ch.lock().unwrap());
+                return true;
+            }
+        }
+        if !create {
+            return false;
+        }
+
+        let mut servers = self.servers.write().unwrap();
+        let ch = servers
+            .entry(server_id.to_string())
+            .or_default()
+            .entry(channel.to_string())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Channel {
+                    channel_id: generate_channel_id(),
+                    ..Channel::default()
+                }))
+            });
+        f(&mut ch.lock().unwrap());
+        true
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &str, &mut Channel)) {
+        let servers = self.servers.read().unwrap();
+        for (sid, channels) in servers.iter() {
+            for (cname, ch) in channels.iter() {
+                f(sid, cname, &mut ch.lock().unwrap());
+            }
+        }
+    }
+
+    fn remove(&self, server_id: &str, channel: &str, only_if_empty: bool) -> Option<Channel> {
+        let removed = {
+            let mut servers = self.servers.write().unwrap();
+            let chans = servers.get_mut(server_id)?;
+            let removed = if only_if_empty
+                && !chans
+                    .get(channel)
+                    .is_some_and(|c| c.lock().unwrap().users.is_empty())
+            {
+                None
+            } else {
+                chans.remove(channel)
+            };
+            if chans.is_empty() {
+                servers.remove(server_id);
+            }
+            removed?
+        };
+        Some(std::mem::take(&mut *removed.lock().unwrap()))
+    }
+
+    fn insert(&self, server_id: &str, channel: &str, ch: Channel) -> Option<Channel> {
+        let replaced = self
+            .servers
+            .write()
+            .unwrap()
+            .entry(server_id.to_string())
+            .or_default()
+            .insert(channel.to_string(), Arc::new(Mutex::new(ch)))?;
+        Some(std::mem::take(&mut *replaced.lock().unwrap()))
+    }
+
+    fn channel_count(&self) -> usize {
+        self.count_where(|_, _| true)
+    }
+}
@@ -1,12 +1,7 @@
-use std::{collections::HashMap, net::SocketAddr, time::Instant};
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum NatKind {
-    Unknown,
-    Public,
-    Cone,
-    Symmetric,
-}
+use std::{net::SocketAddr, time::Instant};
+
+pub use crate::proto::control::{Candidate, NatKind, PortHint};
+use crate::signaling::utils::generate_session_nonce;
 
 #[derive(Clone, Debug)]
 pub struct User {
@@ -16,6 +11,12 @@ pub struct User {
     pub last_pong: Instant,
     pub needs_server_relay: bool,
     pub nat_kind: NatKind,
+    pub nonce: u64, // handed out in WELCOME, echoed in HB/PEER_TIMEOUT/REQUEST_RELAY
+    pub port_hint: PortHint, // how a symmetric user can still be punched
+    pub fingerprint: Vec<u8>, // DTLS certificate digest, forwarded to peers in MODE messages
+    pub candidates: Vec<Candidate>, // gathered by the client, forwarded in MODE DIRECT
+    pub check_key: Vec<u8>, // what peers MAC their connectivity checks with, forwarded in MODE DIRECT
+    pub home: Option<SocketAddr>, // cluster address of the instance the user talks to, see `cluster`
 }
 
 impl User {
@@ -27,6 +28,49 @@ impl User {
             last_pong: Instant::now(),
             needs_server_relay: matches!(nat_kind, NatKind::Symmetric),
             nat_kind,
+            nonce: generate_session_nonce(),
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+            home: None,
+        }
+    }
+
+    // a symmetric user that can be punched is not relayed by the server up front
+    pub fn with_port_hint(mut self, hint: PortHint) -> Self {
+        self.port_hint = hint;
+        self.needs_server_relay = self.nat_kind == NatKind::Symmetric && hint == PortHint::None;
+        self
+    }
+
+    pub fn with_fingerprint(mut self, fingerprint: Vec<u8>) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    pub fn with_candidates(mut self, candidates: Vec<Candidate>) -> Self {
+        self.candidates = candidates;
+        self
+    }
+
+    pub fn with_check_key(mut self, check_key: Vec<u8>) -> Self {
+        self.check_key = check_key;
+        self
+    }
+
+    // lower is a better relay, None if peers cannot reach this user directly
+    pub fn relay_rank(&self) -> Option<u8> {
+        if self.needs_server_relay {
+            return None;
+        }
+        match self.nat_kind {
+            NatKind::Public => Some(0),
+            NatKind::Cone => Some(1),
+            NatKind::RestrictedCone => Some(2),
+            NatKind::PortRestrictedCone => Some(3),
+            NatKind::Unknown => Some(4),
+            NatKind::Symmetric => None,
         }
     }
 }
@@ -36,7 +80,7 @@ pub struct Channel {
     pub channel_id: u64,
     pub next_peer_id: u32,
     pub users: Vec<User>,
-    pub relay: Option<String>,
+    pub relay: Option<u32>, // peer_id of the relay user
 }
 
 impl Default for Channel {
@@ -49,5 +93,3 @@ impl Default for Channel {
         }
     }
 }
-
-pub type ServerMap = HashMap<String, HashMap<String, Channel>>;
@@ -1,12 +1,29 @@
-use crate::signaling::handlers::utils::{add_new_user, update_existing_user};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::net::UdpSocket;
+
+use crate::proto::control::{self, Candidate, CandidateKind, ControlMessage, PortHint};
+use crate::proto::noise;
+use crate::proto::packet::{BROADCAST, Header, Kind};
+use crate::proto::token;
+use crate::signaling::config::{ConfigError, LogFormat, LogLevel, ServerConfig, Timeouts};
+use crate::signaling::handlers::connect::check_join_token;
+use crate::signaling::handlers::handle_message;
+use crate::signaling::handlers::media::media_targets;
+use crate::signaling::handlers::utils::{
+    add_new_user, authenticate_sender, pick_eligible_relay, update_existing_user,
+};
+use crate::signaling::probe::{ProbeSlot, ProbeSockets};
+use crate::signaling::secure::Inbound;
 
+use crate::signaling::state::{PeerLocation, ServerState};
 use crate::signaling::structures::{Channel, NatKind, User};
-use std::sync::Arc;
+use crate::signaling::utils::{Outbox, mode_direct, mode_server_relay};
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use crate::signaling::utils::generate_channel_id;
-
     use super::*;
 
     #[test]
@@ -56,8 +73,8 @@ mod tests {
         assert_eq!(user.nat_kind, NatKind::Symmetric);
     }
 
-    #[tokio::test]
-    async fn add_new_user_assigns_incrementing_peer_ids() {
+    #[test]
+    fn add_new_user_assigns_incrementing_peer_ids() {
         let mut channel = Channel {
             channel_id: 123,
             next_peer_id: 1,
@@ -65,29 +82,50 @@ mod tests {
             relay: None,
         };
 
-        let socket = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut outbox = Outbox::new();
 
         let addr1: std::net::SocketAddr = "127.0.0.1:6001".parse().unwrap();
-        let (updated_channel, peer_id1) =
-            add_new_user(&mut channel, "name1", addr1, &socket, NatKind::Cone).await;
+        let peer_id1 = add_new_user(
+            &mut channel,
+            "name1",
+            addr1,
+            &mut outbox,
+            NatKind::Cone,
+            PortHint::None,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
 
         assert_eq!(peer_id1, 1);
-        assert_eq!(updated_channel.users.len(), 1);
-        assert_eq!(updated_channel.users[0].peer_id, 1);
-        assert_eq!(updated_channel.next_peer_id, 2);
+        assert_eq!(channel.users.len(), 1);
+        assert_eq!(channel.users[0].peer_id, 1);
+        assert_eq!(channel.next_peer_id, 2);
+        // lone user is told to relay
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].0, addr1);
 
         let addr2: std::net::SocketAddr = "127.0.0.1:6002".parse().unwrap();
-        let (updated_channel, peer_id2) =
-            add_new_user(&mut channel, "name2", addr2, &socket, NatKind::Cone).await;
+        let peer_id2 = add_new_user(
+            &mut channel,
+            "name2",
+            addr2,
+            &mut outbox,
+            NatKind::Cone,
+            PortHint::None,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
 
         assert_eq!(peer_id2, 2);
-        assert_eq!(updated_channel.users.len(), 2);
-        assert_eq!(updated_channel.users[1].peer_id, 2);
-        assert_eq!(updated_channel.next_peer_id, 3);
+        assert_eq!(channel.users.len(), 2);
+        assert_eq!(channel.users[1].peer_id, 2);
+        assert_eq!(channel.next_peer_id, 3);
     }
 
-    #[tokio::test]
-    async fn update_existing_user_returns_existing_peer_id() {
+    #[test]
+    fn update_existing_user_returns_existing_peer_id() {
         let addr: std::net::SocketAddr = "127.0.0.1:7001".parse().unwrap();
 
         let mut channel = Channel {
@@ -100,23 +138,25 @@ mod tests {
                 last_pong: std::time::Instant::now(),
                 needs_server_relay: false,
                 nat_kind: NatKind::Cone,
+                nonce: 11,
+                port_hint: PortHint::None,
+                fingerprint: Vec::new(),
+                candidates: Vec::new(),
+                check_key: Vec::new(),
+                home: None,
             }],
             relay: None,
         };
 
-        let result = update_existing_user(&mut channel, "name", addr).await;
+        let result = update_existing_user(&mut channel, "name", addr);
 
-        assert!(result.is_some());
-
-        let (updated_channel, is_new, peer_id) = result.unwrap();
-        assert!(!is_new);
-        assert_eq!(peer_id, 1);
-        assert_eq!(updated_channel.users.len(), 1);
-        assert_eq!(updated_channel.users[0].peer_id, 1);
+        assert_eq!(result, Some(1));
+        assert_eq!(channel.users.len(), 1);
+        assert_eq!(channel.users[0].peer_id, 1);
     }
 
-    #[tokio::test]
-    async fn update_existing_user_returns_none_for_missing_user() {
+    #[test]
+    fn update_existing_user_returns_none_for_missing_user() {
         let addr: std::net::SocketAddr = "127.0.0.1:7002".parse().unwrap();
 
         let mut channel = Channel {
@@ -126,8 +166,2356 @@ mod tests {
             relay: None,
         };
 
-        let result = update_existing_user(&mut channel, "name", addr).await;
+        let result = update_existing_user(&mut channel, "name", addr);
 
         assert!(result.is_none());
     }
+
+    #[test]
+    fn state_creates_channel_once_and_keeps_its_id() {
+        let state = ServerState::new();
+
+        let id1 = state.with_channel_or_create("s", "c", |ch| ch.channel_id);
+        let id2 = state.with_channel_or_create("s", "c", |ch| ch.channel_id);
+
+        assert_ne!(id1, 0);
+        assert_eq!(id1, id2);
+        assert_eq!(state.channel_count(), 1);
+        assert!(state.with_channel("s", "other", |_| ()).is_none());
+    }
+
+    #[test]
+    fn state_index_resolves_and_forgets_addresses() {
+        let state = ServerState::new();
+        let addr: std::net::SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let loc = PeerLocation {
+            server_id: "s".to_string(),
+            channel: "c".to_string(),
+            peer_id: 3,
+        };
+
+        state.index_peer(addr, loc.clone());
+        assert_eq!(state.locate(addr), Some(loc));
+
+        // a stale unindex for another channel leaves the entry alone
+        state.unindex_peer(addr, "s", "other");
+        assert!(state.locate(addr).is_some());
+
+        state.unindex_peer(addr, "s", "c");
+        assert!(state.locate(addr).is_none());
+    }
+
+    #[test]
+    fn state_finds_channels_by_id_until_they_are_gone() {
+        let state = ServerState::new();
+        let id = state.with_channel_or_create("s", "c", |ch| ch.channel_id);
+        assert_eq!(state.find_channel(id), Some(("s".into(), "c".into())));
+
+        state.remove_empty_channels(&[("s".into(), "c".into())]);
+        assert_eq!(state.find_channel(id), None);
+
+        // a channel put back under a name drops the id it replaced
+        let channel = |channel_id| Channel {
+            channel_id,
+            ..Channel::default()
+        };
+        state.restore_channel("s", "c", channel(7));
+        state.restore_channel("s", "c", channel(8));
+        assert_eq!(state.find_channel(7), None);
+        assert_eq!(state.find_channel(8), Some(("s".into(), "c".into())));
+        state.remove_channel("s", "c");
+        assert_eq!(state.find_channel(8), None);
+    }
+
+    #[test]
+    fn state_removes_only_empty_channels() {
+        let state = ServerState::new();
+        let addr: std::net::SocketAddr = "127.0.0.1:8002".parse().unwrap();
+
+        state.with_channel_or_create("s", "empty", |_| ());
+        state.with_channel_or_create("s", "busy", |ch| {
+            ch.users.push(User::new("name", addr, NatKind::Cone, 1))
+        });
+
+        state.remove_empty_channels(&[
+            ("s".to_string(), "empty".to_string()),
+            ("s".to_string(), "busy".to_string()),
+        ]);
+
+        assert!(state.with_channel("s", "empty", |_| ()).is_none());
+        assert!(state.with_channel("s", "busy", |_| ()).is_some());
+        assert_eq!(state.channel_count(), 1);
+    }
+
+    fn media_channel() -> Channel {
+        let mut channel = Channel {
+            channel_id: 42,
+            next_peer_id: 4,
+            users: Vec::new(),
+            relay: None,
+        };
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            let addr = format!("127.0.0.1:{}", 9001 + i).parse().unwrap();
+            channel
+                .users
+                .push(User::new(name, addr, NatKind::Cone, i as u32 + 1));
+        }
+        channel
+    }
+
+    fn media_header(channel_id: u64, src_peer_id: u32, dst_peer_id: u32) -> Header {
+        Header {
+            kind: Kind::Srtp,
+            flags: 0,
+            channel_id,
+            src_peer_id,
+            dst_peer_id,
+            stream_id: 1,
+            payload_len: 0,
+        }
+    }
+
+    #[test]
+    fn media_broadcast_reaches_everyone_but_sender() {
+        let channel = media_channel();
+        let src = channel.users[0].addr;
+
+        let targets = media_targets(&channel, &media_header(42, 1, BROADCAST), src).unwrap();
+
+        assert_eq!(targets, vec![channel.users[1].addr, channel.users[2].addr]);
+    }
+
+    #[test]
+    fn media_unicast_reaches_only_dst_peer() {
+        let channel = media_channel();
+        let src = channel.users[0].addr;
+
+        let targets = media_targets(&channel, &media_header(42, 1, 3), src).unwrap();
+        assert_eq!(targets, vec![channel.users[2].addr]);
+
+        let targets = media_targets(&channel, &media_header(42, 1, 99), src).unwrap();
+        assert!(targets.is_empty());
+    }
+
+    #[test]
+    fn media_rejects_spoofed_source() {
+        let channel = media_channel();
+        let src = channel.users[0].addr;
+
+        // src does not own peer 2
+        assert!(media_targets(&channel, &media_header(42, 2, BROADCAST), src).is_none());
+        // wrong channel
+        assert!(media_targets(&channel, &media_header(7, 1, BROADCAST), src).is_none());
+        // unknown address
+        let stranger = "127.0.0.1:9999".parse().unwrap();
+        assert!(media_targets(&channel, &media_header(42, 1, BROADCAST), stranger).is_none());
+    }
+
+    const SECRET: &[u8] = b"join secret";
+
+    fn connect_msg(user: &str, token: Vec<u8>) -> ControlMessage {
+        ControlMessage::Connect {
+            server_id: "s1".into(),
+            channel: "ch1".into(),
+            user: user.into(),
+            nat_kind: NatKind::Cone,
+            token,
+            port_hint: PortHint::None,
+            fingerprint: Vec::new(),
+            candidates: Vec::new(),
+            check_key: Vec::new(),
+        }
+    }
+
+    async fn recv_control(socket: &UdpSocket) -> ControlMessage {
+        let mut buf = [0u8; 2048];
+        let (len, _) = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            socket.recv_from(&mut buf),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        control::decode_packet(&buf[..len]).unwrap().1
+    }
+
+    #[test]
+    fn open_server_ignores_tokens() {
+        let state = ServerState::new();
+        assert!(check_join_token(&state, "s1", "ch1", "alice", &[]).is_ok());
+    }
+
+    #[test]
+    fn join_token_must_match_claims_and_be_fresh() {
+        let state = ServerState::with_join_secret(SECRET.to_vec());
+        let fresh = token::sign(SECRET, "s1", "ch1", "alice", token::now_secs() + 60);
+        let stale = token::sign(SECRET, "s1", "ch1", "alice", token::now_secs() - 1);
+
+        assert!(check_join_token(&state, "s1", "ch1", "alice", &fresh).is_ok());
+        assert_eq!(
+            check_join_token(&state, "s1", "ch1", "mallory", &fresh),
+            Err(token::TokenError::BadSignature)
+        );
+        assert_eq!(
+            check_join_token(&state, "s1", "ch1", "alice", &stale),
+            Err(token::TokenError::Expired)
+        );
+        assert_eq!(
+            check_join_token(&state, "s1", "ch1", "alice", &[]),
+            Err(token::TokenError::Missing)
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_without_valid_token_is_rejected() {
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = client.local_addr().unwrap();
+        let state = Arc::new(ServerState::with_join_secret(SECRET.to_vec()));
+        let hdr = Header::control(0, 0, 0, 0);
+
+        let forged = token::sign(b"guess", "s1", "ch1", "alice", token::now_secs() + 60);
+        handle_message(
+            connect_msg("alice", forged),
+            &hdr,
+            src,
+            server.clone(),
+            state.clone(),
+        )
+        .await;
+
+        assert!(matches!(
+            recv_control(&client).await,
+            ControlMessage::Rejected { .. }
+        ));
+        assert_eq!(state.channel_count(), 0);
+        assert_eq!(state.locate(src), None);
+
+        let valid = token::sign(SECRET, "s1", "ch1", "alice", token::now_secs() + 60);
+        handle_message(
+            connect_msg("alice", valid),
+            &hdr,
+            src,
+            server,
+            state.clone(),
+        )
+        .await;
+
+        assert!(matches!(
+            recv_control(&client).await,
+            ControlMessage::Welcome { .. }
+        ));
+        assert!(state.session_in(src, "s1", "ch1").is_some());
+    }
+
+    fn relay_request(peer_id: u32, nonce: u64) -> ControlMessage {
+        ControlMessage::RequestRelay {
+            server_id: "s1".into(),
+            channel: "ch1".into(),
+            user: "bob".into(),
+            peer_id,
+            nonce,
+        }
+    }
+
+    fn peer_timeout(peer_id: u32, nonce: u64) -> ControlMessage {
+        ControlMessage::PeerTimeout {
+            server_id: "s1".into(),
+            channel: "ch1".into(),
+            user: "bob".into(),
+            peer_id,
+            nonce,
+        }
+    }
+
+    // alice joins first and becomes the relay, then bob joins
+    async fn two_peer_server() -> (Arc<UdpSocket>, Arc<ServerState>, [(UdpSocket, u32, u64); 2]) {
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let state = Arc::new(ServerState::new());
+        let hdr = Header::control(0, 0, 0, 0);
+
+        let mut peers = Vec::new();
+        for name in ["alice", "bob"] {
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = sock.local_addr().unwrap();
+            handle_message(
+                connect_msg(name, Vec::new()),
+                &hdr,
+                addr,
+                server.clone(),
+                state.clone(),
+            )
+            .await;
+            let ControlMessage::Welcome { peer_id, nonce, .. } = recv_control(&sock).await else {
+                panic!("expected WELCOME");
+            };
+            peers.push((sock, peer_id, nonce));
+        }
+        (server, state, peers.try_into().ok().unwrap())
+    }
+
+    fn channel_users(state: &ServerState) -> Vec<User> {
+        state
+            .with_channel("s1", "ch1", |ch| ch.users.clone())
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_stuck_channel_does_not_hold_up_another() {
+        use crate::signaling::spawn_packet;
+
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let state = Arc::new(ServerState::new());
+        // alice alone in ch1, bob alone in ch2
+        let mut heartbeats = Vec::new();
+        for (i, (name, channel)) in [("alice", "ch1"), ("bob", "ch2")].into_iter().enumerate() {
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let user = User::new(name, sock.local_addr().unwrap(), NatKind::Cone, 1);
+            let hb = ControlMessage::Heartbeat {
+                server_id: "s1".into(),
+                channel: channel.into(),
+                user: name.into(),
+                nonce: user.nonce,
+            };
+            let channel_state = Channel {
+                channel_id: i as u64 + 1,
+                next_peer_id: 2,
+                users: vec![user],
+                relay: Some(1),
+            };
+            state.restore_channel("s1", channel, channel_state);
+            heartbeats.push((sock, control::encode_packet(0, 0, 0, &hb).unwrap()));
+        }
+        let [(alice, alice_hb), (bob, bob_hb)]: [_; 2] = heartbeats.try_into().ok().unwrap();
+
+        // something holds ch1 while alice's HB comes in
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                state.with_channel("s1", "ch1", |_| {
+                    locked_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                });
+            })
+        };
+        locked_rx.recv().unwrap();
+
+        let alice_addr = alice.local_addr().unwrap();
+        let stuck = spawn_packet(alice_hb, alice_addr, server.clone(), state.clone());
+        let bob_addr = bob.local_addr().unwrap();
+        spawn_packet(bob_hb, bob_addr, server.clone(), state.clone());
+
+        // ch2 is served meanwhile
+        assert_eq!(recv_control(&bob).await, ControlMessage::Pong);
+        assert!(!stuck.is_finished());
+
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(recv_control(&alice).await, ControlMessage::Pong);
+        stuck.await.unwrap();
+    }
+
+    #[test]
+    fn authenticate_sender_needs_address_and_nonce() {
+        let addr: std::net::SocketAddr = "127.0.0.1:7100".parse().unwrap();
+        let user = User::new("alice", addr, NatKind::Cone, 1);
+        let nonce = user.nonce;
+        let channel = Channel {
+            users: vec![user],
+            ..Channel::default()
+        };
+
+        assert!(authenticate_sender(&channel, addr, nonce).is_some());
+        assert!(authenticate_sender(&channel, addr, nonce ^ 1).is_none());
+        assert!(authenticate_sender(&channel, "127.0.0.1:7101".parse().unwrap(), nonce).is_none());
+    }
+
+    #[test]
+    fn every_session_gets_its_own_nonce() {
+        let addr: std::net::SocketAddr = "127.0.0.1:7200".parse().unwrap();
+        let a = User::new("alice", addr, NatKind::Cone, 1);
+        let b = User::new("alice", addr, NatKind::Cone, 2);
+        assert_ne!(a.nonce, b.nonce);
+    }
+
+    #[tokio::test]
+    async fn control_from_unknown_address_is_ignored() {
+        let (server, state, [_, (_, bob_id, bob_nonce)]) = two_peer_server().await;
+        let hdr = Header::control(0, 0, 0, 0);
+
+        // a host that never connected cannot flip bob to server relay or evict him,
+        // even if it learned his nonce
+        let spoofer: std::net::SocketAddr = "127.0.0.1:9".parse().unwrap();
+        handle_message(
+            relay_request(bob_id, bob_nonce),
+            &hdr,
+            spoofer,
+            server.clone(),
+            state.clone(),
+        )
+        .await;
+        handle_message(
+            peer_timeout(bob_id, bob_nonce),
+            &hdr,
+            spoofer,
+            server,
+            state.clone(),
+        )
+        .await;
+
+        let users = channel_users(&state);
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().all(|u| !u.needs_server_relay));
+    }
+
+    #[tokio::test]
+    async fn control_without_session_nonce_is_ignored() {
+        let (server, state, [(alice, _, alice_nonce), (_, bob_id, _)]) = two_peer_server().await;
+        let alice_addr = alice.local_addr().unwrap();
+        let hdr = Header::control(0, 0, 0, 0);
+
+        // right address (e.g. spoofed source), wrong nonce
+        let wrong = alice_nonce.wrapping_add(1);
+        handle_message(
+            relay_request(bob_id, wrong),
+            &hdr,
+            alice_addr,
+            server.clone(),
+            state.clone(),
+        )
+        .await;
+        handle_message(
+            peer_timeout(bob_id, wrong),
+            &hdr,
+            alice_addr,
+            server,
+            state.clone(),
+        )
+        .await;
+
+        let users = channel_users(&state);
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().all(|u| !u.needs_server_relay));
+    }
+
+    #[tokio::test]
+    async fn peer_timeout_is_only_accepted_from_the_relay() {
+        let (server, state, [(alice, alice_id, alice_nonce), (bob, _, bob_nonce)]) =
+            two_peer_server().await;
+        let hdr = Header::control(0, 0, 0, 0);
+        assert_eq!(
+            state.with_channel("s1", "ch1", |ch| ch.relay).unwrap(),
+            Some(alice_id)
+        );
+
+        // bob is a member with a valid nonce, but not the relay
+        let bob_addr = bob.local_addr().unwrap();
+        handle_message(
+            peer_timeout(alice_id, bob_nonce),
+            &hdr,
+            bob_addr,
+            server.clone(),
+            state.clone(),
+        )
+        .await;
+        assert_eq!(channel_users(&state).len(), 2);
+
+        // nor can the relay report itself
+        let alice_addr = alice.local_addr().unwrap();
+        handle_message(
+            peer_timeout(alice_id, alice_nonce),
+            &hdr,
+            alice_addr,
+            server.clone(),
+            state.clone(),
+        )
+        .await;
+        assert_eq!(channel_users(&state).len(), 2);
+        assert_eq!(
+            state.with_channel("s1", "ch1", |ch| ch.relay).unwrap(),
+            Some(alice_id)
+        );
+
+        // the relay reporting bob is honoured
+        let bob_id = channel_users(&state)[1].peer_id;
+        handle_message(
+            peer_timeout(bob_id, alice_nonce),
+            &hdr,
+            alice_addr,
+            server,
+            state.clone(),
+        )
+        .await;
+        let users = channel_users(&state);
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].peer_id, alice_id);
+    }
+
+    #[tokio::test]
+    async fn relay_request_with_session_nonce_is_accepted() {
+        let (server, state, [(alice, _, alice_nonce), (_, bob_id, _)]) = two_peer_server().await;
+        let hdr = Header::control(0, 0, 0, 0);
+
+        let alice_addr = alice.local_addr().unwrap();
+        handle_message(
+            relay_request(bob_id, alice_nonce),
+            &hdr,
+            alice_addr,
+            server,
+            state.clone(),
+        )
+        .await;
+
+        let users = channel_users(&state);
+        assert!(
+            users
+                .iter()
+                .any(|u| u.peer_id == bob_id && u.needs_server_relay)
+        );
+    }
+
+    #[test]
+    fn relay_election_prefers_the_most_reachable_nat() {
+        let mut channel = Channel::default();
+        for (id, nat) in [
+            (1, NatKind::Symmetric),
+            (2, NatKind::PortRestrictedCone),
+            (3, NatKind::Cone),
+            (4, NatKind::Public),
+        ] {
+            let addr = format!("127.0.0.1:{}", 9100 + id).parse().unwrap();
+            channel.users.push(User::new("u", addr, nat, id));
+        }
+
+        assert_eq!(pick_eligible_relay(&channel).unwrap().peer_id, 4);
+
+        // an eligible relay is kept even if a better one is around
+        channel.relay = Some(2);
+        assert_eq!(pick_eligible_relay(&channel).unwrap().peer_id, 2);
+
+        // symmetric users are never elected
+        channel.relay = Some(1);
+        channel.users.retain(|u| u.peer_id == 1);
+        assert!(pick_eligible_relay(&channel).is_none());
+    }
+
+    #[test]
+    fn hinted_symmetric_user_is_punched_not_relayed() {
+        let addr = "127.0.0.1:9200".parse().unwrap();
+        let user = User::new("sym", addr, NatKind::Symmetric, 1).with_port_hint(PortHint::Delta(2));
+        assert!(!user.needs_server_relay);
+        // peers can reach it only by prediction, so it never relays for them
+        assert_eq!(user.relay_rank(), None);
+        assert_eq!(
+            mode_direct(&user, "127.0.0.2:9200".parse().unwrap()),
+            ControlMessage::ModeDirect {
+                user: "sym".into(),
+                addr,
+                peer_id: 1,
+                port_hint: PortHint::Delta(2),
+                fingerprint: Vec::new(),
+                candidates: Vec::new(),
+                check_key: Vec::new(),
+            }
+        );
+
+        let plain = User::new("sym", addr, NatKind::Symmetric, 2).with_port_hint(PortHint::None);
+        assert!(plain.needs_server_relay);
+        let cone = User::new("cone", addr, NatKind::Cone, 3).with_port_hint(PortHint::Random);
+        assert!(!cone.needs_server_relay);
+    }
+
+    #[test]
+    fn fingerprint_is_forwarded_in_mode_messages() {
+        let addr = "127.0.0.1:9300".parse().unwrap();
+        let user = User::new("alice", addr, NatKind::Cone, 1).with_fingerprint(vec![7; 32]);
+
+        let ControlMessage::ModeDirect { fingerprint, .. } = mode_direct(&user, addr) else {
+            panic!("not MODE DIRECT");
+        };
+        assert_eq!(fingerprint, vec![7; 32]);
+        let ControlMessage::ModeServerRelay { fingerprint, .. } = mode_server_relay(&user) else {
+            panic!("not MODE SERVER_RELAY");
+        };
+        assert_eq!(fingerprint, vec![7; 32]);
+    }
+
+    async fn probe_pair() -> [Arc<UdpSocket>; 2] {
+        [
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+        ]
+    }
+
+    #[tokio::test]
+    async fn probe_is_answered_from_the_requested_socket() {
+        use crate::proto::control::{PROBE_CHANGE_IP, PROBE_CHANGE_PORT};
+
+        let primary = probe_pair().await;
+        let alternate = probe_pair().await;
+        let alt_port = alternate[1].local_addr().unwrap().port();
+        let probes = ProbeSockets::with_alternate(
+            primary.clone(),
+            alternate.clone(),
+            "127.0.0.2".parse().unwrap(),
+        );
+        let port_of = |s: &Arc<UdpSocket>| s.local_addr().unwrap().port();
+        let slot = ProbeSlot::MAIN;
+
+        assert_eq!(
+            port_of(probes.reply_socket(slot, 0).unwrap()),
+            port_of(&primary[0])
+        );
+        assert_eq!(
+            port_of(probes.reply_socket(slot, PROBE_CHANGE_PORT).unwrap()),
+            port_of(&primary[1])
+        );
+        assert_eq!(
+            port_of(
+                probes
+                    .reply_socket(slot, PROBE_CHANGE_IP | PROBE_CHANGE_PORT)
+                    .unwrap()
+            ),
+            alt_port
+        );
+        assert_eq!(
+            probes.other_address(),
+            Some(format!("127.0.0.2:{alt_port}").parse().unwrap())
+        );
+
+        // the answer carries the observed address, the seq and the alternate address
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = client.local_addr().unwrap();
+        probes.answer(slot, 7, PROBE_CHANGE_PORT, src, None).await;
+        let mut buf = [0u8; 256];
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from.port(), port_of(&primary[1]));
+        assert_eq!(
+            control::decode_packet(&buf[..len]).unwrap().1,
+            ControlMessage::NatSeen {
+                addr: src,
+                seq: 7,
+                other: probes.other_address(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_for_missing_alternate_address_is_not_answered() {
+        use crate::proto::control::PROBE_CHANGE_IP;
+
+        let probes = ProbeSockets::new(probe_pair().await);
+        assert!(
+            probes
+                .reply_socket(ProbeSlot::MAIN, PROBE_CHANGE_IP)
+                .is_none()
+        );
+        assert_eq!(probes.other_address(), None);
+    }
+
+    #[tokio::test]
+    async fn stun_binding_is_answered_like_a_probe() {
+        use crate::proto::stun::{self, Class, Message};
+
+        let primary = probe_pair().await;
+        let alternate = probe_pair().await;
+        let probes = ProbeSockets::with_alternate(
+            primary.clone(),
+            alternate.clone(),
+            "127.0.0.2".parse().unwrap(),
+        );
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = client.local_addr().unwrap();
+
+        let req = stun::binding_request(false, true);
+        probes.answer_binding(ProbeSlot::MAIN, &req, src).await;
+        let mut buf = [0u8; 512];
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        let reply = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(reply.txn, req.txn);
+        assert_eq!(from, primary[1].local_addr().unwrap());
+        assert_eq!(
+            stun::binding_response(&reply),
+            Some((src, probes.other_address()))
+        );
+        assert_eq!(reply.addr(stun::ATTR_RESPONSE_ORIGIN), Some(from));
+
+        // without an alternate address CHANGE-REQUEST for the IP is an unknown attribute
+        let probes = ProbeSockets::new(probe_pair().await);
+        let req = stun::binding_request(true, false);
+        probes.answer_binding(ProbeSlot::MAIN, &req, src).await;
+        let reply = Message::decode(&recv_raw(&client).await).unwrap();
+        assert_eq!(reply.class, Class::Error);
+        assert_eq!(reply.error_code(), Some(420));
+    }
+
+    async fn recv_raw(socket: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0u8; 2048];
+        let (len, _) = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            socket.recv_from(&mut buf),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn noise_body(pkt: &[u8], msg_type: u8) -> Vec<u8> {
+        let (hdr, payload) = crate::proto::packet::decode(pkt).unwrap();
+        assert_eq!(hdr.kind, Kind::Noise);
+        let (got, body) = noise::unframe(payload).unwrap();
+        assert_eq!(got, msg_type);
+        body.to_vec()
+    }
+
+    fn secure_state(allow_plain: bool) -> (ServerState, [u8; noise::KEY_LEN]) {
+        let key = noise::StaticKeypair::generate().unwrap();
+        let public = *key.public();
+        (
+            ServerState::new().with_secure_signaling(key, allow_plain),
+            public,
+        )
+    }
+
+    #[test]
+    fn secure_server_drops_plain_control() {
+        let src: std::net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let pkt = control::encode_packet(0, 0, 0, &connect_msg("alice", Vec::new())).unwrap();
+
+        let (state, _) = secure_state(false);
+        assert!(matches!(state.inbound(&pkt, src), Inbound::Drop(_)));
+        assert!(state.outbound(src, pkt.clone()).is_none());
+
+        // plain clients are served only when allowed, and never once an address has a session
+        let (state, public) = secure_state(true);
+        assert!(matches!(state.inbound(&pkt, src), Inbound::Packet(_)));
+        let (initiator, init) = noise::Initiator::start(&public, b"").unwrap();
+        let init = noise::frame(noise::MSG_INIT, &init);
+        let Inbound::Reply(reply) = state.inbound(&init, src) else {
+            panic!("expected a handshake reply");
+        };
+        let (_, mut transport) = initiator
+            .finish(&noise_body(&reply, noise::MSG_RESPONSE))
+            .unwrap();
+        let sealed = noise::frame(noise::MSG_TRANSPORT, &transport.seal(&pkt));
+        assert!(matches!(state.inbound(&sealed, src), Inbound::Packet(_)));
+        assert!(matches!(state.inbound(&pkt, src), Inbound::Drop(_)));
+    }
+
+    #[test]
+    fn spoofed_handshake_does_not_lock_out_a_plain_client() {
+        let src: std::net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let pkt = control::encode_packet(0, 0, 0, &connect_msg("alice", Vec::new())).unwrap();
+        let (state, public) = secure_state(true);
+
+        // anyone can send a handshake from the client's address; it is never used
+        let (_, init) = noise::Initiator::start(&public, b"").unwrap();
+        let init = noise::frame(noise::MSG_INIT, &init);
+        assert!(matches!(state.inbound(&init, src), Inbound::Reply(_)));
+        assert!(matches!(state.inbound(&pkt, src), Inbound::Packet(_)));
+        assert_eq!(state.outbound(src, pkt.clone()), Some(pkt));
+    }
+
+    #[tokio::test]
+    async fn sealed_connect_gets_a_sealed_welcome() {
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = client.local_addr().unwrap();
+        let (state, public) = secure_state(false);
+        let state = Arc::new(state);
+
+        let (initiator, init) = noise::Initiator::start(&public, b"").unwrap();
+        let Inbound::Reply(reply) = state.inbound(&noise::frame(noise::MSG_INIT, &init), src)
+        else {
+            panic!("expected a handshake reply");
+        };
+        let (_, mut transport) = initiator
+            .finish(&noise_body(&reply, noise::MSG_RESPONSE))
+            .unwrap();
+
+        let connect = control::encode_packet(0, 0, 0, &connect_msg("alice", Vec::new())).unwrap();
+        let sealed = noise::frame(noise::MSG_TRANSPORT, &transport.seal(&connect));
+        let Inbound::Packet(opened) = state.inbound(&sealed, src) else {
+            panic!("expected the sealed CONNECT to open");
+        };
+        let (hdr, msg) = control::decode_packet(&opened).unwrap();
+        handle_message(msg, &hdr, src, server, state.clone()).await;
+
+        // the replayed datagram is refused
+        assert!(matches!(state.inbound(&sealed, src), Inbound::Drop(_)));
+
+        let welcome = recv_raw(&client).await;
+        let plain = transport
+            .open(&noise_body(&welcome, noise::MSG_TRANSPORT))
+            .unwrap();
+        assert!(matches!(
+            control::decode_packet(&plain).unwrap().1,
+            ControlMessage::Welcome { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn one_shot_probe_is_answered_inside_the_handshake() {
+        let probes = ProbeSockets::new(probe_pair().await);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let src = client.local_addr().unwrap();
+        let (state, public) = secure_state(false);
+
+        let probe = control::encode_packet(0, 0, 0, &ControlMessage::NatProbe { seq: 3, flags: 0 })
+            .unwrap();
+        let (initiator, init) = noise::Initiator::start(&public, &probe).unwrap();
+        let Inbound::OneShot { packet, responder } =
+            state.inbound(&noise::frame(noise::MSG_INIT, &init), src)
+        else {
+            panic!("expected a one-shot probe");
+        };
+        assert_eq!(packet, probe);
+        assert_eq!(state.secure().unwrap().session_count(), 0);
+
+        probes
+            .answer(ProbeSlot::MAIN, 3, 0, src, Some(responder))
+            .await;
+        let answer = recv_raw(&client).await;
+        let (seen, _) = initiator
+            .finish(&noise_body(&answer, noise::MSG_RESPONSE))
+            .unwrap();
+        assert_eq!(
+            control::decode_packet(&seen).unwrap().1,
+            ControlMessage::NatSeen {
+                addr: src,
+                seq: 3,
+                other: None,
+            }
+        );
+    }
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<ServerConfig, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        ServerConfig::load(args.iter().map(|a| a.to_string()), |name| {
+            env.get(name).cloned()
+        })
+    }
+
+    #[test]
+    fn defaults_match_the_old_hardcoded_values() {
+        let config = load(&[], &[]).unwrap();
+        assert_eq!(config.network.port, 2131);
+        assert_eq!(config.network.probe_port(), 2132);
+        assert!(config.network.bind.is_unspecified());
+        assert_eq!(config.timeouts, Timeouts::default());
+        assert_eq!(config.log.level, LogLevel::Info);
+    }
+
+    #[test]
+    fn file_is_overridden_by_env_then_flags() {
+        let path = std::env::temp_dir().join(format!("odnp-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [network]
+            port = 3000
+            probe_port = 3005
+
+            [timeouts]
+            heartbeat_secs = 5
+            user_timeout_secs = 12
+
+            [limits]
+            max_users_per_channel = 8
+
+            [log]
+            level = "debug"
+            format = "json"
+
+            [log.targets]
+            "signaling::turn" = "trace"
+            "#,
+        )
+        .unwrap();
+
+        let config = load(
+            &["--config", path.to_str().unwrap(), "--max-channels", "3"],
+            &[
+                ("NAT_PIERCER_PORT", "1001"),
+                ("ODNP_PLAIN_SIGNALING", "1"),
+                ("ODNP_LOG_LEVEL", "debug,client=warn"),
+                ("ODNP_SNAPSHOT_PATH", "/var/lib/odnp/state.json"),
+            ],
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.network.port, 1001);
+        assert_eq!(config.network.probe_port(), 3005);
+        assert_eq!(config.timeouts.heartbeat_secs, 5);
+        assert_eq!(config.limits.max_channels, Some(3));
+        assert_eq!(config.limits.max_users_per_channel, Some(8));
+        assert!(config.security.plain_signaling);
+        assert_eq!(config.log.level, LogLevel::Debug);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(
+            config.log.filter().to_string(),
+            "debug,signaling::turn=trace,client=warn"
+        );
+        assert_eq!(
+            config.snapshot.path.as_deref(),
+            Some(std::path::Path::new("/var/lib/odnp/state.json"))
+        );
+        assert_eq!(config.snapshot.interval_secs, 30);
+
+        let config = load(&["--port", "4000"], &[("NAT_PIERCER_PORT", "1001")]).unwrap();
+        assert_eq!(config.network.port, 4000);
+        assert_eq!(config.network.probe_port(), 4001);
+    }
+
+    #[test]
+    fn bad_settings_are_refused() {
+        let invalid = |args: &[&str], env: &[(&str, &str)]| {
+            matches!(load(args, env), Err(ConfigError::Invalid(_)))
+        };
+        assert!(invalid(&["--port", "2131", "--probe-port", "2131"], &[]));
+        assert!(invalid(&["--alt-ip", "10.0.0.2"], &[]));
+        assert!(invalid(
+            &["--bind", "10.0.0.1", "--alt-ip", "2001:db8::1"],
+            &[]
+        ));
+        assert!(invalid(&["--user-timeout-secs", "20"], &[]));
+        assert!(invalid(&["--max-channels", "0"], &[]));
+        assert!(invalid(&["--snapshot-interval-secs", "0"], &[]));
+        assert!(invalid(&[], &[("ODNP_SIGNALING_KEY", "abcd")]));
+        // TURN needs a secret for its credentials and an address to relay from
+        let secret = [("ODNP_JOIN_SECRET", "s")];
+        assert!(invalid(
+            &["--turn-port", "3478", "--turn-relay-ip", "10.0.0.1"],
+            &[]
+        ));
+        assert!(invalid(&["--turn-port", "3478"], &secret));
+        assert!(invalid(
+            &["--turn-port", "2132", "--bind", "10.0.0.1"],
+            &secret
+        ));
+        assert!(load(&["--turn-port", "3478", "--bind", "10.0.0.1"], &secret).is_ok());
+        // the admin API is never served without a token
+        assert!(invalid(&["--admin-listen", "127.0.0.1:8080"], &[]));
+        assert!(matches!(
+            load(&["--admin-token", "t"], &[]),
+            Err(ConfigError::Override(_))
+        ));
+        // a cluster needs its nodes and secret, and an address the others can reach
+        let cluster = [("ODNP_CLUSTER_SECRET", "c")];
+        let nodes = ["--cluster-nodes", "10.0.0.1:2140, 10.0.0.2:2140"];
+        assert!(invalid(&["--cluster-listen", "10.0.0.1:2140"], &cluster));
+        assert!(invalid(
+            &["--cluster-listen", "10.0.0.1:2140", nodes[0], nodes[1]],
+            &[]
+        ));
+        assert!(invalid(
+            &["--cluster-listen", "0.0.0.0:2140", nodes[0], nodes[1]],
+            &cluster
+        ));
+        assert!(invalid(&nodes, &cluster));
+        assert!(matches!(
+            load(&["--cluster-nodes", "10.0.0.1"], &cluster),
+            Err(ConfigError::Override(_))
+        ));
+        let config = load(
+            &[
+                "--cluster-listen",
+                "0.0.0.0:2140",
+                "--cluster-advertise",
+                "10.0.0.1:2140",
+                nodes[0],
+                nodes[1],
+            ],
+            &cluster,
+        )
+        .unwrap();
+        assert_eq!(
+            config.cluster.node(),
+            Some("10.0.0.1:2140".parse().unwrap())
+        );
+        assert_eq!(
+            config.cluster.nodes,
+            [
+                "10.0.0.1:2140".parse().unwrap(),
+                "10.0.0.2:2140".parse().unwrap()
+            ]
+        );
+        assert!(matches!(
+            load(&["--cluster-secret", "c"], &[]),
+            Err(ConfigError::Override(_))
+        ));
+
+        assert!(matches!(
+            load(&["--port", "http"], &[]),
+            Err(ConfigError::Override(_))
+        ));
+        assert!(matches!(
+            load(&["--join-secret", "s"], &[]),
+            Err(ConfigError::Override(_))
+        ));
+        assert!(matches!(
+            load(&["--log-level", "loud"], &[]),
+            Err(ConfigError::Override(_))
+        ));
+        assert!(matches!(
+            load(&["--log-level", "info,client=loud"], &[]),
+            Err(ConfigError::Override(_))
+        ));
+        assert!(matches!(
+            load(&["--log-format", "xml"], &[]),
+            Err(ConfigError::Override(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_in_the_file_are_errors() {
+        let err = toml::from_str::<ServerConfig>("[network]\nprot = 1\n").unwrap_err();
+        assert!(err.message().contains("prot"));
+    }
+
+    #[tokio::test]
+    async fn connect_beyond_the_limits_is_rejected() {
+        use crate::signaling::config::Limits;
+
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let state = Arc::new(ServerState::new().with_limits(Limits {
+            max_channels: Some(1),
+            max_users_per_channel: Some(1),
+        }));
+        let hdr = Header::control(0, 0, 0, 0);
+
+        let mut replies = Vec::new();
+        for (user, channel) in [("alice", "ch1"), ("bob", "ch1"), ("carol", "ch2")] {
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut msg = connect_msg(user, Vec::new());
+            if let ControlMessage::Connect { channel: c, .. } = &mut msg {
+                *c = channel.into();
+            }
+            let addr = sock.local_addr().unwrap();
+            handle_message(msg, &hdr, addr, server.clone(), state.clone()).await;
+            replies.push((recv_control(&sock).await, state.locate(addr)));
+        }
+
+        assert!(matches!(
+            replies[0],
+            (ControlMessage::Welcome { .. }, Some(_))
+        ));
+        for reply in &replies[1..] {
+            assert!(matches!(reply, (ControlMessage::Rejected { .. }, None)));
+        }
+        assert_eq!(channel_users(&state).len(), 1);
+        assert_eq!(state.channel_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn ipv4_and_ipv6_users_learn_each_others_addresses() {
+        let server = Arc::new(crate::net::bind_any(0).await.unwrap());
+        let state = Arc::new(ServerState::new());
+        let hdr = Header::control(0, 0, 0, 0);
+
+        let alice = crate::net::bind("127.0.0.1".parse().unwrap(), 0)
+            .await
+            .unwrap();
+        let bob = crate::net::bind("::1".parse().unwrap(), 0).await.unwrap();
+        for (name, sock) in [("alice", &alice), ("bob", &bob)] {
+            let addr = sock.local_addr().unwrap();
+            handle_message(
+                connect_msg(name, Vec::new()),
+                &hdr,
+                addr,
+                server.clone(),
+                state.clone(),
+            )
+            .await;
+            assert!(matches!(
+                recv_control(sock).await,
+                ControlMessage::Welcome { .. }
+            ));
+        }
+
+        // alice is the relay and hears about bob's IPv6 address, bob about hers
+        let mut seen = Vec::new();
+        for sock in [&alice, &bob] {
+            loop {
+                if let ControlMessage::ModeDirect { addr, .. } = recv_control(sock).await {
+                    seen.push(addr);
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            seen,
+            vec![bob.local_addr().unwrap(), alice.local_addr().unwrap()]
+        );
+    }
+
+    #[test]
+    fn private_candidates_only_reach_peers_behind_the_same_ip() {
+        let addr: std::net::SocketAddr = "198.51.100.4:40000".parse().unwrap();
+        let host = Candidate {
+            kind: CandidateKind::Host,
+            addr: "192.168.1.20:4000".parse().unwrap(),
+            priority: 3,
+        };
+        let srflx = Candidate {
+            kind: CandidateKind::ServerReflexive,
+            addr,
+            priority: 2,
+        };
+        let user = User::new("lan", addr, NatKind::Cone, 1).with_candidates(vec![host, srflx]);
+
+        let candidates_for = |to: &str| match mode_direct(&user, to.parse().unwrap()) {
+            ControlMessage::ModeDirect { candidates, .. } => candidates,
+            _ => panic!("not MODE DIRECT"),
+        };
+        assert_eq!(candidates_for("198.51.100.4:40001"), vec![host, srflx]);
+        assert_eq!(candidates_for("203.0.113.9:40000"), vec![srflx]);
+    }
+
+    mod turn {
+        use std::net::SocketAddr;
+        use std::time::Duration;
+
+        use super::*;
+        use crate::proto::stun::{self, Class, Message};
+        use crate::signaling::config::{IpRange, TurnConfig};
+        use crate::signaling::turn::TurnServer;
+
+        const SECRET: &[u8] = b"turn secret";
+
+        // the test peers live on loopback, which TURN refuses by default
+        fn loopback() -> TurnConfig {
+            TurnConfig {
+                allowed_peers: vec!["127.0.0.0/8".parse().unwrap()],
+                ..TurnConfig::default()
+            }
+        }
+
+        async fn start(config: TurnConfig) -> (Arc<TurnServer>, SocketAddr) {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let server = TurnServer::new(
+                Arc::new(socket),
+                "127.0.0.1".parse().unwrap(),
+                SECRET.to_vec(),
+                config,
+            );
+            tokio::spawn(Arc::clone(&server).serve());
+            (server, addr)
+        }
+
+        async fn recv(sock: &UdpSocket) -> Vec<u8> {
+            let mut buf = [0u8; 2048];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), sock.recv_from(&mut buf))
+                .await
+                .expect("nothing received")
+                .unwrap();
+            buf[..len].to_vec()
+        }
+
+        async fn nothing_received(sock: &UdpSocket) -> bool {
+            let mut buf = [0u8; 2048];
+            tokio::time::timeout(Duration::from_millis(200), sock.recv_from(&mut buf))
+                .await
+                .is_err()
+        }
+
+        // a client of the TURN server, authenticated the way WebRTC stacks do it
+        struct Client {
+            sock: UdpSocket,
+            server: SocketAddr,
+            username: String,
+            password: String,
+            realm: String,
+            nonce: String,
+        }
+
+        impl Client {
+            async fn new(server: SocketAddr, password: Option<&str>) -> Self {
+                let t = token::sign(SECRET, "s1", "general", "alice", token::now_secs() + 60);
+                let (username, real) =
+                    token::turn_credentials("s1", "general", "alice", &t).unwrap();
+                Self {
+                    sock: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                    server,
+                    username,
+                    password: password.map_or(real, str::to_string),
+                    realm: String::new(),
+                    nonce: String::new(),
+                }
+            }
+
+            async fn send(&self, buf: &[u8]) {
+                self.sock.send_to(buf, self.server).await.unwrap();
+            }
+
+            // sends `msg` signed, first learning realm and nonce from a 401
+            async fn request(&mut self, mut msg: Message) -> Message {
+                if self.nonce.is_empty() {
+                    self.send(&msg.encode(None)).await;
+                    let challenge = Message::decode(&recv(&self.sock).await).unwrap();
+                    assert_eq!(challenge.error_code(), Some(401));
+                    self.realm = challenge.str_attr(stun::ATTR_REALM).unwrap().into();
+                    self.nonce = challenge.str_attr(stun::ATTR_NONCE).unwrap().into();
+                }
+                msg.add(stun::ATTR_USERNAME, self.username.as_str())
+                    .add(stun::ATTR_REALM, self.realm.as_str())
+                    .add(stun::ATTR_NONCE, self.nonce.as_str());
+                let key = stun::long_term_key(&self.username, &self.realm, &self.password);
+                self.send(&msg.encode(Some(&key))).await;
+
+                let raw = recv(&self.sock).await;
+                let reply = Message::decode(&raw).unwrap();
+                assert_eq!(reply.txn, msg.txn);
+                if reply.class == Class::Success {
+                    assert!(stun::check_integrity(&raw, &key));
+                }
+                reply
+            }
+
+            async fn allocate(&mut self) -> Message {
+                let mut msg = Message::request(stun::ALLOCATE);
+                msg.add(
+                    stun::ATTR_REQUESTED_TRANSPORT,
+                    [stun::TRANSPORT_UDP, 0, 0, 0],
+                );
+                self.request(msg).await
+            }
+
+            async fn permit(&mut self, peer: SocketAddr) -> Message {
+                let mut msg = Message::request(stun::CREATE_PERMISSION);
+                msg.add_xor_addr(stun::ATTR_XOR_PEER_ADDRESS, peer);
+                self.request(msg).await
+            }
+        }
+
+        fn send_indication(peer: SocketAddr, data: &[u8]) -> Vec<u8> {
+            let mut msg = Message::new(stun::SEND, Class::Indication, rand::random());
+            msg.add_xor_addr(stun::ATTR_XOR_PEER_ADDRESS, peer)
+                .add(stun::ATTR_DATA, data);
+            msg.encode(None)
+        }
+
+        #[tokio::test]
+        async fn allocation_relays_both_ways_once_permitted() {
+            let (server, addr) = start(loopback()).await;
+            let mut client = Client::new(addr, None).await;
+            let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer_addr = peer.local_addr().unwrap();
+
+            let reply = client.allocate().await;
+            assert_eq!(reply.class, Class::Success);
+            let relayed = reply.xor_addr(stun::ATTR_XOR_RELAYED_ADDRESS).unwrap();
+            assert_eq!(
+                reply.xor_addr(stun::ATTR_XOR_MAPPED_ADDRESS),
+                Some(client.sock.local_addr().unwrap())
+            );
+            assert_eq!(reply.u32_attr(stun::ATTR_LIFETIME), Some(600));
+            assert_eq!(server.allocation_count(), 1);
+
+            // nothing passes without a permission
+            client.send(&send_indication(peer_addr, b"early")).await;
+            peer.send_to(b"early", relayed).await.unwrap();
+            assert!(nothing_received(&peer).await);
+            assert!(nothing_received(&client.sock).await);
+
+            assert_eq!(client.permit(peer_addr).await.class, Class::Success);
+            client
+                .send(&send_indication(peer_addr, b"hello peer"))
+                .await;
+            assert_eq!(recv(&peer).await, b"hello peer");
+
+            peer.send_to(b"hello client", relayed).await.unwrap();
+            let data = Message::decode(&recv(&client.sock).await).unwrap();
+            assert_eq!((data.method, data.class), (stun::DATA, Class::Indication));
+            assert_eq!(data.xor_addr(stun::ATTR_XOR_PEER_ADDRESS), Some(peer_addr));
+            assert_eq!(data.attr(stun::ATTR_DATA), Some(&b"hello client"[..]));
+
+            // a bound channel carries ChannelData instead
+            let mut bind = Message::request(stun::CHANNEL_BIND);
+            bind.add(stun::ATTR_CHANNEL_NUMBER, [0x40, 0x01, 0, 0])
+                .add_xor_addr(stun::ATTR_XOR_PEER_ADDRESS, peer_addr);
+            assert_eq!(client.request(bind).await.class, Class::Success);
+            client
+                .send(&stun::channel_data(0x4001, b"on channel"))
+                .await;
+            assert_eq!(recv(&peer).await, b"on channel");
+            peer.send_to(b"back", relayed).await.unwrap();
+            assert_eq!(
+                stun::parse_channel_data(&recv(&client.sock).await),
+                Some((0x4001, &b"back"[..]))
+            );
+
+            // a second Allocate from the same address is refused, a zero Refresh frees it
+            assert_eq!(client.allocate().await.error_code(), Some(437));
+            let mut refresh = Message::request(stun::REFRESH);
+            refresh.add(stun::ATTR_LIFETIME, 0u32.to_be_bytes());
+            assert_eq!(client.request(refresh).await.class, Class::Success);
+            assert_eq!(server.allocation_count(), 0);
+        }
+
+        #[tokio::test]
+        async fn credentials_must_match_a_join_token() {
+            let (server, addr) = start(TurnConfig::default()).await;
+            let mut client = Client::new(addr, Some("not the token")).await;
+            assert_eq!(client.allocate().await.error_code(), Some(401));
+
+            // a nonce the server never handed out is stale
+            let mut client = Client::new(addr, None).await;
+            client.realm = "od-nat-piercer".into();
+            client.nonce = "0123456789abcdef0123456789abcdef".into();
+            assert_eq!(client.allocate().await.error_code(), Some(438));
+            assert_eq!(server.allocation_count(), 0);
+        }
+
+        #[tokio::test]
+        async fn allocations_are_held_to_their_quotas() {
+            let (_server, addr) = start(TurnConfig {
+                max_allocations: Some(1),
+                max_permissions: Some(1),
+                max_bytes_per_sec: Some(10),
+                ..loopback()
+            })
+            .await;
+            let mut client = Client::new(addr, None).await;
+            assert_eq!(client.allocate().await.class, Class::Success);
+            let mut other = Client::new(addr, None).await;
+            assert_eq!(other.allocate().await.error_code(), Some(486));
+
+            let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer_addr = peer.local_addr().unwrap();
+            assert_eq!(client.permit(peer_addr).await.class, Class::Success);
+            assert_eq!(
+                client
+                    .permit("127.0.0.2:9".parse().unwrap())
+                    .await
+                    .error_code(),
+                Some(508)
+            );
+
+            client.send(&send_indication(peer_addr, b"12345678")).await;
+            assert_eq!(recv(&peer).await, b"12345678");
+            client.send(&send_indication(peer_addr, b"over")).await;
+            assert!(nothing_received(&peer).await);
+        }
+
+        #[tokio::test]
+        async fn internal_peers_are_forbidden_unless_allowed() {
+            let (_server, addr) = start(TurnConfig {
+                allowed_peers: vec!["10.0.0.0/8".parse().unwrap()],
+                ..TurnConfig::default()
+            })
+            .await;
+            let mut client = Client::new(addr, None).await;
+            assert_eq!(client.allocate().await.class, Class::Success);
+
+            for peer in [
+                "127.0.0.1:9",
+                "192.168.1.20:9",
+                "172.16.0.1:9",
+                "169.254.1.1:9",
+                "0.0.0.0:9",
+            ] {
+                let reply = client.permit(peer.parse().unwrap()).await;
+                assert_eq!(reply.error_code(), Some(403), "{peer}");
+            }
+            let mut bind = Message::request(stun::CHANNEL_BIND);
+            bind.add(stun::ATTR_CHANNEL_NUMBER, [0x40, 0x01, 0, 0])
+                .add_xor_addr(stun::ATTR_XOR_PEER_ADDRESS, "127.0.0.1:9".parse().unwrap());
+            assert_eq!(client.request(bind).await.error_code(), Some(403));
+
+            // public peers and the allow-list pass
+            for peer in ["198.51.100.7:9", "10.1.2.3:9"] {
+                let reply = client.permit(peer.parse().unwrap()).await;
+                assert_eq!(reply.class, Class::Success, "{peer}");
+            }
+        }
+
+        #[test]
+        fn ip_ranges_parse_and_match() {
+            let range: IpRange = "10.0.0.0/8".parse().unwrap();
+            assert!(range.contains("10.200.0.1".parse().unwrap()));
+            assert!(range.contains("::ffff:10.0.0.1".parse().unwrap()));
+            assert!(!range.contains("11.0.0.1".parse().unwrap()));
+            let single: IpRange = "fe80::1".parse().unwrap();
+            assert!(single.contains("fe80::1".parse().unwrap()));
+            assert!(!single.contains("fe80::2".parse().unwrap()));
+            assert!(
+                "0.0.0.0/0"
+                    .parse::<IpRange>()
+                    .unwrap()
+                    .contains("203.0.113.1".parse().unwrap())
+            );
+            assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+            assert!("example.org".parse::<IpRange>().is_err());
+        }
+    }
+
+    mod admin {
+        use serde_json::Value;
+
+        use super::*;
+        use crate::signaling::admin::routes;
+
+        const TOKEN: &str = "admin token";
+
+        async fn channel_with(users: &[(&str, NatKind)]) -> (Arc<ServerState>, Vec<UdpSocket>) {
+            let state = Arc::new(ServerState::new());
+            let mut sockets = Vec::new();
+            let mut list = Vec::new();
+            for (i, (name, nat)) in users.iter().enumerate() {
+                let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let addr = sock.local_addr().unwrap();
+                let peer_id = i as u32 + 1;
+                list.push(User::new(name, addr, *nat, peer_id));
+                state.index_peer(
+                    addr,
+                    PeerLocation {
+                        server_id: "s1".into(),
+                        channel: "ch1".into(),
+                        peer_id,
+                    },
+                );
+                sockets.push(sock);
+            }
+            state.with_channel_or_create("s1", "ch1", |ch| {
+                ch.users = list;
+                ch.relay = Some(1);
+            });
+            (state, sockets)
+        }
+
+        async fn call(
+            state: &Arc<ServerState>,
+            method: &str,
+            path: &str,
+            body: Option<Value>,
+        ) -> (u16, Value) {
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let mut req = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", format!("Bearer {TOKEN}"));
+            if let Some(body) = body {
+                req = req.json(&body);
+            }
+            let res = req
+                .reply(&routes(state.clone(), socket, TOKEN.into()))
+                .await;
+            let json = serde_json::from_slice(res.body()).unwrap_or(Value::Null);
+            (res.status().as_u16(), json)
+        }
+
+        #[tokio::test]
+        async fn requests_need_the_bearer_token() {
+            let (state, _) = channel_with(&[("alice", NatKind::Cone)]).await;
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let api = routes(state, socket, TOKEN.into());
+
+            let res = warp::test::request().path("/servers").reply(&api).await;
+            assert_eq!(res.status(), 401);
+            let res = warp::test::request()
+                .path("/servers")
+                .header("authorization", "Bearer admin tokeN")
+                .reply(&api)
+                .await;
+            assert_eq!(res.status(), 401);
+        }
+
+        #[tokio::test]
+        async fn servers_and_channels_are_listed() {
+            let (state, _) =
+                channel_with(&[("alice", NatKind::Cone), ("bob", NatKind::Symmetric)]).await;
+
+            let (status, servers) = call(&state, "GET", "/servers", None).await;
+            assert_eq!(status, 200);
+            assert_eq!(servers[0]["id"], "s1");
+            assert_eq!(servers[0]["channels"][0]["name"], "ch1");
+            assert_eq!(servers[0]["channels"][0]["users"], 2);
+            assert_eq!(servers[0]["channels"][0]["relay"], 1);
+
+            let (status, channel) = call(&state, "GET", "/servers/s1/channels/ch1", None).await;
+            assert_eq!(status, 200);
+            let bob = &channel["users"][1];
+            assert_eq!(bob["name"], "bob");
+            assert_eq!(bob["peer_id"], 2);
+            assert_eq!(bob["nat_kind"], "Symmetric");
+            assert_eq!(bob["server_relay"], true);
+            assert_eq!(bob["last_pong_secs"], 0);
+
+            let (status, _) = call(&state, "GET", "/servers/s1/channels/nope", None).await;
+            assert_eq!(status, 404);
+        }
+
+        #[tokio::test]
+        async fn relay_can_be_forced_on_an_eligible_user() {
+            let (state, sockets) = channel_with(&[
+                ("alice", NatKind::Cone),
+                ("bob", NatKind::Cone),
+                ("carol", NatKind::Symmetric),
+            ])
+            .await;
+
+            let body = Some(serde_json::json!({ "peer_id": 3 }));
+            let (status, _) = call(&state, "POST", "/servers/s1/channels/ch1/relay", body).await;
+            assert_eq!(status, 409);
+
+            let body = Some(serde_json::json!({ "peer_id": 2 }));
+            let (status, channel) =
+                call(&state, "POST", "/servers/s1/channels/ch1/relay", body).await;
+            assert_eq!(status, 200);
+            assert_eq!(channel["relay"], 2);
+            assert_eq!(recv_control(&sockets[1]).await, ControlMessage::ModeRelay);
+            // the relay it replaces steps down first
+            assert_eq!(recv_control(&sockets[0]).await, ControlMessage::ModePeer);
+            assert!(matches!(
+                recv_control(&sockets[0]).await,
+                ControlMessage::ModeDirect { peer_id: 2, .. }
+            ));
+        }
+
+        #[tokio::test]
+        async fn path_segments_are_percent_decoded() {
+            let state = Arc::new(ServerState::new());
+            state.with_channel_or_create("s 1", "ch/1 ü", |ch| {
+                ch.users = vec![User::new(
+                    "alice",
+                    "127.0.0.1:7001".parse().unwrap(),
+                    NatKind::Cone,
+                    1,
+                )];
+            });
+
+            let path = "/servers/s%201/channels/ch%2F1%20%C3%bc";
+            let (status, channel) = call(&state, "GET", path, None).await;
+            assert_eq!(status, 200);
+            assert_eq!(channel["server_id"], "s 1");
+            assert_eq!(channel["name"], "ch/1 ü");
+
+            // broken escapes and invalid UTF-8 match no route
+            for bad in [
+                "/servers/s%201/channels/ch%2",
+                "/servers/s%201/channels/ch%zz1",
+                "/servers/s%201/channels/%FF",
+            ] {
+                assert_ne!(call(&state, "GET", bad, None).await.0, 200, "{bad}");
+            }
+
+            let (status, _) = call(&state, "DELETE", path, None).await;
+            assert_eq!(status, 204);
+            assert_eq!(state.channel_count(), 0);
+        }
+
+        #[tokio::test]
+        async fn kicked_user_is_told_and_peers_see_it_leave() {
+            let (state, sockets) =
+                channel_with(&[("alice", NatKind::Cone), ("bob", NatKind::Cone)]).await;
+            let bob = sockets[1].local_addr().unwrap();
+
+            let body = Some(serde_json::json!({ "peer_id": 2 }));
+            let (status, kicked) =
+                call(&state, "POST", "/servers/s1/channels/ch1/kick", body).await;
+            assert_eq!(status, 200);
+            assert_eq!(kicked["name"], "bob");
+            assert!(matches!(
+                recv_control(&sockets[1]).await,
+                ControlMessage::Rejected { .. }
+            ));
+            assert!(matches!(
+                recv_control(&sockets[0]).await,
+                ControlMessage::UserLeft { peer_id: 2, .. }
+            ));
+            assert_eq!(state.locate(bob), None);
+            assert_eq!(channel_users(&state).len(), 1);
+
+            let body = Some(serde_json::json!({ "peer_id": 2 }));
+            let (status, _) = call(&state, "POST", "/servers/s1/channels/ch1/kick", body).await;
+            assert_eq!(status, 404);
+        }
+
+        #[tokio::test]
+        async fn deleted_channel_rejects_its_users() {
+            let (state, sockets) =
+                channel_with(&[("alice", NatKind::Cone), ("bob", NatKind::Cone)]).await;
+
+            let (status, _) = call(&state, "DELETE", "/servers/s1/channels/ch1", None).await;
+            assert_eq!(status, 204);
+            for sock in &sockets {
+                assert!(matches!(
+                    recv_control(sock).await,
+                    ControlMessage::Rejected { .. }
+                ));
+                assert_eq!(state.locate(sock.local_addr().unwrap()), None);
+            }
+            assert_eq!(state.channel_count(), 0);
+
+            let (status, _) = call(&state, "DELETE", "/servers/s1/channels/ch1", None).await;
+            assert_eq!(status, 404);
+        }
+
+        #[tokio::test]
+        async fn log_levels_change_at_runtime() {
+            use crate::log::{self, LogLevel};
+
+            let state = Arc::new(ServerState::new());
+            let body = Some(serde_json::json!({ "level": "warn,signaling::turn=debug" }));
+            let (status, json) = call(&state, "PUT", "/log", body).await;
+            assert_eq!(status, 200);
+            assert_eq!(json["level"], "warn,signaling::turn=debug");
+            assert!(log::enabled(
+                LogLevel::Debug,
+                "od_nat_piercer::signaling::turn"
+            ));
+            assert!(!log::enabled(
+                LogLevel::Info,
+                "od_nat_piercer::signaling::admin"
+            ));
+
+            let body = Some(serde_json::json!({ "level": "loud" }));
+            let (status, _) = call(&state, "PUT", "/log", body).await;
+            assert_eq!(status, 400);
+            let (_, json) = call(&state, "GET", "/log", None).await;
+            assert_eq!(json["level"], "warn,signaling::turn=debug");
+
+            log::set_filter(Default::default());
+        }
+    }
+
+    mod metrics {
+        use super::*;
+        use crate::proto::packet;
+        use crate::signaling::handlers::handle_packet;
+        use crate::signaling::metrics::{self, Histogram, Relayed};
+
+        #[test]
+        fn histogram_buckets_are_cumulative() {
+            let h = Histogram::new([1.0, 10.0]);
+            for v in [0.5, 5.0, 7.0, 50.0] {
+                h.observe(v);
+            }
+            let mut out = String::new();
+            h.render(&mut out, "h", "Test.");
+            assert_eq!(
+                out,
+                "# HELP h Test.\n# TYPE h histogram\n\
+                 h_bucket{le=\"1\"} 1\nh_bucket{le=\"10\"} 3\nh_bucket{le=\"+Inf\"} 4\n\
+                 h_sum 62.5\nh_count 4\n"
+            );
+        }
+
+        #[test]
+        fn gauges_count_server_relayed_users() {
+            let state = ServerState::new();
+            state.with_channel_or_create("s1", "ch1", |ch| {
+                ch.users = vec![
+                    User::new("alice", "127.0.0.1:7001".parse().unwrap(), NatKind::Cone, 1),
+                    User::new(
+                        "bob",
+                        "127.0.0.1:7002".parse().unwrap(),
+                        NatKind::Symmetric,
+                        2,
+                    ),
+                ];
+            });
+            let out = metrics::render(&state);
+            assert!(out.contains("\nodnp_channels 1\n"));
+            assert!(out.contains("\nodnp_users 2\n"));
+            assert!(out.contains("\nodnp_server_relay_users 1\n"));
+        }
+
+        #[tokio::test]
+        async fn malformed_packets_are_counted() {
+            let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let state = Arc::new(ServerState::new());
+            let before = metrics::MALFORMED_PACKETS.get();
+            handle_packet(b"garbage", "127.0.0.1:7003".parse().unwrap(), server, state).await;
+            assert!(metrics::MALFORMED_PACKETS.get() > before);
+        }
+
+        #[tokio::test]
+        async fn relayed_media_is_counted_by_kind() {
+            let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let state = Arc::new(ServerState::new());
+            let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut channel = media_channel();
+            channel.users.truncate(2);
+            channel.users[0].addr = a.local_addr().unwrap();
+            channel.users[1].addr = b.local_addr().unwrap();
+            state.restore_channel("s1", "ch1", channel);
+
+            let before = metrics::RELAYED_BYTES[Relayed::Srtp].get();
+            let pkt = packet::encode(media_header(42, 1, BROADCAST), b"media");
+            handle_packet(&pkt, a.local_addr().unwrap(), server, state).await;
+            let mut buf = [0u8; 64];
+            assert_eq!(b.recv(&mut buf).await.unwrap(), pkt.len());
+
+            assert!(metrics::RELAYED_BYTES[Relayed::Srtp].get() >= before + pkt.len() as u64);
+            assert!(metrics::RELAYED_PACKET_SIZE[Relayed::Srtp].count() > 0);
+            let out = metrics::render(&ServerState::new());
+            assert!(out.contains("odnp_relayed_bytes_total{kind=\"srtp\"} "));
+            assert!(out.contains("odnp_relayed_bytes_total{kind=\"dtls\"} "));
+            assert!(out.contains("odnp_relayed_packet_bytes_count{kind=\"data\"} "));
+        }
+
+        // reelections counted when peer `leaving` of a relayed channel of `users`
+        // disconnects, and when it times out instead
+        fn reelections(users: usize, leaving: u32) -> (u64, u64) {
+            use crate::signaling::handlers::notifications::handle_disconnect_notifications;
+            use crate::signaling::handlers::utils::{
+                find_and_remove_user, update_relay_after_departure,
+            };
+            use crate::signaling::heartbeat::collect_heartbeat_data;
+            use std::time::{Duration, Instant};
+
+            let mut channel = media_channel();
+            channel.users.truncate(users);
+            channel.relay = Some(1);
+            let user = channel.users[leaving as usize - 1].clone();
+
+            let before = metrics::RELAY_REELECTIONS.get();
+            let mut disconnected = channel.clone();
+            let (was_relay, gone) =
+                find_and_remove_user(&mut disconnected, &user.name, user.addr).unwrap();
+            let lone = update_relay_after_departure(&mut disconnected, was_relay);
+            handle_disconnect_notifications(
+                &mut disconnected,
+                was_relay,
+                &gone,
+                lone,
+                &mut Outbox::new(),
+            );
+            let by_disconnect = metrics::RELAY_REELECTIONS.get() - before;
+
+            let state = ServerState::new();
+            let mut timed_out = channel;
+            timed_out.users[leaving as usize - 1].last_pong =
+                Instant::now() - Duration::from_secs(60);
+            state.restore_channel("s1", "ch1", timed_out);
+            let before = metrics::RELAY_REELECTIONS.get();
+            collect_heartbeat_data(&state, Duration::from_secs(30));
+            let by_timeout = metrics::RELAY_REELECTIONS.get() - before;
+
+            (by_disconnect, by_timeout)
+        }
+
+        // no other test replaces a relay, so the counter only moves here
+        #[test]
+        fn disconnect_and_timeout_count_reelections_alike() {
+            // the relay leaves two users, one of them takes over
+            assert_eq!(reelections(3, 1), (1, 1));
+            // the relay leaves one user, who is relay now
+            assert_eq!(reelections(2, 1), (1, 1));
+            // a peer leaves, the relay stays
+            assert_eq!(reelections(3, 2), (0, 0));
+        }
+    }
+
+    mod snapshot {
+        use super::*;
+        use crate::signaling::snapshot::{self, Snapshot};
+        use crate::signaling::utils::generate_session_nonce;
+
+        // what a restart with the snapshot of `state` leaves behind
+        async fn restarted(state: &ServerState) -> Arc<ServerState> {
+            let path = std::env::temp_dir()
+                .join(format!("odnp-snapshot-{}.json", generate_session_nonce()));
+            snapshot::save(state, &path).await.unwrap();
+            let snap = snapshot::load(&path).await.unwrap().unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let fresh = Arc::new(ServerState::new());
+            assert_eq!(snap.restore(&fresh), 1);
+            fresh
+        }
+
+        #[cfg(unix)]
+        #[tokio::test]
+        async fn snapshot_file_is_private() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let (_, state, _) = two_peer_server().await;
+            let path = std::env::temp_dir()
+                .join(format!("odnp-snapshot-{}.json", generate_session_nonce()));
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            // a world-readable leftover of an interrupted save is not reused
+            std::fs::write(&tmp, b"{}").unwrap();
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+            snapshot::save(&state, &path).await.unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(mode & 0o777, 0o600);
+            assert!(!std::path::Path::new(&tmp).exists());
+        }
+
+        // skips the MODE messages of joining
+        async fn recv_skipping_modes(socket: &UdpSocket) -> ControlMessage {
+            loop {
+                let msg = recv_control(socket).await;
+                if !msg.is_mode() {
+                    return msg;
+                }
+            }
+        }
+
+        fn resume(channel_id: u64, peer_id: u32, nonce: u64) -> ControlMessage {
+            ControlMessage::Resume {
+                channel_id,
+                peer_id,
+                nonce,
+            }
+        }
+
+        #[tokio::test]
+        async fn snapshot_keeps_ids_relay_and_sessions() {
+            let (_, state, [(alice, alice_id, _), (bob, bob_id, bob_nonce)]) =
+                two_peer_server().await;
+            state.with_channel("s1", "ch1", |ch| {
+                ch.users[1].fingerprint = vec![0xab; 32];
+                ch.users[1].port_hint = PortHint::Delta(-2);
+            });
+            let before = state.with_channel("s1", "ch1", |ch| ch.clone()).unwrap();
+
+            let fresh = restarted(&state).await;
+            assert_eq!(Snapshot::capture(&fresh), Snapshot::capture(&state));
+            let after = fresh.with_channel("s1", "ch1", |ch| ch.clone()).unwrap();
+            assert_eq!(after.channel_id, before.channel_id);
+            assert_eq!(after.next_peer_id, 3);
+            assert_eq!(after.relay, Some(alice_id));
+            assert_eq!(after.users[1].nonce, bob_nonce);
+            assert_eq!(after.users[1].fingerprint, vec![0xab; 32]);
+            assert_eq!(
+                fresh.locate(bob.local_addr().unwrap()).map(|l| l.peer_id),
+                Some(bob_id)
+            );
+            assert!(fresh.locate(alice.local_addr().unwrap()).is_some());
+            assert_eq!(
+                snapshot::load("/nonexistent/odnp".as_ref()).await.unwrap(),
+                None
+            );
+        }
+
+        #[tokio::test]
+        async fn resumed_user_keeps_its_peer_id_from_a_new_address() {
+            let (server, state, [(alice, _, _), (bob, bob_id, bob_nonce)]) =
+                two_peer_server().await;
+            // MODE RELAY, then MODE DIRECT about bob
+            for _ in 0..2 {
+                let _ = recv_control(&alice).await;
+            }
+            let channel_id = state.with_channel("s1", "ch1", |ch| ch.channel_id).unwrap();
+            let state = restarted(&state).await;
+
+            let moved = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let moved_addr = moved.local_addr().unwrap();
+            handle_message(
+                resume(channel_id, bob_id, bob_nonce),
+                &Header::control(0, 0, 0, 0),
+                moved_addr,
+                server,
+                state.clone(),
+            )
+            .await;
+
+            assert_eq!(
+                recv_control(&moved).await,
+                ControlMessage::Welcome {
+                    channel_id,
+                    peer_id: bob_id,
+                    nonce: bob_nonce,
+                }
+            );
+            let ControlMessage::ModeDirect { peer_id, addr, .. } = recv_control(&alice).await
+            else {
+                panic!("expected MODE DIRECT");
+            };
+            assert_eq!((peer_id, addr), (bob_id, moved_addr));
+            assert_eq!(state.locate(moved_addr).map(|l| l.peer_id), Some(bob_id));
+            assert_eq!(state.locate(bob.local_addr().unwrap()), None);
+        }
+
+        #[tokio::test]
+        async fn resume_drops_the_session_its_address_held() {
+            let (server, state, [(alice, _, _), (_, bob_id, bob_nonce)]) = two_peer_server().await;
+            let channel_id = state.with_channel("s1", "ch1", |ch| ch.channel_id).unwrap();
+
+            // carol sits in another channel; bob comes back from her address
+            let carol = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let carol_addr = carol.local_addr().unwrap();
+            let mut other = Channel {
+                channel_id: channel_id ^ 1,
+                next_peer_id: 2,
+                ..Channel::default()
+            };
+            other
+                .users
+                .push(User::new("carol", carol_addr, NatKind::Cone, 1));
+            state.restore_channel("s1", "ch2", other);
+
+            let hdr = Header::control(0, 0, 0, 0);
+            handle_message(
+                resume(channel_id, bob_id, bob_nonce),
+                &hdr,
+                carol_addr,
+                server.clone(),
+                state.clone(),
+            )
+            .await;
+            assert!(matches!(
+                recv_control(&carol).await,
+                ControlMessage::Welcome { peer_id, .. } if peer_id == bob_id
+            ));
+            assert!(channel_users(&state).iter().any(|u| u.addr == carol_addr));
+            let ch2 = state.with_channel("s1", "ch2", |ch| ch.users.len());
+            assert_eq!(ch2, Some(0));
+            assert_eq!(state.locate(carol_addr).unwrap().channel, "ch1");
+
+            // nor does an address hold two sessions of one channel
+            let alice_addr = alice.local_addr().unwrap();
+            handle_message(
+                resume(channel_id, bob_id, bob_nonce),
+                &hdr,
+                alice_addr,
+                server,
+                state.clone(),
+            )
+            .await;
+            let users = channel_users(&state);
+            assert_eq!(users.len(), 1);
+            assert_eq!((users[0].peer_id, users[0].addr), (bob_id, alice_addr));
+            assert_eq!(
+                state.with_channel("s1", "ch1", |ch| ch.relay).unwrap(),
+                Some(bob_id)
+            );
+        }
+
+        #[tokio::test]
+        async fn resume_with_a_wrong_nonce_is_refused() {
+            let (server, state, [_, (bob, bob_id, bob_nonce)]) = two_peer_server().await;
+            let channel_id = state.with_channel("s1", "ch1", |ch| ch.channel_id).unwrap();
+            let bob_addr = bob.local_addr().unwrap();
+
+            for msg in [
+                resume(channel_id, bob_id, bob_nonce ^ 1),
+                resume(channel_id ^ 1, bob_id, bob_nonce),
+            ] {
+                handle_message(
+                    msg,
+                    &Header::control(0, 0, 0, 0),
+                    bob_addr,
+                    server.clone(),
+                    state.clone(),
+                )
+                .await;
+                assert!(matches!(
+                    recv_skipping_modes(&bob).await,
+                    ControlMessage::Rejected { .. }
+                ));
+            }
+        }
+
+        #[tokio::test]
+        async fn heartbeat_is_answered() {
+            let (server, state, [(alice, _, alice_nonce), _]) = two_peer_server().await;
+            let hb = ControlMessage::Heartbeat {
+                server_id: "s1".into(),
+                channel: "ch1".into(),
+                user: "alice".into(),
+                nonce: alice_nonce,
+            };
+            handle_message(
+                hb,
+                &Header::control(0, 0, 0, 0),
+                alice.local_addr().unwrap(),
+                server,
+                state,
+            )
+            .await;
+            assert_eq!(recv_skipping_modes(&alice).await, ControlMessage::Pong);
+        }
+    }
+
+    mod store {
+        use super::*;
+        use crate::signaling::{
+            cluster::{Cluster, ClusterStore},
+            store::{ChannelStore, MemoryStore},
+        };
+        use std::net::SocketAddr;
+
+        fn names(store: &dyn ChannelStore) -> Vec<String> {
+            let mut names = Vec::new();
+            store.for_each(&mut |sid, name, _| names.push(format!("{sid}/{name}")));
+            names.sort();
+            names
+        }
+
+        // what `ServerState` relies on, through the trait alone
+        fn holds_channels(store: &dyn ChannelStore) {
+            let addr: SocketAddr = "127.0.0.1:8003".parse().unwrap();
+            assert!(!store.update("s", "busy", false, &mut |_| panic!("no such channel")));
+            let mut created = 0;
+            assert!(store.update("s", "busy", true, &mut |ch| {
+                ch.users.push(User::new("name", addr, NatKind::Cone, 1));
+                created = ch.channel_id;
+            }));
+            let mut found = 0;
+            assert!(store.update("s", "busy", true, &mut |ch| found = ch.channel_id));
+            assert_ne!(created, 0);
+            assert_eq!(found, created);
+
+            let channel = |channel_id| Channel {
+                channel_id,
+                ..Channel::default()
+            };
+            assert!(store.insert("s", "empty", channel(7)).is_none());
+            assert_eq!(store.channel_count(), 2);
+            assert_eq!(names(store), ["s/busy", "s/empty"]);
+
+            assert!(store.remove("s", "busy", true).is_none());
+            assert_eq!(store.remove("s", "empty", true).unwrap().channel_id, 7);
+            let replaced = store.insert("s", "busy", channel(8)).unwrap();
+            assert_eq!((replaced.channel_id, replaced.users.len()), (created, 1));
+            assert_eq!(store.remove("s", "busy", false).unwrap().channel_id, 8);
+            assert_eq!(store.channel_count(), 0);
+            assert!(names(store).is_empty());
+            assert!(store.remove("s", "busy", false).is_none());
+        }
+
+        async fn cluster_of(nodes: Vec<SocketAddr>) -> Cluster {
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let node = socket.local_addr().unwrap();
+            Cluster::new(socket, node, nodes, b"cluster secret".to_vec())
+        }
+
+        #[test]
+        fn memory_store_holds_channels() {
+            let store = MemoryStore::default();
+            holds_channels(&store);
+            assert_eq!(store.owner("s", "busy"), None);
+            assert!(store.cluster().is_none());
+        }
+
+        #[tokio::test]
+        async fn cluster_store_of_one_instance_holds_channels() {
+            let store = ClusterStore::new(cluster_of(Vec::new()).await);
+            holds_channels(&store);
+            assert_eq!(store.owner("s", "busy"), None);
+            assert!(store.cluster().is_some());
+        }
+
+        #[tokio::test]
+        async fn cluster_store_leaves_replicas_to_their_owner() {
+            let other: SocketAddr = "127.0.0.1:9".parse().unwrap();
+            let store = ClusterStore::new(cluster_of(vec![other]).await);
+            let name = (0..)
+                .map(|i| format!("ch{i}"))
+                .find(|name| store.owner("s", name).is_some())
+                .unwrap();
+            assert_eq!(store.owner("s", &name), Some(other));
+
+            store.insert("s", &name, Channel::default());
+            assert!(store.update("s", &name, false, &mut |_| ()));
+            assert_eq!(store.channel_count(), 0);
+            assert!(names(&store).is_empty());
+            assert!(store.remove("s", &name, false).is_some());
+        }
+    }
+
+    mod cluster {
+        use super::*;
+        use crate::signaling::{
+            cluster::{self, Cluster, Message},
+            snapshot::ChannelSnapshot,
+        };
+        use std::net::SocketAddr;
+
+        const CLUSTER_SECRET: &[u8] = b"cluster secret";
+
+        async fn cluster_socket() -> Arc<UdpSocket> {
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+        }
+
+        fn link(socket: Arc<UdpSocket>, nodes: Vec<SocketAddr>, secret: &[u8]) -> Cluster {
+            let node = socket.local_addr().unwrap();
+            Cluster::new(socket, node, nodes, secret.to_vec())
+        }
+
+        // two instances serving "s1"/"ch1", the one owning it first
+        async fn pair() -> [(Arc<UdpSocket>, Arc<ServerState>); 2] {
+            let sockets = [cluster_socket().await, cluster_socket().await];
+            let nodes: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+            let mut instances = Vec::new();
+            for socket in sockets {
+                let state =
+                    ServerState::new().with_cluster(link(socket, nodes.clone(), CLUSTER_SECRET));
+                let state = Arc::new(state);
+                let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+                tokio::spawn(cluster::serve(server.clone(), state.clone()));
+                instances.push((server, state));
+            }
+            if instances[0].1.owner("s1", "ch1").is_some() {
+                instances.swap(0, 1);
+            }
+            let owner = instances[0].1.cluster().unwrap().node();
+            assert_eq!(instances[1].1.owner("s1", "ch1"), Some(owner));
+            instances.try_into().ok().unwrap()
+        }
+
+        // joins "s1"/"ch1", returning the client socket, peer id and channel id
+        async fn join(
+            name: &str,
+            nat_kind: NatKind,
+            server: &Arc<UdpSocket>,
+            state: &Arc<ServerState>,
+        ) -> (UdpSocket, u32, u64) {
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut msg = connect_msg(name, Vec::new());
+            if let ControlMessage::Connect { nat_kind: kind, .. } = &mut msg {
+                *kind = nat_kind;
+            }
+            handle_message(
+                msg,
+                &Header::control(0, 0, 0, 0),
+                sock.local_addr().unwrap(),
+                server.clone(),
+                state.clone(),
+            )
+            .await;
+            let ControlMessage::Welcome {
+                peer_id,
+                channel_id,
+                ..
+            } = recv_until(&sock, server.local_addr().unwrap(), |m| {
+                matches!(m, ControlMessage::Welcome { .. })
+            })
+            .await
+            else {
+                panic!("expected WELCOME");
+            };
+            (sock, peer_id, channel_id)
+        }
+
+        // the next message `wanted` matches, all of them sent by `from`
+        async fn recv_until(
+            socket: &UdpSocket,
+            from: SocketAddr,
+            wanted: impl Fn(&ControlMessage) -> bool,
+        ) -> ControlMessage {
+            let mut buf = [0u8; 2048];
+            loop {
+                let (len, src) = tokio::time::timeout(
+                    std::time::Duration::from_secs(1),
+                    socket.recv_from(&mut buf),
+                )
+                .await
+                .unwrap()
+                .unwrap();
+                assert_eq!(src, from);
+                let msg = control::decode_packet(&buf[..len]).unwrap().1;
+                if wanted(&msg) {
+                    return msg;
+                }
+            }
+        }
+
+        // waits for the owner to replicate a channel of `users` users to `state`
+        async fn replicated(state: &ServerState, users: usize) {
+            for _ in 0..50 {
+                if state.with_channel("s1", "ch1", |ch| ch.users.len()) == Some(users) {
+                    return;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            panic!("the channel was not replicated");
+        }
+
+        #[tokio::test]
+        async fn users_on_different_instances_learn_of_each_other() {
+            let [(server_a, state_a), (server_b, state_b)] = pair().await;
+            let (alice, alice_id, channel_a) =
+                join("alice", NatKind::Cone, &server_a, &state_a).await;
+            // bob's CONNECT is handled by A, which owns the channel
+            let (bob, bob_id, channel_b) = join("bob", NatKind::Cone, &server_b, &state_b).await;
+
+            assert_eq!(channel_a, channel_b);
+            assert_eq!((alice_id, bob_id), (1, 2));
+            assert_eq!((state_a.channel_count(), state_b.channel_count()), (1, 0));
+            let server_b_addr = server_b.local_addr().unwrap();
+            let about_alice = recv_until(&bob, server_b_addr, |m| {
+                matches!(m, ControlMessage::ModeDirect { peer_id: 1, .. })
+            })
+            .await;
+            assert!(
+                matches!(about_alice, ControlMessage::ModeDirect { addr, .. }
+                if addr == alice.local_addr().unwrap())
+            );
+            let server_a_addr = server_a.local_addr().unwrap();
+            recv_until(&alice, server_a_addr, |m| {
+                matches!(m, ControlMessage::ModeDirect { peer_id: 2, .. })
+            })
+            .await;
+            replicated(&state_b, 2).await;
+
+            handle_message(
+                ControlMessage::Disconnect {
+                    server_id: "s1".into(),
+                    channel: "ch1".into(),
+                    user: "bob".into(),
+                },
+                &Header::control(0, 0, 0, 0),
+                bob.local_addr().unwrap(),
+                server_b,
+                state_b.clone(),
+            )
+            .await;
+            let left = recv_until(&alice, server_a_addr, |m| {
+                matches!(m, ControlMessage::UserLeft { .. })
+            })
+            .await;
+            assert!(matches!(left, ControlMessage::UserLeft { peer_id: 2, .. }));
+            assert_eq!(channel_users(&state_a).len(), 1);
+            replicated(&state_b, 1).await;
+            assert!(state_b.locate(bob.local_addr().unwrap()).is_none());
+        }
+
+        #[tokio::test]
+        async fn server_relayed_data_is_forwarded_to_the_home_instance() {
+            let [(server_a, state_a), (server_b, state_b)] = pair().await;
+            let (alice, _, _) = join("alice", NatKind::Cone, &server_a, &state_a).await;
+            let (bob, bob_id, channel_id) =
+                join("bob", NatKind::Symmetric, &server_b, &state_b).await;
+            // B relays bob's DATA from its replica, without asking A
+            replicated(&state_b, 2).await;
+
+            let data = ControlMessage::Data {
+                sender: "bob".into(),
+                payload: b"hello".to_vec(),
+            };
+            handle_message(
+                data.clone(),
+                &Header::control(channel_id, bob_id, BROADCAST, 0),
+                bob.local_addr().unwrap(),
+                server_b,
+                state_b,
+            )
+            .await;
+            let got = recv_until(&alice, server_a.local_addr().unwrap(), |m| {
+                matches!(m, ControlMessage::Data { .. })
+            })
+            .await;
+            assert_eq!(got, data);
+        }
+
+        fn now_ms() -> u64 {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+        }
+
+        fn envelope(cluster: &Cluster, msg: &Message, sent_ms: u64) -> Vec<u8> {
+            let mut datagrams = cluster.seal(msg, sent_ms).unwrap();
+            assert_eq!(datagrams.len(), 1);
+            datagrams.remove(0)
+        }
+
+        #[tokio::test]
+        async fn forged_stale_and_replayed_envelopes_are_dropped() {
+            let socket = cluster_socket().await;
+            let cluster = link(socket, Vec::new(), CLUSTER_SECRET);
+            let msg = Message::Deliver {
+                dst: "[2001:db8::1]:4000".parse().unwrap(),
+                sealed: true,
+                packet: b"packet".to_vec(),
+            };
+            let now = now_ms();
+
+            let first = envelope(&cluster, &msg, now);
+            let second = envelope(&cluster, &msg, now);
+            let other = link(cluster_socket().await, Vec::new(), b"another secret");
+            assert_eq!(other.open(&first), None);
+            for at in [10, 30, first.len() - 1] {
+                let mut tampered = first.clone();
+                tampered[at] ^= 1;
+                assert_eq!(cluster.open(&tampered), None);
+            }
+            assert_eq!(cluster.open(&first[..20]), None);
+            assert_eq!(cluster.open(&envelope(&cluster, &msg, now - 60_000)), None);
+
+            // late is fine, twice is not
+            assert_eq!(cluster.open(&second).unwrap().message, msg);
+            assert_eq!(cluster.open(&first).unwrap().message, msg);
+            assert_eq!(cluster.open(&first), None);
+            assert_eq!(cluster.open(&second), None);
+        }
+
+        #[tokio::test]
+        async fn a_large_channel_goes_out_in_parts_that_fit_a_datagram() {
+            let cluster = link(cluster_socket().await, Vec::new(), CLUSTER_SECRET);
+            let mut channel = Channel {
+                channel_id: 42,
+                ..Channel::default()
+            };
+            for i in 0..300u32 {
+                let addr = SocketAddr::from(([10, 0, (i >> 8) as u8, i as u8], 4000));
+                let user = User::new(&format!("user{i}"), addr, NatKind::Cone, i + 1)
+                    .with_fingerprint(vec![0xab; 32]);
+                channel.users.push(user);
+            }
+            let snapshot = ChannelSnapshot::capture("s1", "ch1", &channel);
+            let msg = Message::Channel(snapshot);
+
+            let mut datagrams = cluster.seal(&msg, now_ms()).unwrap();
+            assert!(datagrams.len() > 1);
+            assert!(datagrams.iter().all(|d| d.len() <= 1200));
+            // parts may come in out of order, the channel is whole after the last
+            datagrams.swap(0, 1);
+            let last = datagrams.pop().unwrap();
+            for part in &datagrams {
+                assert_eq!(cluster.open(part), None);
+            }
+            let envelope = cluster.open(&last).unwrap();
+            assert_eq!(envelope.message, msg);
+
+            // one too large for the parts is refused, not sent empty
+            channel.users[0].name = "x".repeat(300 * 1024);
+            let snapshot = ChannelSnapshot::capture("s1", "ch1", &channel);
+            assert!(cluster.seal(&Message::Channel(snapshot), now_ms()).is_err());
+        }
+
+        #[tokio::test]
+        async fn envelopes_carry_nothing_in_the_clear() {
+            let cluster = link(cluster_socket().await, Vec::new(), CLUSTER_SECRET);
+            let mut channel = media_channel();
+            channel.users[0].check_key = b"the check key".to_vec();
+            let snapshot = ChannelSnapshot::capture("s1", "ch1", &channel);
+            let secrets = [
+                snapshot.users[0].nonce.to_string(),
+                snapshot.users[0].check_key.clone(),
+                "a control packet".to_string(),
+            ];
+            let client = Message::Client {
+                src: channel.users[0].addr,
+                packet: b"a control packet".to_vec(),
+            };
+
+            for msg in [Message::Channel(snapshot), client] {
+                for datagram in cluster.seal(&msg, now_ms()).unwrap() {
+                    let text = String::from_utf8_lossy(&datagram);
+                    assert!(secrets.iter().all(|s| !text.contains(s.as_str())));
+                    assert_eq!(cluster.open(&datagram).unwrap().message, msg);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,562 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::{
+    log, net,
+    proto::{
+        stun::{self, Class, Message},
+        token,
+    },
+    signaling::config::TurnConfig,
+};
+
+const DEFAULT_LIFETIME_SECS: u32 = 600;
+const MAX_LIFETIME_SECS: u32 = 3600;
+const PERMISSION_SECS: u64 = 300;
+const CHANNEL_SECS: u64 = 600;
+const NONCE_SECS: u64 = 600;
+const SWEEP_SECS: u64 = 30; // how often expired allocations are dropped
+
+// RFC 8656 §21: the relay must not become a way into the server's own host
+// or network, so these peers need `allowed_peers`
+fn internal_peer(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+        }
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_unique_local()
+                || v6.is_unicast_link_local()
+        }
+    }
+}
+
+// relayed bytes per second, `None` unlimited
+struct Quota {
+    limit: Option<u64>,
+    window: Instant,
+    used: u64,
+}
+
+impl Quota {
+    fn take(&mut self, len: usize, now: Instant) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.used = 0;
+        }
+        if self.used + len as u64 > limit {
+            return false;
+        }
+        self.used += len as u64;
+        true
+    }
+}
+
+struct Allocation {
+    relay: Arc<UdpSocket>,
+    relayed: SocketAddr,
+    username: String,
+    expires: Instant,
+    permissions: HashMap<IpAddr, Instant>,
+    channels: HashMap<u16, (SocketAddr, Instant)>,
+    quota: Quota,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Allocation {
+    fn permitted(&self, ip: IpAddr, now: Instant) -> bool {
+        self.permissions.get(&ip).is_some_and(|until| *until > now)
+    }
+
+    fn channel_of(&self, peer: SocketAddr, now: Instant) -> Option<u16> {
+        self.channels
+            .iter()
+            .find(|(_, (addr, until))| *addr == peer && *until > now)
+            .map(|(channel, _)| *channel)
+    }
+
+    fn peer_on(&self, channel: u16, now: Instant) -> Option<SocketAddr> {
+        self.channels
+            .get(&channel)
+            .filter(|(_, until)| *until > now)
+            .map(|(addr, _)| *addr)
+    }
+
+    // installs or refreshes permissions for `ips`, unless that exceeds `max`
+    fn permit(&mut self, ips: &[IpAddr], max: Option<usize>, now: Instant) -> bool {
+        self.permissions.retain(|_, until| *until > now);
+        let new = ips
+            .iter()
+            .filter(|ip| !self.permissions.contains_key(ip))
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        if max.is_some_and(|max| self.permissions.len() + new > max) {
+            return false;
+        }
+        for ip in ips {
+            self.permissions
+                .insert(*ip, now + Duration::from_secs(PERMISSION_SECS));
+        }
+        true
+    }
+}
+
+/// A TURN server (RFC 8656, UDP only) for WebRTC stacks that cannot speak
+/// the ODNP relay. Allocations are keyed by client address; each gets its own
+/// relay socket, permissions, channel bindings and byte quota.
+pub struct TurnServer {
+    socket: Arc<UdpSocket>,
+    relay_ip: IpAddr,
+    secret: Vec<u8>,
+    config: TurnConfig,
+    nonce_key: [u8; 32],
+    allocations: Mutex<HashMap<SocketAddr, Allocation>>,
+}
+
+type Reply = Result<Message, Message>;
+
+fn error(req: &Message, code: u16, reason: &str) -> Message {
+    let mut reply = req.reply(Class::Error);
+    reply.add_error(code, reason);
+    reply
+}
+
+impl TurnServer {
+    /// `socket` is where clients reach the server, relay sockets are bound on
+    /// `relay_ip`, and `secret` is the join token secret.
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        relay_ip: IpAddr,
+        secret: Vec<u8>,
+        config: TurnConfig,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            socket,
+            relay_ip,
+            secret,
+            config,
+            nonce_key: rand::random(),
+            allocations: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn allocation_count(&self) -> usize {
+        self.allocations.lock().unwrap().len()
+    }
+
+    fn peer_allowed(&self, ip: IpAddr) -> bool {
+        !internal_peer(ip) || self.config.allowed_peers.iter().any(|r| r.contains(ip))
+    }
+
+    pub async fn serve(self: Arc<Self>) {
+        let mut buf = [0u8; 2048];
+        let mut sweep = tokio::time::interval(Duration::from_secs(SWEEP_SECS));
+        loop {
+            tokio::select! {
+                received = net::recv_from(&self.socket, &mut buf) => {
+                    if let Ok((len, src)) = received {
+                        self.handle(&buf[..len], src).await;
+                    }
+                }
+                _ = sweep.tick() => self.sweep(),
+            }
+        }
+    }
+
+    fn sweep(&self) {
+        let now = Instant::now();
+        self.allocations.lock().unwrap().retain(|client, a| {
+            a.permissions.retain(|_, until| *until > now);
+            a.channels.retain(|_, (_, until)| *until > now);
+            if a.expires <= now {
+                log!(Info, addr = *client; "TURN allocation expired");
+            }
+            a.expires > now
+        });
+    }
+
+    async fn handle(self: &Arc<Self>, buf: &[u8], src: SocketAddr) {
+        if let Some((channel, data)) = stun::parse_channel_data(buf) {
+            self.forward_to_peer(src, None, Some(channel), data).await;
+            return;
+        }
+        let Some(msg) = Message::decode(buf) else {
+            log!(
+                Debug,
+                addr = src;
+                "Unknown/Bad TURN packet ({} bytes)",
+                buf.len()
+            );
+            return;
+        };
+        match msg.class {
+            Class::Indication if msg.method == stun::SEND => {
+                if let (Some(peer), Some(data)) = (
+                    msg.xor_addr(stun::ATTR_XOR_PEER_ADDRESS),
+                    msg.attr(stun::ATTR_DATA),
+                ) {
+                    self.forward_to_peer(src, Some(peer), None, data).await;
+                }
+            }
+            Class::Request => {
+                let reply = self.request(&msg, buf, src).await;
+                let _ = net::send_to(&self.socket, &reply, src).await;
+            }
+            _ => {}
+        }
+    }
+
+    async fn request(self: &Arc<Self>, msg: &Message, raw: &[u8], src: SocketAddr) -> Vec<u8> {
+        if msg.method == stun::BINDING {
+            let mut reply = msg.reply(Class::Success);
+            reply.add_xor_addr(stun::ATTR_XOR_MAPPED_ADDRESS, src);
+            return reply.encode(None);
+        }
+        if !matches!(
+            msg.method,
+            stun::ALLOCATE | stun::REFRESH | stun::CREATE_PERMISSION | stun::CHANNEL_BIND
+        ) {
+            return error(msg, 400, "Bad Request").encode(None);
+        }
+        let (username, key) = match self.authenticate(msg, raw) {
+            Ok(credentials) => credentials,
+            Err(reply) => return reply.encode(None),
+        };
+
+        let result = match msg.method {
+            stun::ALLOCATE => self.allocate(msg, src, username).await,
+            stun::REFRESH => self.refresh(msg, src, &username),
+            stun::CREATE_PERMISSION => self.create_permission(msg, src, &username),
+            _ => self.channel_bind(msg, src, &username),
+        };
+        let (Ok(mut reply) | Err(mut reply)) = result;
+        reply.add(stun::ATTR_SOFTWARE, "od-nat-piercer");
+        reply.encode(Some(&key))
+    }
+
+    fn nonce(&self, expires_at: u64) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.nonce_key).expect("HMAC accepts any key length");
+        mac.update(&expires_at.to_be_bytes());
+        let tag = mac.finalize().into_bytes();
+        format!("{expires_at:016x}{}", token::to_hex(&tag[..8]))
+    }
+
+    fn nonce_valid(&self, nonce: &str, now: u64) -> bool {
+        let Some(expires_at) = nonce
+            .get(..16)
+            .and_then(|e| u64::from_str_radix(e, 16).ok())
+        else {
+            return false;
+        };
+        expires_at >= now && self.nonce(expires_at) == nonce
+    }
+
+    // the long-term credential mechanism, the password is the join token
+    fn authenticate(&self, msg: &Message, raw: &[u8]) -> Result<(String, Vec<u8>), Message> {
+        let now = token::now_secs();
+        let challenge = |code, reason| {
+            let mut reply = error(msg, code, reason);
+            reply
+                .add(stun::ATTR_REALM, self.config.realm.as_str())
+                .add(stun::ATTR_NONCE, self.nonce(now + NONCE_SECS));
+            reply
+        };
+
+        if msg.attr(stun::ATTR_MESSAGE_INTEGRITY).is_none() {
+            return Err(challenge(401, "Unauthorized"));
+        }
+        let (Some(username), Some(realm), Some(nonce)) = (
+            msg.str_attr(stun::ATTR_USERNAME),
+            msg.str_attr(stun::ATTR_REALM),
+            msg.str_attr(stun::ATTR_NONCE),
+        ) else {
+            return Err(error(msg, 400, "Bad Request"));
+        };
+        if realm != self.config.realm || !self.nonce_valid(nonce, now) {
+            return Err(challenge(438, "Stale Nonce"));
+        }
+
+        let password = token::parse_turn_username(username)
+            .filter(|(expires_at, ..)| *expires_at >= now)
+            .map(|(expires_at, server_id, channel, user)| {
+                token::to_hex(&token::sign(
+                    &self.secret,
+                    server_id,
+                    channel,
+                    user,
+                    expires_at,
+                ))
+            });
+        let key = password.map(|p| stun::long_term_key(username, realm, &p));
+        match key {
+            Some(key) if stun::check_integrity(raw, &key) => Ok((username.to_string(), key)),
+            _ => {
+                log!(Info, "TURN request with bad credentials ({})", username);
+                Err(challenge(401, "Unauthorized"))
+            }
+        }
+    }
+
+    fn lifetime(msg: &Message) -> u32 {
+        msg.u32_attr(stun::ATTR_LIFETIME)
+            .unwrap_or(DEFAULT_LIFETIME_SECS)
+            .clamp(DEFAULT_LIFETIME_SECS, MAX_LIFETIME_SECS)
+    }
+
+    async fn allocate(self: &Arc<Self>, msg: &Message, src: SocketAddr, username: String) -> Reply {
+        if self.allocations.lock().unwrap().contains_key(&src) {
+            return Err(error(msg, 437, "Allocation Mismatch"));
+        }
+        match msg.attr(stun::ATTR_REQUESTED_TRANSPORT) {
+            Some([stun::TRANSPORT_UDP, ..]) => {}
+            Some(_) => return Err(error(msg, 442, "Unsupported Transport Protocol")),
+            None => return Err(error(msg, 400, "Bad Request")),
+        }
+        if self
+            .config
+            .max_allocations
+            .is_some_and(|max| self.allocation_count() >= max)
+        {
+            return Err(error(msg, 486, "Allocation Quota Reached"));
+        }
+
+        let relay = match net::bind(self.relay_ip, 0).await {
+            Ok(socket) => Arc::new(socket),
+            Err(e) => {
+                log!(Warn, "Cannot bind a TURN relay socket: {}", e);
+                return Err(error(msg, 508, "Insufficient Capacity"));
+            }
+        };
+        let relayed = relay
+            .local_addr()
+            .map_err(|_| error(msg, 508, "Insufficient Capacity"))?;
+        let lifetime = Self::lifetime(msg);
+        let reader = tokio::spawn(relay_loop(Arc::downgrade(self), src, Arc::clone(&relay)));
+        let allocation = Allocation {
+            relay,
+            relayed,
+            username,
+            expires: Instant::now() + Duration::from_secs(lifetime as u64),
+            permissions: HashMap::new(),
+            channels: HashMap::new(),
+            quota: Quota {
+                limit: self.config.max_bytes_per_sec,
+                window: Instant::now(),
+                used: 0,
+            },
+            reader,
+        };
+
+        let mut allocations = self.allocations.lock().unwrap();
+        if allocations.contains_key(&src) {
+            return Err(error(msg, 437, "Allocation Mismatch"));
+        }
+        log!(
+            Info,
+            addr = src;
+            "TURN allocation {} ({})",
+            relayed,
+            allocation.username
+        );
+        allocations.insert(src, allocation);
+
+        let mut reply = msg.reply(Class::Success);
+        reply
+            .add_xor_addr(stun::ATTR_XOR_RELAYED_ADDRESS, relayed)
+            .add(stun::ATTR_LIFETIME, lifetime.to_be_bytes())
+            .add_xor_addr(stun::ATTR_XOR_MAPPED_ADDRESS, src);
+        Ok(reply)
+    }
+
+    // runs `f` on the live allocation of `src`, made with the same credentials
+    fn with_allocation(
+        &self,
+        msg: &Message,
+        src: SocketAddr,
+        username: &str,
+        f: impl FnOnce(&mut Allocation, Instant) -> Reply,
+    ) -> Reply {
+        let now = Instant::now();
+        let mut allocations = self.allocations.lock().unwrap();
+        match allocations.get_mut(&src).filter(|a| a.expires > now) {
+            None => Err(error(msg, 437, "Allocation Mismatch")),
+            Some(a) if a.username != username => Err(error(msg, 441, "Wrong Credentials")),
+            Some(a) => f(a, now),
+        }
+    }
+
+    fn refresh(&self, msg: &Message, src: SocketAddr, username: &str) -> Reply {
+        let requested = msg.u32_attr(stun::ATTR_LIFETIME);
+        let reply = self.with_allocation(msg, src, username, |a, now| {
+            let lifetime = match requested {
+                Some(0) => 0,
+                _ => Self::lifetime(msg),
+            };
+            a.expires = now + Duration::from_secs(lifetime as u64);
+            let mut reply = msg.reply(Class::Success);
+            reply.add(stun::ATTR_LIFETIME, lifetime.to_be_bytes());
+            Ok(reply)
+        })?;
+        if requested == Some(0) {
+            self.allocations.lock().unwrap().remove(&src);
+            log!(Info, addr = src; "TURN allocation released");
+        }
+        Ok(reply)
+    }
+
+    fn create_permission(&self, msg: &Message, src: SocketAddr, username: &str) -> Reply {
+        let peers = msg.xor_addrs(stun::ATTR_XOR_PEER_ADDRESS);
+        let max = self.config.max_permissions;
+        self.with_allocation(msg, src, username, |a, now| {
+            if peers.is_empty() {
+                return Err(error(msg, 400, "Bad Request"));
+            }
+            if peers.iter().any(|p| p.is_ipv4() != a.relayed.is_ipv4()) {
+                return Err(error(msg, 443, "Peer Address Family Mismatch"));
+            }
+            if let Some(peer) = peers.iter().find(|p| !self.peer_allowed(p.ip())) {
+                log!(Info, addr = src; "TURN permission for {} refused", peer.ip());
+                return Err(error(msg, 403, "Forbidden"));
+            }
+            let ips: Vec<IpAddr> = peers.iter().map(|p| p.ip()).collect();
+            if !a.permit(&ips, max, now) {
+                return Err(error(msg, 508, "Insufficient Capacity"));
+            }
+            Ok(msg.reply(Class::Success))
+        })
+    }
+
+    fn channel_bind(&self, msg: &Message, src: SocketAddr, username: &str) -> Reply {
+        let channel = msg
+            .u32_attr(stun::ATTR_CHANNEL_NUMBER)
+            .map(|v| (v >> 16) as u16)
+            .filter(|c| (stun::CHANNEL_MIN..=stun::CHANNEL_MAX).contains(c));
+        let peer = msg.xor_addr(stun::ATTR_XOR_PEER_ADDRESS);
+        let max = self.config.max_permissions;
+        self.with_allocation(msg, src, username, |a, now| {
+            let (Some(channel), Some(peer)) = (channel, peer) else {
+                return Err(error(msg, 400, "Bad Request"));
+            };
+            if peer.is_ipv4() != a.relayed.is_ipv4() {
+                return Err(error(msg, 443, "Peer Address Family Mismatch"));
+            }
+            if !self.peer_allowed(peer.ip()) {
+                log!(Info, addr = src; "TURN channel to {} refused", peer.ip());
+                return Err(error(msg, 403, "Forbidden"));
+            }
+            // a channel stays with one peer and a peer with one channel
+            if a.peer_on(channel, now).is_some_and(|p| p != peer)
+                || a.channel_of(peer, now).is_some_and(|c| c != channel)
+            {
+                return Err(error(msg, 400, "Bad Request"));
+            }
+            if !a.permit(&[peer.ip()], max, now) {
+                return Err(error(msg, 508, "Insufficient Capacity"));
+            }
+            a.channels
+                .insert(channel, (peer, now + Duration::from_secs(CHANNEL_SECS)));
+            Ok(msg.reply(Class::Success))
+        })
+    }
+
+    // a Send indication (to `peer`) or ChannelData (on `channel`) from a client
+    async fn forward_to_peer(
+        &self,
+        src: SocketAddr,
+        peer: Option<SocketAddr>,
+        channel: Option<u16>,
+        data: &[u8],
+    ) {
+        let target = {
+            let now = Instant::now();
+            let mut allocations = self.allocations.lock().unwrap();
+            allocations
+                .get_mut(&src)
+                .filter(|a| a.expires > now)
+                .and_then(|a| {
+                    let peer = peer.or_else(|| a.peer_on(channel?, now))?;
+                    (a.permitted(peer.ip(), now) && a.quota.take(data.len(), now))
+                        .then(|| (Arc::clone(&a.relay), peer))
+                })
+        };
+        match target {
+            Some((relay, peer)) => {
+                let _ = net::send_to(&relay, data, peer).await;
+            }
+            None => log!(Trace, addr = src; "Dropping TURN data"),
+        }
+    }
+
+    // what reaches `client` for `data` its relay socket got from `peer`
+    fn forward_to_client(
+        &self,
+        client: SocketAddr,
+        peer: SocketAddr,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let mut allocations = self.allocations.lock().unwrap();
+        let a = allocations.get_mut(&client).filter(|a| a.expires > now)?;
+        if !a.permitted(peer.ip(), now) || !a.quota.take(data.len(), now) {
+            return None;
+        }
+        Some(match a.channel_of(peer, now) {
+            Some(channel) => stun::channel_data(channel, data),
+            None => {
+                let mut indication = Message::new(stun::DATA, Class::Indication, rand::random());
+                indication
+                    .add_xor_addr(stun::ATTR_XOR_PEER_ADDRESS, peer)
+                    .add(stun::ATTR_DATA, data);
+                indication.encode(None)
+            }
+        })
+    }
+}
+
+// forwards what peers send to a relayed address; ends with the allocation
+async fn relay_loop(server: Weak<TurnServer>, client: SocketAddr, relay: Arc<UdpSocket>) {
+    let mut buf = [0u8; 2048];
+    loop {
+        let Ok((len, peer)) = net::recv_from(&relay, &mut buf).await else {
+            continue;
+        };
+        let Some(server) = server.upgrade() else {
+            return;
+        };
+        match server.forward_to_client(client, peer, &buf[..len]) {
+            Some(out) => {
+                let _ = net::send_to(&server.socket, &out, client).await;
+            }
+            None => log!(
+                Trace,
+                addr = client;
+                "Dropping relayed packet from {}",
+                peer
+            ),
+        }
+    }
+}
@@ -1,6 +1,15 @@
 use std::net::SocketAddr;
 
 use rand::{RngCore, rngs::OsRng};
+use tokio::net::UdpSocket;
+
+use crate::log;
+use crate::net;
+use crate::proto::control::{self, CandidateKind, ControlMessage, TooLong};
+use crate::signaling::{cluster::Message, state::ServerState, structures::User};
+
+// packets built while a channel is locked, sent once the lock is released
+pub type Outbox = Vec<(SocketAddr, Vec<u8>)>;
 
 //helper to avoid moving Vec in pattern
 pub fn cleanup_and_notify_iter<I>(it: I) -> Vec<(Vec<SocketAddr>, Vec<u8>)>
@@ -13,3 +22,118 @@ where
 pub fn generate_channel_id() -> u64 {
     OsRng.next_u64()
 }
+
+pub fn generate_session_nonce() -> u64 {
+    OsRng.next_u64()
+}
+
+// server-originated control packets carry no channel/peer addressing
+pub fn control_packet(msg: &ControlMessage) -> Result<Vec<u8>, TooLong> {
+    control::encode_packet(0, 0, 0, msg)
+}
+
+pub fn queue_control(outbox: &mut Outbox, msg: &ControlMessage, dst: SocketAddr) {
+    match control_packet(msg) {
+        Ok(pkt) => outbox.push((dst, pkt)),
+        Err(e) => log!(Warn, addr = dst; "Not sending {}: {}", msg.name(), e),
+    }
+}
+
+pub async fn flush_outbox(socket: &UdpSocket, state: &ServerState, outbox: Outbox) {
+    for (addr, pkt) in outbox {
+        if let Err(e) = send_sealed(socket, state, pkt, addr).await {
+            log!(Warn, addr = addr; "Failed to send: {}", e);
+        }
+    }
+}
+
+pub async fn send_control(
+    socket: &UdpSocket,
+    state: &ServerState,
+    msg: &ControlMessage,
+    dst: SocketAddr,
+) -> std::io::Result<usize> {
+    let pkt = control_packet(msg).map_err(std::io::Error::other)?;
+    send_sealed(socket, state, pkt, dst).await
+}
+
+// every control packet to a client goes through its Noise session, if it has
+// one; users of another instance get it from there, sealed in their session
+pub async fn send_sealed(
+    socket: &UdpSocket,
+    state: &ServerState,
+    pkt: Vec<u8>,
+    dst: SocketAddr,
+) -> std::io::Result<usize> {
+    if let Some((cluster, home)) = state.remote_home(dst) {
+        let deliver = Message::Deliver {
+            dst,
+            sealed: true,
+            packet: pkt,
+        };
+        return cluster.send(home, &deliver).await;
+    }
+    match state.outbound(dst, pkt) {
+        Some(pkt) => net::send_to(socket, &pkt, dst).await,
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            "no signaling session",
+        )),
+    }
+}
+
+// media is end-to-end encrypted and forwarded as received
+pub async fn send_media(
+    socket: &UdpSocket,
+    state: &ServerState,
+    pkt: &[u8],
+    dst: SocketAddr,
+) -> std::io::Result<usize> {
+    match state.remote_home(dst) {
+        Some((cluster, home)) => {
+            let deliver = Message::Deliver {
+                dst,
+                sealed: false,
+                packet: pkt.to_vec(),
+            };
+            cluster.send(home, &deliver).await
+        }
+        None => net::send_to(socket, pkt, dst).await,
+    }
+}
+
+// `user` as announced to whoever sits at `to`: private (host) addresses only
+// reach peers behind the same public IP, the only ones that can use them
+pub fn mode_direct(user: &User, to: SocketAddr) -> ControlMessage {
+    let same_nat = to.ip() == user.addr.ip();
+    ControlMessage::ModeDirect {
+        user: user.name.clone(),
+        addr: user.addr,
+        peer_id: user.peer_id,
+        port_hint: user.port_hint,
+        fingerprint: user.fingerprint.clone(),
+        candidates: user
+            .candidates
+            .iter()
+            .filter(|c| same_nat || c.kind != CandidateKind::Host)
+            .copied()
+            .collect(),
+        check_key: user.check_key.clone(),
+    }
+}
+
+pub fn mode_server_relay(user: &User) -> ControlMessage {
+    ControlMessage::ModeServerRelay {
+        user: user.name.clone(),
+        peer_id: user.peer_id,
+        fingerprint: user.fingerprint.clone(),
+    }
+}
+
+pub fn user_left(user: &User) -> ControlMessage {
+    ControlMessage::UserLeft {
+        user: user.name.clone(),
+        addr: Some(user.addr),
+        peer_id: user.peer_id,
+    }
+}